@@ -0,0 +1,202 @@
+use snafu::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not read aliases file `{}': {}", path.to_string_lossy(), source))]
+    ReadAliasesFile { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Invalid alias on line {} of `{}'", line, path.to_string_lossy()))]
+    ParseAlias { path: PathBuf, line: usize },
+
+    #[snafu(display("Alias `{}' refers to itself", alias))]
+    AliasCycle { alias: String },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Email aliases, which map names to lists of addresses and other aliases.
+pub struct Aliases(HashMap<String, Vec<String>>);
+
+impl Aliases {
+    /// Read an aliases file. Both the mutt format, `alias name Full Name <address>, ...`, and the
+    /// msmtp and sendmail format, `name: address, ...`, are accepted. Blank lines and lines
+    /// beginning with `#` are ignored, as are lines which begin with whitespace.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).context(ReadAliasesFileSnafu { path })?;
+        Self::parse(&contents, path)
+    }
+
+    /// Parse the contents of the aliases file at `path`.
+    fn parse(contents: &str, path: &Path) -> Result<Self> {
+        let mut aliases = HashMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with(['#', ' ', '\t']) {
+                continue;
+            }
+            let (name, targets) = match line.strip_prefix("alias ") {
+                Some(rest) => rest.trim_start().split_once([' ', '\t']),
+                None => line.split_once(':'),
+            }
+            .context(ParseAliasSnafu { path, line: i + 1 })?;
+            let targets: Vec<String> = targets
+                .split(',')
+                .map(|target| {
+                    // Only keep the address of targets like `Full Name <address>`.
+                    let target = target.trim();
+                    target
+                        .rsplit_once('<')
+                        .and_then(|(_, address)| address.strip_suffix('>'))
+                        .unwrap_or(target)
+                        .to_string()
+                })
+                .filter(|target| !target.is_empty())
+                .collect();
+            ensure!(!targets.is_empty(), ParseAliasSnafu { path, line: i + 1 });
+            aliases.insert(name.trim().to_string(), targets);
+        }
+        Ok(Self(aliases))
+    }
+
+    /// Replace each alias among the given recipients with the addresses it refers to, recursively.
+    pub fn expand(&self, recipients: impl IntoIterator<Item = String>) -> Result<HashSet<String>> {
+        let mut expanded = HashSet::new();
+        for recipient in recipients {
+            self.expand_into(recipient, &mut Vec::new(), &mut expanded)?;
+        }
+        Ok(expanded)
+    }
+
+    fn expand_into(
+        &self,
+        recipient: String,
+        stack: &mut Vec<String>,
+        expanded: &mut HashSet<String>,
+    ) -> Result<()> {
+        let targets = match self.0.get(&recipient) {
+            Some(targets) => targets,
+            None => {
+                expanded.insert(recipient);
+                return Ok(());
+            }
+        };
+        ensure!(
+            !stack.contains(&recipient),
+            AliasCycleSnafu { alias: recipient }
+        );
+        stack.push(recipient);
+        for target in targets {
+            self.expand_into(target.clone(), stack, expanded)?;
+        }
+        stack.pop();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aliases(contents: &str) -> Aliases {
+        Aliases::parse(contents, Path::new("aliases")).unwrap()
+    }
+
+    fn expand(aliases: &Aliases, recipients: &[&str]) -> Result<Vec<String>> {
+        let mut expanded: Vec<String> = aliases
+            .expand(recipients.iter().map(|x| x.to_string()))?
+            .into_iter()
+            .collect();
+        expanded.sort();
+        Ok(expanded)
+    }
+
+    #[test]
+    fn mutt_aliases() {
+        let aliases = aliases(
+            "\
+# Friends
+alias alice Alice Liddell <alice@example.com>
+alias  both  Alice <alice@example.com>, bob@example.com
+",
+        );
+        assert_eq!(expand(&aliases, &["alice"]).unwrap(), ["alice@example.com"]);
+        assert_eq!(
+            expand(&aliases, &["both"]).unwrap(),
+            ["alice@example.com", "bob@example.com"]
+        );
+    }
+
+    #[test]
+    fn sendmail_aliases() {
+        let aliases = aliases(
+            "\
+team: alice@example.com, Bob <bob@example.com>
+  ignored: nobody@example.com
+",
+        );
+        assert_eq!(
+            expand(&aliases, &["team", "carol@example.com"]).unwrap(),
+            ["alice@example.com", "bob@example.com", "carol@example.com"]
+        );
+        assert_eq!(expand(&aliases, &["ignored"]).unwrap(), ["ignored"]);
+    }
+
+    #[test]
+    fn invalid_aliases() {
+        assert!(matches!(
+            Aliases::parse("\nteam\n", Path::new("aliases")),
+            Err(Error::ParseAlias { line: 2, .. })
+        ));
+        assert!(matches!(
+            Aliases::parse("team: , \n", Path::new("aliases")),
+            Err(Error::ParseAlias { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn nested_aliases() {
+        let aliases = aliases(
+            "\
+everyone: team, carol@example.com
+team: alice, bob@example.com
+alias alice Alice <alice@example.com>
+",
+        );
+        assert_eq!(
+            expand(&aliases, &["everyone"]).unwrap(),
+            ["alice@example.com", "bob@example.com", "carol@example.com"]
+        );
+    }
+
+    #[test]
+    fn shared_targets_are_not_cycles() {
+        let aliases = aliases(
+            "\
+all: left, right
+left: shared
+right: shared
+shared: alice@example.com
+",
+        );
+        assert_eq!(expand(&aliases, &["all"]).unwrap(), ["alice@example.com"]);
+    }
+
+    #[test]
+    fn cycles_are_errors() {
+        let aliases = aliases(
+            "\
+a: b
+b: c, alice@example.com
+c: a
+",
+        );
+        assert!(matches!(
+            expand(&aliases, &["a"]),
+            Err(Error::AliasCycle { alias }) if alias == "a"
+        ));
+    }
+}
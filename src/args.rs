@@ -1,4 +1,5 @@
 use clap::{Parser, Subcommand};
+use clap_complete::Shell;
 use clap_verbosity_flag::{Verbosity, WarnLevel};
 use const_format::formatcp;
 use std::path::PathBuf;
@@ -13,16 +14,38 @@ const VERSION: &str = formatcp!("{}\n{}", clap::crate_version!(), LICENSE);
 #[derive(Parser, Debug)]
 #[clap(author, version, long_version = VERSION, about, long_about = None)]
 pub struct Args {
-    /// Path to config file.
+    /// Path to the maildir containing `mujmap.toml`, or to a config file.
     ///
     /// Defaults to the current working directory.
-    #[clap(short = 'C', long)]
+    #[clap(short = 'C', long, alias = "config")]
     pub path: Option<PathBuf>,
 
     /// Test a sync without committing any changes.
     #[clap(short, long)]
     pub dry_run: bool,
 
+    /// Don't contact the server. Local changes are pushed by the next sync which isn't offline.
+    #[clap(long)]
+    pub offline: bool,
+
+    /// Download at most this many new messages, e.g. to try out a config on a large account.
+    ///
+    /// The next sync continues where this one stopped.
+    #[clap(long, name = "N")]
+    pub max_messages: Option<usize>,
+
+    /// Don't show a progress bar. It's never shown when stderr isn't a terminal.
+    #[clap(long)]
+    pub no_progress: bool,
+
+    /// Report the progress of a sync as JSON objects, one per line, instead of as text.
+    #[clap(long)]
+    pub json_progress: bool,
+
+    /// Report how long each part of a sync took, and how much mail was downloaded.
+    #[clap(long)]
+    pub profile: bool,
+
     #[clap(flatten)]
     pub verbose: Verbosity<WarnLevel>,
 
@@ -36,14 +59,21 @@ pub enum Command {
     Push,
     /// Synchronize mail.
     Sync,
+    /// Synchronize mail, discarding mujmap's saved state and rebuilding it from the server.
+    ///
+    /// Existing mail files are reused rather than downloaded again. Tags of all messages are
+    /// replaced with those on the server.
+    Resync,
     /// Send mail.
     Send {
         /// Ignored sendmail-compatible flag.
         #[clap(short = 'i')]
         sendmail1: bool,
-        /// Ignored sendmail-compatible flag.
+        /// Envelope sender address, to which bounces are sent.
+        ///
+        /// It must match one of your identities; otherwise, the sender of the message is used.
         #[clap(short = 'f', name = "NAME")]
-        sendmail2: Option<String>,
+        envelope_from: Option<String>,
         /// Ignored sendmail-compatible flag.
         #[clap(short = 'F', name = "FULLNAME")]
         sendmail3: Option<String>,
@@ -52,7 +82,140 @@ pub enum Command {
         /// If specified, the recipient arguments are ignored.
         #[clap(short = 't', long)]
         read_recipients: bool,
+        /// Don't expand recipient arguments which are aliases from the `aliases_file` option.
+        #[clap(long)]
+        no_aliases: bool,
+        /// Read the message from the given file instead of stdin.
+        #[clap(long, name = "PATH", conflicts_with = "DRAFT")]
+        file: Option<PathBuf>,
+        /// Send the notmuch message with the given ID, e.g. `id:1234@example.com`.
+        ///
+        /// If it's a draft synchronized by mujmap, it's replaced by the sent copy by the next sync.
+        #[clap(long, name = "DRAFT")]
+        draft: Option<String>,
+        /// Wait for the message to be delivered and report its status for each recipient.
+        ///
+        /// Exits unsuccessfully if delivery to any recipient fails.
+        #[clap(long)]
+        wait: bool,
+        /// Instead of sending mail, cancel all mail which has not been sent yet because of
+        /// `send_delay`, and move it back to the drafts mailbox.
+        #[clap(long)]
+        undo: bool,
+        /// Have the server send the message at the given time, e.g. `2024-06-01T09:00+02:00`.
+        ///
+        /// The time must end with `Z` or a UTC offset. Until then, the message stays in the drafts
+        /// mailbox, and `--undo` cancels it.
+        #[clap(long, name = "TIME", conflicts_with = "wait")]
+        send_at: Option<String>,
+        /// Request delivery status notifications for the given comma-separated events: `success`,
+        /// `failure`, and `delay`, or `never`.
+        #[clap(short = 'N', long, name = "EVENTS")]
+        dsn: Option<String>,
+        /// How much of the message to return in delivery status notifications: `full` or `hdrs`.
+        #[clap(short = 'R', long, name = "RETURN")]
+        dsn_return: Option<String>,
+        /// Once the message is submitted, print the `id`, `blobId`, and `threadId` of the email
+        /// created for it on the server, along with its `Message-ID`.
+        #[clap(long)]
+        print_ids: bool,
+        /// Like `--print-ids`, but print them as a JSON object.
+        #[clap(long)]
+        json: bool,
         /// Email addresses of the recipients of the message.
         recipients: Vec<String>,
     },
+    /// Send mail which was queued because the server could not be reached.
+    Flush,
+    /// Add and remove tags of messages, given by search terms like `id:<message-id>`.
+    ///
+    /// Tags are changed directly on the server, so the messages needn't be in the local maildir.
+    Tag {
+        /// Change the tags on the server only. This is currently required; use `notmuch tag` to
+        /// change tags locally.
+        #[clap(long, required = true)]
+        remote: bool,
+        /// Tag operations, e.g. `+archive -inbox`.
+        operations: String,
+        /// Search terms of the form `id:<message-id>`.
+        #[clap(required = true)]
+        queries: Vec<String>,
+    },
+    /// List every tag mujmap knows of, what it maps to on the server, and how many local messages
+    /// have it.
+    ///
+    /// Tags map to mailboxes, keywords, or, for tags which are neither, nothing ("local-only").
+    /// Automatic tags like `attachment` are never synced.
+    Tags,
+    /// Explain why a message has its tags: which mailboxes and keywords on the server they come
+    /// from, which are local-only or yet to be pushed, and which pushed changes touched it.
+    Blame {
+        /// Search term of the form `id:<message-id>`.
+        query: String,
+    },
+    /// Revert the tag changes pushed by the last sync, both on the server and in notmuch.
+    ///
+    /// Each invocation reverts one more sync, up to the last 100.
+    Undo,
+    /// Print a shell completion script for mujmap.
+    Completions {
+        /// Shell to print the completion script for.
+        #[clap(arg_enum)]
+        shell: Shell,
+    },
+    /// Print a man page for mujmap in roff format.
+    Manpage,
+    /// Check that the local maildir and notmuch database are consistent with the server.
+    ///
+    /// Nothing is changed on either side. Exits unsuccessfully if any inconsistencies are found.
+    Verify {
+        /// Make the next sync a full sync, which fixes most inconsistencies, and exit successfully.
+        #[clap(long)]
+        repair: bool,
+    },
+    /// Inspect the config file.
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommand,
+    },
+    /// List and manage mailboxes on the server.
+    ///
+    /// Without a subcommand, list all mailboxes and the tags they map to.
+    #[clap(alias = "mailbox")]
+    Mailboxes {
+        #[clap(subcommand)]
+        command: Option<MailboxesCommand>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Check the config file and print every problem found with it, such as unknown options,
+    /// conflicting options, a failing password command, or an inaccessible maildir.
+    ///
+    /// Exits unsuccessfully if any problems are found.
+    Check,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum MailboxesCommand {
+    /// Subscribe to a mailbox, given by its ID or full path.
+    Subscribe { mailbox: String },
+    /// Unsubscribe from a mailbox, given by its ID or full path.
+    Unsubscribe { mailbox: String },
+    /// Create a mailbox, given by its full path, along with any missing parent mailboxes.
+    Create { mailbox: String },
+    /// Rename or move a mailbox, given by its ID or full path, to a new full path.
+    Rename {
+        mailbox: String,
+        new_mailbox: String,
+    },
+    /// Delete a mailbox, given by its ID or full path.
+    Delete {
+        mailbox: String,
+        /// Also remove all emails from the mailbox, destroying those which belong to no other
+        /// mailbox. Without this flag, only empty mailboxes can be deleted.
+        #[clap(long)]
+        with_emails: bool,
+    },
 }
@@ -0,0 +1,243 @@
+use crate::config::Config;
+use crate::jmap;
+use crate::journal;
+use crate::local::{self, Local};
+use crate::remote::{self, Remote};
+use crate::sync::{self, LatestState};
+use crate::tag;
+use itertools::Itertools;
+use snafu::prelude::*;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("{}", source))]
+    ParseQuery { source: tag::Error },
+
+    #[snafu(display("Could not open local database: {}", source))]
+    OpenLocal { source: local::Error },
+
+    #[snafu(display("Could not index local emails: {}", source))]
+    IndexLocalEmails { source: local::Error },
+
+    #[snafu(display("Could not read sync state: {}", source))]
+    ReadState { source: sync::Error },
+
+    #[snafu(display("Could not read journal: {}", source))]
+    ReadJournal { source: journal::Error },
+
+    #[snafu(display("Could not open remote session: {}", source))]
+    OpenRemote { source: remote::Error },
+
+    #[snafu(display("Could not index mailboxes: {}", source))]
+    IndexMailboxes { source: remote::Error },
+
+    #[snafu(display("Could not find emails with Message-ID `{}': {}", message_id, source))]
+    QueryEmails {
+        message_id: String,
+        source: remote::Error,
+    },
+
+    #[snafu(display("Could not get emails from the server: {}", source))]
+    GetRemoteEmails { source: remote::Error },
+
+    #[snafu(display(
+        "No email has Message-ID `{}', neither locally nor on the server",
+        message_id
+    ))]
+    NoSuchMessage { message_id: String },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Explain the tags of the message addressed by a search term like `id:1234@example.com`: which
+/// mailboxes and keywords on the server produce each of them, which are local-only, how they
+/// differ from the server and whether that difference is yet to be pushed, and which pushed
+/// changes in the journal touched the message. Returns the report line by line.
+pub fn blame(mail_dir: &Path, config: Config, query: &str) -> Result<Vec<String>> {
+    let message_id = tag::parse_query(query).context(ParseQuerySnafu {})?;
+
+    let local = Local::open(mail_dir, true, &config).context(OpenLocalSnafu {})?;
+    let local_emails: Vec<local::Email> = local
+        .all_emails()
+        .context(IndexLocalEmailsSnafu {})?
+        .into_values()
+        .filter(|x| x.message_id == message_id)
+        .collect();
+    // Emails changed locally since the last sync have tag changes which are yet to be pushed.
+    let key = sync::open_key(mail_dir, &config).context(ReadStateSnafu {})?;
+    let pending = match LatestState::open(mail_dir.join(sync::STATE_FILE), key.as_ref())
+        .ok()
+        .and_then(|latest_state| latest_state.notmuch_revision)
+    {
+        Some(notmuch_revision) => {
+            let pending_ids: HashSet<jmap::Id> = local
+                .all_emails_since(notmuch_revision)
+                .context(IndexLocalEmailsSnafu {})?
+                .into_keys()
+                .collect();
+            Some(local_emails.iter().any(|x| pending_ids.contains(&x.id)))
+        }
+        None => None,
+    };
+
+    let mut remote = Remote::open(&config).context(OpenRemoteSnafu {})?;
+    let jmap_mailboxes = remote
+        .get_jmap_mailboxes()
+        .context(IndexMailboxesSnafu {})?;
+    let mailboxes = remote
+        .get_mailboxes(&config)
+        .context(IndexMailboxesSnafu {})?;
+    let remote_ids = remote
+        .email_ids_by_message_id(message_id)
+        .context(QueryEmailsSnafu { message_id })?;
+    let remote_emails = remote
+        .get_emails(remote_ids.iter(), &mailboxes, &config.tags)
+        .context(GetRemoteEmailsSnafu {})?;
+    ensure!(
+        !local_emails.is_empty() || !remote_emails.is_empty(),
+        NoSuchMessageSnafu { message_id }
+    );
+
+    let keyword_mappings: HashMap<&str, String> = tag::keyword_mappings(&config, &mailboxes)
+        .into_iter()
+        .collect();
+    let synced_tags: HashSet<&str> = mailboxes
+        .mailboxes_by_id
+        .values()
+        .flat_map(|x| [Some(x.tag.as_str()), x.alias.as_deref()])
+        .flatten()
+        .chain(keyword_mappings.keys().copied())
+        .collect();
+
+    let mut report = vec![format!("id:{message_id}")];
+
+    // notmuch tags messages rather than files, so every file has the same tags.
+    let local_tags: BTreeSet<&str> = local_emails
+        .iter()
+        .flat_map(|x| x.tags.iter().map(|tag| tag.as_str()))
+        .collect();
+    if local_emails.is_empty() {
+        report.push("  not in the local maildir".to_owned());
+    } else {
+        for local_email in &local_emails {
+            report.push(format!(
+                "  local email {} `{}'",
+                local_email.id,
+                local_email.path.to_string_lossy()
+            ));
+        }
+        report.push(format!("  local tags: {}", local_tags.iter().join(" ")));
+        report.push(
+            match pending {
+                Some(true) => {
+                    "  modified locally since the last sync; the next sync pushes its tags"
+                }
+                Some(false) => "  not modified locally since the last sync",
+                None => "  never synced",
+            }
+            .to_owned(),
+        );
+    }
+
+    for remote_email in remote_emails.values().sorted_by(|a, b| a.id.0.cmp(&b.id.0)) {
+        report.push(format!("  server email {}:", remote_email.id));
+        let mut sources: Vec<(&str, String)> = Vec::new();
+        for id in &remote_email.mailbox_ids {
+            let mapping = tag::mailbox_mapping(&jmap_mailboxes, id, &config);
+            match mailboxes.mailboxes_by_id.get(id) {
+                Some(mailbox) => {
+                    if let Some(alias) = &mailbox.alias {
+                        sources.push((alias, format!("{mapping}, leaf")));
+                    }
+                    sources.push((&mailbox.tag, mapping));
+                }
+                None => sources.push(("(none)", format!("{mapping}, not synced"))),
+            }
+        }
+        for tag in &remote_email.tags {
+            let mapping = keyword_mappings
+                .get(tag.as_str())
+                .cloned()
+                .unwrap_or_else(|| "keyword".to_owned());
+            sources.push((tag, mapping));
+        }
+        sources.sort();
+        let width = sources.iter().map(|(tag, _)| tag.len()).max().unwrap_or(0);
+        for (tag, mapping) in sources {
+            report.push(format!("    {tag:width$}  {mapping}"));
+        }
+
+        if local_emails.is_empty() {
+            continue;
+        }
+        let remote_tags = remote_email.local_tags(&mailboxes);
+        let automatic: BTreeSet<&str> = local_tags
+            .iter()
+            .copied()
+            .filter(|tag| local::AUTOMATIC_TAGS.contains(tag))
+            .collect();
+        let local_only: BTreeSet<&str> = local_tags
+            .iter()
+            .copied()
+            .filter(|tag| !synced_tags.contains(tag) && !automatic.contains(tag))
+            .collect();
+        let added = local_tags
+            .iter()
+            .filter(|tag| {
+                !remote_tags.contains(*tag)
+                    && !automatic.contains(*tag)
+                    && !local_only.contains(*tag)
+            })
+            .map(|tag| format!("+{tag}"));
+        let removed = remote_tags
+            .iter()
+            .filter(|tag| !local_tags.contains(*tag))
+            .sorted()
+            .map(|tag| format!("-{tag}"));
+        let difference = added.chain(removed).join(" ");
+        if !automatic.is_empty() {
+            report.push(format!(
+                "    automatic, not synced: {}",
+                automatic.iter().join(" ")
+            ));
+        }
+        if !local_only.is_empty() {
+            report.push(format!("    local-only: {}", local_only.iter().join(" ")));
+        }
+        if !difference.is_empty() {
+            report.push(format!("    local tags differ: {difference}"));
+        }
+    }
+
+    let ids: HashSet<&jmap::Id> = local_emails
+        .iter()
+        .map(|x| &x.id)
+        .chain(remote_emails.keys())
+        .collect();
+    let batches = journal::batches(mail_dir).context(ReadJournalSnafu {})?;
+    let changes: Vec<String> = batches
+        .iter()
+        .flat_map(|batch| {
+            batch
+                .changes
+                .iter()
+                .filter(|change| ids.contains(&change.id))
+                .map(|change| {
+                    format!(
+                        "    {} {}: tags before were {}",
+                        jmap::utc_date(UNIX_EPOCH + Duration::from_secs(batch.pushed_at)),
+                        change.id,
+                        change.old_tags.iter().sorted().join(" ")
+                    )
+                })
+        })
+        .collect();
+    if !changes.is_empty() {
+        report.push("  pushed by mujmap:".to_owned());
+        report.extend(changes);
+    }
+    Ok(report)
+}
@@ -1,13 +1,13 @@
 use crate::config::Config;
+use crate::crypto;
 use crate::jmap;
-use crate::sync::NewEmail;
-use directories::ProjectDirs;
+use crate::line_endings;
 use snafu::prelude::*;
 use snafu::Snafu;
 use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::path::Path;
 use std::path::PathBuf;
 
@@ -16,12 +16,6 @@ pub enum Error {
     #[snafu(display("Could not create cache dir `{}': {}", path.to_string_lossy(), source))]
     CreateCacheDir { path: PathBuf, source: io::Error },
 
-    #[snafu(display("Could not create mail file `{}': {}", path.to_string_lossy(), source))]
-    CreateUnixMailFile {
-        path: PathBuf,
-        source: loe::ParseError,
-    },
-
     #[snafu(display("Could not create mail file `{}': {}", path.to_string_lossy(), source))]
     CreateMailFile { path: PathBuf, source: io::Error },
 
@@ -31,6 +25,43 @@ pub enum Error {
         to: PathBuf,
         source: io::Error,
     },
+
+    #[snafu(display("Could not copy mail file from `{}' to `{}': {}", from.to_string_lossy(), to.to_string_lossy(), source))]
+    CopyMailFile {
+        from: PathBuf,
+        to: PathBuf,
+        source: io::Error,
+    },
+
+    #[snafu(display("Could not remove mail file `{}': {}", path.to_string_lossy(), source))]
+    RemoveMailFile { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Could not flush mail file `{}' to disk: {}", path.to_string_lossy(), source))]
+    SyncMailFile { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Could not flush cache dir `{}' to disk: {}", path.to_string_lossy(), source))]
+    SyncCacheDir { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Could not read mail file `{}': {}", path.to_string_lossy(), source))]
+    ReadMailFile { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Could not encrypt mail file `{}': {}", path.to_string_lossy(), source))]
+    EncryptMailFile {
+        path: PathBuf,
+        source: crypto::Error,
+    },
+
+    #[snafu(display("Could not decrypt mail file `{}': {}", path.to_string_lossy(), source))]
+    DecryptMailFile {
+        path: PathBuf,
+        source: crypto::Error,
+    },
+
+    #[snafu(display("Could not compress mail file `{}': {}", path.to_string_lossy(), source))]
+    CompressMailFile { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Could not decompress mail file `{}': {}", path.to_string_lossy(), source))]
+    DecompressMailFile { path: PathBuf, source: io::Error },
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -49,23 +80,27 @@ pub struct Cache {
     ///
     /// `!home!username!Maildir!username@example.com!cur!XxXxXx.YyYyYy`
     cached_file_prefix: String,
+    /// Whether to flush files and the cache dir to disk after writing them.
+    fsync: bool,
+    /// The key to encrypt cached files with, if any.
+    key: Option<crypto::Key>,
+    /// Whether to compress cached files.
+    compress: bool,
 }
 
 impl Cache {
     /// Open the local store.
     ///
     /// `mail_dir` *must* be a subdirectory of the notmuch path.
-    pub fn open(mail_cur_dir: impl AsRef<Path>, config: &Config) -> Result<Self> {
-        let project_dirs = ProjectDirs::from("sh.eliza", "", "mujmap").unwrap();
-        let default_cache_dir = project_dirs.cache_dir();
-
-        let cache_dir = match &config.cache_dir {
-            Some(cache_dir) => cache_dir.as_ref(),
-            None => default_cache_dir,
-        };
+    pub fn open(
+        mail_cur_dir: impl AsRef<Path>,
+        config: &Config,
+        key: Option<crypto::Key>,
+    ) -> Result<Self> {
+        let cache_dir = config.cache_dir();
 
         // Ensure the cache dir exists.
-        fs::create_dir_all(cache_dir).context(CreateCacheDirSnafu { path: cache_dir })?;
+        fs::create_dir_all(&cache_dir).context(CreateCacheDirSnafu { path: &cache_dir })?;
 
         // Create the cache filename prefix for this particular maildir. More information about this
         // is found in the documentation for `Local::cached_file_prefix`.
@@ -78,19 +113,35 @@ impl Cache {
         cached_file_prefix.push('!');
 
         Ok(Self {
-            cache_dir: cache_dir.into(),
+            cache_dir,
             cached_file_prefix,
+            fsync: config.fsync,
+            key,
+            compress: config.compress_cache,
         })
     }
 
     /// Return the path in the cache for the given IDs.
+    ///
+    /// Compressed and encrypted files are named differently so that toggling either option never
+    /// mixes up files of different formats.
     pub fn cache_path(&self, email_id: &jmap::Id, blob_id: &jmap::Id) -> PathBuf {
         self.cache_dir.join(format!(
-            "{}{}.{}",
-            self.cached_file_prefix, email_id.0, blob_id.0
+            "{}{}.{}{}{}",
+            self.cached_file_prefix,
+            email_id.0,
+            blob_id.0,
+            if self.compress { ".zst" } else { "" },
+            if self.key.is_some() { ".enc" } else { "" }
         ))
     }
 
+    /// Return true if cached files are compressed or encrypted, in which case they must be moved
+    /// into the maildir with `decode_into` rather than linked or renamed.
+    pub fn is_encoded(&self) -> bool {
+        self.compress || self.key.is_some()
+    }
+
     /// Save the data from the given reader into the cache.
     ///
     /// This is done first by downloading to a temporary file so that in the event of a catastrophic
@@ -99,7 +150,7 @@ impl Cache {
     /// blob IDs, so it's important we take every precaution.
     pub fn download_into_cache(
         &self,
-        new_email: &NewEmail,
+        cache_path: &Path,
         mut reader: impl Read,
         convert_dos_to_unix: bool,
     ) -> Result<()> {
@@ -112,22 +163,138 @@ impl Cache {
         let mut writer = File::create(&temporary_file_path).context(CreateMailFileSnafu {
             path: &temporary_file_path,
         })?;
-        if convert_dos_to_unix {
-            loe::process(&mut reader, &mut writer, loe::Config::default()).context(
-                CreateUnixMailFileSnafu {
+        if convert_dos_to_unix || self.is_encoded() {
+            // Conversion needs to see the MIME structure of the whole message.
+            let mut message = Vec::new();
+            reader
+                .read_to_end(&mut message)
+                .context(CreateMailFileSnafu {
                     path: &temporary_file_path,
-                },
-            )?;
+                })?;
+            if convert_dos_to_unix {
+                message = line_endings::dos_to_unix(&message);
+            }
+            let message = self.encode(message, &temporary_file_path)?;
+            writer.write_all(&message).context(CreateMailFileSnafu {
+                path: &temporary_file_path,
+            })?;
         } else {
             io::copy(&mut reader, &mut writer).context(CreateMailFileSnafu {
                 path: &temporary_file_path,
             })?;
         }
+        if self.fsync {
+            writer.sync_all().context(SyncMailFileSnafu {
+                path: &temporary_file_path,
+            })?;
+        }
         // ...and move to its proper location.
-        fs::rename(&temporary_file_path, &new_email.cache_path).context(RenameMailFileSnafu {
+        fs::rename(&temporary_file_path, cache_path).context(RenameMailFileSnafu {
             from: &temporary_file_path,
-            to: &new_email.cache_path,
+            to: cache_path,
         })?;
+        self.sync_cache_dir()
+    }
+
+    /// Move an existing mail file, e.g. one found in the maildir, into the cache in place of
+    /// downloading it.
+    pub fn move_into_cache(&self, cache_path: &Path, path: &Path) -> Result<()> {
+        if self.is_encoded() {
+            let message = fs::read(path).context(ReadMailFileSnafu { path })?;
+            let message = self.encode(message, path)?;
+            self.write_file(cache_path, &message)?;
+            fs::remove_file(path).context(RemoveMailFileSnafu { path })?;
+        } else if fs::rename(path, cache_path).is_err() {
+            // The cache may live on a different filesystem, in which case renaming fails.
+            fs::copy(path, cache_path).context(CopyMailFileSnafu {
+                from: path,
+                to: cache_path,
+            })?;
+            fs::remove_file(path).context(RemoveMailFileSnafu { path })?;
+        }
+        self.sync_cache_dir()
+    }
+
+    /// Link a mail file which belongs to another program, e.g. one with the same `Message-ID` as a
+    /// new email in another maildir, into the cache in place of downloading it. The file is left in
+    /// place, and shares its storage with the cached file if possible.
+    pub fn link_into_cache(&self, cache_path: &Path, path: &Path) -> Result<()> {
+        if self.is_encoded() {
+            let message = fs::read(path).context(ReadMailFileSnafu { path })?;
+            let message = self.encode(message, path)?;
+            self.write_file(cache_path, &message)?;
+        } else if fs::hard_link(path, cache_path).is_err() {
+            // The cache may live on a different filesystem, in which case linking fails.
+            fs::copy(path, cache_path).context(CopyMailFileSnafu {
+                from: path,
+                to: cache_path,
+            })?;
+        }
+        self.sync_cache_dir()
+    }
+
+    /// Decompress and decrypt a cached file into the given path, e.g. in the maildir. The cached
+    /// file is left in place.
+    pub fn decode_into(&self, cache_path: &Path, path: &Path) -> Result<()> {
+        let mut message = fs::read(cache_path).context(ReadMailFileSnafu { path: cache_path })?;
+        if let Some(key) = &self.key {
+            message = key
+                .decrypt(&message)
+                .context(DecryptMailFileSnafu { path: cache_path })?;
+        }
+        if self.compress {
+            message = zstd::decode_all(message.as_slice())
+                .context(DecompressMailFileSnafu { path: cache_path })?;
+        }
+        self.write_file(path, &message)
+    }
+
+    /// Compress and encrypt a message as configured. Compression comes first, since encrypted
+    /// data doesn't compress.
+    fn encode(&self, mut message: Vec<u8>, path: &Path) -> Result<Vec<u8>> {
+        if self.compress {
+            // Level 0 is zstd's default.
+            message =
+                zstd::encode_all(message.as_slice(), 0).context(CompressMailFileSnafu { path })?;
+        }
+        if let Some(key) = &self.key {
+            message = key
+                .encrypt(&message)
+                .context(EncryptMailFileSnafu { path })?;
+        }
+        Ok(message)
+    }
+
+    /// Write a file, flushing it to disk if configured to.
+    fn write_file(&self, path: &Path, contents: &[u8]) -> Result<()> {
+        let mut file = File::create(path).context(CreateMailFileSnafu { path })?;
+        file.write_all(contents)
+            .context(CreateMailFileSnafu { path })?;
+        if self.fsync {
+            file.sync_all().context(SyncMailFileSnafu { path })?;
+        }
+        Ok(())
+    }
+
+    /// Flush the cache dir to disk so that renames within it survive a crash, if configured to.
+    fn sync_cache_dir(&self) -> Result<()> {
+        self.flush_dir(&self.cache_dir).context(SyncCacheDirSnafu {
+            path: &self.cache_dir,
+        })
+    }
+
+    /// Flush the given directory to disk if configured to, e.g. after moving files from the cache
+    /// into it.
+    pub fn flush_dir(&self, path: &Path) -> io::Result<()> {
+        if self.fsync {
+            sync_dir(path)?;
+        }
         Ok(())
     }
 }
+
+/// Flush a directory to disk, which is necessary for the files created, renamed, or removed within
+/// it to survive a crash.
+pub fn sync_dir(path: &Path) -> io::Result<()> {
+    File::open(path)?.sync_all()
+}
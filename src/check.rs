@@ -0,0 +1,124 @@
+use crate::config::Config;
+use snafu::prelude::*;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Found {} problems with the config", count))]
+    Problems { count: usize },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Check the config file at `config_path` and return every problem found with it, rather than
+/// stopping at the first one.
+pub fn check(config_path: &Path) -> Vec<String> {
+    let mut report = Vec::new();
+    let mut unknown = Vec::new();
+    match Config::from_file_reporting_unknown(config_path, |path| unknown.push(path)) {
+        Ok(config) => {
+            report.extend(
+                unknown
+                    .into_iter()
+                    .map(|path| format!("unknown option `{path}'")),
+            );
+            report.extend(config.problems().iter().map(ToString::to_string));
+            check_password_command(&config, &mut report);
+            check_tags(&config, &mut report);
+            check_paths(&config, config_path, &mut report);
+        }
+        // Nothing else can be checked without a parsed config.
+        Err(e) => report.push(e.to_string()),
+    }
+    report
+}
+
+fn check_password_command(config: &Config, report: &mut Vec<String>) {
+    match config.password() {
+        Ok(password) if password.is_empty() => {
+            report.push("`password_command' printed an empty password".to_owned())
+        }
+        Ok(_) => {}
+        Err(e) => report.push(format!("`password_command' failed: {e}")),
+    }
+}
+
+/// Report tags which notmuch would accept, but which can't be used in a search query without
+/// quoting or which would be mistaken for a tag operation by `notmuch tag`.
+fn check_tags(config: &Config, report: &mut Vec<String>) {
+    let tags = &config.tags;
+    for (option, tag) in [
+        ("inbox", &tags.inbox),
+        ("deleted", &tags.deleted),
+        ("sent", &tags.sent),
+        ("spam", &tags.spam),
+        ("important", &tags.important),
+        ("phishing", &tags.phishing),
+        ("draft", &tags.draft),
+        ("flagged", &tags.flagged),
+        ("replied", &tags.replied),
+        ("passed", &tags.passed),
+        ("unread", &tags.unread),
+    ] {
+        if tag.starts_with(['-', '+']) {
+            report.push(format!("`tags.{option}' must not start with `-' or `+'"));
+        }
+        if tag.contains(|c: char| c.is_whitespace() || "\"'()".contains(c)) {
+            report.push(format!(
+                "`tags.{option}' must not contain whitespace, quotes, or parentheses"
+            ));
+        }
+    }
+    if tags.automatic_tag_prefix.starts_with(['-', '+']) {
+        report.push("`tags.automatic_tag_prefix' must not start with `-' or `+'".to_owned());
+    }
+    if tags.directory_separator.contains(char::is_whitespace) {
+        report.push("`tags.directory_separator' must not contain whitespace".to_owned());
+    }
+}
+
+fn check_paths(config: &Config, config_path: &Path, report: &mut Vec<String>) {
+    let mail_dir = config.mail_dir(config_path);
+    check_dir("maildir", &mail_dir, report);
+    for maildir in &config.maildirs {
+        let path = mail_dir.join(&maildir.path);
+        // Additional maildirs are created by the first sync.
+        if path.exists() {
+            check_dir("maildir", &path, report);
+        }
+    }
+    let cache_dir = config.cache_dir();
+    // The cache directory is created by the first sync.
+    if cache_dir.exists() {
+        check_dir("cache directory", &cache_dir, report);
+    }
+    if let Some(aliases_file) = &config.sending.aliases_file {
+        let aliases_file = mail_dir.join(aliases_file);
+        if let Err(e) = fs::File::open(&aliases_file) {
+            report.push(format!(
+                "cannot read `aliases_file' `{}': {e}",
+                aliases_file.to_string_lossy()
+            ));
+        }
+    }
+}
+
+/// Report whether `path` is a directory mujmap can write to.
+fn check_dir(name: &str, path: &Path, report: &mut Vec<String>) {
+    match fs::metadata(path) {
+        Ok(metadata) if !metadata.is_dir() => report.push(format!(
+            "{name} `{}' is not a directory",
+            path.to_string_lossy()
+        )),
+        Ok(metadata) if metadata.permissions().readonly() => report.push(format!(
+            "{name} `{}' is not writable",
+            path.to_string_lossy()
+        )),
+        Ok(_) => {}
+        Err(e) => report.push(format!(
+            "cannot access {name} `{}': {e}",
+            path.to_string_lossy()
+        )),
+    }
+}
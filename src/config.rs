@@ -1,10 +1,14 @@
+use directories::{BaseDirs, ProjectDirs};
 use serde::Deserialize;
 use snafu::prelude::*;
 use std::{
-    fs, io,
+    collections::{BTreeMap, HashMap},
+    env, fs, io,
+    net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
     process::{Command, ExitStatus},
     string::FromUtf8Error,
+    time::Duration,
 };
 
 use snafu::Snafu;
@@ -23,15 +27,63 @@ pub enum Error {
         source: toml::de::Error,
     },
 
+    #[snafu(display(
+        "`include' in config file `{}' must be a list of paths",
+        filename.to_string_lossy()
+    ))]
+    InvalidInclude { filename: PathBuf },
+
+    #[snafu(display("Config file `{}' includes itself", filename.to_string_lossy()))]
+    IncludeCycle { filename: PathBuf },
+
+    #[snafu(display("Environment variable `{}' used in config is not set", name))]
+    UndefinedVariable { name: String },
+
+    #[snafu(display("Unterminated `${{' in config value `{}'", value))]
+    UnterminatedVariable { value: String },
+
     #[snafu(display("Can only specify one of `fqdn' or `session_url' in the same config"))]
     FqdnOrSessionUrl {},
 
+    #[snafu(display("Can only specify one of `account_id' or `account_name' in the same config"))]
+    AccountIdOrAccountName {},
+
+    #[snafu(display(
+        "Can only specify one of `nameservers' or `dns_over_https' in the same config"
+    ))]
+    NameserversOrDnsOverHttps {},
+
+    #[snafu(display("Invalid nameserver address `{}'", nameserver))]
+    InvalidNameserver { nameserver: String },
+
     #[snafu(display("Must specify at least 1 for `concurrent_downloads'"))]
     ConcurrentDownloadsIsZero {},
 
+    #[snafu(display("Must specify at least 1 for `max_connections'"))]
+    MaxConnectionsIsZero {},
+
+    #[snafu(display("Must specify at least 1 for `max_changes'"))]
+    MaxChangesIsZero {},
+
+    #[snafu(display(
+        "Invalid age `{}' for `expunge.{}', expected e.g. `30d', `2w', or `12h'",
+        value,
+        option
+    ))]
+    InvalidExpungeAge { option: &'static str, value: String },
+
+    #[snafu(display("`quota_warning_percent' must be at most 100"))]
+    QuotaWarningPercentTooLarge {},
+
     #[snafu(display("`directory_separator' must not be empty"))]
     EmptyDirectorySeparator {},
 
+    #[snafu(display("`tags.{}' must not be empty", option))]
+    EmptyKeywordTag { option: &'static str },
+
+    #[snafu(display("`tags.replacements' must not replace the empty string"))]
+    EmptyReplacement {},
+
     #[snafu(display("Could not execute password command: {}", source))]
     ExecutePasswordCommand { source: io::Error },
 
@@ -52,10 +104,21 @@ pub struct Config {
     /// Shell command which will print a password to stdout for basic HTTP authentication.
     pub password_command: String,
 
+    /// Path to the maildir, where mujmap also keeps its state. Relative paths are relative to the
+    /// directory containing the config file, which is also the default.
+    pub mail_dir: Option<PathBuf>,
+
+    /// Additional maildirs for the mail of some mailboxes, e.g. to keep mailing lists apart from
+    /// the rest for backups. Each message is stored in the maildir of the first entry which
+    /// matches one of its mailboxes, or in `mail_dir` if none does.
+    #[serde(default = "Default::default")]
+    pub maildirs: Vec<SubtreeMaildir>,
+
     /// Fully qualified domain name of the JMAP service.
     ///
-    /// mujmap looks up the JMAP SRV record for this host to determine the JMAP session URL.
-    /// Mutually exclusive with `session_url`.
+    /// mujmap looks up the JMAP SRV record for this host to determine the JMAP session URL. If
+    /// there is no such record, it tries the well-known URL on this host and on its `jmap`
+    /// subdomain instead. Mutually exclusive with `session_url`.
     pub fqdn: Option<String>,
 
     /// Session URL to connect to.
@@ -63,38 +126,366 @@ pub struct Config {
     /// Mutually exclusive with `fqdn`.
     pub session_url: Option<String>,
 
+    /// DNS servers to look up the JMAP SRV record with instead of the system's, as IP addresses
+    /// with optional ports, e.g. `1.1.1.1` or `[2606:4700:4700::1111]:53`.
+    ///
+    /// Mutually exclusive with `dns_over_https`.
+    #[serde(default = "Default::default")]
+    pub nameservers: Vec<String>,
+
+    /// URL of a DNS-over-HTTPS service to look up the JMAP SRV record with instead of the system's
+    /// DNS servers, e.g. `https://cloudflare-dns.com/dns-query`.
+    ///
+    /// Mutually exclusive with `nameservers`.
+    pub dns_over_https: Option<String>,
+
+    /// ID of the JMAP account to synchronize with.
+    ///
+    /// By default, mujmap uses the primary mail account of the session. Set this to synchronize
+    /// with a shared or delegated account instead. Mutually exclusive with `account_name`.
+    pub account_id: Option<String>,
+
+    /// Name of the JMAP account to synchronize with, e.g. the email address of a shared mailbox.
+    ///
+    /// Mutually exclusive with `account_id`.
+    pub account_name: Option<String>,
+
     /// Number of email files to download in parallel.
     ///
     /// This corresponds to the number of blocking OS threads that will be created for HTTP download
     /// requests. Increasing this number too high will likely result in many failed connections.
+    ///
+    /// Fewer are downloaded at once if the server allows fewer concurrent requests, or if
+    /// `max_connections` is lower.
     #[serde(default = "default_concurrent_downloads")]
     pub concurrent_downloads: usize,
 
-    /// Number of seconds before timing out on a stalled connection.
-    #[serde(default = "default_timeout")]
-    pub timeout: u64,
+    /// Maximum number of connections to the server at once, including the one used to retrieve
+    /// metadata while downloading, whatever the server claims to allow. Some servers throttle or
+    /// block accounts which open more connections than they like. With only one, mail is downloaded
+    /// once all metadata has been retrieved.
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+
+    /// Maximum number of changed emails to ask the server for at once. If more emails changed
+    /// since the last sync, e.g. after a long time offline, they're synced in batches of this
+    /// size, and the progress is saved after each, so an interrupted sync needn't start over.
+    #[serde(default = "default_max_changes")]
+    pub max_changes: u64,
+
+    /// Number of seconds before timing out while connecting to the server.
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+
+    /// Number of seconds before timing out on a JMAP API request, including reading its response.
+    #[serde(default = "default_api_timeout")]
+    pub api_timeout: u64,
+
+    /// Number of seconds before timing out on an email download which has stalled. Downloads which
+    /// are still making progress are never timed out.
+    #[serde(default = "default_download_timeout")]
+    pub download_timeout: u64,
+
+    /// Maximum size in bytes of an email to download. Emails larger than this fail to download
+    /// rather than being truncated.
+    ///
+    /// By default, the server's maximum upload size is used.
+    pub max_email_size: Option<u64>,
+
+    /// Deprecated. If set, overrides `connect_timeout`, `api_timeout`, and `download_timeout`.
+    pub timeout: Option<u64>,
 
     /// Number of retries to download an email file. 0 means infinite.
     #[serde(default = "default_retries")]
     pub retries: usize,
 
+    /// Whether to download the most recently received mail first. If false, mail is downloaded
+    /// as soon as its metadata is retrieved, in no particular order.
+    #[serde(default = "default_download_newest_first")]
+    pub download_newest_first: bool,
+
+    /// Whether to download the largest mail last, so that a few huge messages don't hold up the
+    /// rest. Takes precedence over `download_newest_first`, which still orders mail of the same
+    /// size.
+    #[serde(default = "default_download_largest_last")]
+    pub download_largest_last: bool,
+
+    /// Warn after a sync if the account has used at least this percentage of any of its storage
+    /// quotas on the server. 0 disables the warning.
+    #[serde(default = "default_quota_warning_percent")]
+    pub quota_warning_percent: u8,
+
+    /// Whether to perform the initial sync in windows of increasingly old mail: the last week,
+    /// the last month, the last year, and then everything. The notmuch database is updated after
+    /// each window, so recent mail is available quickly and the backfill may be interrupted and
+    /// resumed at any time.
+    #[serde(default = "default_progressive_backfill")]
+    pub progressive_backfill: bool,
+
+    /// Mailbox to assign messages to which have no tags corresponding to any other mailbox, given
+    /// by its ID or full path.
+    ///
+    /// By default, the mailbox with the `Archive` role is used.
+    pub archive_mailbox: Option<String>,
+
+    /// What to do when pushing a message which has no tags corresponding to any mailbox.
+    #[serde(default)]
+    pub unfiled_mail: UnfiledMail,
+
+    /// Whether to create a mailbox with the `Archive` role if the server has none and
+    /// `archive_mailbox` is not set. Only a sync which isn't a dry run creates it.
+    #[serde(default = "default_auto_create_archive_mailbox")]
+    pub auto_create_archive_mailbox: bool,
+
     /// Whether to create new mailboxes automatically on the server from notmuch tags.
     #[serde(default = "default_auto_create_new_mailboxes")]
     pub auto_create_new_mailboxes: bool,
 
+    /// Whether to destroy mailboxes on the server which have no role, contain no messages, and no
+    /// longer correspond to any notmuch tag.
+    #[serde(default = "default_auto_destroy_empty_mailboxes")]
+    pub auto_destroy_empty_mailboxes: bool,
+
+    /// Whether to upload drafts which exist only locally, e.g. because they were postponed in an
+    /// MUA, to the drafts mailbox on the server, replacing them there whenever they're edited.
+    #[serde(default = "default_push_drafts")]
+    pub push_drafts: bool,
+
+    /// Whether to push local changes to messages with one of the tags in notmuch's
+    /// `search.exclude_tags`, e.g. `deleted` or `spam`. If false, such changes are neither pushed
+    /// nor overwritten by the server, and are pushed along with the next change to the message
+    /// once it no longer has any of these tags.
+    #[serde(default = "default_push_excluded")]
+    pub push_excluded: bool,
+
+    /// Whether to reuse a file which notmuch already has for a new email with the same
+    /// `Message-ID`, e.g. one fetched into another maildir by mbsync, instead of downloading it.
+    /// The file is hard-linked into mujmap's maildir if possible, and copied otherwise.
+    #[serde(default = "default_link_existing_messages")]
+    pub link_existing_messages: bool,
+
+    /// Shell command to run when a sync finds that a message was moved into spam on the server,
+    /// e.g. `rspamc learn_spam`. The path of the mail file is appended as an argument.
+    pub learn_spam_command: Option<String>,
+
+    /// Like `learn_spam_command`, but run when a message was moved out of spam, e.g. `rspamc
+    /// learn_ham`.
+    pub learn_ham_command: Option<String>,
+
+    /// Whether to flush downloaded mail files, the maildir, and the state file to disk after
+    /// writing them, so that a crash or power loss can't leave behind empty mail files which
+    /// notmuch has already indexed.
+    #[serde(default = "default_fsync")]
+    pub fsync: bool,
+
+    /// Whether to compress downloaded mail in the cache. Mail is decompressed as it's moved into the
+    /// maildir.
+    #[serde(default = "default_compress_cache")]
+    pub compress_cache: bool,
+
+    /// Whether to encrypt downloaded mail in the cache, the state file, and the index of local mail
+    /// with a key derived from the password returned by `password_command`. Mail is decrypted as it's moved into the
+    /// maildir.
+    #[serde(default = "default_encrypt_cache")]
+    pub encrypt_cache: bool,
+
+    /// Number of seconds the server holds sent mail before sending it, during which `mujmap send
+    /// --undo` cancels it. Requires server support for delayed sending.
+    #[serde(default = "default_send_delay")]
+    pub send_delay: u64,
+
+    /// Customize how mail sent with `mujmap send` is filed.
+    #[serde(default = "Default::default")]
+    pub sending: Sending,
+
+    /// Map of sender address patterns to the IDs of the JMAP identities to send their mail as. `*`
+    /// in a pattern matches any number of characters.
+    ///
+    /// Senders not matching any pattern use the identity with the same address, the same address
+    /// without its `+` subaddress, or a wildcard address for the same domain, in that order.
+    #[serde(default = "Default::default")]
+    pub identities: HashMap<String, String>,
+
     /// If true, convert all DOS newlines in downloaded mail files to Unix newlines.
+    ///
+    /// Only headers and parts encoded as lines of text are converted; `base64` and `binary` parts
+    /// are left untouched.
     #[serde(default = "default_convert_dos_to_unix")]
     pub convert_dos_to_unix: bool,
 
-    /// The cache directory in which to store mail files while they are being downloaded. The
-    /// default is operating-system specific.
+    /// The cache directory in which to store mail files while they are being downloaded, and
+    /// session URLs discovered through DNS. The default is operating-system specific.
     #[serde(default = "Default::default")]
     pub cache_dir: Option<PathBuf>,
 
+    /// The notmuch profile whose config and database to use, as with `NOTMUCH_PROFILE`, for users
+    /// with several notmuch databases. Defaults to `NOTMUCH_PROFILE`, or the default profile.
+    #[serde(default = "Default::default")]
+    pub notmuch_profile: Option<String>,
+
+    /// The path of the notmuch database to use, overriding the one given by the notmuch config.
+    /// Defaults to `NOTMUCH_DATABASE`, or the database of the notmuch config.
+    #[serde(default = "Default::default")]
+    pub notmuch_database: Option<PathBuf>,
+
     /// Customize the names and synchronization behaviors of notmuch tags with JMAP keywords and
     /// mailboxes.
     #[serde(default = "Default::default")]
     pub tags: Tags,
+
+    /// Assign roles to mailboxes which the server does not report correctly.
+    #[serde(default = "Default::default")]
+    pub roles: Roles,
+
+    /// Override limits which the server advertises incorrectly.
+    #[serde(default = "Default::default")]
+    pub compatibility: Compatibility,
+
+    /// Destroy old mail in the trash and spam mailboxes.
+    #[serde(default = "Default::default")]
+    pub expunge: Expunge,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Sending {
+    /// Whether to keep a copy of sent mail in the sent mailbox.
+    #[serde(default = "Default::default")]
+    pub save_to_sent: SaveToSent,
+
+    /// Mailbox to file sent mail in, given by its ID or full path, instead of the mailbox with the
+    /// `Sent` role.
+    pub sent_mailbox: Option<String>,
+
+    /// Identity to send mail as if no identity matches its sender, given by its ID or email
+    /// address.
+    pub default_identity: Option<String>,
+
+    /// File of aliases for recipient arguments, in the mutt or msmtp format. Relative paths are
+    /// relative to the maildir.
+    pub aliases_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SaveToSent {
+    /// Keep a copy unless the server files one on its own, which is only detected if it does so
+    /// as soon as the mail is submitted.
+    #[default]
+    Auto,
+    /// Always keep a copy.
+    Always,
+    /// Never keep a copy, e.g. because the server always files one on its own.
+    Never,
+}
+
+/// A maildir for the mail of some mailboxes. See `Config::maildirs`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SubtreeMaildir {
+    /// The mailboxes, given by their tag, e.g. `Lists`. A pattern ending in `**`, e.g. `Lists/**`,
+    /// matches every tag starting with what precedes it, so both the mailbox and all mailboxes
+    /// below it.
+    pub mailboxes: String,
+    /// Path to the maildir, which must be inside the notmuch root. Relative paths are relative to
+    /// `mail_dir`.
+    pub path: PathBuf,
+}
+
+impl SubtreeMaildir {
+    /// Return true if the mailbox with the given tag belongs in this maildir.
+    pub fn matches(&self, tag: &str, directory_separator: &str) -> bool {
+        match self.mailboxes.strip_suffix("**") {
+            Some(prefix) => {
+                tag.starts_with(prefix) || prefix.strip_suffix(directory_separator) == Some(tag)
+            }
+            None => tag == self.mailboxes,
+        }
+    }
+}
+
+/// What to do with messages which have no mailbox tags. See `Config::unfiled_mail`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnfiledMail {
+    /// Move them to `archive_mailbox`.
+    #[default]
+    Archive,
+    /// Leave them in whichever mailboxes they are in on the server.
+    Keep,
+    /// Refuse to push any changes until they are given a mailbox tag.
+    Error,
+}
+
+/// How the hierarchy of mailboxes is reflected in their tags. See `Tags::hierarchy`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Hierarchy {
+    /// Tag mail with the full path of its mailbox.
+    #[default]
+    Path,
+    /// Tag mail with only the name of its mailbox, or with the full path as well if the name is
+    /// not unique.
+    Leaf,
+    /// Tag mail with both the full path and the name of its mailbox.
+    Both,
+}
+
+/// Overrides for mailbox roles, each given by the ID or full path of a mailbox.
+///
+/// If set, the role is assigned to this mailbox instead of whichever mailbox the server reports
+/// for it.
+#[derive(Debug, Default, Deserialize)]
+pub struct Roles {
+    /// Mailbox with the `Archive` role.
+    pub archive: Option<String>,
+    /// Mailbox with the `Drafts` role.
+    pub drafts: Option<String>,
+    /// Mailbox with the `Flagged` role.
+    pub flagged: Option<String>,
+    /// Mailbox with the `Important` role.
+    pub important: Option<String>,
+    /// Mailbox with the `Inbox` role.
+    pub inbox: Option<String>,
+    /// Mailbox with the `Junk` role.
+    pub junk: Option<String>,
+    /// Mailbox with the `Sent` role.
+    pub sent: Option<String>,
+    /// Mailbox with the `Trash` role.
+    pub trash: Option<String>,
+}
+
+/// Overrides for the limits the server advertises in its core capability, for servers known to
+/// advertise wrong ones. Each is used instead of the server's value if set.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Compatibility {
+    /// Maximum size in octets of a single upload.
+    pub max_size_upload: Option<u64>,
+    /// Maximum number of concurrent requests to the upload endpoint.
+    pub max_concurrent_upload: Option<u64>,
+    /// Maximum size in octets of a single API request.
+    pub max_size_request: Option<u64>,
+    /// Maximum number of concurrent requests to the API endpoint.
+    pub max_concurrent_requests: Option<u64>,
+    /// Maximum number of method calls in a single API request.
+    pub max_calls_in_request: Option<u64>,
+    /// Maximum number of objects in a single /get method call.
+    pub max_objects_in_get: Option<u64>,
+    /// Maximum number of objects in a single /set method call.
+    pub max_objects_in_set: Option<u64>,
+}
+
+/// How long mail may stay in the trash and spam mailboxes before each sync destroys it, both on
+/// the server and locally, given as a number of hours, days, or weeks, e.g. `30d`. Mail is kept
+/// forever if unset.
+///
+/// JMAP doesn't tell when mail was moved to a mailbox, so the time counts from the first sync
+/// which found it there, including mail which was already there when the option was set. Mail
+/// which is also in any other mailbox is never destroyed.
+#[derive(Debug, Default, Deserialize)]
+pub struct Expunge {
+    /// Age after which mail in the mailbox with the `Trash` role is destroyed.
+    pub trash_after: Option<String>,
+    /// Age after which mail in the mailbox with the `Junk` role is destroyed.
+    pub spam_after: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -111,6 +502,41 @@ pub struct Tags {
     #[serde(default = "default_directory_separator")]
     pub directory_separator: String,
 
+    /// How the hierarchy of mailboxes is reflected in their tags. Mail in the mailbox
+    /// `Lists/rust/announce` is tagged `lists/rust/announce` with `"path"`, just `announce` with
+    /// `"leaf"`, and both with `"both"`. In the latter case, only the full path decides which
+    /// mailbox mail belongs to when pushing. The same goes for mailboxes whose names are not unique
+    /// with `"leaf"`, e.g. `Lists/a/announce` and `Lists/b/announce`.
+    ///
+    /// Defaults to `"path"`.
+    #[serde(default)]
+    pub hierarchy: Hierarchy,
+
+    /// Only synchronize mailboxes which the user is subscribed to. Unsubscribed mailboxes are
+    /// ignored.
+    ///
+    /// Defaults to `false`.
+    #[serde(default = "default_only_subscribed")]
+    pub only_subscribed: bool,
+
+    /// Prefix for the tags of mailboxes whose tags would otherwise be the same as one of notmuch's
+    /// automatic tags, such as `attachment`, which mujmap must not touch. Mail in such a mailbox
+    /// is tagged e.g. `mb-attachment` instead. If set to an empty string, such mailboxes are
+    /// ignored.
+    ///
+    /// Defaults to `"mb-"`.
+    #[serde(default = "default_automatic_tag_prefix")]
+    pub automatic_tag_prefix: String,
+
+    /// Replacements for text in mailbox names when mapping them to notmuch tags, e.g. `{ " " =
+    /// "-" }` to avoid tags containing spaces, which must be quoted in notmuch searches. Names are
+    /// normalized to Unicode NFC first, and replacements are applied in order of the text they
+    /// replace.
+    ///
+    /// Defaults to no replacements.
+    #[serde(default)]
+    pub replacements: BTreeMap<String, String>,
+
     /// Tag for notmuch to use for messages stored in the mailbox labeled with the [Inbox name
     /// attribute](https://www.rfc-editor.org/rfc/rfc8621.html).
     ///
@@ -196,6 +622,38 @@ pub struct Tags {
     /// Defaults to `"phishing"`.
     #[serde(default = "default_phishing")]
     pub phishing: String,
+
+    /// Tag for notmuch to use for messages stored in the mailbox labeled with the [`Drafts` name
+    /// attribute](https://www.rfc-editor.org/rfc/rfc6154.html) and/or with the `$draft` keyword.
+    ///
+    /// Defaults to `"draft"`.
+    #[serde(default = "default_draft")]
+    pub draft: String,
+
+    /// Tag for notmuch to use for messages stored in the mailbox labeled with the [`Flagged` name
+    /// attribute](https://www.rfc-editor.org/rfc/rfc6154.html) and/or with the `$flagged` keyword.
+    ///
+    /// Defaults to `"flagged"`.
+    #[serde(default = "default_flagged")]
+    pub flagged: String,
+
+    /// Tag for notmuch to use for messages with the `$answered` keyword.
+    ///
+    /// Defaults to `"replied"`.
+    #[serde(default = "default_replied")]
+    pub replied: String,
+
+    /// Tag for notmuch to use for messages with the `$forwarded` keyword.
+    ///
+    /// Defaults to `"passed"`.
+    #[serde(default = "default_passed")]
+    pub passed: String,
+
+    /// Tag for notmuch to use for messages *without* the `$seen` keyword.
+    ///
+    /// Defaults to `"unread"`.
+    #[serde(default = "default_unread")]
+    pub unread: String,
 }
 
 impl Default for Tags {
@@ -203,12 +661,21 @@ impl Default for Tags {
         Self {
             lowercase: default_lowercase(),
             directory_separator: default_directory_separator(),
+            hierarchy: Hierarchy::default(),
+            only_subscribed: default_only_subscribed(),
+            automatic_tag_prefix: default_automatic_tag_prefix(),
+            replacements: BTreeMap::new(),
             inbox: default_inbox(),
             deleted: default_deleted(),
             sent: default_sent(),
             spam: default_spam(),
             important: default_important(),
             phishing: default_phishing(),
+            draft: default_draft(),
+            flagged: default_flagged(),
+            replied: default_replied(),
+            passed: default_passed(),
+            unread: default_unread(),
         }
     }
 }
@@ -221,6 +688,14 @@ fn default_directory_separator() -> String {
     "/".to_owned()
 }
 
+fn default_only_subscribed() -> bool {
+    false
+}
+
+fn default_automatic_tag_prefix() -> String {
+    "mb-".to_owned()
+}
+
 fn default_inbox() -> String {
     "inbox".to_owned()
 }
@@ -245,66 +720,477 @@ fn default_phishing() -> String {
     "phishing".to_owned()
 }
 
+fn default_draft() -> String {
+    "draft".to_owned()
+}
+
+fn default_flagged() -> String {
+    "flagged".to_owned()
+}
+
+fn default_replied() -> String {
+    "replied".to_owned()
+}
+
+fn default_passed() -> String {
+    "passed".to_owned()
+}
+
+fn default_unread() -> String {
+    "unread".to_owned()
+}
+
 fn default_concurrent_downloads() -> usize {
     8
 }
 
-fn default_timeout() -> u64 {
+fn default_max_changes() -> u64 {
+    10000
+}
+
+fn default_connect_timeout() -> u64 {
     5
 }
 
+fn default_api_timeout() -> u64 {
+    30
+}
+
+fn default_download_timeout() -> u64 {
+    30
+}
+
 fn default_retries() -> usize {
     5
 }
 
+fn default_download_newest_first() -> bool {
+    true
+}
+
+fn default_download_largest_last() -> bool {
+    false
+}
+
+fn default_quota_warning_percent() -> u8 {
+    90
+}
+
+fn default_progressive_backfill() -> bool {
+    false
+}
+
 fn default_auto_create_new_mailboxes() -> bool {
     true
 }
 
+fn default_auto_destroy_empty_mailboxes() -> bool {
+    false
+}
+
+fn default_link_existing_messages() -> bool {
+    false
+}
+
+fn default_push_excluded() -> bool {
+    true
+}
+
+fn default_push_drafts() -> bool {
+    false
+}
+
+fn default_auto_create_archive_mailbox() -> bool {
+    false
+}
+
+fn default_fsync() -> bool {
+    true
+}
+
+fn default_compress_cache() -> bool {
+    false
+}
+
+fn default_encrypt_cache() -> bool {
+    false
+}
+
+fn default_send_delay() -> u64 {
+    0
+}
+
 fn default_convert_dos_to_unix() -> bool {
     true
 }
 
 impl Config {
     pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
-        let contents = fs::read_to_string(path.as_ref()).context(ReadConfigFileSnafu {
-            filename: path.as_ref(),
-        })?;
-        let config: Self = toml::from_str(contents.as_str()).context(ParseConfigFileSnafu {
-            filename: path.as_ref(),
-        })?;
-
-        // Perform final validation.
-        ensure!(
-            !(config.fqdn.is_some() && config.session_url.is_some()),
-            FqdnOrSessionUrlSnafu {}
+        let config = Self::from_file_reporting_unknown(path, |_| {})?;
+        match config.problems().into_iter().next() {
+            Some(problem) => Err(problem),
+            None => Ok(config),
+        }
+    }
+
+    /// Read a config file without validating it, calling `unknown` with the path of each option
+    /// which isn't recognized, e.g. `tags.inobx`.
+    pub fn from_file_reporting_unknown(
+        path: impl AsRef<Path>,
+        mut unknown: impl FnMut(String),
+    ) -> Result<Self> {
+        let mut value = read_value(path.as_ref(), &mut Vec::new())?;
+        apply_env_overrides(&mut value);
+        let mut config: Self = serde_ignored::deserialize(value, |path| unknown(path.to_string()))
+            .context(ParseConfigFileSnafu {
+                filename: path.as_ref(),
+            })?;
+
+        // Honor the deprecated catch-all timeout.
+        if let Some(timeout) = config.timeout {
+            config.connect_timeout = timeout;
+            config.api_timeout = timeout;
+            config.download_timeout = timeout;
+        }
+
+        Ok(config)
+    }
+
+    /// Return every problem with the combination of options, e.g. options which are mutually
+    /// exclusive.
+    pub fn problems(&self) -> Vec<Error> {
+        let mut problems = Vec::new();
+        if self.fqdn.is_some() && self.session_url.is_some() {
+            problems.push(Error::FqdnOrSessionUrl {});
+        }
+        if self.account_id.is_some() && self.account_name.is_some() {
+            problems.push(Error::AccountIdOrAccountName {});
+        }
+        if !self.nameservers.is_empty() && self.dns_over_https.is_some() {
+            problems.push(Error::NameserversOrDnsOverHttps {});
+        }
+        problems.extend(
+            self.nameservers
+                .iter()
+                .filter(|nameserver| parse_nameserver(nameserver).is_none())
+                .map(|nameserver| Error::InvalidNameserver {
+                    nameserver: nameserver.clone(),
+                }),
         );
-        ensure!(
-            config.concurrent_downloads > 0,
-            ConcurrentDownloadsIsZeroSnafu {}
+        if self.concurrent_downloads == 0 {
+            problems.push(Error::ConcurrentDownloadsIsZero {});
+        }
+        if self.max_connections == Some(0) {
+            problems.push(Error::MaxConnectionsIsZero {});
+        }
+        if self.max_changes == 0 {
+            problems.push(Error::MaxChangesIsZero {});
+        }
+        problems.extend(
+            [
+                ("trash_after", &self.expunge.trash_after),
+                ("spam_after", &self.expunge.spam_after),
+            ]
+            .into_iter()
+            .filter_map(|(option, value)| value.as_ref().map(|value| (option, value)))
+            .filter(|(_, value)| parse_age(value).is_none())
+            .map(|(option, value)| Error::InvalidExpungeAge {
+                option,
+                value: value.clone(),
+            }),
         );
-        ensure!(
-            !config.tags.directory_separator.is_empty(),
-            EmptyDirectorySeparatorSnafu {}
+        if self.quota_warning_percent > 100 {
+            problems.push(Error::QuotaWarningPercentTooLarge {});
+        }
+        if self.tags.directory_separator.is_empty() {
+            problems.push(Error::EmptyDirectorySeparator {});
+        }
+        problems.extend(
+            [
+                ("draft", &self.tags.draft),
+                ("flagged", &self.tags.flagged),
+                ("replied", &self.tags.replied),
+                ("passed", &self.tags.passed),
+                ("unread", &self.tags.unread),
+            ]
+            .into_iter()
+            .filter(|(_, tag)| tag.is_empty())
+            .map(|(option, _)| Error::EmptyKeywordTag { option }),
         );
-        Ok(config)
+        if self.tags.replacements.contains_key("") {
+            problems.push(Error::EmptyReplacement {});
+        }
+        problems
+    }
+
+    /// Return the maildir, given the path of the config file it was read from.
+    pub fn mail_dir(&self, config_path: &Path) -> PathBuf {
+        let config_dir = match config_path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        match &self.mail_dir {
+            Some(mail_dir) => config_dir.join(mail_dir),
+            None => config_dir,
+        }
+    }
+
+    /// Return the configured cache directory, or the operating system's default.
+    pub fn cache_dir(&self) -> PathBuf {
+        match &self.cache_dir {
+            Some(cache_dir) => cache_dir.clone(),
+            None => ProjectDirs::from("sh.eliza", "", "mujmap")
+                .unwrap()
+                .cache_dir()
+                .to_path_buf(),
+        }
     }
 
     pub fn password(&self) -> Result<String> {
-        let output = Command::new("sh")
-            .arg("-c")
-            .arg(self.password_command.as_str())
-            .output()
-            .context(ExecutePasswordCommandSnafu {})?;
-        ensure!(
-            output.status.success(),
-            PasswordCommandStatusSnafu {
-                status: output.status,
-                stderr: String::from_utf8(output.stderr)
-                    .unwrap_or_else(|e| format!("<utf-8 decode error: {e}>")),
+        password_from_command(&self.password_command)
+    }
+}
+
+/// Read a config file, with the files it includes merged beneath it. `stack` contains the files
+/// currently being read, to detect files which include themselves.
+fn read_value(path: &Path, stack: &mut Vec<PathBuf>) -> Result<toml::Value> {
+    let contents = fs::read_to_string(path).context(ReadConfigFileSnafu { filename: path })?;
+    let mut value: toml::Value =
+        toml::from_str(contents.as_str()).context(ParseConfigFileSnafu { filename: path })?;
+    expand_env_vars(&mut value)?;
+
+    let includes = match value
+        .as_table_mut()
+        .and_then(|table| table.remove("include"))
+    {
+        Some(toml::Value::Array(includes)) => includes,
+        Some(_) => return InvalidIncludeSnafu { filename: path }.fail(),
+        None => return Ok(value),
+    };
+    let canonical_path = path
+        .canonicalize()
+        .context(ReadConfigFileSnafu { filename: path })?;
+    ensure!(
+        !stack.contains(&canonical_path),
+        IncludeCycleSnafu { filename: path }
+    );
+    stack.push(canonical_path);
+
+    // Later includes take precedence over earlier ones, and the including file over all of them.
+    let mut merged = toml::Value::Table(toml::value::Table::new());
+    for include in includes {
+        let include = include
+            .as_str()
+            .context(InvalidIncludeSnafu { filename: path })?;
+        let include_path = match include.strip_prefix("~/") {
+            Some(rest) => BaseDirs::new()
+                .context(InvalidIncludeSnafu { filename: path })?
+                .home_dir()
+                .join(rest),
+            // Relative paths are relative to the including file.
+            None => path.parent().unwrap_or(Path::new("")).join(include),
+        };
+        merge_value(&mut merged, read_value(&include_path, stack)?);
+    }
+    merge_value(&mut merged, value);
+
+    stack.pop();
+    Ok(merged)
+}
+
+/// Merge `value` over `base`. Tables are merged recursively; anything else in `value` replaces
+/// the corresponding value in `base`.
+fn merge_value(base: &mut toml::Value, value: toml::Value) {
+    match (base, value) {
+        (toml::Value::Table(base), toml::Value::Table(table)) => {
+            for (key, value) in table {
+                match base.get_mut(&key) {
+                    Some(base_value) => merge_value(base_value, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
             }
+        }
+        (base, value) => *base = value,
+    }
+}
+
+/// Replace each `${VAR}` in the strings of `value` with the value of the environment variable
+/// `VAR`, and each `$${` with a literal `${`.
+///
+/// `password_command` is left alone, since the shell expands variables in it itself.
+fn expand_env_vars(value: &mut toml::Value) -> Result<()> {
+    match value {
+        toml::Value::String(s) => *s = expand_env_vars_in_str(s)?,
+        toml::Value::Array(array) => {
+            for value in array {
+                expand_env_vars(value)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                if key != "password_command" {
+                    expand_env_vars(value)?;
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn expand_env_vars_in_str(s: &str) -> Result<String> {
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            expanded.push_str(&rest[..start - 1]);
+            expanded.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        expanded.push_str(&rest[..start]);
+        let len = rest[start..]
+            .find('}')
+            .context(UnterminatedVariableSnafu { value: s })?;
+        let name = &rest[start + 2..start + len];
+        expanded.push_str(
+            &env::var(name)
+                .ok()
+                .context(UndefinedVariableSnafu { name })?,
+        );
+        rest = &rest[start + len + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Override top-level options with `MUJMAP_<OPTION>` environment variables, e.g.
+/// `MUJMAP_SESSION_URL`.
+///
+/// Values are read as TOML if possible, e.g. numbers, booleans, and arrays, and as strings
+/// otherwise. Options which are strings in the config file stay strings.
+fn apply_env_overrides(value: &mut toml::Value) {
+    let table = match value.as_table_mut() {
+        Some(table) => table,
+        None => return,
+    };
+    for (name, override_value) in env::vars_os() {
+        let (name, override_value) = match (name.into_string(), override_value.into_string()) {
+            (Ok(name), Ok(override_value)) => (name, override_value),
+            _ => continue,
+        };
+        let key = match name.strip_prefix("MUJMAP_") {
+            Some(key) => key.to_lowercase(),
+            None => continue,
+        };
+        let parsed = match table.get(&key) {
+            Some(toml::Value::String(_)) => None,
+            _ => toml::from_str::<toml::value::Table>(&format!("value = {override_value}"))
+                .ok()
+                .and_then(|mut x| x.remove("value")),
+        };
+        table.insert(key, parsed.unwrap_or(toml::Value::String(override_value)));
+    }
+}
+
+/// Parse a nameserver address, which may omit the port.
+pub fn parse_nameserver(nameserver: &str) -> Option<SocketAddr> {
+    nameserver.parse().ok().or_else(|| {
+        nameserver
+            .parse::<IpAddr>()
+            .ok()
+            .map(|ip| SocketAddr::new(ip, 53))
+    })
+}
+
+/// Parse an age like `30d`, i.e. a positive number of hours (`h`), days (`d`), or weeks (`w`).
+pub fn parse_age(age: &str) -> Option<Duration> {
+    let age = age.trim();
+    let unit = match age.chars().last()? {
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    let count: u64 = age[..age.len() - 1].trim().parse().ok()?;
+    if count == 0 {
+        return None;
+    }
+    count.checked_mul(unit).map(Duration::from_secs)
+}
+
+/// Run the given password command and return the password it prints.
+pub fn password_from_command(password_command: &str) -> Result<String> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(password_command)
+        .output()
+        .context(ExecutePasswordCommandSnafu {})?;
+    ensure!(
+        output.status.success(),
+        PasswordCommandStatusSnafu {
+            status: output.status,
+            stderr: String::from_utf8(output.stderr)
+                .unwrap_or_else(|e| format!("<utf-8 decode error: {e}>")),
+        }
+    );
+    let stdout = String::from_utf8(output.stdout).context(DecodePasswordCommandSnafu {})?;
+    Ok(stdout.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_vars_are_expanded() {
+        env::set_var("MUJMAP_TEST_EXPANDED", "value");
+        assert_eq!(
+            expand_env_vars_in_str("a ${MUJMAP_TEST_EXPANDED} b ${MUJMAP_TEST_EXPANDED}").unwrap(),
+            "a value b value"
+        );
+    }
+
+    #[test]
+    fn escaped_env_vars_are_kept() {
+        env::set_var("MUJMAP_TEST_ESCAPED", "value");
+        assert_eq!(
+            expand_env_vars_in_str("$${MUJMAP_TEST_ESCAPED} ${MUJMAP_TEST_ESCAPED}").unwrap(),
+            "${MUJMAP_TEST_ESCAPED} value"
+        );
+        assert_eq!(expand_env_vars_in_str("$${").unwrap(), "${");
+    }
+
+    #[test]
+    fn unterminated_env_var_is_an_error() {
+        assert!(matches!(
+            expand_env_vars_in_str("a ${MUJMAP_TEST_UNTERMINATED"),
+            Err(Error::UnterminatedVariable { .. })
+        ));
+    }
+
+    #[test]
+    fn undefined_env_var_is_an_error() {
+        assert!(matches!(
+            expand_env_vars_in_str("${MUJMAP_TEST_UNDEFINED}"),
+            Err(Error::UndefinedVariable { name }) if name == "MUJMAP_TEST_UNDEFINED"
+        ));
+    }
+
+    #[test]
+    fn password_command_is_not_expanded() {
+        let mut value: toml::Value = toml::from_str(
+            r#"
+            password_command = "pass ${MUJMAP_TEST_PASSWORD_UNDEFINED}"
+            "#,
+        )
+        .unwrap();
+        expand_env_vars(&mut value).unwrap();
+        assert_eq!(
+            value["password_command"].as_str(),
+            Some("pass ${MUJMAP_TEST_PASSWORD_UNDEFINED}")
         );
-        let stdout = String::from_utf8(output.stdout).context(DecodePasswordCommandSnafu {})?;
-        Ok(stdout.trim().to_string())
     }
 }
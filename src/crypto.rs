@@ -0,0 +1,127 @@
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use snafu::prelude::*;
+use std::fs;
+use std::io;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not read salt file `{}': {}", path.to_string_lossy(), source))]
+    ReadSalt { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Could not write salt file `{}': {}", path.to_string_lossy(), source))]
+    WriteSalt { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Could not generate random bytes"))]
+    Random {},
+
+    #[snafu(display("Could not encrypt data"))]
+    Encrypt {},
+
+    #[snafu(display(
+        "Could not decrypt data; was the password returned by `password_command' changed?"
+    ))]
+    Decrypt {},
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Prefix of all encrypted data, which distinguishes it from plaintext.
+const MAGIC: &[u8] = b"mujmap-encrypted-v1\n";
+
+/// Number of PBKDF2 iterations used to derive a key from the password.
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+/// Length of the salt used to derive a key from the password.
+const SALT_LEN: usize = 16;
+
+/// Symmetric key used to encrypt the cache and state file at rest.
+#[derive(Clone)]
+pub struct Key([u8; 32]);
+
+impl Key {
+    /// Derive the key from the given password and the salt stored at `salt_path`. If there is no
+    /// salt yet, a random one is generated and saved.
+    pub fn derive(password: &str, salt_path: &Path) -> Result<Self> {
+        let salt = match fs::read(salt_path) {
+            Ok(salt) => salt,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let mut salt = vec![0; SALT_LEN];
+                SystemRandom::new()
+                    .fill(&mut salt)
+                    .ok()
+                    .context(RandomSnafu {})?;
+                fs::write(salt_path, &salt).context(WriteSaltSnafu { path: salt_path })?;
+                salt
+            }
+            Err(e) => return Err(e).context(ReadSaltSnafu { path: salt_path }),
+        };
+
+        let mut key = [0; 32];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+            &salt,
+            password.as_bytes(),
+            &mut key,
+        );
+        Ok(Self(key))
+    }
+
+    fn aead_key(&self) -> LessSafeKey {
+        LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &self.0).unwrap())
+    }
+
+    /// Encrypt the given data with a random nonce.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .ok()
+            .context(RandomSnafu {})?;
+
+        let mut in_out = plaintext.to_vec();
+        self.aead_key()
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut in_out,
+            )
+            .ok()
+            .context(EncryptSnafu {})?;
+
+        let mut output = Vec::with_capacity(MAGIC.len() + NONCE_LEN + in_out.len());
+        output.extend_from_slice(MAGIC);
+        output.extend_from_slice(&nonce);
+        output.extend_from_slice(&in_out);
+        Ok(output)
+    }
+
+    /// Decrypt data previously returned by `encrypt`.
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let data = data.strip_prefix(MAGIC).context(DecryptSnafu {})?;
+        ensure!(data.len() >= NONCE_LEN, DecryptSnafu {});
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce)
+            .ok()
+            .context(DecryptSnafu {})?;
+
+        let mut in_out = ciphertext.to_vec();
+        let len = self
+            .aead_key()
+            .open_in_place(nonce, Aad::empty(), &mut in_out)
+            .ok()
+            .context(DecryptSnafu {})?
+            .len();
+        in_out.truncate(len);
+        Ok(in_out)
+    }
+}
+
+/// Return true if the given data was encrypted by `Key::encrypt`.
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
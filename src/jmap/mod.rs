@@ -3,6 +3,7 @@ mod response;
 mod session;
 
 use core::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub use request::*;
 pub use response::*;
@@ -27,6 +28,34 @@ impl fmt::Display for State {
     }
 }
 
+/// Format a time as a JMAP `UTCDate`, e.g. `2014-10-30T06:12:00Z`.
+pub fn utc_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Convert days since the epoch to a civil date. See
+    // <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
 /// Keywords that assign meaning to email.
 ///
 /// Note that JMAP mandates that these be lowercase.
@@ -55,3 +84,11 @@ pub enum EmailKeyword {
     #[serde(other)]
     Unknown,
 }
+
+impl EmailKeyword {
+    /// Return the keyword with the given name, or `Unknown` if mujmap doesn't know of it.
+    pub fn from_name(name: &str) -> Self {
+        serde_json::from_value(serde_json::Value::String(name.to_lowercase()))
+            .unwrap_or(Self::Unknown)
+    }
+}
@@ -1,4 +1,4 @@
-use super::{EmailKeyword, Id, State};
+use super::{EmailKeyword, Id, MailboxRole, State};
 use serde::{ser::SerializeSeq, Serialize, Serializer};
 use serde_json::Value;
 use std::collections::HashMap;
@@ -9,6 +9,8 @@ pub enum CapabilityKind {
     Mail,
     #[serde(rename = "urn:ietf:params:jmap:submission")]
     Submission,
+    #[serde(rename = "urn:ietf:params:jmap:quota")]
+    Quota,
 }
 
 #[derive(Serialize)]
@@ -82,9 +84,18 @@ impl<'a> Serialize for RequestInvocation<'a> {
             MethodCall::IdentityGet { .. } => {
                 seq.serialize_element("Identity/get")?;
             }
+            MethodCall::EmailSubmissionGet { .. } => {
+                seq.serialize_element("EmailSubmission/get")?;
+            }
+            MethodCall::EmailSubmissionQuery { .. } => {
+                seq.serialize_element("EmailSubmission/query")?;
+            }
             MethodCall::EmailSubmissionSet { .. } => {
                 seq.serialize_element("EmailSubmission/set")?;
             }
+            MethodCall::QuotaGet { .. } => {
+                seq.serialize_element("Quota/get")?;
+            }
         }
 
         seq.serialize_element(&self.call)?;
@@ -138,6 +149,12 @@ pub enum MethodCall<'a> {
     MailboxSet {
         #[serde(flatten)]
         set: MethodCallSet<'a, MailboxCreate>,
+        /// If false, any attempt to destroy a `Mailbox` that still has `Email`s in it will be
+        /// rejected with a `MailboxHasEmail` error. If true, any `Email`s that were in the
+        /// `Mailbox` will be removed from it, and if in no other `Mailbox`es, they will be
+        /// destroyed when the `Mailbox` is destroyed.
+        #[serde(default, skip_serializing_if = "default")]
+        on_destroy_remove_emails: bool,
     },
 
     #[serde(rename_all = "camelCase")]
@@ -146,6 +163,18 @@ pub enum MethodCall<'a> {
         get: MethodCallGet<'a>,
     },
 
+    #[serde(rename_all = "camelCase")]
+    EmailSubmissionGet {
+        #[serde(flatten)]
+        get: MethodCallGet<'a>,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    EmailSubmissionQuery {
+        #[serde(flatten)]
+        query: MethodCallQuery<'a>,
+    },
+
     #[serde(rename_all = "camelCase")]
     EmailSubmissionSet {
         #[serde(flatten)]
@@ -157,6 +186,16 @@ pub enum MethodCall<'a> {
         /// with a "#".)
         #[serde(skip_serializing_if = "Option::is_none")]
         on_success_update_email: Option<HashMap<&'a Id, HashMap<&'a str, Value>>>,
+        /// A list of `EmailSubmission` ids for which the `Email` with the corresponding `emailId`
+        /// should be destroyed if the create/update/destroy succeeds.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        on_success_destroy_email: Option<&'a [&'a Id]>,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    QuotaGet {
+        #[serde(flatten)]
+        get: MethodCallGet<'a>,
     },
 }
 
@@ -183,6 +222,10 @@ pub struct MethodCallGet<'a> {
 pub struct MethodCallQuery<'a> {
     /// The id of the account to use.
     pub account_id: &'a Id,
+    /// Determines the set of Foos returned in the results. If `None`, all objects of this type in
+    /// the account must be included in the results.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<&'a Value>,
     /// The zero-based index of the first id in the full list of results to return.
     ///
     /// If a negative value is given, it is an offset from the end of the list. Specifically, the
@@ -313,6 +356,10 @@ pub struct MailboxCreate {
     /// sibling Mailboxes with both the same parent and the same name. Servers MAY reject names that
     /// violate server policy (e.g., names containing a slash (/) or control characters).
     pub name: String,
+    /// Identifies Mailboxes that have a particular common purpose (e.g., the “inbox”), regardless
+    /// of the name property (which may be localised).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<MailboxRole>,
 }
 
 #[derive(Debug, Serialize)]
@@ -364,6 +411,10 @@ pub struct Address<'a> {
     /// The email address being represented by the object. This is a "Mailbox" as used in the
     /// Reverse-path or Forward-path of the MAIL FROM or RCPT TO command in [RFC532].
     pub email: &'a str,
+    /// Any parameters to send with the email address (either mail-parameter or rcpt-parameter as
+    /// appropriate, as specified in [RFC5321]), e.g. `HOLDFOR` to delay sending.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<HashMap<&'a str, String>>,
 }
 
 fn default<T: Default + PartialEq>(t: &T) -> bool {
@@ -1,13 +1,14 @@
 use serde::{
     de::{Error, SeqAccess, Visitor},
-    Deserialize, Deserializer,
+    Deserialize, Deserializer, Serialize,
 };
+use serde_json::Value;
 use std::{
     collections::HashMap,
     fmt::{self, Display},
 };
 
-use super::{EmailKeyword, Id, State};
+use super::{Id, State};
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -89,10 +90,22 @@ impl<'de> Deserialize<'de> for ResponseInvocation {
                         seq.next_element::<MethodResponseGetIdentity>()?
                             .ok_or(length_err)?,
                     )),
+                    "EmailSubmission/get" => Ok(MethodResponse::EmailSubmissionGet(
+                        seq.next_element::<MethodResponseGet<EmailSubmission>>()?
+                            .ok_or(length_err)?,
+                    )),
+                    "EmailSubmission/query" => Ok(MethodResponse::EmailSubmissionQuery(
+                        seq.next_element::<MethodResponseQuery>()?
+                            .ok_or(length_err)?,
+                    )),
                     "EmailSubmission/set" => Ok(MethodResponse::EmailSubmissionSet(
                         seq.next_element::<MethodResponseSet<GenericObjectWithId>>()?
                             .ok_or(length_err)?,
                     )),
+                    "Quota/get" => Ok(MethodResponse::QuotaGet(
+                        seq.next_element::<MethodResponseGet<Quota>>()?
+                            .ok_or(length_err)?,
+                    )),
                     "error" => Ok(MethodResponse::Error(
                         seq.next_element::<MethodResponseError>()?
                             .ok_or(length_err)?,
@@ -108,7 +121,10 @@ impl<'de> Deserialize<'de> for ResponseInvocation {
                             "Mailbox/get",
                             "Mailbox/set",
                             "Identity/get",
+                            "EmailSubmission/get",
+                            "EmailSubmission/query",
                             "EmailSubmission/set",
+                            "Quota/get",
                             "error",
                         ],
                     )),
@@ -258,7 +274,10 @@ pub struct MethodResponseSet<T> {
     /// client know of any changes to server-set or computed properties.
     ///
     /// This argument is `None` if no `T` objects were successfully updated.
-    pub updated: Option<HashMap<Id, T>>,
+    ///
+    /// NB: The values are kept as raw JSON since they are usually `null` and only ever contain a
+    /// subset of the properties of `T`.
+    pub updated: Option<HashMap<Id, Option<Value>>>,
     /// A list of `T` ids for records that were successfully destroyed, or `None` if none.
     pub destroyed: Option<Vec<Id>>,
     /// A map of the creation id to a `MethodResponseError` object for each record that failed to be
@@ -282,14 +301,26 @@ pub struct MethodResponseEmailImport {
     pub old_state: Option<State>,
     /// The state string that will now be returned by `Email/get`.
     pub new_state: Option<State>,
-    /// A map of the creation id to an object containing the "id" property for each successfully
-    /// imported `Email`, or `None` if none.
-    pub created: Option<HashMap<Id, GenericObjectWithId>>,
+    /// A map of the creation id to an object containing the "id", "blobId", and "threadId"
+    /// properties for each successfully imported `Email`, or `None` if none.
+    pub created: Option<HashMap<Id, ImportedEmail>>,
     /// A map of the creation id to a SetError object for each `Email` that failed to be created, or
     /// `None` if all successful.
     pub not_created: Option<HashMap<Id, MethodResponseError>>,
 }
 
+/// An `Email` created by `Email/import`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportedEmail {
+    /// The id of the Email.
+    pub id: Id,
+    /// The id of the blob of the raw RFC 5322 message.
+    pub blob_id: Id,
+    /// The id of the Thread to which the Email belongs.
+    pub thread_id: Id,
+}
+
 /// Struct for updates in a call to `T/set` which we don't care about.
 #[derive(Debug, Deserialize)]
 pub struct EmptySetUpdated;
@@ -307,8 +338,19 @@ pub struct GenericObjectWithId {
 pub struct Email {
     pub id: Id,
     pub blob_id: Id,
-    pub keywords: HashMap<EmailKeyword, bool>,
+    /// The keywords of the email by name, including those mujmap doesn't know of, so that they
+    /// can be kept when the keywords are replaced as a whole.
+    pub keywords: HashMap<String, bool>,
     pub mailbox_ids: HashMap<Id, bool>,
+    /// The date the email was received by the message store, as an RFC 3339 `UTCDate`.
+    #[serde(default)]
+    pub received_at: Option<String>,
+    /// The `Message-ID` header field, without angle brackets. There is usually exactly one.
+    #[serde(default)]
+    pub message_id: Option<Vec<String>>,
+    /// The size of the raw message in octets.
+    #[serde(default)]
+    pub size: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -329,11 +371,34 @@ pub struct Mailbox {
     /// Identifies Mailboxes that have a particular common purpose (e.g., the “inbox”), regardless
     /// of the name property (which may be localised).
     pub role: Option<MailboxRole>,
+    /// The set of rights (Access Control Lists (ACLs)) the user has in relation to this Mailbox.
+    ///
+    /// NB: The spec requires this property, but we tolerate servers which omit it, in which case
+    /// all rights are assumed.
+    #[serde(default)]
+    pub my_rights: Option<MailboxRights>,
+    /// Has the user indicated they wish to see this Mailbox in their client?
+    #[serde(default)]
+    pub is_subscribed: Option<bool>,
+    /// The number of Emails in this Mailbox.
+    #[serde(default)]
+    pub total_emails: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MailboxRights {
+    /// The user may add mail to this mailbox (by either creating a new Email or moving an existing
+    /// one).
+    pub may_add_items: bool,
+    /// The user may remove mail from this mailbox (by either changing the Mailboxes of an Email or
+    /// destroying the Email).
+    pub may_remove_items: bool,
 }
 
 /// See
 /// <https://www.iana.org/assignments/imap-mailbox-name-attributes/imap-mailbox-name-attributes.xhtml>.
-#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug, Deserialize)]
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum MailboxRole {
     /// All messages.
@@ -369,6 +434,137 @@ pub struct Identity {
     /// "*" (e.g., "*@example.com"), the client may use any valid address ending in that domain
     /// (e.g., "foo@example.com").
     pub email: String,
+    /// The "From" name the client SHOULD use when creating a new Email from this `Identity`.
+    #[serde(default)]
+    pub name: String,
+    /// The Reply-To value the client SHOULD set when creating a new Email from this `Identity`.
+    #[serde(default)]
+    pub reply_to: Option<Vec<EmailAddress>>,
+    /// Is the user allowed to delete this `Identity`? Servers may wish to set this to false for
+    /// the user's username or other default address.
+    #[serde(default)]
+    pub may_delete: bool,
+}
+
+/// A limit on the resources an account may use, as defined by RFC 9425.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Quota {
+    /// The id of the `Quota`.
+    pub id: Id,
+    /// The type of resource counted, e.g. `octets` for the total size of all data, or `count`
+    /// for the number of objects.
+    pub resource_type: String,
+    /// The amount of the resource used.
+    pub used: u64,
+    /// The amount of the resource which may be used, beyond which the server rejects new data.
+    pub hard_limit: u64,
+    /// A name for the quota, for display to the user.
+    #[serde(default)]
+    pub name: String,
+    /// The data types counted by this quota, e.g. `Mail`.
+    #[serde(default)]
+    pub types: Vec<String>,
+}
+
+/// A name and email address, as used in the address headers of an `Email`.
+#[derive(Debug, Deserialize)]
+pub struct EmailAddress {
+    /// The display name of the mailbox, if any.
+    pub name: Option<String>,
+    /// The address of the mailbox.
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailSubmission {
+    /// The id of the `EmailSubmission`.
+    pub id: Id,
+    /// The id of the `Email` to send.
+    #[serde(default)]
+    pub email_id: Option<Id>,
+    /// Whether the submission may be canceled.
+    pub undo_status: UndoStatus,
+    /// A map of recipient email address to the status of delivery to that recipient, or `None` if
+    /// the server does not support delivery status notifications.
+    #[serde(default)]
+    pub delivery_status: Option<HashMap<String, DeliveryStatus>>,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UndoStatus {
+    /// It may be possible to cancel this submission.
+    Pending,
+    /// The message has been relayed to at least one recipient in a manner that cannot be recalled.
+    Final,
+    /// The submission was canceled and will not be delivered to any recipient.
+    Canceled,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeliveryStatus {
+    /// The SMTP reply string returned for this recipient when the server last tried to relay the
+    /// message, or in a later Delivery Status Notification (DSN) response for the message.
+    pub smtp_reply: String,
+    /// Represents whether the message has been successfully delivered to the recipient.
+    pub delivered: Delivered,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Delivered {
+    /// The message is in a local mail queue and the status will change once it exits the local
+    /// mail queues.
+    Queued,
+    /// The message was successfully delivered to the mail store of the recipient.
+    Yes,
+    /// Delivery to the recipient permanently failed.
+    No,
+    /// The final delivery status is unknown, e.g. because it was relayed to an external server
+    /// which does not report it.
+    Unknown,
+}
+
+/// The `type` of a request-level error, i.e. an RFC 7807 problem details response to a whole API
+/// request rather than to one of its method calls.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestErrorType {
+    /// The client included a capability in the “using” property of the request that the server does
+    /// not support.
+    UnknownCapability,
+    /// The content type of the request was not application/json or the request did not parse as
+    /// I-JSON.
+    NotJSON,
+    /// The request parsed as JSON but did not match the type signature of the Request object.
+    NotRequest,
+    /// The request was not processed as it would have exceeded one of the request limits defined on
+    /// the capability object.
+    Limit,
+    /// Not defined by RFC 8620, but used by some servers to reject requests made with credentials
+    /// or a session they no longer accept, e.g. after a password change.
+    Forbidden,
+    /// Any other type, verbatim.
+    Other(String),
+}
+
+impl RequestErrorType {
+    const PREFIX: &'static str = "urn:ietf:params:jmap:error:";
+
+    pub fn from_uri(uri: &str) -> Self {
+        match uri.strip_prefix(Self::PREFIX) {
+            Some("unknownCapability") => Self::UnknownCapability,
+            Some("notJSON") => Self::NotJSON,
+            Some("notRequest") => Self::NotRequest,
+            Some("limit") => Self::Limit,
+            Some("forbidden" | "unauthorized" | "unknownSession" | "sessionExpired") => {
+                Self::Forbidden
+            }
+            _ => Self::Other(uri.to_owned()),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -384,8 +580,12 @@ pub enum MethodResponse {
 
     IdentityGet(MethodResponseGetIdentity),
 
+    EmailSubmissionGet(MethodResponseGet<EmailSubmission>),
+    EmailSubmissionQuery(MethodResponseQuery),
     EmailSubmissionSet(MethodResponseSet<GenericObjectWithId>),
 
+    QuotaGet(MethodResponseGet<Quota>),
+
     Error(MethodResponseError),
 }
 
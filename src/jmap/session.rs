@@ -1,4 +1,5 @@
 use serde::Deserialize;
+use serde_json::Value;
 use std::collections::HashMap;
 
 use super::{Id, State};
@@ -43,18 +44,28 @@ pub struct PrimaryAccounts {
     pub core: Id,
     #[serde(rename = "urn:ietf:params:jmap:mail")]
     pub mail: Id,
+    /// The account to send mail from, which need not be the primary mail account.
+    #[serde(rename = "urn:ietf:params:jmap:submission", default)]
+    pub submission: Option<Id>,
 }
 
+/// Servers disagree on how strictly to follow the spec here, so only the core capability is
+/// required. Anything else is kept as is in `other`.
 #[derive(Debug, Deserialize)]
 pub struct Capabilities {
     #[serde(rename = "urn:ietf:params:jmap:core")]
     pub core: CoreCapabilities,
-    #[serde(rename = "urn:ietf:params:jmap:mail")]
-    pub mail: EmptyCapabilities,
+    #[serde(rename = "urn:ietf:params:jmap:mail", default)]
+    pub mail: Option<EmptyCapabilities>,
+    /// Capabilities mujmap doesn't use, e.g. vendor extensions.
+    #[serde(flatten)]
+    pub other: HashMap<String, Value>,
 }
 
+/// Limits missing from the server's core capability take the minimums RFC 8620 recommends
+/// servers support.
 #[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(rename_all = "camelCase", default)]
 pub struct CoreCapabilities {
     /// The maximum file size, in octets, that the server will accept for a single file upload (for
     /// any purpose).
@@ -81,6 +92,21 @@ pub struct CoreCapabilities {
     pub collation_algorithms: Vec<String>,
 }
 
+impl Default for CoreCapabilities {
+    fn default() -> Self {
+        Self {
+            max_size_upload: 50_000_000,
+            max_concurrent_upload: 4,
+            max_size_request: 10_000_000,
+            max_concurrent_requests: 4,
+            max_calls_in_request: 16,
+            max_objects_in_get: 500,
+            max_objects_in_set: 500,
+            collation_algorithms: Vec::new(),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct EmptyCapabilities {}
 
@@ -102,16 +128,42 @@ pub struct Account {
     pub account_capabilities: AccountCapabilities,
 }
 
+/// Like `Capabilities`, every field is optional, since some servers omit even the mail capability
+/// of the mail account.
 #[derive(Debug, Deserialize)]
 pub struct AccountCapabilities {
-    #[serde(rename = "urn:ietf:params:jmap:core")]
-    pub core: EmptyCapabilities,
-    #[serde(rename = "urn:ietf:params:jmap:mail")]
-    pub mail: MailAccountCapabilities,
+    #[serde(rename = "urn:ietf:params:jmap:core", default)]
+    pub core: Option<EmptyCapabilities>,
+    #[serde(rename = "urn:ietf:params:jmap:mail", default)]
+    pub mail: Option<MailAccountCapabilities>,
+    /// Present if the user may send mail from this account.
+    #[serde(rename = "urn:ietf:params:jmap:submission", default)]
+    pub submission: Option<SubmissionAccountCapabilities>,
+    /// Present if the server reports the quotas of this account (RFC 9425).
+    #[serde(rename = "urn:ietf:params:jmap:quota", default)]
+    pub quota: Option<EmptyCapabilities>,
+    /// Capabilities mujmap doesn't use, e.g. vendor extensions.
+    #[serde(flatten)]
+    pub other: HashMap<String, Value>,
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
+pub struct SubmissionAccountCapabilities {
+    /// The number in seconds of the maximum delay the server supports in sending (see the
+    /// `EmailSubmission` object description). This is 0 if the server does not support delayed
+    /// send.
+    #[serde(default)]
+    pub max_delayed_send: u64,
+    /// The set of SMTP submission extensions supported by the server, which the client may use
+    /// when creating an `EmailSubmission` object. Each key is the extension's EHLO name, and each
+    /// value is the list of arguments it was advertised with, if any.
+    #[serde(default)]
+    pub submission_extensions: HashMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
 pub struct MailAccountCapabilities {
     /// The maximum number of Mailboxes that can be can assigned to a single Email object. This MUST
     /// be an integer >= 1, or `None` for no limit (or rather, the limit is always the number of
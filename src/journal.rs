@@ -0,0 +1,173 @@
+use crate::config::Config;
+use crate::local::{self, Local};
+use crate::lock::{self, Lock};
+use crate::remote::{self, PushedChange, Remote};
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// File in the maildir which records the changes pushed by each sync, one JSON batch per line.
+pub const JOURNAL_FILE: &str = "mujmap.journal.jsonl";
+
+/// Number of batches kept in the journal. Older ones are dropped when a new one is recorded.
+const MAX_BATCHES: usize = 100;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not lock `{}': {}", path.to_string_lossy(), source))]
+    Lock { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Could not read journal `{}': {}", path.to_string_lossy(), source))]
+    ReadJournal { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Could not parse journal `{}': {}", path.to_string_lossy(), source))]
+    ParseJournal {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Could not write journal `{}': {}", path.to_string_lossy(), source))]
+    WriteJournal { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Could not serialize journal batch: {}", source))]
+    SerializeBatch { source: serde_json::Error },
+
+    #[snafu(display("Could not open local database: {}", source))]
+    OpenLocal { source: local::Error },
+
+    #[snafu(display("Could not index local emails: {}", source))]
+    IndexLocalEmails { source: local::Error },
+
+    #[snafu(display("Could not update local email: {}", source))]
+    UpdateLocalEmail { source: notmuch::Error },
+
+    #[snafu(display("Could not begin atomic database operation: {}", source))]
+    BeginAtomic { source: notmuch::Error },
+
+    #[snafu(display("Could not end atomic database operation: {}", source))]
+    EndAtomic { source: notmuch::Error },
+
+    #[snafu(display("Could not open remote session: {}", source))]
+    OpenRemote { source: remote::Error },
+
+    #[snafu(display("Could not revert changes on JMAP server: {}", source))]
+    RevertRemote { source: remote::Error },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The changes pushed to the server by one sync.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Batch {
+    /// When the changes were pushed, in seconds since the Unix epoch.
+    pub pushed_at: u64,
+    pub changes: Vec<PushedChange>,
+}
+
+impl Batch {
+    pub fn new(changes: Vec<PushedChange>) -> Self {
+        Self {
+            pushed_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|x| x.as_secs())
+                .unwrap_or_default(),
+            changes,
+        }
+    }
+}
+
+/// Append `batch` to the journal in `mail_dir`. The caller must hold the sync lock.
+pub fn record(mail_dir: &Path, batch: &Batch) -> Result<()> {
+    let path = mail_dir.join(JOURNAL_FILE);
+    let mut line = serde_json::to_string(batch).context(SerializeBatchSnafu {})?;
+    line.push('\n');
+
+    let lines = read_lines(&path)?;
+    if lines.len() < MAX_BATCHES {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .context(WriteJournalSnafu { path: &path })?;
+        return file
+            .write_all(line.as_bytes())
+            .context(WriteJournalSnafu { path: &path });
+    }
+    let mut lines = lines[lines.len() + 1 - MAX_BATCHES..].to_vec();
+    lines.push(line);
+    write_lines(&path, &lines)
+}
+
+/// Revert the last batch of changes pushed to the server, both on the server and in the notmuch
+/// database, and remove it from the journal. Returns how many emails were reverted, or `None` if
+/// there was nothing to undo.
+pub fn undo(mail_dir: PathBuf, config: Config) -> Result<Option<usize>> {
+    let lock_path = mail_dir.join(lock::SYNC_LOCK);
+    let _lock = Lock::acquire(&lock_path).context(LockSnafu { path: &lock_path })?;
+
+    let path = mail_dir.join(JOURNAL_FILE);
+    let mut lines = read_lines(&path)?;
+    let batch: Batch = match lines.last() {
+        Some(line) => serde_json::from_str(line).context(ParseJournalSnafu { path: &path })?,
+        None => return Ok(None),
+    };
+
+    let mut remote = Remote::open(&config).context(OpenRemoteSnafu {})?;
+    remote.undo(&batch.changes).context(RevertRemoteSnafu {})?;
+
+    let local = Local::open(&mail_dir, false, &config).context(OpenLocalSnafu {})?;
+    let local_emails = local.all_emails().context(IndexLocalEmailsSnafu {})?;
+    local.begin_atomic().context(BeginAtomicSnafu {})?;
+    for change in &batch.changes {
+        // The message may have been removed locally since.
+        if let Some(local_email) = local_emails.get(&change.id) {
+            local
+                .update_email_tags(
+                    local_email,
+                    change.old_tags.iter().map(|tag| tag.as_str()).collect(),
+                )
+                .context(UpdateLocalEmailSnafu {})?;
+        }
+    }
+    local.end_atomic().context(EndAtomicSnafu {})?;
+
+    lines.pop();
+    write_lines(&path, &lines)?;
+    Ok(Some(batch.changes.len()))
+}
+
+/// Return every batch in the journal in `mail_dir`, oldest first.
+pub fn batches(mail_dir: &Path) -> Result<Vec<Batch>> {
+    let path = mail_dir.join(JOURNAL_FILE);
+    read_lines(&path)?
+        .iter()
+        .map(|line| serde_json::from_str(line).context(ParseJournalSnafu { path: &path }))
+        .collect()
+}
+
+fn read_lines(path: &Path) -> Result<Vec<String>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context(ReadJournalSnafu { path }),
+    };
+    let mut lines = BufReader::new(file)
+        .lines()
+        .map(|line| line.map(|line| line + "\n"))
+        .collect::<Result<Vec<_>, _>>()
+        .context(ReadJournalSnafu { path })?;
+    lines.retain(|line| !line.trim().is_empty());
+    Ok(lines)
+}
+
+/// Replace the journal with `lines`, each of which ends with a newline.
+fn write_lines(path: &Path, lines: &[String]) -> Result<()> {
+    let temporary_path = path.with_extension("jsonl.tmp");
+    fs::write(&temporary_path, lines.concat()).context(WriteJournalSnafu {
+        path: &temporary_path,
+    })?;
+    fs::rename(&temporary_path, path).context(WriteJournalSnafu { path })
+}
@@ -0,0 +1,44 @@
+//! mujmap bridges notmuch and JMAP mail servers. Each subcommand of the `mujmap` tool is available
+//! as a function, such as [`sync::sync`] and [`send::send`], alongside the lower-level
+//! [`remote::Remote`], [`local::Local`], and [`cache::Cache`] interfaces and the JMAP types in
+//! [`jmap`]. Every module has its own `Error` type.
+//!
+//! The functions never print to stdout. They return what the command line tool would print, and
+//! [`sync::sync`] reports its progress to a [`observer::SyncObserver`].
+
+/// Email aliases for send recipients.
+pub mod aliases;
+/// Blame command.
+pub mod blame;
+/// Local cache interface.
+pub mod cache;
+/// Config check command.
+pub mod check;
+/// Configuration file options.
+pub mod config;
+/// Encryption of the cache and state file at rest.
+pub mod crypto;
+/// Miniature JMAP API.
+pub mod jmap;
+/// Journal of pushed changes and the undo command.
+pub mod journal;
+/// MIME-aware line ending conversion.
+pub mod line_endings;
+/// Local notmuch database interface.
+pub mod local;
+/// File locks shared between mujmap processes.
+pub mod lock;
+/// Mailboxes command.
+pub mod mailboxes;
+/// Progress reporting for the sync command.
+pub mod observer;
+/// Remote JMAP interface.
+pub mod remote;
+/// Send command.
+pub mod send;
+/// Sync command.
+pub mod sync;
+/// Tag command.
+pub mod tag;
+/// Verify command.
+pub mod verify;
@@ -0,0 +1,354 @@
+/// Convert the DOS line endings of an RFC 5322 message to Unix line endings wherever it's safe to
+/// do so.
+///
+/// Header sections are always converted, as are bodies with a `7bit` or `8bit` (or no)
+/// `Content-Transfer-Encoding`, which are line-oriented by definition. Multipart bodies and
+/// encapsulated messages are converted part by part, and everything else, e.g. `base64` and
+/// `binary` parts, is left untouched. Lone carriage returns are always preserved, and the message
+/// need not be valid UTF-8.
+pub fn dos_to_unix(message: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(message.len());
+    convert_entity(message, "text/plain", &mut output);
+    output
+}
+
+/// Convert the line endings of a message to DOS line endings, as required by RFC 5322. Unix line
+/// endings and lone carriage returns are both converted, and the message need not be valid UTF-8.
+pub fn unix_to_dos(message: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(message.len() + message.len() / 32);
+    let mut iter = message.iter().peekable();
+    while let Some(&byte) = iter.next() {
+        match byte {
+            b'\r' => {
+                iter.next_if_eq(&&b'\n');
+                output.extend_from_slice(b"\r\n");
+            }
+            b'\n' => output.extend_from_slice(b"\r\n"),
+            _ => output.push(byte),
+        }
+    }
+    output
+}
+
+/// The parts of a MIME header which determine how its body is converted.
+struct Header {
+    /// The lowercase media type, e.g. `multipart/mixed`.
+    content_type: String,
+    /// The multipart boundary, if any.
+    boundary: Option<String>,
+    /// The lowercase content transfer encoding.
+    encoding: String,
+}
+
+impl Header {
+    fn parse(header: &[u8], default_content_type: &str) -> Self {
+        let mut content_type = default_content_type.to_string();
+        let mut boundary = None;
+        let mut encoding = "7bit".to_string();
+
+        // Unfold the header so that each field is on a single line.
+        let header = String::from_utf8_lossy(header).replace("\r\n", "\n");
+        let mut fields: Vec<String> = Vec::new();
+        for line in header.lines() {
+            match fields.last_mut() {
+                Some(field) if line.starts_with([' ', '\t']) => field.push_str(line),
+                _ => fields.push(line.to_string()),
+            }
+        }
+
+        for field in fields {
+            let (name, value) = match field.split_once(':') {
+                Some(x) => x,
+                None => continue,
+            };
+            let name = name.trim();
+            if name.eq_ignore_ascii_case("Content-Type") {
+                let mut params = value.split(';');
+                content_type = params
+                    .next()
+                    .unwrap_or_default()
+                    .trim()
+                    .to_ascii_lowercase();
+                boundary = params
+                    .filter_map(|param| param.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case("boundary"))
+                    .map(|(_, value)| value.trim().trim_matches('"').to_string());
+            } else if name.eq_ignore_ascii_case("Content-Transfer-Encoding") {
+                encoding = value.trim().to_ascii_lowercase();
+            }
+        }
+
+        Self {
+            content_type,
+            boundary,
+            encoding,
+        }
+    }
+
+    /// Whether the body is made up of lines of text.
+    fn is_line_oriented(&self) -> bool {
+        matches!(self.encoding.as_str(), "7bit" | "8bit")
+    }
+}
+
+fn convert_entity(entity: &[u8], default_content_type: &str, output: &mut Vec<u8>) {
+    // The header ends at the first empty line.
+    let mut header_len = entity.len();
+    let mut offset = 0;
+    for line in entity.split_inclusive(|&x| x == b'\n') {
+        offset += line.len();
+        if line == b"\n" || line == b"\r\n" {
+            header_len = offset;
+            break;
+        }
+    }
+    let (header, body) = entity.split_at(header_len);
+    crlf_to_lf(header, output);
+
+    let header = Header::parse(header, default_content_type);
+    match &header.boundary {
+        Some(boundary) if header.content_type.starts_with("multipart/") => {
+            // Parts of a digest are messages unless stated otherwise.
+            let default_content_type = if header.content_type == "multipart/digest" {
+                "message/rfc822"
+            } else {
+                "text/plain"
+            };
+            convert_multipart(body, boundary.as_bytes(), default_content_type, output);
+        }
+        _ if header.content_type == "message/rfc822" && header.is_line_oriented() => {
+            convert_entity(body, "text/plain", output);
+        }
+        _ if header.is_line_oriented() => crlf_to_lf(body, output),
+        _ => output.extend_from_slice(body),
+    }
+}
+
+fn convert_multipart(
+    body: &[u8],
+    boundary: &[u8],
+    default_content_type: &str,
+    output: &mut Vec<u8>,
+) {
+    // Whether we've reached the first delimiter, i.e. are past the preamble.
+    let mut in_parts = false;
+    let mut closed = false;
+    let mut part_start = 0;
+    let mut offset = 0;
+    for line in body.split_inclusive(|&x| x == b'\n') {
+        let line_start = offset;
+        offset += line.len();
+
+        let trimmed = trim_line(line);
+        let is_close = trimmed.len() == boundary.len() + 4 && trimmed.ends_with(b"--");
+        let is_delimiter = (trimmed.len() == boundary.len() + 2 || is_close)
+            && trimmed.starts_with(b"--")
+            && &trimmed[2..boundary.len() + 2] == boundary;
+        if !is_delimiter {
+            continue;
+        }
+
+        let segment = &body[part_start..line_start];
+        if in_parts {
+            // The line ending before a delimiter belongs to the delimiter, not to the part.
+            let part = segment
+                .strip_suffix(b"\r\n")
+                .or_else(|| segment.strip_suffix(b"\n"));
+            match part {
+                Some(part) => {
+                    convert_entity(part, default_content_type, output);
+                    output.push(b'\n');
+                }
+                None => convert_entity(segment, default_content_type, output),
+            }
+        } else {
+            crlf_to_lf(segment, output);
+        }
+        crlf_to_lf(line, output);
+        in_parts = true;
+        part_start = offset;
+
+        if is_close {
+            closed = true;
+            break;
+        }
+    }
+
+    let rest = &body[part_start..];
+    if in_parts && !closed {
+        // The closing delimiter is missing, so treat the rest as the final part.
+        convert_entity(rest, default_content_type, output);
+    } else {
+        // Either the epilogue or, if there were no delimiters at all, the preamble.
+        crlf_to_lf(rest, output);
+    }
+}
+
+/// Strip the line ending and any trailing whitespace from a line.
+fn trim_line(mut line: &[u8]) -> &[u8] {
+    while let [rest @ .., b'\n' | b'\r' | b' ' | b'\t'] = line {
+        line = rest;
+    }
+    line
+}
+
+fn crlf_to_lf(input: &[u8], output: &mut Vec<u8>) {
+    let mut iter = input.iter().peekable();
+    while let Some(&byte) = iter.next() {
+        if byte != b'\r' || iter.peek() != Some(&&b'\n') {
+            output.push(byte);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_parts_of_nested_multiparts_are_untouched() {
+        let message = b"\
+Content-Type: multipart/mixed; boundary=\"outer\"\r\n\
+\r\n\
+--outer\r\n\
+Content-Type: multipart/alternative; boundary=inner\r\n\
+\r\n\
+--inner\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+Hello\r\n\
+--inner\r\n\
+Content-Type: image/png\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+iVBORw0K\r\n\
+GgoAAAAN\r\n\
+--inner--\r\n\
+--outer--\r\n";
+        let expected = b"\
+Content-Type: multipart/mixed; boundary=\"outer\"\n\
+\n\
+--outer\n\
+Content-Type: multipart/alternative; boundary=inner\n\
+\n\
+--inner\n\
+Content-Type: text/plain\n\
+\n\
+Hello\n\
+--inner\n\
+Content-Type: image/png\n\
+Content-Transfer-Encoding: base64\n\
+\n\
+iVBORw0K\r\n\
+GgoAAAAN\n\
+--inner--\n\
+--outer--\n";
+        assert_eq!(dos_to_unix(message), expected);
+    }
+
+    #[test]
+    fn missing_closing_delimiter_ends_the_last_part() {
+        let message = b"\
+Content-Type: multipart/mixed; boundary=b\r\n\
+\r\n\
+--b\r\n\
+\r\n\
+Text\r\n";
+        let expected = b"\
+Content-Type: multipart/mixed; boundary=b\n\
+\n\
+--b\n\
+\n\
+Text\n";
+        assert_eq!(dos_to_unix(message), expected);
+    }
+
+    #[test]
+    fn lines_starting_with_the_boundary_are_not_delimiters() {
+        let message = b"\
+Content-Type: multipart/mixed; boundary=b\r\n\
+\r\n\
+--b\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+--bb\r\n\
+--b--x\r\n\
+--b--\r\n";
+        let expected = b"\
+Content-Type: multipart/mixed; boundary=b\n\
+\n\
+--b\n\
+Content-Transfer-Encoding: base64\n\
+\n\
+--bb\r\n\
+--b--x\n\
+--b--\n";
+        assert_eq!(dos_to_unix(message), expected);
+    }
+
+    #[test]
+    fn encapsulated_messages_are_converted_part_by_part() {
+        let message = b"\
+Content-Type: multipart/mixed; boundary=b\r\n\
+\r\n\
+--b\r\n\
+Content-Type: message/rfc822\r\n\
+\r\n\
+Subject: Forwarded\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+SGVsbG8K\r\n\
+SGVsbG8K\r\n\
+--b--\r\n";
+        let expected = b"\
+Content-Type: multipart/mixed; boundary=b\n\
+\n\
+--b\n\
+Content-Type: message/rfc822\n\
+\n\
+Subject: Forwarded\n\
+Content-Transfer-Encoding: base64\n\
+\n\
+SGVsbG8K\r\n\
+SGVsbG8K\n\
+--b--\n";
+        assert_eq!(dos_to_unix(message), expected);
+    }
+
+    #[test]
+    fn lone_carriage_returns_are_preserved() {
+        assert_eq!(
+            dos_to_unix(b"Subject: a\rb\r\n\r\nc\rd\r\n"),
+            b"Subject: a\rb\n\nc\rd\n"
+        );
+        assert_eq!(unix_to_dos(b"a\rb\n"), b"a\r\nb\r\n");
+    }
+
+    #[test]
+    fn unix_to_dos_round_trips() {
+        let message = b"\
+Subject: Hi\n\
+Content-Type: multipart/mixed; boundary=b\n\
+\n\
+--b\n\
+\n\
+Hello\n\
+\xff\n\
+--b--\n";
+        let dos = unix_to_dos(message);
+        assert_eq!(
+            dos,
+            b"\
+Subject: Hi\r\n\
+Content-Type: multipart/mixed; boundary=b\r\n\
+\r\n\
+--b\r\n\
+\r\n\
+Hello\r\n\
+\xff\r\n\
+--b--\r\n"
+        );
+        assert_eq!(dos_to_unix(&dos), message);
+        assert_eq!(unix_to_dos(&dos), dos);
+    }
+}
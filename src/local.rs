@@ -1,22 +1,27 @@
+use crate::config::{Config, SubtreeMaildir};
+use crate::crypto;
 use crate::jmap;
 use crate::sync::NewEmail;
 use const_format::formatcp;
 use lazy_static::lazy_static;
-use log::debug;
+use log::{debug, warn};
 use notmuch::ConfigKey;
 use notmuch::Database;
 use notmuch::Exclude;
 use notmuch::Message;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use snafu::prelude::*;
 use snafu::Snafu;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fs;
 use std::io;
+use std::iter;
 use std::path::Path;
 use std::path::PathBuf;
 use std::path::StripPrefixError;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 const ID_PATTERN: &'static str = r"[-A-Za-z0-9_]+";
 const MAIL_PATTERN: &'static str = formatcp!(r"^({})\.({})(?:$|:)", ID_PATTERN, ID_PATTERN);
@@ -28,8 +33,20 @@ lazy_static! {
     /// These values taken from: https://notmuchmail.org/special-tags/
     pub static ref AUTOMATIC_TAGS: HashSet<&'static str> =
         HashSet::from(["attachment", "signed", "encrypted"]);
+
+    static ref MAIL_FILE: Regex = Regex::new(MAIL_PATTERN).unwrap();
 }
 
+/// notmuch message property holding the JMAP ID of each of mujmap's files of the message.
+pub const ID_PROPERTY: &str = "mujmap.id";
+/// notmuch message property holding the JMAP blob ID of each of mujmap's files of the message.
+pub const BLOB_ID_PROPERTY: &str = "mujmap.blob-id";
+/// notmuch message property holding when mujmap last synced the message, in seconds since the
+/// Unix epoch.
+pub const SYNCED_AT_PROPERTY: &str = "mujmap.synced-at";
+/// notmuch message property holding the size in octets of the message as it is on the server.
+pub const SIZE_PROPERTY: &str = "mujmap.size";
+
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display("Could not canonicalize given path: {}", source))]
@@ -63,17 +80,49 @@ pub enum Error {
         query: String,
         source: notmuch::Error,
     },
+
+    #[snafu(display("Could not read maildir `{}': {}", path.to_string_lossy(), source))]
+    ReadMaildir { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Could not rename tags of message `{}': {}", message_id, source))]
+    RenameTags {
+        message_id: String,
+        source: notmuch::Error,
+    },
+
+    #[snafu(display("Could not rename mail file `{}': {}", path.to_string_lossy(), source))]
+    RenameMailFile { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Could not index renamed mail file `{}': {}", path.to_string_lossy(), source))]
+    IndexRenamedMailFile {
+        path: PathBuf,
+        source: notmuch::Error,
+    },
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// A draft in the notmuch database with a file outside of mujmap's maildir, e.g. one postponed by
+/// an MUA.
 #[derive(Debug)]
+pub struct LocalDraft {
+    pub message_id: String,
+    /// The most recently modified file of the draft which mujmap doesn't own.
+    pub path: PathBuf,
+    /// The `Email`s of the draft which mujmap owns, i.e. copies of it downloaded from the server.
+    pub emails: Vec<Email>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Email {
     pub id: jmap::Id,
     pub blob_id: jmap::Id,
     pub message_id: String,
     pub path: PathBuf,
     pub tags: HashSet<String>,
+    /// The size of the message on the server, if it was recorded when the message was added.
+    #[serde(default)]
+    pub size: Option<u64>,
 }
 
 pub struct Local {
@@ -81,80 +130,198 @@ pub struct Local {
     db: Database,
     /// The path to mujmap's maildir/cur.
     pub mail_cur_dir: PathBuf,
+    /// The configured maildirs for the mail of some mailboxes, each with the path to its cur dir.
+    subtree_cur_dirs: Vec<(SubtreeMaildir, PathBuf)>,
+    /// Separator of the tags of nested mailboxes, which `subtree_cur_dirs` are matched against.
+    directory_separator: String,
     /// Notmuch search query which searches for all mail in mujmap's maildir.
     all_mail_query: String,
     /// Flag, whether or not notmuch should add maildir flags to message filenames.
     pub synchronize_maildir_flags: bool,
+    /// Tags of messages which notmuch excludes from searches, from `search.exclude_tags`.
+    pub exclude_tags: HashSet<String>,
+    /// The path to the persisted index of all mail, which saves querying notmuch for every message
+    /// on each invocation.
+    index_path: PathBuf,
+    /// Whether `all_emails` writes the index. Only set by `persist_index`.
+    write_index: bool,
+    /// Key the index is encrypted with, if the cache is encrypted.
+    index_key: Option<crypto::Key>,
+}
+
+/// A snapshot of all `Email`s mujmap owns, as of the given notmuch database revision.
+#[derive(Serialize, Deserialize)]
+struct EmailIndex {
+    /// UUID of the notmuch database. Revisions are meaningless across databases.
+    uuid: String,
+    revision: u64,
+    emails: HashMap<jmap::Id, Email>,
 }
 
 impl Local {
     /// Open the local store.
     ///
     /// `mail_dir` *must* be a subdirectory of the notmuch path.
-    pub fn open(mail_dir: impl AsRef<Path>, read_only: bool) -> Result<Self> {
-        // Open the notmuch database with default config options.
-        let db = Database::open_with_config::<PathBuf, PathBuf>(
-            None,
+    pub fn open(mail_dir: impl AsRef<Path>, read_only: bool, config: &Config) -> Result<Self> {
+        // Open the notmuch database with the configured profile and database, if any. Otherwise,
+        // notmuch honors `NOTMUCH_CONFIG`, `NOTMUCH_PROFILE`, and `NOTMUCH_DATABASE` itself.
+        let db = Database::open_with_config::<&Path, PathBuf>(
+            config.notmuch_database.as_deref(),
             if read_only {
                 notmuch::DatabaseMode::ReadOnly
             } else {
                 notmuch::DatabaseMode::ReadWrite
             },
             None,
-            None,
+            config.notmuch_profile.as_deref(),
         )
         .context(OpenDatabaseSnafu {})?;
 
-        // Get the relative directory of the maildir to the database path.
-        let canonical_db_path = db.path().canonicalize().context(CanonicalizeSnafu {})?;
-        let canonical_mail_dir_path = mail_dir
-            .as_ref()
-            .canonicalize()
-            .context(CanonicalizeSnafu {})?;
-        let relative_mail_dir = canonical_mail_dir_path
-            .strip_prefix(&canonical_db_path)
-            .context(MailDirNotASubdirOfNotmuchRootSnafu {
-                mail_dir: &canonical_mail_dir_path,
-                notmuch_root: &canonical_db_path,
-            })?;
-
-        // Build the query to search for all mail in our maildir.
-        let all_mail_query = format!("path:\"{}/**\"", relative_mail_dir.to_str().unwrap());
-
-        // Ensure the maildir contains the standard cur, new, and tmp dirs.
-        let mail_cur_dir = canonical_mail_dir_path.join("cur");
+        // Ensure each maildir contains the standard cur, new, and tmp dirs.
+        let mail_dir = mail_dir.as_ref();
+        let mail_dirs: Vec<PathBuf> = iter::once(mail_dir.to_path_buf())
+            .chain(config.maildirs.iter().map(|x| mail_dir.join(&x.path)))
+            .collect();
         if !read_only {
-            for path in &[
-                &mail_cur_dir,
-                &canonical_mail_dir_path.join("new"),
-                &canonical_mail_dir_path.join("tmp"),
-            ] {
-                fs::create_dir_all(path).context(CreateMaildirDirSnafu { path })?;
+            for mail_dir in &mail_dirs {
+                for path in &[
+                    mail_dir.join("cur"),
+                    mail_dir.join("new"),
+                    mail_dir.join("tmp"),
+                ] {
+                    fs::create_dir_all(path).context(CreateMaildirDirSnafu { path })?;
+                }
             }
         }
 
+        // Build the query to search for all mail in our maildirs, rather than every message in the
+        // database. A maildir may also be the notmuch root itself, which has no relative path.
+        let canonical_db_path = db.path().canonicalize().context(CanonicalizeSnafu {})?;
+        let mut path_terms = Vec::new();
+        let mut cur_dirs = Vec::new();
+        for (i, mail_dir) in mail_dirs.iter().enumerate() {
+            // An additional maildir which nothing has been synced into yet holds no mail.
+            if i > 0 && !mail_dir.exists() {
+                cur_dirs.push(mail_dir.join("cur"));
+                continue;
+            }
+            let canonical_mail_dir_path = mail_dir.canonicalize().context(CanonicalizeSnafu {})?;
+            let relative_mail_dir = canonical_mail_dir_path
+                .strip_prefix(&canonical_db_path)
+                .context(MailDirNotASubdirOfNotmuchRootSnafu {
+                    mail_dir: &canonical_mail_dir_path,
+                    notmuch_root: &canonical_db_path,
+                })?;
+            path_terms.push(if relative_mail_dir.as_os_str().is_empty() {
+                "path:**".to_owned()
+            } else {
+                format!("path:\"{}/**\"", relative_mail_dir.to_str().unwrap())
+            });
+            cur_dirs.push(canonical_mail_dir_path.join("cur"));
+        }
+        let all_mail_query = if path_terms.len() == 1 {
+            path_terms.remove(0)
+        } else {
+            format!("({})", path_terms.join(" or "))
+        };
+        let mail_cur_dir = cur_dirs.remove(0);
+        let subtree_cur_dirs = config.maildirs.iter().cloned().zip(cur_dirs).collect();
+
         let synchronize_maildir_flags = db.config_bool(ConfigKey::MaildirFlags).unwrap_or(true);
+        let exclude_tags = db
+            .config_values(ConfigKey::ExcludeTags)
+            .map(|tags| tags.collect())
+            .unwrap_or_default();
+
+        let index_path = mail_cur_dir.with_file_name("mujmap.index.json");
 
         Ok(Self {
             db,
             mail_cur_dir,
+            subtree_cur_dirs,
+            directory_separator: config.tags.directory_separator.clone(),
             all_mail_query,
             synchronize_maildir_flags,
+            exclude_tags,
+            index_path,
+            write_index: false,
+            index_key: None,
         })
     }
 
+    /// Make `all_emails` write the email index, encrypted with `key` if given. Only a sync which
+    /// changes the maildir should do so; everything else only reads the index.
+    pub fn persist_index(&mut self, key: Option<crypto::Key>) {
+        self.write_index = true;
+        self.index_key = key;
+    }
+
     pub fn revision(&self) -> u64 {
         self.db.revision().revision
     }
 
-    /// Create a path for a newly added file to the maildir.
-    pub fn new_maildir_path(&self, id: &jmap::Id, blob_id: &jmap::Id) -> PathBuf {
-        self.mail_cur_dir.join(format!("{}.{}", id, blob_id))
+    /// Return the cur dirs of all of mujmap's maildirs.
+    pub fn cur_dirs(&self) -> impl Iterator<Item = &Path> {
+        iter::once(self.mail_cur_dir.as_path())
+            .chain(self.subtree_cur_dirs.iter().map(|x| x.1.as_path()))
+    }
+
+    /// Return the cur dir of the maildir for mail in the mailboxes with the given tags.
+    pub fn cur_dir_for<'a>(&self, mailbox_tags: impl IntoIterator<Item = &'a str>) -> &Path {
+        let mailbox_tags: Vec<&str> = mailbox_tags.into_iter().collect();
+        self.subtree_cur_dirs
+            .iter()
+            .find(|(maildir, _)| {
+                mailbox_tags
+                    .iter()
+                    .any(|tag| maildir.matches(tag, &self.directory_separator))
+            })
+            .map_or(&self.mail_cur_dir, |x| &x.1)
+    }
+
+    /// Return true if `path` is in one of mujmap's maildirs.
+    fn owns_path(&self, path: &Path) -> bool {
+        self.cur_dirs().any(|cur_dir| path.starts_with(cur_dir))
+    }
+
+    /// Create a path for a newly added file to the maildir for mail in the mailboxes with the
+    /// given tags.
+    pub fn new_maildir_path<'a>(
+        &self,
+        id: &jmap::Id,
+        blob_id: &jmap::Id,
+        mailbox_tags: impl IntoIterator<Item = &'a str>,
+    ) -> PathBuf {
+        self.cur_dir_for(mailbox_tags)
+            .join(format!("{}.{}", id, blob_id))
     }
 
     /// Return all `Email`s that mujmap owns for this maildir.
+    ///
+    /// Querying every message is slow for large databases, so if `persist_index` was called, the
+    /// result is persisted alongside the notmuch revision it was taken at, and only messages
+    /// modified since then are queried on subsequent calls.
     pub fn all_emails(&self) -> Result<HashMap<jmap::Id, Email>> {
-        self.query(&self.all_mail_query)
+        let revision = self.db.revision();
+        let emails = match self.read_index(&revision.uuid) {
+            Some(index) => self.update_index(index)?,
+            None => None,
+        };
+        let emails = match emails {
+            Some(emails) => emails,
+            None => {
+                debug!("Local email index missing or stale, querying all mail");
+                self.query(&self.all_mail_query)?
+            }
+        };
+        if !self.write_index {
+            return Ok(emails);
+        }
+        Ok(self.write_index(EmailIndex {
+            uuid: revision.uuid,
+            revision: revision.revision,
+            emails,
+        }))
     }
 
     /// Return all `Email`s that mujmap owns which were modified since the given database revision.
@@ -167,6 +334,113 @@ impl Local {
         ))
     }
 
+    /// Return the paths of all regular mail files in the maildir whose names follow mujmap's
+    /// `id.blobId` pattern, regardless of whether notmuch has indexed them.
+    pub fn all_mail_files(&self) -> Result<HashMap<(jmap::Id, jmap::Id), PathBuf>> {
+        let mut mail_files = HashMap::new();
+        for cur_dir in self.cur_dirs() {
+            let entries = fs::read_dir(cur_dir).context(ReadMaildirSnafu { path: cur_dir })?;
+            for entry in entries {
+                let entry = entry.context(ReadMaildirSnafu { path: cur_dir })?;
+                let file_type = entry
+                    .file_type()
+                    .context(ReadMaildirSnafu { path: entry.path() })?;
+                // Symlinks are left over from interrupted syncs and point into the cache.
+                if !file_type.is_file() {
+                    continue;
+                }
+                if let Some(captures) = MAIL_FILE.captures(&entry.file_name().to_string_lossy()) {
+                    let id = jmap::Id(captures.get(1).unwrap().as_str().to_string());
+                    let blob_id = jmap::Id(captures.get(2).unwrap().as_str().to_string());
+                    mail_files.insert((id, blob_id), entry.path());
+                }
+            }
+        }
+        Ok(mail_files)
+    }
+
+    /// Read the persisted email index, if it exists, belongs to this database, and can be
+    /// decrypted.
+    fn read_index(&self, uuid: &str) -> Option<EmailIndex> {
+        let mut contents = fs::read(&self.index_path).ok()?;
+        if crypto::is_encrypted(&contents) {
+            contents = match self.index_key.as_ref().map(|key| key.decrypt(&contents)) {
+                Some(Ok(contents)) => contents,
+                Some(Err(e)) => {
+                    warn!(
+                        "Could not decrypt local email index `{}': {e}",
+                        self.index_path.to_string_lossy()
+                    );
+                    return None;
+                }
+                None => return None,
+            };
+        }
+        match serde_json::from_slice::<EmailIndex>(&contents) {
+            Ok(index) if index.uuid == uuid => Some(index),
+            Ok(_) => None,
+            Err(e) => {
+                warn!(
+                    "Could not parse local email index `{}': {e}",
+                    self.index_path.to_string_lossy()
+                );
+                None
+            }
+        }
+    }
+
+    /// Bring the given index up to date with the database. Returns `None` if the index could not
+    /// be reconciled with the database, in which case all mail must be queried again.
+    fn update_index(&self, index: EmailIndex) -> Result<Option<HashMap<jmap::Id, Email>>> {
+        let mut emails = index.emails;
+        let updated_emails = self.all_emails_since(index.revision)?;
+
+        // Replace every file belonging to an updated message, since the message may have lost
+        // some of its files.
+        let updated_message_ids: HashSet<&str> = updated_emails
+            .values()
+            .map(|x| x.message_id.as_str())
+            .collect();
+        emails.retain(|_, x| !updated_message_ids.contains(x.message_id.as_str()));
+        emails.extend(updated_emails);
+
+        // Messages removed from the database since the index was written leave no trace in the
+        // revision history, so detect them by comparing message counts.
+        let message_count = self.count_messages(&self.all_mail_query)?;
+        let count_messages = |emails: &HashMap<jmap::Id, Email>| {
+            emails
+                .values()
+                .map(|x| x.message_id.as_str())
+                .collect::<HashSet<_>>()
+                .len()
+        };
+        if count_messages(&emails) != message_count {
+            emails.retain(|_, x| x.path.exists());
+            if count_messages(&emails) != message_count {
+                return Ok(None);
+            }
+        }
+        Ok(Some(emails))
+    }
+
+    /// Persist the given index, returning its emails. Failure to do so is not fatal.
+    fn write_index(&self, index: EmailIndex) -> HashMap<jmap::Id, Email> {
+        let result = serde_json::to_vec(&index)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| match &self.index_key {
+                Some(key) => key.encrypt(&contents).map_err(|e| e.to_string()),
+                None => Ok(contents),
+            })
+            .and_then(|contents| fs::write(&self.index_path, contents).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            warn!(
+                "Could not write local email index `{}': {e}",
+                self.index_path.to_string_lossy()
+            );
+        }
+        index.emails
+    }
+
     /// Return all tags in the database.
     pub fn all_tags(&self) -> Result<notmuch::Tags, notmuch::Error> {
         self.db.all_tags()
@@ -186,6 +460,13 @@ impl Local {
     pub fn add_new_email(&self, new_email: &NewEmail) -> Result<Email, notmuch::Error> {
         debug!("Adding new email: {:?}", new_email);
         let message = self.db.index_file(&new_email.maildir_path, None)?;
+        message.add_property(ID_PROPERTY, &new_email.remote_email.id.0)?;
+        message.add_property(BLOB_ID_PROPERTY, &new_email.remote_email.blob_id.0)?;
+        if let Some(size) = new_email.remote_email.size {
+            message.remove_all_properties(Some(SIZE_PROPERTY))?;
+            message.add_property(SIZE_PROPERTY, &size.to_string())?;
+        }
+        record_synced_at(&message)?;
         let tags = message
             .tags()
             .into_iter()
@@ -197,15 +478,83 @@ impl Local {
             message_id: message.id().to_string(),
             path: new_email.maildir_path.clone(),
             tags,
+            size: new_email.remote_email.size,
         })
     }
 
     /// Remove the given email file from notmuch's database and the disk.
     pub fn remove_email(&self, email: &Email) -> Result<(), notmuch::Error> {
         debug!("Removing email: {:?}", email);
+        // The message outlives this file if it has duplicates, so forget this file's IDs.
+        if let Some(message) = self.get_message(&email.message_id)? {
+            message.remove_property(ID_PROPERTY, &email.id.0)?;
+            message.remove_property(BLOB_ID_PROPERTY, &email.blob_id.0)?;
+        }
         self.db.remove_message(&email.path)
     }
 
+    /// Rename the file of `email` after `blob_id`, a new blob ID the server gave the same content,
+    /// so that it needn't be downloaded again. Returns the renamed email.
+    pub fn rename_email_blob(&self, email: &Email, blob_id: &jmap::Id) -> Result<Email> {
+        // Keep any maildir flags.
+        let flags = email
+            .path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .and_then(|x| x.strip_prefix(&format!("{}.{}", email.id, email.blob_id)))
+            .unwrap_or_default();
+        let path = email
+            .path
+            .with_file_name(format!("{}.{}{}", email.id, blob_id, flags));
+        self.rename_email_file(email, path, blob_id)
+    }
+
+    /// Move the file of `email` into `cur_dir`, the cur dir of another of mujmap's maildirs, e.g.
+    /// because it was moved to a mailbox which belongs in that maildir. Returns the moved email.
+    pub fn move_email(&self, email: &Email, cur_dir: &Path) -> Result<Email> {
+        let path = cur_dir.join(email.path.file_name().unwrap_or_default());
+        self.rename_email_file(email, path, &email.blob_id)
+    }
+
+    fn rename_email_file(&self, email: &Email, path: PathBuf, blob_id: &jmap::Id) -> Result<Email> {
+        debug!(
+            "Renaming `{}' to `{}'",
+            email.path.to_string_lossy(),
+            path.to_string_lossy()
+        );
+        fs::rename(&email.path, &path).context(RenameMailFileSnafu { path: &email.path })?;
+        let index = || -> Result<(), notmuch::Error> {
+            let message = self.db.index_file(&path, None)?;
+            if *blob_id != email.blob_id {
+                message.remove_property(BLOB_ID_PROPERTY, &email.blob_id.0)?;
+                message.add_property(BLOB_ID_PROPERTY, &blob_id.0)?;
+            }
+            self.db.remove_message(&email.path)
+        };
+        index().context(IndexRenamedMailFileSnafu { path: &path })?;
+        Ok(Email {
+            blob_id: blob_id.clone(),
+            path,
+            ..email.clone()
+        })
+    }
+
+    fn count_messages(&self, query_string: &str) -> Result<usize> {
+        let query =
+            self.db
+                .create_query(query_string)
+                .with_context(|_| CreateNotmuchQuerySnafu {
+                    query: query_string,
+                })?;
+        query.set_omit_excluded(Exclude::False);
+        let count = query
+            .count_messages()
+            .with_context(|_| ExecuteNotmuchQuerySnafu {
+                query: query_string,
+            })?;
+        Ok(count as usize)
+    }
+
     fn query(&self, query_string: &str) -> Result<HashMap<jmap::Id, Email>> {
         debug!("notmuch query: {}", query_string);
 
@@ -237,15 +586,107 @@ impl Local {
         Ok(messages.into_iter().next())
     }
 
+    /// Return all messages tagged `draft_tag` and not `deleted` which have a file outside of
+    /// mujmap's maildir.
+    pub fn local_drafts(&self, draft_tag: &str) -> Result<Vec<LocalDraft>> {
+        let query_string = format!("tag:\"{draft_tag}\" and not tag:deleted");
+        let query = self
+            .db
+            .create_query(&query_string)
+            .context(CreateNotmuchQuerySnafu {
+                query: &query_string,
+            })?;
+        query.set_omit_excluded(Exclude::False);
+        let messages = query.search_messages().context(ExecuteNotmuchQuerySnafu {
+            query: &query_string,
+        })?;
+        Ok(messages
+            .into_iter()
+            .filter_map(|message| {
+                let path = message
+                    .filenames()
+                    .filter(|path| !self.owns_path(path))
+                    .max_by_key(|path| fs::metadata(path).and_then(|x| x.modified()).ok())?;
+                Some(LocalDraft {
+                    message_id: message.id().to_string(),
+                    path,
+                    emails: self.emails_from_message(message),
+                })
+            })
+            .collect())
+    }
+
+    /// Return a file of the message with the given `Message-ID` which belongs to another program,
+    /// i.e. which is outside of mujmap's maildir, if there is one.
+    pub fn foreign_message_file(
+        &self,
+        message_id: &str,
+    ) -> Result<Option<PathBuf>, notmuch::Error> {
+        Ok(self.get_message(message_id)?.and_then(|message| {
+            message
+                .filenames()
+                .find(|path| !self.owns_path(path) && path.exists())
+        }))
+    }
+
+    /// Return the paths of all files of the message with the given `Message-ID`, along with an
+    /// `Email` for each of them which mujmap owns. Both are empty if there is no such message.
+    pub fn message_files(
+        &self,
+        message_id: &str,
+    ) -> Result<(Vec<PathBuf>, Vec<Email>), notmuch::Error> {
+        Ok(match self.get_message(message_id)? {
+            Some(message) => {
+                let paths = message.filenames().collect();
+                (paths, self.emails_from_message(message))
+            }
+            None => (Vec::new(), Vec::new()),
+        })
+    }
+
+    /// Remove the database entries of files of `message` which no longer exist, e.g. because they
+    /// were deleted by hand, since notmuch fails to rename them when synchronizing maildir flags.
+    /// Returns the message again, unless none of its files remain.
+    fn remove_missing_files(&self, message: Message) -> Result<Option<Message>, notmuch::Error> {
+        let missing: Vec<PathBuf> = message.filenames().filter(|path| !path.exists()).collect();
+        if missing.is_empty() {
+            return Ok(Some(message));
+        }
+        let message_id = message.id().to_string();
+        drop(message);
+        for path in &missing {
+            warn!(
+                "Removing `{}' from the notmuch database, since the file no longer exists",
+                path.to_string_lossy()
+            );
+            self.db.remove_message(path)?;
+        }
+        self.get_message(&message_id)
+    }
+
     /// Returns a separate `Email` object for each duplicate email file mujmap owns.
+    ///
+    /// IDs are taken from the file name, or, for a file whose name doesn't follow mujmap's pattern,
+    /// from the message's properties if they name exactly one email.
     fn emails_from_message(&self, message: Message) -> Vec<Email> {
-        lazy_static! {
-            static ref MAIL_FILE: Regex = Regex::new(MAIL_PATTERN).unwrap();
-        }
+        let property_ids = || -> Option<(jmap::Id, jmap::Id)> {
+            let ids: Vec<_> = message.properties(ID_PROPERTY, true).collect();
+            let blob_ids: Vec<_> = message.properties(BLOB_ID_PROPERTY, true).collect();
+            match (&ids[..], &blob_ids[..]) {
+                ([(_, id)], [(_, blob_id)]) => {
+                    Some((jmap::Id(id.clone()), jmap::Id(blob_id.clone())))
+                }
+                _ => None,
+            }
+        };
+        let size = match &message.properties(SIZE_PROPERTY, true).collect::<Vec<_>>()[..] {
+            [(_, size)] => size.parse().ok(),
+            _ => None,
+        };
         message
             .filenames()
             .into_iter()
-            .filter(|x| x.starts_with(&self.mail_cur_dir))
+            .filter(|x| self.owns_path(x))
             .flat_map(|path| {
                 MAIL_FILE
                     .captures(&path.file_name().unwrap().to_string_lossy())
@@ -254,6 +695,7 @@ impl Local {
                         let blob_id = jmap::Id(x.get(2).unwrap().as_str().to_string());
                         (id, blob_id)
                     })
+                    .or_else(property_ids)
                     .map(|(id, blob_id)| (id, blob_id, path))
             })
             .map(|(id, blob_id, path)| Email {
@@ -266,16 +708,69 @@ impl Local {
                     .into_iter()
                     .filter(|tag| !AUTOMATIC_TAGS.contains(tag.as_str()))
                     .collect(),
+                size,
             })
             .collect()
     }
 
+    /// Rename tags on all messages mujmap owns, given a list of `(old, new)` pairs. All renames are
+    /// applied at once, so swapping two tags is handled correctly. Returns the number of messages
+    /// that were changed.
+    pub fn rename_tags(&self, renames: &[(&str, &str)]) -> Result<usize> {
+        // Gather every affected message before changing anything.
+        let mut renames_by_message: HashMap<String, Vec<(&str, &str)>> = HashMap::new();
+        for (old, new) in renames {
+            let query_string = format!(
+                "{} and tag:\"{}\"",
+                self.all_mail_query,
+                old.replace('"', "\"\"")
+            );
+            for email in self.query(&query_string)?.into_values() {
+                renames_by_message
+                    .entry(email.message_id)
+                    .or_default()
+                    .push((old, new));
+            }
+        }
+        for (message_id, renames) in &renames_by_message {
+            debug!("Renaming tags of message `{message_id}': {renames:?}");
+            let apply = || -> Result<(), notmuch::Error> {
+                let message = match self.get_message(message_id)? {
+                    Some(message) => self.remove_missing_files(message)?,
+                    None => None,
+                };
+                if let Some(message) = message {
+                    message.freeze()?;
+                    for (old, _) in renames {
+                        message.remove_tag(old)?;
+                    }
+                    for (_, new) in renames {
+                        message.add_tag(new)?;
+                    }
+                    message.thaw()?;
+                    if self.synchronize_maildir_flags {
+                        message.tags_to_maildir_flags()?;
+                    }
+                }
+                Ok(())
+            };
+            apply().context(RenameTagsSnafu {
+                message_id: message_id.as_str(),
+            })?;
+        }
+        Ok(renames_by_message.len())
+    }
+
     pub fn update_email_tags(
         &self,
         email: &Email,
         tags: HashSet<&str>,
     ) -> Result<(), notmuch::Error> {
-        if let Some(message) = self.get_message(&email.message_id)? {
+        let message = match self.get_message(&email.message_id)? {
+            Some(message) => self.remove_missing_files(message)?,
+            None => None,
+        };
+        if let Some(message) = message {
             // Build diffs for tags and apply them.
             message.freeze()?;
             let extant_tags: HashSet<String> = message.tags().into_iter().collect();
@@ -302,7 +797,23 @@ impl Local {
             if self.synchronize_maildir_flags {
                 message.tags_to_maildir_flags()?;
             }
+            // Mail synced before mujmap recorded properties gets them the next time it changes.
+            if message.count_properties(ID_PROPERTY)? == 0 {
+                message.add_property(ID_PROPERTY, &email.id.0)?;
+                message.add_property(BLOB_ID_PROPERTY, &email.blob_id.0)?;
+            }
+            record_synced_at(&message)?;
         }
         Ok(())
     }
 }
+
+/// Set the message's `SYNCED_AT_PROPERTY` to the current time.
+fn record_synced_at(message: &Message) -> Result<(), notmuch::Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default();
+    message.remove_all_properties(Some(SYNCED_AT_PROPERTY))?;
+    message.add_property(SYNCED_AT_PROPERTY, &now.to_string())
+}
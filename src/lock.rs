@@ -0,0 +1,40 @@
+use fslock::LockFile;
+use std::io;
+use std::path::Path;
+
+/// Lock held by a sync for its whole duration, so that only one process changes the maildir,
+/// notmuch database, and state file at a time.
+pub const SYNC_LOCK: &str = "mujmap.lock";
+
+/// Lock held briefly while queueing mail in, or sending mail from, the outbox.
+pub const OUTBOX_LOCK: &str = "mujmap.outbox.lock";
+
+/// An exclusive advisory lock on a file, released when dropped.
+#[derive(Debug)]
+pub struct Lock {
+    _file: LockFile,
+}
+
+impl Lock {
+    /// Lock the file at `path`, creating it if necessary, waiting for other processes to release
+    /// it first.
+    pub fn acquire(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open_locked(path.as_ref(), true).map(|lock| lock.expect("blocking lock acquired"))
+    }
+
+    /// Like `acquire`, but return `None` instead of waiting if another process holds the lock.
+    pub fn try_acquire(path: impl AsRef<Path>) -> io::Result<Option<Self>> {
+        Self::open_locked(path.as_ref(), false)
+    }
+
+    fn open_locked(path: &Path, wait: bool) -> io::Result<Option<Self>> {
+        let mut file = LockFile::open(path)?;
+        let locked = if wait {
+            file.lock()?;
+            true
+        } else {
+            file.try_lock()?
+        };
+        Ok(locked.then_some(Self { _file: file }))
+    }
+}
@@ -0,0 +1,212 @@
+use crate::config::Config;
+use crate::jmap;
+use crate::local;
+use crate::remote::{self, Remote};
+use serde_json::Value;
+use snafu::prelude::*;
+use std::collections::HashMap;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not open remote session: {}", source))]
+    OpenRemote { source: remote::Error },
+
+    #[snafu(display("Could not index mailboxes: {}", source))]
+    IndexMailboxes { source: remote::Error },
+
+    #[snafu(display("No mailbox matches `{}'", mailbox))]
+    NoSuchMailbox { mailbox: String },
+
+    #[snafu(display("Could not update mailbox `{}': {}", mailbox, source))]
+    UpdateMailbox {
+        mailbox: String,
+        source: remote::Error,
+    },
+
+    #[snafu(display("Mailbox `{}' already exists", mailbox))]
+    MailboxExists { mailbox: String },
+
+    #[snafu(display("Could not create mailbox `{}': {}", mailbox, source))]
+    CreateMailbox {
+        mailbox: String,
+        source: remote::Error,
+    },
+
+    #[snafu(display("Could not destroy mailbox `{}': {}", mailbox, source))]
+    DestroyMailbox {
+        mailbox: String,
+        source: remote::Error,
+    },
+
+    #[snafu(display("Server refused to destroy mailbox `{}': {}", mailbox, source))]
+    NotDestroyed {
+        mailbox: String,
+        source: jmap::MethodResponseError,
+    },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// List all mailboxes on the server by path, along with the tags they map to and their flags,
+/// sorted by path.
+pub fn list(config: Config) -> Result<Vec<(String, String, String)>> {
+    let mut remote = Remote::open(&config).context(OpenRemoteSnafu {})?;
+    let jmap_mailboxes = remote
+        .get_jmap_mailboxes()
+        .context(IndexMailboxesSnafu {})?;
+    let mailboxes = remote
+        .get_mailboxes(&config)
+        .context(IndexMailboxesSnafu {})?;
+
+    let mut rows: Vec<(String, String, String)> = jmap_mailboxes
+        .values()
+        .map(|jmap_mailbox| {
+            let path = remote::mailbox_path(
+                &jmap_mailboxes,
+                &jmap_mailbox.id,
+                &config.tags.directory_separator,
+            )
+            .unwrap_or_else(|| jmap_mailbox.name.clone());
+            let tag = match mailboxes.mailboxes_by_id.get(&jmap_mailbox.id) {
+                Some(mailbox) => match &mailbox.alias {
+                    Some(alias) => format!("{} {alias}", mailbox.tag),
+                    None => mailbox.tag.clone(),
+                },
+                None if mailboxes.archive_id.as_ref() == Some(&jmap_mailbox.id) => {
+                    "(archive)".to_string()
+                }
+                None => "(ignored)".to_string(),
+            };
+            let mut flags = Vec::new();
+            if let Some(role) = jmap_mailbox.role {
+                flags.push(format!("{role:?}").to_lowercase());
+            }
+            if jmap_mailbox.is_subscribed == Some(false) {
+                flags.push("unsubscribed".to_string());
+            }
+            // Mailboxes which would have the same tag as an automatic tag get a prefixed one.
+            let prefix = config.tags.automatic_tag_prefix.as_str();
+            let renamed = mailboxes
+                .mailboxes_by_id
+                .get(&jmap_mailbox.id)
+                .and_then(|mailbox| mailbox.tag.strip_prefix(prefix))
+                .is_some_and(|tag| !prefix.is_empty() && local::AUTOMATIC_TAGS.contains(tag));
+            if renamed {
+                flags.push("renamed".to_string());
+            }
+            (path, tag, flags.join(","))
+        })
+        .collect();
+    rows.sort();
+    Ok(rows)
+}
+
+/// Subscribe to or unsubscribe from the given mailbox on the server.
+pub fn set_subscribed(config: Config, mailbox: String, subscribed: bool) -> Result<()> {
+    let mut remote = Remote::open(&config).context(OpenRemoteSnafu {})?;
+    let jmap_mailboxes = remote
+        .get_jmap_mailboxes()
+        .context(IndexMailboxesSnafu {})?;
+    let id = remote::find_mailbox_id(&jmap_mailboxes, &mailbox, &config.tags.directory_separator)
+        .context(NoSuchMailboxSnafu { mailbox: &mailbox })?;
+    remote
+        .update_mailbox(
+            &id,
+            HashMap::from([("isSubscribed", Value::Bool(subscribed))]),
+        )
+        .context(UpdateMailboxSnafu { mailbox })
+}
+
+/// Create the mailbox with the given full path on the server, along with any missing ancestors.
+pub fn create(config: Config, mailbox: String) -> Result<()> {
+    let separator = &config.tags.directory_separator;
+    let mut remote = Remote::open(&config).context(OpenRemoteSnafu {})?;
+    let jmap_mailboxes = remote
+        .get_jmap_mailboxes()
+        .context(IndexMailboxesSnafu {})?;
+    ensure!(
+        remote::find_mailbox_id(&jmap_mailboxes, &mailbox, separator).is_none(),
+        MailboxExistsSnafu { mailbox }
+    );
+
+    let mut parent_id = None;
+    let mut created = false;
+    let names: Vec<&str> = mailbox.split(separator.as_str()).collect();
+    for (index, name) in names.iter().enumerate() {
+        let path = names[..=index].join(separator);
+        // Once one ancestor has been created, none of its descendants can exist yet.
+        if !created {
+            if let Some(id) = remote::find_mailbox_id(&jmap_mailboxes, &path, separator) {
+                parent_id = Some(id);
+                continue;
+            }
+        }
+        let id = remote
+            .create_mailbox(jmap::MailboxCreate {
+                parent_id: parent_id.take(),
+                name: name.to_string(),
+                role: None,
+            })
+            .context(CreateMailboxSnafu { mailbox: &path })?;
+        parent_id = Some(id);
+        created = true;
+    }
+    Ok(())
+}
+
+/// Rename or move the given mailbox on the server. The parent of the new path must exist.
+pub fn rename(config: Config, mailbox: String, new_mailbox: String) -> Result<()> {
+    let separator = &config.tags.directory_separator;
+    let mut remote = Remote::open(&config).context(OpenRemoteSnafu {})?;
+    let jmap_mailboxes = remote
+        .get_jmap_mailboxes()
+        .context(IndexMailboxesSnafu {})?;
+    let id = remote::find_mailbox_id(&jmap_mailboxes, &mailbox, separator)
+        .context(NoSuchMailboxSnafu { mailbox: &mailbox })?;
+    ensure!(
+        remote::find_mailbox_id(&jmap_mailboxes, &new_mailbox, separator).is_none(),
+        MailboxExistsSnafu {
+            mailbox: new_mailbox
+        }
+    );
+
+    let (parent_id, name) = match new_mailbox.rfind(separator.as_str()) {
+        Some(index) => {
+            let parent = &new_mailbox[..index];
+            let parent_id = remote::find_mailbox_id(&jmap_mailboxes, parent, separator)
+                .context(NoSuchMailboxSnafu { mailbox: parent })?;
+            (
+                Value::String(parent_id.0),
+                &new_mailbox[index + separator.len()..],
+            )
+        }
+        None => (Value::Null, new_mailbox.as_str()),
+    };
+    remote
+        .update_mailbox(
+            &id,
+            HashMap::from([
+                ("parentId", parent_id),
+                ("name", Value::String(name.to_string())),
+            ]),
+        )
+        .context(UpdateMailboxSnafu { mailbox })
+}
+
+/// Destroy the given mailbox on the server. If `with_emails` is false, the server will refuse to
+/// destroy a mailbox which still contains emails.
+pub fn delete(config: Config, mailbox: String, with_emails: bool) -> Result<()> {
+    let mut remote = Remote::open(&config).context(OpenRemoteSnafu {})?;
+    let jmap_mailboxes = remote
+        .get_jmap_mailboxes()
+        .context(IndexMailboxesSnafu {})?;
+    let id = remote::find_mailbox_id(&jmap_mailboxes, &mailbox, &config.tags.directory_separator)
+        .context(NoSuchMailboxSnafu { mailbox: &mailbox })?;
+    let mut not_destroyed = remote
+        .destroy_mailboxes(&[&id], with_emails)
+        .context(DestroyMailboxSnafu { mailbox: &mailbox })?;
+    match not_destroyed.remove(&id) {
+        Some(e) => Err(e).context(NotDestroyedSnafu { mailbox }),
+        None => Ok(()),
+    }
+}
@@ -1,32 +1,24 @@
-#![doc = include_str!("../README.md")]
-
 /// Command line arguments.
 mod args;
-/// Local cache interface.
-mod cache;
-/// Configuration file options.
-mod config;
-/// Miniature JMAP API.
-mod jmap;
-/// Local notmuch database interface.
-mod local;
-/// Remote JMAP interface.
-mod remote;
-/// Send command.
-mod send;
-/// Sync command.
-mod sync;
 
 use args::Args;
 use atty::Stream;
-use clap::Parser;
-use config::Config;
+use clap::{CommandFactory, FromArgMatches};
+use clap_verbosity_flag::{Verbosity, WarnLevel};
 use log::debug;
-use send::send;
+use mujmap::config::{self, Config};
+use mujmap::observer::{JsonObserver, SummarizingLogger, SyncObserver, TerminalObserver};
+use mujmap::remote::{self, Release};
+use mujmap::send::{self, send, Delivery, Sent};
+use mujmap::sync::{self, sync};
+use mujmap::{blame, check, jmap, journal, mailboxes, tag, verify};
 use snafu::prelude::*;
 use std::path::PathBuf;
-use std::{env, io::Write};
-use sync::sync;
+use std::{
+    env,
+    fmt::Display,
+    io::{self, Write},
+};
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
 #[derive(Debug, Snafu)]
@@ -39,62 +31,459 @@ pub enum Error {
 
     #[snafu(display("Could not send mail: {}", source))]
     Send { source: send::Error },
+
+    #[snafu(display("Could not manage mailboxes: {}", source))]
+    Mailboxes { source: mailboxes::Error },
+
+    #[snafu(display("Could not verify mail: {}", source))]
+    Verify { source: verify::Error },
+
+    #[snafu(display("Could not undo pushed changes: {}", source))]
+    Undo { source: journal::Error },
+
+    #[snafu(display("Could not tag mail: {}", source))]
+    Tag { source: tag::Error },
+
+    #[snafu(display("Could not explain tags: {}", source))]
+    Blame { source: blame::Error },
+
+    #[snafu(display("Could not check config: {}", source))]
+    Check { source: check::Error },
+
+    #[snafu(display("Could not write man page: {}", source))]
+    WriteManpage { source: io::Error },
+
+    #[snafu(display("Could not write output: {}", source))]
+    WriteOutput { source: io::Error },
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
-fn try_main(stdout: &mut StandardStream) -> Result<(), Error> {
+fn try_main(stdout: StandardStream) -> Result<(), Error> {
     // HACK: Remove -oi from the command-line arguments. If someone is weird enough to have named
     // their maildir "-oi", or something like that, this would cause mujmap to fail unnecessarily.
     // However, clap does not yet support "long" arguments with more than one character, so this is
     // our best option. See: https://github.com/clap-rs/clap/issues/1210
-    let args = Args::parse_from(env::args().into_iter().filter(|a| a != "-oi"));
+    let matches = Args::command().get_matches_from(env::args().filter(|a| a != "-oi"));
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    // The first `-q` hides the decorative output of a sync, so that cron mail and the journal only
+    // contain warnings, errors, and the final summary. Only further ones lower the log level.
+    let quiet = matches.occurrences_of("quiet") as i8;
+    let verbosity =
+        Verbosity::<WarnLevel>::new(matches.occurrences_of("verbose") as i8, (quiet - 1).max(0));
 
-    env_logger::Builder::new()
-        .filter_level(args.verbose.log_level_filter())
+    // Repeated warnings are summarized at the end of a sync, unless asked to be verbose.
+    let logger = env_logger::Builder::new()
+        .filter_level(verbosity.log_level_filter())
         .parse_default_env()
-        .init();
+        .build();
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(SummarizingLogger {
+        show_repeats: logger.filter() > log::LevelFilter::Warn,
+        inner: logger,
+    }))
+    .expect("logger is only set once");
 
     let info_color_spec = ColorSpec::new()
         .set_fg(Some(Color::Green))
         .set_bold(true)
         .to_owned();
+    let observer: Box<dyn SyncObserver> = if args.json_progress {
+        Box::new(JsonObserver::new())
+    } else {
+        Box::new(TerminalObserver::new(
+            stdout,
+            info_color_spec,
+            quiet > 0,
+            quiet == 0 && !args.no_progress && atty::is(Stream::Stderr),
+        ))
+    };
+
+    // Generated files describe the command line itself, so they don't need a config.
+    match args.command {
+        args::Command::Completions { shell } => {
+            clap_complete::generate(shell, &mut Args::command(), "mujmap", &mut io::stdout());
+            return Ok(());
+        }
+        args::Command::Manpage => {
+            return clap_mangen::Man::new(Args::command())
+                .render(&mut io::stdout())
+                .context(WriteManpageSnafu {});
+        }
+        _ => {}
+    }
+
+    // Determine working directory and load all data files. `-C` names either the maildir, which
+    // contains the config, or the config file itself, which may live elsewhere.
+    let path = args.path.clone().unwrap_or_else(|| PathBuf::from("."));
+    let config_path = if path.is_dir() {
+        path.join("mujmap.toml")
+    } else {
+        path
+    };
 
-    // Determine working directory and load all data files.
-    let mail_dir = args.path.clone().unwrap_or_else(|| PathBuf::from("."));
+    // Checking the config reports problems which would otherwise stop it from loading.
+    if let args::Command::Config {
+        command: args::ConfigCommand::Check,
+    } = args.command
+    {
+        let problems = check::check(&config_path);
+        print_lines(&problems)?;
+        if !problems.is_empty() {
+            return Err(check::Error::Problems {
+                count: problems.len(),
+            })
+            .context(CheckSnafu {});
+        }
+        return print_lines(["mujmap: ok"]);
+    }
 
-    let config = Config::from_file(mail_dir.join("mujmap.toml")).context(OpenConfigFileSnafu {})?;
+    let config = Config::from_file(&config_path).context(OpenConfigFileSnafu {})?;
     debug!("Using config: {:?}", config);
 
+    let mail_dir = config.mail_dir(&config_path);
+
     match args.command {
-        args::Command::Push => sync(
-            stdout,
-            info_color_spec,
+        args::Command::Push => sync_confirming(
+            observer.as_ref(),
             mail_dir,
-            args,
-            config,
-            /*pull=*/ false,
+            sync::Options {
+                dry_run: args.dry_run,
+                offline: args.offline,
+                pull: false,
+                resync: false,
+                max_messages: args.max_messages,
+                profile: args.profile,
+                accept_missing_revision: false,
+            },
+            &config,
         )
         .context(SyncSnafu {}),
-        args::Command::Sync => sync(
-            stdout,
-            info_color_spec,
+        args::Command::Sync => sync_confirming(
+            observer.as_ref(),
+            mail_dir,
+            sync::Options {
+                dry_run: args.dry_run,
+                offline: args.offline,
+                pull: true,
+                resync: false,
+                max_messages: args.max_messages,
+                profile: args.profile,
+                accept_missing_revision: false,
+            },
+            &config,
+        )
+        .context(SyncSnafu {}),
+        args::Command::Resync => sync_confirming(
+            observer.as_ref(),
             mail_dir,
-            args,
-            config,
-            /*pull=*/ true,
+            sync::Options {
+                dry_run: args.dry_run,
+                offline: args.offline,
+                pull: true,
+                resync: true,
+                max_messages: args.max_messages,
+                profile: args.profile,
+                accept_missing_revision: false,
+            },
+            &config,
         )
         .context(SyncSnafu {}),
         args::Command::Send {
             read_recipients,
+            no_aliases,
+            file,
+            draft,
+            wait,
+            undo,
+            send_at,
+            dsn,
+            dsn_return,
+            envelope_from,
+            print_ids,
+            json,
             recipients,
             ..
-        } => send(read_recipients, recipients, config).context(SendSnafu {}),
+        } => {
+            if undo {
+                let canceled = send::undo(config).context(SendSnafu {})?;
+                return print_lines([format!("Canceled {canceled} emails.")]);
+            }
+            let source = match (file, draft) {
+                (Some(path), _) => send::Source::File(path),
+                (_, Some(message_id)) => send::Source::Draft(message_id),
+                (None, None) => send::Source::Stdin,
+            };
+            let sent = send(
+                &mail_dir,
+                source,
+                read_recipients,
+                recipients,
+                !no_aliases,
+                envelope_from,
+                wait,
+                send_at,
+                remote::Dsn {
+                    notify: dsn,
+                    ret: dsn_return,
+                },
+                config,
+            )
+            .context(SendSnafu {})?;
+            print_sent(&sent, print_ids, json)
+        }
+        args::Command::Flush => {
+            let sent = send::flush(&mail_dir, config).context(SendSnafu {})?;
+            print_lines([format!("Sent {sent} queued emails.")])
+        }
+        args::Command::Undo => match journal::undo(mail_dir, config).context(UndoSnafu {})? {
+            Some(reverted) => print_lines([format!("Reverted changes to {reverted} emails.")]),
+            None => print_lines(["Nothing to undo."]),
+        },
+        args::Command::Tag {
+            operations,
+            queries,
+            ..
+        } => {
+            let tagged = tag::tag(config, &operations, &queries).context(TagSnafu {})?;
+            print_lines([format!("Tagged {tagged} emails.")])
+        }
+        args::Command::Tags => {
+            let rows = tag::list(&mail_dir, config).context(TagSnafu {})?;
+            let tag_width = rows.iter().map(|(tag, _, _)| tag.len()).max().unwrap_or(0);
+            let count_width = rows
+                .iter()
+                .map(|(_, count, _)| count.to_string().len())
+                .max()
+                .unwrap_or(0);
+            print_lines(rows.iter().map(|(tag, count, mapping)| {
+                format!("{tag:tag_width$}  {count:>count_width$}  {mapping}")
+            }))
+        }
+        args::Command::Blame { query } => {
+            print_lines(blame::blame(&mail_dir, config, &query).context(BlameSnafu {})?)
+        }
+        args::Command::Mailboxes { command: None } => {
+            let rows = mailboxes::list(config).context(MailboxesSnafu {})?;
+            let path_width = rows
+                .iter()
+                .map(|(path, _, _)| path.len())
+                .max()
+                .unwrap_or(0);
+            let tag_width = rows.iter().map(|(_, tag, _)| tag.len()).max().unwrap_or(0);
+            print_lines(
+                rows.iter().map(|(path, tag, flags)| {
+                    format!("{path:path_width$}  {tag:tag_width$}  {flags}")
+                }),
+            )
+        }
+        args::Command::Mailboxes {
+            command: Some(command),
+        } => match command {
+            args::MailboxesCommand::Subscribe { mailbox } => {
+                mailboxes::set_subscribed(config, mailbox, true)
+            }
+            args::MailboxesCommand::Unsubscribe { mailbox } => {
+                mailboxes::set_subscribed(config, mailbox, false)
+            }
+            args::MailboxesCommand::Create { mailbox } => mailboxes::create(config, mailbox),
+            args::MailboxesCommand::Rename {
+                mailbox,
+                new_mailbox,
+            } => mailboxes::rename(config, mailbox, new_mailbox),
+            args::MailboxesCommand::Delete {
+                mailbox,
+                with_emails,
+            } => mailboxes::delete(config, mailbox, with_emails),
+        }
+        .context(MailboxesSnafu {}),
+        args::Command::Verify { repair } => {
+            let report =
+                verify::verify(mail_dir, config, args.dry_run, repair).context(VerifySnafu {})?;
+            print_lines(&report)?;
+            if report.is_empty() {
+                return print_lines(["mujmap: ok"]);
+            }
+            if !repair {
+                return Err(verify::Error::Inconsistent {
+                    count: report.len(),
+                })
+                .context(VerifySnafu {});
+            }
+            print_lines([format!(
+                "Found {} inconsistencies; the next sync will be a full sync.",
+                report.len()
+            )])
+        }
+        args::Command::Completions { .. }
+        | args::Command::Manpage
+        | args::Command::Config { .. } => {
+            unreachable!()
+        }
+    }
+}
+
+/// Sync, asking the user whether to carry on if the notmuch database revision of the last sync is
+/// missing.
+fn sync_confirming(
+    observer: &dyn SyncObserver,
+    mail_dir: PathBuf,
+    options: sync::Options,
+    config: &Config,
+) -> Result<(), sync::Error> {
+    match sync(observer, mail_dir.clone(), options, config) {
+        Err(sync::Error::MissingNotmuchDatabaseRevision {})
+            if confirm_missing_revision(options.dry_run) =>
+        {
+            let options = sync::Options {
+                accept_missing_revision: true,
+                ..options
+            };
+            sync(observer, mail_dir, options, config)
+        }
+        result => result,
+    }
+}
+
+/// Explain what a missing notmuch database revision means and, in an interactive terminal, ask
+/// whether to carry on anyway.
+fn confirm_missing_revision(dry_run: bool) -> bool {
+    if dry_run {
+        println!(
+            "\
+THIS IS A DRY RUN, SO NO CHANGES WILL BE MADE NO MATTER THE CHOICE. HOWEVER,
+HEED THE WARNING FOR THE REAL DEAL.
+"
+        );
+    }
+    println!(
+        "\
+mujmap was unable to read the notmuch database revision (stored in
+mujmap.state.json) since the last time it was run. As a result, it cannot
+determine the changes made in the local database since the last time a
+synchronization was performed.
+"
+    );
+    if !atty::is(Stream::Stdout) {
+        println!(
+            "\
+Please run mujmap again in an interactive terminal to resolve.
+"
+        );
+        return false;
+    }
+    println!(
+        "\
+If you continue, any potential changes made to your database since your last
+sync will not be pushed to the JMAP server, and some may be overwritten by the
+JMAP server's state. Depending on your situation, this has potential to create
+inconsistencies between the state of your database and the state of the server.
+
+If this is the first time you are synchronizing mujmap in a pre-existing
+database, this is not an issue. If the mail files in your database are different
+from your mail on the JMAP server, you can proceed and mujmap will perform the
+initial setup with no issues. This would be the case if you had multiple email
+accounts in the same database, for example.
+
+If this is the first time you are synchronizing, and you are attempting to
+migrate your existing notmuch database tags to mailboxes on a JMAP server,
+DO NOT continue. Instead, follow the relevant instructions in mujmap's README.
+
+If this is the first time you are synchronizing, but you do not care about your
+notmuch tags and would like them to be replaced with the JMAP server's state,
+you may continue.
+
+If this is NOT the first time you are synchronizing, you should quit and force
+a full sync by deleting the `mujmap.state.json' file and invoking mujmap again.
+This will overwrite all of your local JMAP state with the JMAP server's state.
+You will encounter this message again, but at that point you can safely proceed.
+
+Continue? (y/N)
+"
+    );
+    let mut response = String::new();
+    io::stdin().read_line(&mut response).ok();
+    let trimmed = response.trim();
+    trimmed == "y" || trimmed == "Y"
+}
+
+/// Print each of `lines` to stdout.
+fn print_lines(lines: impl IntoIterator<Item = impl Display>) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    for line in lines {
+        writeln!(stdout, "{line}").context(WriteOutputSnafu {})?;
+    }
+    Ok(())
+}
+
+/// Print what became of an email given to `mujmap send`: its IDs if asked for, when it will be
+/// sent, and its delivery status if it was waited for.
+fn print_sent(sent: &Sent, print_ids: bool, json: bool) -> Result<()> {
+    let (ids, message_id, release, delivery) = match sent {
+        Sent::Submitted {
+            ids,
+            message_id,
+            release,
+            delivery,
+        } => (ids, message_id, release, delivery),
+        // This was already warned about.
+        Sent::Queued(_) => return Ok(()),
+    };
+    let mut lines = Vec::new();
+    if json {
+        lines.push(
+            serde_json::json!({
+                "id": ids.email_id,
+                "blobId": ids.blob_id,
+                "threadId": ids.thread_id,
+                "messageId": message_id,
+            })
+            .to_string(),
+        );
+    } else if print_ids {
+        lines.push(format!(
+            "id={} blobId={} threadId={} messageId={}",
+            ids.email_id,
+            ids.blob_id,
+            ids.thread_id,
+            message_id.as_deref().unwrap_or_default(),
+        ));
+    }
+    match release {
+        // Keep the output parsable as JSON.
+        _ if json => {}
+        Release::At(time) => lines.push(format!(
+            "Sending at {}; run `mujmap send --undo' to cancel.",
+            jmap::utc_date(*time)
+        )),
+        Release::After(delay) => lines.push(format!(
+            "Sending in {delay} seconds; run `mujmap send --undo' to cancel."
+        )),
+        Release::Immediately => {}
+    }
+    match delivery {
+        None | Some(Delivery::Unknown) => {}
+        Some(Delivery::Unreported) => {
+            lines.push("Sent; the server does not report delivery status.".to_owned())
+        }
+        Some(Delivery::Recipients(recipients)) => {
+            lines.extend(recipients.iter().map(|(recipient, delivered, smtp_reply)| {
+                let delivered = match delivered {
+                    jmap::Delivered::Queued => "queued",
+                    jmap::Delivered::Yes => "delivered",
+                    jmap::Delivered::No => "failed",
+                    jmap::Delivered::Unknown => "unknown",
+                };
+                format!("{recipient}: {delivered} ({smtp_reply})")
+            }))
+        }
     }
+    print_lines(lines)
 }
 
 fn main() {
-    let mut stdout = StandardStream::stdout(if atty::is(Stream::Stdout) {
+    let stdout = StandardStream::stdout(if atty::is(Stream::Stdout) {
         ColorChoice::Auto
     } else {
         ColorChoice::Never
@@ -105,14 +494,24 @@ fn main() {
         ColorChoice::Never
     });
 
-    std::process::exit(match try_main(&mut stdout) {
+    std::process::exit(match try_main(stdout) {
         Ok(_) => 0,
         Err(err) => {
             stderr
                 .set_color(ColorSpec::new().set_fg(Some(Color::Red)))
                 .ok();
             writeln!(&mut stderr, "error: {err}").ok();
-            1
+            // Everything else was synced, so scripts may want to tell this apart.
+            if matches!(
+                err,
+                Error::Sync {
+                    source: sync::Error::IncompleteDownloads { .. }
+                }
+            ) {
+                2
+            } else {
+                1
+            }
         }
     });
 }
@@ -0,0 +1,387 @@
+use crate::sync::SyncSummary;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{warn, Level, Log, Metadata, Record};
+use serde_json::json;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use termcolor::{ColorSpec, StandardStream, WriteColor};
+
+/// Warnings counted so far by category, in the order each category first appeared.
+static WARNINGS: Mutex<Vec<WarningCount>> = Mutex::new(Vec::new());
+
+/// How many times a category of warnings was emitted.
+#[derive(Debug, Clone)]
+pub struct WarningCount {
+    /// The message of the warnings, with the parts which differ between them left out.
+    pub category: String,
+    /// The first warning of the category.
+    pub example: String,
+    pub count: usize,
+}
+
+/// Count `message` under its category, returning whether it's the first of its category.
+pub fn count_warning(message: &str) -> bool {
+    let category = warning_category(message);
+    let mut warnings = WARNINGS.lock().unwrap();
+    match warnings.iter_mut().find(|x| x.category == category) {
+        Some(warning) => {
+            warning.count += 1;
+            false
+        }
+        None => {
+            warnings.push(WarningCount {
+                category,
+                example: message.to_owned(),
+                count: 1,
+            });
+            true
+        }
+    }
+}
+
+/// Return the warnings counted since the last call, by category.
+pub fn take_warning_counts() -> Vec<WarningCount> {
+    std::mem::take(&mut *WARNINGS.lock().unwrap())
+}
+
+/// Return the category of a warning: its message, with the quoted names and the numbers which
+/// differ between warnings of the same kind left out.
+fn warning_category(message: &str) -> String {
+    let mut category = String::with_capacity(message.len());
+    let mut chars = message.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '`' {
+            category.push_str("`…'");
+            chars.by_ref().find(|&c| c == '\'');
+        } else if c.is_ascii_digit() {
+            category.push('…');
+            while chars.next_if(char::is_ascii_digit).is_some() {}
+        } else {
+            category.push(c);
+        }
+    }
+    category
+}
+
+/// Wraps a logger so that of each category of warnings, only the first is logged, unless
+/// `show_repeats` is set. All are counted, so that they can be summarized at the end of a sync.
+pub struct SummarizingLogger<L> {
+    pub inner: L,
+    pub show_repeats: bool,
+}
+
+impl<L: Log> Log for SummarizingLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() == Level::Warn
+            && self.inner.enabled(record.metadata())
+            && !count_warning(&record.args().to_string())
+            && !self.show_repeats
+        {
+            return;
+        }
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Receives the progress of a sync, so that frontends can present it however they like.
+///
+/// Items may be completed and warnings emitted from several threads at once.
+pub trait SyncObserver: Sync {
+    /// A phase of the sync started, e.g. "Applying changes to JMAP server". `detail` summarizes
+    /// its extent, e.g. "12 changed", and may be empty.
+    fn phase_started(&self, phase: &str, detail: &str);
+
+    /// `count` more items are due to be completed in the current phase, e.g. emails to download.
+    fn items_added(&self, count: u64);
+
+    /// One of the items of the current phase was completed.
+    fn item_completed(&self);
+
+    /// The items added to the current phase amount to `bytes` more bytes to transfer, e.g. the
+    /// size of an email to download. Frontends which only count items may ignore this.
+    fn bytes_added(&self, _bytes: u64) {}
+
+    /// `bytes` more bytes of the items of the current phase were transferred. A transfer which is
+    /// retried adds its bytes again, and so does `bytes_added`.
+    fn bytes_transferred(&self, _bytes: u64) {}
+
+    /// Something went wrong which doesn't stop the sync.
+    fn warning(&self, message: &str);
+
+    /// The sync finished.
+    fn finished(&self, summary: &SyncSummary);
+}
+
+/// Reports progress to a terminal, with a progress bar for items.
+pub struct TerminalObserver {
+    stdout: Mutex<StandardStream>,
+    info_color_spec: ColorSpec,
+    progress: Mutex<Option<Progress>>,
+    /// Only report warnings and the final summary, e.g. for cron jobs.
+    quiet: bool,
+    /// Show a progress bar for items.
+    show_progress: bool,
+}
+
+impl TerminalObserver {
+    pub fn new(
+        stdout: StandardStream,
+        info_color_spec: ColorSpec,
+        quiet: bool,
+        show_progress: bool,
+    ) -> Self {
+        Self {
+            stdout: Mutex::new(stdout),
+            info_color_spec,
+            progress: Mutex::new(None),
+            quiet,
+            show_progress,
+        }
+    }
+
+    fn finish_progress_bar(&self) {
+        if let Some(progress) = self.progress.lock().unwrap().take() {
+            progress.bar.finish_with_message("done");
+        }
+    }
+
+    /// Call `f` with the progress of the current phase, starting it if need be, unless progress
+    /// isn't shown.
+    fn with_progress(&self, f: impl FnOnce(&mut Progress)) {
+        if !self.show_progress {
+            return;
+        }
+        f(self
+            .progress
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| Progress {
+                bar: ProgressBar::new(0),
+                completed: 0,
+                total: 0,
+                bytes: false,
+            }));
+    }
+}
+
+/// The progress bar of a phase. It counts items until their size in bytes is known, and bytes from
+/// then on, with the items counted in its message instead.
+struct Progress {
+    bar: ProgressBar,
+    completed: u64,
+    total: u64,
+    bytes: bool,
+}
+
+impl Progress {
+    fn count_bytes(&mut self) {
+        if self.bytes {
+            return;
+        }
+        self.bytes = true;
+        self.bar.set_style(
+            ProgressStyle::default_bar()
+                .template("{msg} {wide_bar} {bytes}/{total_bytes} ({bytes_per_sec}, {eta} left)"),
+        );
+        self.bar.set_length(0);
+        self.bar.set_position(0);
+        self.update_message();
+    }
+
+    fn update_message(&self) {
+        if self.bytes {
+            self.bar
+                .set_message(format!("{}/{}", self.completed, self.total));
+        }
+    }
+}
+
+impl SyncObserver for TerminalObserver {
+    fn phase_started(&self, phase: &str, detail: &str) {
+        self.finish_progress_bar();
+        if self.quiet {
+            return;
+        }
+        // Failing to print progress shouldn't fail the sync.
+        let mut stdout = self.stdout.lock().unwrap();
+        stdout.set_color(&self.info_color_spec).ok();
+        write!(stdout, "{phase}").ok();
+        stdout.reset().ok();
+        if detail.is_empty() {
+            writeln!(stdout).ok();
+        } else {
+            writeln!(stdout, " ({detail})").ok();
+        }
+        stdout.flush().ok();
+    }
+
+    fn items_added(&self, count: u64) {
+        self.with_progress(|progress| {
+            progress.total += count;
+            if progress.bytes {
+                progress.update_message();
+            } else {
+                progress.bar.inc_length(count);
+            }
+        });
+    }
+
+    fn item_completed(&self) {
+        if let Some(progress) = &mut *self.progress.lock().unwrap() {
+            progress.completed += 1;
+            if progress.bytes {
+                progress.update_message();
+            } else {
+                progress.bar.inc(1);
+            }
+        }
+    }
+
+    fn bytes_added(&self, bytes: u64) {
+        self.with_progress(|progress| {
+            progress.count_bytes();
+            progress.bar.inc_length(bytes);
+        });
+    }
+
+    fn bytes_transferred(&self, bytes: u64) {
+        if let Some(progress) = &*self.progress.lock().unwrap() {
+            if progress.bytes {
+                progress.bar.inc(bytes);
+            }
+        }
+    }
+
+    fn warning(&self, message: &str) {
+        warn!("{message}");
+    }
+
+    fn finished(&self, summary: &SyncSummary) {
+        self.finish_progress_bar();
+        // The summary is printed even when quiet, for the benefit of scripts.
+        {
+            let mut stdout = self.stdout.lock().unwrap();
+            writeln!(stdout, "{summary}").ok();
+            if let Some(profile) = &summary.profile {
+                writeln!(stdout, "{profile}").ok();
+            }
+        }
+        // Only the first of each category of warnings was shown, so tell how many more there were,
+        // next to the warnings themselves.
+        let repeated: Vec<_> = take_warning_counts()
+            .into_iter()
+            .filter(|warning| warning.count > 1)
+            .collect();
+        if !repeated.is_empty() {
+            let mut stderr = io::stderr().lock();
+            writeln!(stderr, "Repeated warnings:").ok();
+            for warning in repeated {
+                writeln!(stderr, "{:>8}  {}", warning.count, warning.category).ok();
+            }
+        }
+    }
+}
+
+/// Reports progress to stdout as JSON objects, one per line, for the benefit of other programs.
+#[derive(Default)]
+pub struct JsonObserver {
+    total: AtomicU64,
+    completed: AtomicU64,
+    total_bytes: AtomicU64,
+    transferred_bytes: AtomicU64,
+}
+
+impl JsonObserver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn emit(&self, event: serde_json::Value) {
+        // Hold the lock while writing so that lines from different threads aren't interleaved.
+        let mut stdout = std::io::stdout().lock();
+        writeln!(stdout, "{event}").ok();
+        stdout.flush().ok();
+    }
+}
+
+impl SyncObserver for JsonObserver {
+    fn phase_started(&self, phase: &str, detail: &str) {
+        self.total.store(0, Ordering::Relaxed);
+        self.completed.store(0, Ordering::Relaxed);
+        self.total_bytes.store(0, Ordering::Relaxed);
+        self.transferred_bytes.store(0, Ordering::Relaxed);
+        self.emit(json!({
+            "event": "phase",
+            "phase": phase.trim_end_matches('.'),
+            "detail": detail,
+        }));
+    }
+
+    fn items_added(&self, count: u64) {
+        self.total.fetch_add(count, Ordering::Relaxed);
+    }
+
+    fn item_completed(&self) {
+        let completed = self.completed.fetch_add(1, Ordering::Relaxed) + 1;
+        self.emit(json!({
+            "event": "progress",
+            "completed": completed,
+            "total": self.total.load(Ordering::Relaxed),
+            "bytes": self.transferred_bytes.load(Ordering::Relaxed),
+            "total_bytes": self.total_bytes.load(Ordering::Relaxed),
+        }));
+    }
+
+    fn bytes_added(&self, bytes: u64) {
+        self.total_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn bytes_transferred(&self, bytes: u64) {
+        self.transferred_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn warning(&self, message: &str) {
+        count_warning(message);
+        self.emit(json!({
+            "event": "warning",
+            "message": message,
+        }));
+    }
+
+    fn finished(&self, summary: &SyncSummary) {
+        self.emit(json!({
+            "event": "finished",
+            "new": summary.new,
+            "changed": summary.changed,
+            "destroyed": summary.destroyed,
+            "pushed": summary.pushed,
+            "duration": summary.duration.as_secs_f64(),
+            "profile": summary.profile.as_ref().map(|profile| json!({
+                "session": profile.session.as_secs_f64(),
+                "local_index": profile.local_index.as_secs_f64(),
+                "metadata": profile.metadata.as_secs_f64(),
+                "downloads": profile.downloads.as_secs_f64(),
+                "merge": profile.merge.as_secs_f64(),
+                "push": profile.push.as_secs_f64(),
+                "downloaded_bytes": profile.downloaded_bytes,
+            })),
+            "warnings": take_warning_counts()
+                .into_iter()
+                .map(|warning| json!({
+                    "category": warning.category,
+                    "example": warning.example,
+                    "count": warning.count,
+                }))
+                .collect::<Vec<_>>(),
+        }));
+    }
+}
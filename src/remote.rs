@@ -1,7 +1,11 @@
 use std::{
     collections::{HashMap, HashSet},
+    fs,
     io::{self, Read},
-    time::Duration,
+    iter,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
@@ -12,10 +16,20 @@ use crate::{
 use itertools::Itertools;
 use lazy_static::lazy_static;
 use log::{debug, log_enabled, trace, warn};
-use serde::{de::DeserializeOwned, Serialize};
-use serde_json::Value;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
 use snafu::prelude::*;
-use trust_dns_resolver::{error::ResolveError, Resolver};
+use trust_dns_resolver::{
+    config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
+    error::{ResolveError, ResolveErrorKind},
+    proto::{
+        error::ProtoError,
+        op::{Message, Query, ResponseCode},
+        rr::{Name, RData, RecordType},
+    },
+    Resolver,
+};
+use unicode_normalization::UnicodeNormalization;
 use uritemplate::UriTemplate;
 
 #[derive(Debug, Snafu)]
@@ -29,39 +43,84 @@ pub enum Error {
     #[snafu(display("Could not determine DNS settings from resolv.conf: {}", source))]
     ParseResolvConf { source: io::Error },
 
+    #[snafu(display("Could not create DNS resolver: {}", source))]
+    CreateResolver { source: io::Error },
+
     #[snafu(display("Could not lookup SRV address `{}': {}", address, source))]
     SrvLookup {
         address: String,
         source: ResolveError,
     },
 
+    #[snafu(display("No SRV record found for `{}'", address))]
+    NoSrvRecord { address: String },
+
+    #[snafu(display("Could not query DNS-over-HTTPS service {}: {}", url, source))]
+    DnsOverHttps {
+        url: String,
+        #[snafu(source(from(ureq::Error, HttpError::from)))]
+        source: HttpError,
+    },
+
+    #[snafu(display(
+        "Could not read response of DNS-over-HTTPS service {}: {}",
+        url,
+        source
+    ))]
+    ReadDnsOverHttps { url: String, source: io::Error },
+
+    #[snafu(display("Invalid DNS message for DNS-over-HTTPS service {}: {}", url, source))]
+    DnsMessage { url: String, source: ProtoError },
+
+    #[snafu(display("DNS-over-HTTPS service {} responded with {}", url, code))]
+    DnsOverHttpsResponse { url: String, code: ResponseCode },
+
     #[snafu(display("Could not resolve JMAP SRV record for {}: {}", hostname, source))]
     ResolveJmapSrvRecord {
         hostname: String,
-        source: ureq::Error,
+        #[snafu(source(from(ureq::Error, HttpError::from)))]
+        source: HttpError,
     },
 
     #[snafu(display("Could not open session at {}: {}", session_url, source))]
     OpenSession {
         session_url: String,
-        source: ureq::Error,
+        #[snafu(source(from(ureq::Error, HttpError::from)))]
+        source: HttpError,
     },
 
     #[snafu(display("Could not update session at {}: {}", session_url, source))]
     UpdateSession {
         session_url: String,
-        source: ureq::Error,
+        #[snafu(source(from(ureq::Error, HttpError::from)))]
+        source: HttpError,
     },
 
     #[snafu(display("Session username doesn't match configured username: {}", username))]
     UsernameMismatch { username: String },
 
+    #[snafu(display("Session has no account matching `{}'", account))]
+    UnknownAccount { account: String },
+
+    #[snafu(display("Could not serialize API request: {}", source))]
+    SerializeRequest { source: serde_json::Error },
+
     #[snafu(display("Could not complete API request: {}", source))]
-    Request { source: ureq::Error },
+    Request {
+        #[snafu(source(from(ureq::Error, HttpError::from)))]
+        source: HttpError,
+    },
 
     #[snafu(display("Could not interpret API response: {}", source))]
     Response { source: io::Error },
 
+    #[snafu(display(
+        "Server responded to `{}' with `{}' instead of JSON; it may have invalidated the session",
+        url,
+        content_type
+    ))]
+    NotJsonResponse { url: String, content_type: String },
+
     #[snafu(display("Could not deserialize API response: {}", source))]
     DeserializeResponse { source: serde_json::Error },
 
@@ -72,19 +131,68 @@ pub enum Error {
     MethodError { error: jmap::MethodResponseError },
 
     #[snafu(display("Could not read Email blob from server: {}", source))]
-    ReadEmailBlobError { source: ureq::Error },
+    ReadEmailBlobError {
+        #[snafu(source(from(ureq::Error, HttpError::from)))]
+        source: HttpError,
+    },
 
-    #[snafu(display("Could not find an archive mailbox"))]
+    #[snafu(display(
+        "Email blob is {} bytes, which exceeds the maximum of {} bytes; see `max_email_size'",
+        size,
+        max_size
+    ))]
+    EmailTooLarge { size: u64, max_size: u64 },
+
+    #[snafu(display(concat!(
+        "Could not find an archive mailbox; set `archive_mailbox' or ",
+        "`auto_create_archive_mailbox' in the config"
+    )))]
     NoArchive {},
 
+    #[snafu(display("JMAP server has no archive mailbox yet; the next sync creates one"))]
+    NoArchiveYet {},
+
+    #[snafu(display("Could not find configured archive mailbox `{}'", mailbox))]
+    NoConfiguredArchive { mailbox: String },
+
+    #[snafu(display(
+        "Email `{}' has no tags corresponding to a mailbox; tag it with one, or set `unfiled_mail'",
+        id
+    ))]
+    UnfiledEmail { id: Id },
+
+    #[snafu(display("Could not find configured sent mailbox `{}'", mailbox))]
+    NoConfiguredSentMailbox { mailbox: String },
+
+    #[snafu(display("Could not find mailbox `{}' configured for role `{}'", mailbox, role))]
+    NoRoleMailbox { role: String, mailbox: String },
+
+    #[snafu(display("Failed to create mailbox: {}", source))]
+    CreateMailbox { source: jmap::MethodResponseError },
+
     #[snafu(display("Mailbox contained an invalid path"))]
     InvalidMailboxPath {},
 
+    #[snafu(display(
+        "Mailboxes `{}' and `{}' both map to the tag `{}'; rename one of them or configure `tags.replacements'",
+        first,
+        second,
+        tag
+    ))]
+    MailboxTagCollision {
+        tag: String,
+        first: String,
+        second: String,
+    },
+
     #[snafu(display("Failed to update messages on server: {:?}", not_updated))]
     UpdateEmail {
         not_updated: HashMap<jmap::Id, jmap::MethodResponseError>,
     },
 
+    #[snafu(display("Failed to update mailbox: {}", source))]
+    UpdateMailbox { source: jmap::MethodResponseError },
+
     #[snafu(display("Failed to import email: {}", source))]
     ImportEmail { source: jmap::MethodResponseError },
 
@@ -96,83 +204,626 @@ pub enum Error {
 
     #[snafu(display("Failed to update submitted email: {}", source))]
     UpdateSubmittedEmail { source: jmap::MethodResponseError },
+
+    #[snafu(display("Failed to cancel email submission: {}", source))]
+    CancelEmailSubmission { source: jmap::MethodResponseError },
+
+    #[snafu(display("Failed to move canceled email back to drafts: {}", source))]
+    UpdateCanceledEmail { source: jmap::MethodResponseError },
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+impl Error {
+    /// Return true if the server could not be reached at all, e.g. because there is no network
+    /// connection, as opposed to the server rejecting us.
+    pub fn is_unreachable(&self) -> bool {
+        match self {
+            Error::SrvLookup { source, .. } => {
+                !matches!(source.kind(), ResolveErrorKind::NoRecordsFound { .. })
+            }
+            Error::ResolveJmapSrvRecord { source, .. }
+            | Error::DnsOverHttps { source, .. }
+            | Error::OpenSession { source, .. }
+            | Error::Request { source } => source.is_unreachable(),
+            _ => false,
+        }
+    }
+
+    /// Return true if the server refused a change because the account is read-only, e.g. because
+    /// it became so since the session was opened.
+    pub fn is_account_read_only(&self) -> bool {
+        matches!(
+            self,
+            Error::MethodError {
+                error: jmap::MethodResponseError::AccountReadOnly,
+            }
+        )
+    }
+
+    /// Return true if the request might succeed if it asked for fewer objects, i.e. if it was too
+    /// large for the server or a proxy in between, or if it timed out.
+    fn is_too_large(&self) -> bool {
+        match self {
+            Error::Request { source } => source.is_too_large(),
+            Error::MethodError {
+                error: jmap::MethodResponseError::RequestTooLarge,
+            } => true,
+            _ => false,
+        }
+    }
+
+    /// Return true if the server no longer accepts our credentials or session, e.g. because they
+    /// were revoked by a password change, such that the request might succeed after
+    /// re-authenticating and opening the session again.
+    fn is_session_invalidated(&self) -> bool {
+        match self {
+            Error::Request { source } => source.is_session_invalidated(),
+            Error::NotJsonResponse { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Return true if the server responded that the requested resource doesn't exist.
+    fn is_not_found(&self) -> bool {
+        match self {
+            Error::OpenSession { source, .. } | Error::Request { source } => {
+                matches!(source, HttpError::Status { status: 404, .. })
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Maximum number of bytes of an error response's body to include in error messages.
+const MAX_ERROR_BODY_EXCERPT: usize = 512;
+
+/// A failed HTTP request. Unlike `ureq::Error`, this includes the server's explanation of why it
+/// rejected the request, if it gave one.
+#[derive(Debug, Snafu)]
+pub enum HttpError {
+    #[snafu(display("{}", source))]
+    Transport { source: ureq::Error },
+
+    #[snafu(display(
+        "{}: status code {}{}",
+        url,
+        status,
+        match (problem_type, detail) {
+            (Some(problem_type), Some(detail)) => format!(" ({problem_type}): {detail}"),
+            (Some(problem_type), None) => format!(" ({problem_type})"),
+            _ if body_excerpt.is_empty() => String::new(),
+            _ => format!(": {body_excerpt}"),
+        }
+    ))]
+    Status {
+        url: String,
+        status: u16,
+        /// The `type` of an RFC 7807 problem details response, e.g.
+        /// `urn:ietf:params:jmap:error:limit`.
+        problem_type: Option<String>,
+        /// The `detail` of an RFC 7807 problem details response.
+        detail: Option<String>,
+        /// The beginning of the response body.
+        body_excerpt: String,
+    },
+}
+
+impl HttpError {
+    fn is_too_large(&self) -> bool {
+        match self {
+            HttpError::Transport { source } => {
+                matches!(source.kind(), ureq::ErrorKind::Io)
+                    && std::error::Error::source(source)
+                        .and_then(|e| e.downcast_ref::<io::Error>())
+                        .is_some_and(|e| {
+                            matches!(
+                                e.kind(),
+                                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                            )
+                        })
+            }
+            HttpError::Status { status, .. } => {
+                matches!(status, 413 | 504) || self.problem() == Some(jmap::RequestErrorType::Limit)
+            }
+        }
+    }
+
+    fn is_session_invalidated(&self) -> bool {
+        match self {
+            HttpError::Transport { .. } => false,
+            HttpError::Status { status, .. } => {
+                matches!(status, 401 | 403)
+                    || self.problem() == Some(jmap::RequestErrorType::Forbidden)
+            }
+        }
+    }
+
+    /// Return the type of the server's problem details response, if it gave one.
+    fn problem(&self) -> Option<jmap::RequestErrorType> {
+        match self {
+            HttpError::Status {
+                problem_type: Some(problem_type),
+                ..
+            } => Some(jmap::RequestErrorType::from_uri(problem_type)),
+            _ => None,
+        }
+    }
+
+    fn is_unreachable(&self) -> bool {
+        match self {
+            HttpError::Transport { source } => matches!(
+                source.kind(),
+                ureq::ErrorKind::Dns | ureq::ErrorKind::ConnectionFailed | ureq::ErrorKind::Io
+            ),
+            HttpError::Status { .. } => false,
+        }
+    }
+}
+
+impl From<ureq::Error> for HttpError {
+    fn from(error: ureq::Error) -> Self {
+        #[derive(Deserialize)]
+        struct Problem {
+            #[serde(rename = "type")]
+            problem_type: Option<String>,
+            detail: Option<String>,
+        }
+
+        match error {
+            ureq::Error::Status(status, response) => {
+                let url = response.get_url().to_string();
+                let mut body = Vec::new();
+                // The body is only read for the error message, so ignore any error reading it.
+                let _ = response
+                    .into_reader()
+                    .take(MAX_ERROR_BODY_EXCERPT as u64)
+                    .read_to_end(&mut body);
+                let problem = serde_json::from_slice::<Problem>(&body).ok();
+                let body_excerpt = String::from_utf8_lossy(&body).trim().to_string();
+                HttpError::Status {
+                    url,
+                    status,
+                    problem_type: problem.as_ref().and_then(|x| x.problem_type.clone()),
+                    detail: problem.and_then(|x| x.detail),
+                    body_excerpt,
+                }
+            }
+            source => HttpError::Transport { source },
+        }
+    }
+}
+
+/// Maximum size of a DNS message.
+const MAX_DNS_MESSAGE_SIZE: u64 = 65535;
+
+/// A JMAP SRV record.
+struct SrvRecord {
+    priority: u16,
+    target: String,
+    port: u16,
+}
+
+/// Look up the SRV records at `address` with the DNS servers given in the config, or the
+/// system's. Return `None` if there are none.
+fn lookup_srv(
+    config: &Config,
+    address: &str,
+    timeouts: Timeouts,
+) -> Result<Option<Vec<SrvRecord>>> {
+    if let Some(url) = &config.dns_over_https {
+        return lookup_srv_over_https(url, address, timeouts);
+    }
+
+    let resolver = if config.nameservers.is_empty() {
+        Resolver::from_system_conf().context(ParseResolvConfSnafu {})?
+    } else {
+        let nameservers = config
+            .nameservers
+            .iter()
+            .filter_map(|nameserver| config::parse_nameserver(nameserver))
+            .flat_map(|socket_addr| {
+                [Protocol::Udp, Protocol::Tcp].map(|protocol| NameServerConfig {
+                    socket_addr,
+                    protocol,
+                    tls_dns_name: None,
+                    trust_nx_responses: true,
+                    bind_addr: None,
+                })
+            })
+            .collect::<Vec<_>>();
+        Resolver::new(
+            ResolverConfig::from_parts(None, vec![], nameservers),
+            ResolverOpts::default(),
+        )
+        .context(CreateResolverSnafu {})?
+    };
+    match resolver.srv_lookup(address) {
+        Ok(resolver_response) => Ok(Some(
+            resolver_response
+                .into_iter()
+                .map(|srv| SrvRecord {
+                    priority: srv.priority(),
+                    target: srv.target().to_utf8(),
+                    port: srv.port(),
+                })
+                .collect(),
+        )),
+        Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Ok(None),
+        Err(e) => Err(e).context(SrvLookupSnafu { address }),
+    }
+}
+
+/// Look up the SRV records at `address` with the DNS-over-HTTPS service at `url`, as described
+/// in RFC 8484. Return `None` if there are none.
+fn lookup_srv_over_https(
+    url: &str,
+    address: &str,
+    timeouts: Timeouts,
+) -> Result<Option<Vec<SrvRecord>>> {
+    let name = Name::from_ascii(address).context(DnsMessageSnafu { url })?;
+    let mut query = Message::new();
+    query
+        .set_id(0)
+        .set_recursion_desired(true)
+        .add_query(Query::query(name, RecordType::SRV));
+    let body = query.to_vec().context(DnsMessageSnafu { url })?;
+
+    let response = timeouts
+        .agent()
+        .post(url)
+        .timeout(timeouts.api)
+        .set("Content-Type", "application/dns-message")
+        .set("Accept", "application/dns-message")
+        .send_bytes(&body)
+        .context(DnsOverHttpsSnafu { url })?;
+    let mut body = Vec::new();
+    response
+        .into_reader()
+        .take(MAX_DNS_MESSAGE_SIZE)
+        .read_to_end(&mut body)
+        .context(ReadDnsOverHttpsSnafu { url })?;
+    let response = Message::from_vec(&body).context(DnsMessageSnafu { url })?;
+
+    match response.response_code() {
+        ResponseCode::NoError => {}
+        ResponseCode::NXDomain => return Ok(None),
+        code => return DnsOverHttpsResponseSnafu { url, code }.fail(),
+    }
+    let records: Vec<_> = response
+        .answers()
+        .iter()
+        .filter_map(|record| match record.data() {
+            Some(RData::SRV(srv)) => Some(SrvRecord {
+                priority: srv.priority(),
+                target: srv.target().to_utf8(),
+                port: srv.port(),
+            }),
+            _ => None,
+        })
+        .collect();
+    Ok(if records.is_empty() {
+        None
+    } else {
+        Some(records)
+    })
+}
+
+/// How long a session URL discovered through DNS is used before it's looked up again.
+const SESSION_URL_CACHE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Session URLs previously discovered through DNS, keyed by the domain they were looked up for.
+#[derive(Default, Serialize, Deserialize)]
+struct SessionUrlCache(HashMap<String, CachedSessionUrl>);
+
+#[derive(Serialize, Deserialize)]
+struct CachedSessionUrl {
+    session_url: String,
+    /// When the session URL must be looked up again, in seconds since the Unix epoch.
+    expires: u64,
+}
+
+impl SessionUrlCache {
+    fn path(config: &Config) -> PathBuf {
+        config.cache_dir().join("mujmap.sessions.json")
+    }
+
+    /// Load the cache, or return an empty one if it doesn't exist or can't be read.
+    fn load(path: &Path) -> Self {
+        fs::read(path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_vec(self)?)
+    }
+
+    /// Return the cached session URL for the given domain, unless it has expired.
+    fn get(&self, fqdn: &str) -> Option<&str> {
+        self.0
+            .get(fqdn)
+            .filter(|cached| cached.expires > unix_now())
+            .map(|cached| cached.session_url.as_str())
+    }
+
+    fn insert(&mut self, fqdn: &str, session_url: String) {
+        self.0.insert(
+            fqdn.to_string(),
+            CachedSessionUrl {
+                session_url,
+                expires: unix_now() + SESSION_URL_CACHE_TTL.as_secs(),
+            },
+        );
+    }
+}
+
+/// Replace the limits of `session` with those configured in `overrides`.
+fn override_limits(session: &mut jmap::Session, overrides: &config::Compatibility) {
+    let core = &mut session.capabilities.core;
+    for (limit, value) in [
+        (&mut core.max_size_upload, overrides.max_size_upload),
+        (
+            &mut core.max_concurrent_upload,
+            overrides.max_concurrent_upload,
+        ),
+        (&mut core.max_size_request, overrides.max_size_request),
+        (
+            &mut core.max_concurrent_requests,
+            overrides.max_concurrent_requests,
+        ),
+        (
+            &mut core.max_calls_in_request,
+            overrides.max_calls_in_request,
+        ),
+        (&mut core.max_objects_in_get, overrides.max_objects_in_get),
+        (&mut core.max_objects_in_set, overrides.max_objects_in_set),
+    ] {
+        if let Some(value) = value {
+            *limit = value;
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs())
+}
+
+/// Timeouts for HTTP requests.
+#[derive(Clone, Copy)]
+struct Timeouts {
+    /// Timeout for establishing a connection.
+    connect: Duration,
+    /// Timeout for an entire API request, including reading the response.
+    api: Duration,
+    /// Timeout for a blob download which has stalled. Downloads which continue to make progress
+    /// are never timed out, no matter how large.
+    download: Duration,
+}
+
+impl Timeouts {
+    fn new(config: &Config) -> Self {
+        Self {
+            connect: Duration::from_secs(config.connect_timeout),
+            api: Duration::from_secs(config.api_timeout),
+            download: Duration::from_secs(config.download_timeout),
+        }
+    }
+
+    /// Build an agent with these timeouts. The read and write timeouts are those of downloads;
+    /// API requests must additionally set their overall timeout.
+    fn agent(&self) -> ureq::Agent {
+        ureq::AgentBuilder::new()
+            .redirect_auth_headers(ureq::RedirectAuthHeaders::SameHost)
+            .timeout_connect(self.connect)
+            .timeout_read(self.download)
+            .timeout_write(self.download)
+            .build()
+    }
+}
+
+/// Maximum number of times a request is retried with fresh credentials after the server rejects
+/// ours.
+const MAX_REAUTH_ATTEMPTS: usize = 2;
+
+/// HTTP authentication scheme offered by the server.
+#[derive(Clone, Copy)]
+enum AuthScheme {
+    Basic,
+    Bearer,
+}
+
+impl AuthScheme {
+    /// Return the value of the HTTP Authorization header for the given credentials.
+    fn authorization(self, username: &str, password: &str) -> String {
+        match self {
+            AuthScheme::Basic => {
+                let safe_username = match username.find(':') {
+                    Some(idx) => &username[..idx],
+                    None => username,
+                };
+                format!(
+                    "Basic {}",
+                    base64::encode(format!("{}:{}", safe_username, password))
+                )
+            }
+            AuthScheme::Bearer => format!("Bearer {}", password),
+        }
+    }
+}
+
+/// Everything needed to obtain fresh credentials when the server rejects ours, e.g. because a
+/// token expired during a long sync.
+struct Reauth {
+    scheme: AuthScheme,
+    username: String,
+    password_command: String,
+}
+
+#[derive(Clone)]
 struct HttpWrapper {
-    /// Value of HTTP Authorization header.
-    authorization: Option<String>,
+    /// Value of HTTP Authorization header. It's shared between all clones, so that they all use
+    /// fresh credentials once any of them re-authenticates.
+    authorization: Arc<RwLock<Option<String>>>,
+    /// How to re-authenticate, or `None` if the server didn't ask us to authenticate.
+    reauth: Option<Arc<Reauth>>,
     /// Persistent ureq agent to use for all HTTP requests.
     agent: ureq::Agent,
+    /// Overall timeout of API requests.
+    api_timeout: Duration,
 }
 
 impl HttpWrapper {
-    fn new(authorization: Option<String>, timeout: u64) -> Self {
-        let agent = ureq::AgentBuilder::new()
-            .redirect_auth_headers(ureq::RedirectAuthHeaders::SameHost)
-            .timeout(Duration::from_secs(timeout))
-            .build();
-
+    fn new(
+        authorization: Option<String>,
+        reauth: Option<Reauth>,
+        agent: ureq::Agent,
+        timeouts: Timeouts,
+    ) -> Self {
         Self {
-            authorization,
+            authorization: Arc::new(RwLock::new(authorization)),
+            reauth: reauth.map(Arc::new),
             agent,
+            api_timeout: timeouts.api,
+        }
+    }
+
+    /// Build a request with the given method and URL, and call `send` with it. If the server
+    /// responds with 401 Unauthorized, re-run the password command and try again with the new
+    /// credentials, up to `MAX_REAUTH_ATTEMPTS` times.
+    fn call(
+        &self,
+        method: &str,
+        url: &str,
+        send: impl Fn(ureq::Request) -> Result<ureq::Response, ureq::Error>,
+    ) -> Result<ureq::Response, ureq::Error> {
+        let mut attempts = 0;
+        loop {
+            let authorization = self.authorization.read().unwrap().clone();
+            let mut req = self.agent.request(method, url);
+            if let Some(authorization) = &authorization {
+                req = req.set("Authorization", authorization);
+            }
+            match send(req) {
+                Err(ureq::Error::Status(401, response))
+                    if attempts < MAX_REAUTH_ATTEMPTS && self.reauth.is_some() =>
+                {
+                    attempts += 1;
+                    warn!(
+                        "Server rejected credentials, re-authenticating (attempt {} of {})",
+                        attempts, MAX_REAUTH_ATTEMPTS
+                    );
+                    if let Err(e) = self.reauthenticate(authorization.as_deref()) {
+                        warn!("Could not re-authenticate: {}", e);
+                        return Err(ureq::Error::Status(401, response));
+                    }
+                }
+                result => return result,
+            }
         }
     }
 
-    fn apply_authorization(&self, req: ureq::Request) -> ureq::Request {
-        match &self.authorization {
-            Some(authorization) => req.set("Authorization", authorization),
-            _ => req,
+    /// Replace the rejected Authorization header with one built from the password command's
+    /// current output.
+    fn reauthenticate(&self, rejected: Option<&str>) -> Result<(), config::Error> {
+        let reauth = match &self.reauth {
+            Some(reauth) => reauth,
+            None => return Ok(()),
+        };
+        let mut authorization = self.authorization.write().unwrap();
+        // Another request may have already re-authenticated while this one was in flight.
+        if authorization.as_deref() != rejected {
+            return Ok(());
         }
+        let password = config::password_from_command(&reauth.password_command)?;
+        *authorization = Some(reauth.scheme.authorization(&reauth.username, &password));
+        Ok(())
     }
 
     fn get_session(&self, session_url: &str) -> Result<(String, jmap::Session), ureq::Error> {
-        let response = self
-            .apply_authorization(self.agent.get(session_url))
-            .call()?;
+        let api_timeout = self.api_timeout;
+        let response = self.call("GET", session_url, |req| req.timeout(api_timeout).call())?;
 
         let session_url = response.get_url().to_string();
         let session: jmap::Session = response.into_json()?;
         Ok((session_url, session))
     }
 
-    fn get_reader(&self, url: &str) -> Result<impl Read + Send> {
-        Ok(self
-            .apply_authorization(self.agent.get(url))
-            .call()
-            .context(ReadEmailBlobSnafu {})?
-            .into_reader()
-            // Limiting download size as advised by ureq's documentation:
-            // https://docs.rs/ureq/latest/ureq/struct.Response.html#method.into_reader
-            .take(10_000_000))
+    /// Re-run the password command and fetch the session anew, for when the server invalidated
+    /// the one we had. Return the new session.
+    fn reopen_session(&self, session_url: &str) -> Result<jmap::Session> {
+        let rejected = self.authorization.read().unwrap().clone();
+        self.reauthenticate(rejected.as_deref())
+            .context(GetPasswordSnafu {})?;
+        let (_, session) = self
+            .get_session(session_url)
+            .context(UpdateSessionSnafu { session_url })?;
+        Ok(session)
+    }
+
+    fn get_reader(&self, url: &str, max_size: u64) -> Result<impl Read + Send> {
+        let response = self
+            .call("GET", url, |req| req.call())
+            .context(ReadEmailBlobSnafu {})?;
+        // Don't bother downloading anything if the server tells us up front that it's too large.
+        if let Some(size) = response
+            .header("Content-Length")
+            .and_then(|x| x.parse::<u64>().ok())
+        {
+            ensure!(size <= max_size, EmailTooLargeSnafu { size, max_size });
+        }
+        // Limiting download size as advised by ureq's documentation:
+        // https://docs.rs/ureq/latest/ureq/struct.Response.html#method.into_reader
+        Ok(SizeLimitedReader {
+            inner: response.into_reader(),
+            remaining: max_size,
+            max_size,
+        })
     }
 
-    fn post_string<D: DeserializeOwned>(&self, url: &str, body: &str) -> Result<D> {
+    fn post_bytes<D: DeserializeOwned>(&self, url: &str, body: &[u8]) -> Result<D> {
+        let api_timeout = self.api_timeout;
         let post = self
-            .apply_authorization(self.agent.post(url))
-            .send_string(body)
+            .call("POST", url, |req| req.timeout(api_timeout).send_bytes(body))
             .context(RequestSnafu {})?;
-        if log_enabled!(log::Level::Trace) {
-            let json = post.into_string().context(ResponseSnafu {})?;
-            trace!("Post response: {json}");
-            serde_json::from_str(&json).context(DeserializeResponseSnafu {})
-        } else {
-            post.into_json().context(ResponseSnafu {})
-        }
+        Self::read_json(post)
     }
 
     fn post_json<S: Serialize, D: DeserializeOwned>(&self, url: &str, body: S) -> Result<D> {
+        // Serialize the body up front, since it may need to be sent more than once.
+        let body = serde_json::to_vec(&body).context(SerializeRequestSnafu {})?;
+        let api_timeout = self.api_timeout;
         let post = self
-            .apply_authorization(self.agent.post(url))
-            .send_json(body)
+            .call("POST", url, |req| {
+                req.timeout(api_timeout)
+                    .set("Content-Type", "application/json")
+                    .send_bytes(&body)
+            })
             .context(RequestSnafu {})?;
+        Self::read_json(post)
+    }
+
+    fn read_json<D: DeserializeOwned>(response: ureq::Response) -> Result<D> {
+        // Some servers which invalidated the session send a login page rather than an error.
+        ensure!(
+            response.content_type() != "text/html",
+            NotJsonResponseSnafu {
+                url: response.get_url(),
+                content_type: response.content_type(),
+            }
+        );
         if log_enabled!(log::Level::Trace) {
-            let json = post.into_string().context(ResponseSnafu {})?;
+            let json = response.into_string().context(ResponseSnafu {})?;
             trace!("Post response: {json}");
             serde_json::from_str(&json).context(DeserializeResponseSnafu {})
         } else {
-            post.into_json().context(ResponseSnafu {})
+            response.into_json().context(ResponseSnafu {})
         }
     }
 }
@@ -183,28 +834,35 @@ pub struct Remote {
     session_url: String,
     /// The latest session object returned by the server.
     pub session: jmap::Session,
+    /// The ID of the mail account to synchronize with.
+    pub account_id: Id,
+    /// The ID of the account to send mail from.
+    pub submission_account_id: Id,
+    /// How many `Email`s to ask for or change per request.
+    chunk_sizes: ChunkSizes,
+    /// The session's download URL template, shared with every `Downloader` so that downloads
+    /// already under way follow the server to a new URL.
+    download_url: Arc<RwLock<String>>,
+    /// Limits to use instead of those the server advertises.
+    compatibility: config::Compatibility,
 }
 
 impl Remote {
     pub fn open(config: &Config) -> Result<Self> {
         let password = config.password().context(GetPasswordSnafu {})?;
 
-        let remote = match (&config.fqdn, &config.session_url) {
-            (Some(fqdn), _) => {
-                Self::open_host(&fqdn, config.username.as_str(), &password, config.timeout)
+        let timeouts = Timeouts::new(config);
+        let mut remote = match (&config.fqdn, &config.session_url) {
+            (Some(fqdn), _) => Self::open_host(&fqdn, config, &password, timeouts),
+            (_, Some(session_url)) => {
+                Remote::open_url(&session_url.as_str(), config, &password, timeouts)
             }
-            (_, Some(session_url)) => Remote::open_url(
-                &session_url.as_str(),
-                config.username.as_str(),
-                &password,
-                config.timeout,
-            ),
             _ => {
                 let (_, domain) = config
                     .username
                     .split_once('@')
                     .context(NoDomainNameSnafu {})?;
-                Self::open_host(domain, config.username.as_str(), &password, config.timeout)
+                Self::open_host(domain, config, &password, timeouts)
             }
         }?;
 
@@ -215,86 +873,277 @@ impl Remote {
             }
         );
 
+        // Select a non-primary account if the user asked for one.
+        if let Some(account_id) = &config.account_id {
+            let account_id = Id(account_id.clone());
+            ensure!(
+                remote.session.accounts.contains_key(&account_id),
+                UnknownAccountSnafu {
+                    account: &account_id.0
+                }
+            );
+            remote.account_id = account_id;
+        } else if let Some(account_name) = &config.account_name {
+            remote.account_id = remote
+                .session
+                .accounts
+                .iter()
+                .find(|(_, account)| account.name == *account_name)
+                .map(|(id, _)| id.clone())
+                .context(UnknownAccountSnafu {
+                    account: account_name,
+                })?;
+        }
+        debug!("Using account `{}'", remote.account_id);
+
+        // Send mail from the same account if it can, and from the primary submission account
+        // otherwise.
+        if remote
+            .session
+            .accounts
+            .get(&remote.account_id)
+            .is_some_and(|account| account.account_capabilities.submission.is_some())
+        {
+            remote.submission_account_id = remote.account_id.clone();
+        }
+        debug!(
+            "Using submission account `{}'",
+            remote.submission_account_id
+        );
+
         Ok(remote)
     }
 
-    fn open_host(fqdn: &str, username: &str, password: &str, timeout: u64) -> Result<Self> {
-        let resolver = Resolver::from_system_conf().context(ParseResolvConfSnafu {})?;
+    /// Return the chunk sizes learned so far, to be passed to `learn_chunk_sizes` next time.
+    pub fn chunk_sizes(&self) -> ChunkSizes {
+        self.chunk_sizes
+    }
+
+    /// Use chunk sizes learned previously, as far as the server still allows them.
+    pub fn learn_chunk_sizes(&mut self, learned: ChunkSizes) {
+        let max = ChunkSizes::new(&self.session.capabilities.core);
+        self.chunk_sizes = ChunkSizes {
+            get: learned.get.clamp(1, max.get),
+            set: learned.set.clamp(1, max.set),
+        };
+    }
+
+    /// Call `f` with consecutive chunks of `items`, sized according to `kind`. A chunk which the
+    /// server or a proxy in between finds too large, or which times out, is split and tried again,
+    /// and the chunk size is kept smaller from then on. Each chunk which succeeds grows it back
+    /// towards the limit advertised by the server.
+    fn for_each_chunk<T>(
+        &mut self,
+        items: &[T],
+        kind: ChunkKind,
+        mut f: impl FnMut(&mut Self, &[T]) -> Result<()>,
+    ) -> Result<()> {
+        let max = *kind.size(&mut ChunkSizes::new(&self.session.capabilities.core));
+        let mut rest = items;
+        while !rest.is_empty() {
+            let size = *kind.size(&mut self.chunk_sizes);
+            let (chunk, remaining) = rest.split_at(rest.len().min(size));
+            match f(self, chunk) {
+                Ok(()) => {
+                    rest = remaining;
+                    *kind.size(&mut self.chunk_sizes) = (size + size / 4 + 1).min(max);
+                }
+                Err(e) if chunk.len() > 1 && e.is_too_large() => {
+                    let size = chunk.len() / 2;
+                    debug!(
+                        "Request of {} {kind:?} objects failed ({e}); retrying with {size}",
+                        chunk.len()
+                    );
+                    *kind.size(&mut self.chunk_sizes) = size;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the maximum number of requests the server allows at once.
+    pub fn max_concurrent_requests(&self) -> usize {
+        self.session
+            .capabilities
+            .core
+            .max_concurrent_requests
+            .try_into()
+            .unwrap_or(usize::MAX)
+            .max(1)
+    }
+
+    /// Return true if the selected account is read-only, e.g. a shared mailbox which the user may
+    /// only read from.
+    pub fn is_read_only(&self) -> bool {
+        self.session
+            .accounts
+            .get(&self.account_id)
+            .is_some_and(|account| account.is_read_only)
+    }
+
+    /// Return the submission capabilities of the account to send mail from, or `None` if it may
+    /// not send mail.
+    pub fn submission_capabilities(&self) -> Option<&jmap::SubmissionAccountCapabilities> {
+        self.session
+            .accounts
+            .get(&self.submission_account_id)
+            .and_then(|account| account.account_capabilities.submission.as_ref())
+    }
+
+    /// Return the arguments of the given SMTP submission extension, e.g. `DSN`, or `None` if the
+    /// server doesn't support it.
+    pub fn submission_extension(&self, name: &str) -> Option<&[String]> {
+        self.submission_capabilities()?
+            .submission_extensions
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, args)| args.as_slice())
+    }
+
+    /// Return the maximum number of seconds the server can delay sending an email by, or 0 if it
+    /// doesn't support delayed sending.
+    ///
+    /// Delayed sending is requested with the `FUTURERELEASE` extension, so it's only considered
+    /// supported if the server lists that extension, or lists no extensions at all, since some
+    /// servers don't report them.
+    pub fn max_delayed_send(&self) -> u64 {
+        match self.submission_capabilities() {
+            Some(submission)
+                if submission.submission_extensions.is_empty()
+                    || self.submission_extension("FUTURERELEASE").is_some() =>
+            {
+                submission.max_delayed_send
+            }
+            _ => 0,
+        }
+    }
+
+    fn open_host(fqdn: &str, config: &Config, password: &str, timeouts: Timeouts) -> Result<Self> {
+        // Skip the lookup if we already know the session URL, unless it no longer works.
+        let cache_path = SessionUrlCache::path(config);
+        let mut cache = SessionUrlCache::load(&cache_path);
+        if let Some(session_url) = cache.get(fqdn) {
+            match Self::open_url(session_url, config, password, timeouts) {
+                Ok(s) => return Ok(s),
+                Err(e) => {
+                    debug!("cached session URL {session_url} failed, looking it up again: {e}")
+                }
+            }
+        }
+
         let mut address = format!("_jmap._tcp.{}", fqdn);
         if !address.ends_with(".") {
             address.push('.');
         }
-        let resolver_response = resolver
-            .srv_lookup(address.as_str())
-            .context(SrvLookupSnafu { address })?;
+        let (urls, srv_lookup_err) = match lookup_srv(config, &address, timeouts)? {
+            // Try all SRV names in order of priority.
+            Some(records) => (
+                records
+                    .into_iter()
+                    .sorted_by_key(|x| x.priority)
+                    .map(|record| {
+                        // Remove the final ".".
+                        let target = record.target.trim_end_matches('.');
+                        format!("https://{}:{}/.well-known/jmap", target, record.port)
+                    })
+                    .collect(),
+                None,
+            ),
+            // Many servers don't publish an SRV record, so try the well-known URL on the domain
+            // itself and on its `jmap` subdomain.
+            None => {
+                debug!("no SRV record for {address}, trying well-known URLs");
+                let fqdn = fqdn.trim_end_matches('.');
+                (
+                    vec![
+                        format!("https://{}/.well-known/jmap", fqdn),
+                        format!("https://jmap.{}/.well-known/jmap", fqdn),
+                    ],
+                    Some(Error::NoSrvRecord { address }),
+                )
+            }
+        };
 
-        // Try all SRV names in order of priority.
         let mut last_err = None;
-        for name in resolver_response
-            .into_iter()
-            .sorted_by_key(|x| x.priority())
-        {
-            let mut target = name.target().to_utf8();
-            // Remove the final ".".
-            assert!(target.ends_with("."));
-            target.pop();
-
-            let url = format!("https://{}:{}/.well-known/jmap", target, name.port());
-            match Self::open_url(url.as_str(), username, password, timeout) {
-                Ok(s) => return Ok(s),
-                Err(e) => last_err = Some(e),
+        for url in urls {
+            match Self::open_url(url.as_str(), config, password, timeouts) {
+                Ok(s) => {
+                    cache.insert(fqdn, url);
+                    if let Err(e) = cache.save(&cache_path) {
+                        warn!(
+                            "Could not save session URL cache `{}': {}",
+                            cache_path.to_string_lossy(),
+                            e
+                        );
+                    }
+                    return Ok(s);
+                }
+                Err(e) => {
+                    debug!("could not open session at {url}: {e}");
+                    last_err = Some(e)
+                }
             };
         }
-        // All of them failed! Return the last error.
-        Err(last_err.unwrap())
+        // All of them failed! Return the last error, unless we were only guessing URLs and none of
+        // them led to a JMAP server, in which case the missing SRV record is the real problem.
+        let last_err = last_err.unwrap();
+        match srv_lookup_err {
+            Some(srv_lookup_err) if last_err.is_unreachable() || last_err.is_not_found() => {
+                Err(srv_lookup_err)
+            }
+            _ => Err(last_err),
+        }
     }
 
-    fn open_url(session_url: &str, username: &str, password: &str, timeout: u64) -> Result<Self> {
-        let agent = ureq::AgentBuilder::new()
-            .redirect_auth_headers(ureq::RedirectAuthHeaders::SameHost)
-            .timeout(Duration::from_secs(timeout))
-            .build();
+    fn open_url(
+        session_url: &str,
+        config: &Config,
+        password: &str,
+        timeouts: Timeouts,
+    ) -> Result<Self> {
+        let username = config.username.as_str();
+        let agent = timeouts.agent();
 
-        match agent.get(session_url).call() {
+        match agent.get(session_url).timeout(timeouts.api).call() {
             Ok(r) => {
                 // Server returned success without authentication. Surprising, but valid.
                 let session_url = r.get_url().to_string();
-                let session: jmap::Session = r.into_json().context(ResponseSnafu {})?;
+                let mut session: jmap::Session = r.into_json().context(ResponseSnafu {})?;
+                override_limits(&mut session, &config.compatibility);
                 Ok(Self {
-                    http_wrapper: HttpWrapper::new(None, timeout),
+                    http_wrapper: HttpWrapper::new(None, None, agent, timeouts),
                     session_url,
+                    account_id: session.primary_accounts.mail.clone(),
+                    submission_account_id: session
+                        .primary_accounts
+                        .submission
+                        .clone()
+                        .unwrap_or_else(|| session.primary_accounts.mail.clone()),
+                    chunk_sizes: ChunkSizes::new(&session.capabilities.core),
+                    download_url: Arc::new(RwLock::new(session.download_url.clone())),
+                    compatibility: config.compatibility.clone(),
                     session,
                 })
             }
 
             Err(ureq::Error::Status(code, ref r)) if code == 401 => {
-                fn encode_basic(username: &str, password: &str) -> String {
-                    let safe_username = match username.find(':') {
-                        Some(idx) => &username[..idx],
-                        None => username,
-                    };
-                    format!(
-                        "Basic {}",
-                        base64::encode(format!("{}:{}", safe_username, password))
-                    )
-                }
-
-                let authorization = match r.header("WWW-Authenticate") {
+                let scheme = match r.header("WWW-Authenticate") {
                     Some(v) if v.starts_with("Basic") => {
                         debug!("server offered Basic auth");
-                        Some(encode_basic(username, password))
+                        Some(AuthScheme::Basic)
                     }
 
                     Some(v) if v.starts_with("Bearer") => {
                         debug!("server offered Bearer auth");
-                        Some(format!("Bearer {}", password))
+                        Some(AuthScheme::Bearer)
                     }
 
                     // Server didn't offer any auth schemes but still requires authentication.
                     // Probably it will accept Basic; try that.
                     None => {
                         debug!("server requires auth but didn't offer a scheme, assuming Basic");
-                        Some(encode_basic(username, password))
+                        Some(AuthScheme::Basic)
                     }
 
                     // No authorization, which will make the next call fail, and then we'll just
@@ -305,18 +1154,35 @@ impl Remote {
                     }
                 };
 
+                let authorization = scheme.map(|scheme| scheme.authorization(username, password));
+                let reauth = scheme.map(|scheme| Reauth {
+                    scheme,
+                    username: username.to_string(),
+                    password_command: config.password_command.clone(),
+                });
+
                 let url = r.get_url();
 
-                let mut req = agent.get(url);
+                let mut req = agent.get(url).timeout(timeouts.api);
                 if let Some(a) = &authorization {
                     req = req.set("Authorization", a);
                 }
 
                 let r = req.call().context(OpenSessionSnafu { session_url })?;
-                let session: jmap::Session = r.into_json().context(ResponseSnafu {})?;
+                let mut session: jmap::Session = r.into_json().context(ResponseSnafu {})?;
+                override_limits(&mut session, &config.compatibility);
                 Ok(Self {
-                    http_wrapper: HttpWrapper::new(authorization, timeout),
+                    http_wrapper: HttpWrapper::new(authorization, reauth, agent, timeouts),
                     session_url: url.to_string(),
+                    account_id: session.primary_accounts.mail.clone(),
+                    submission_account_id: session
+                        .primary_accounts
+                        .submission
+                        .clone()
+                        .unwrap_or_else(|| session.primary_accounts.mail.clone()),
+                    chunk_sizes: ChunkSizes::new(&session.capabilities.core),
+                    download_url: Arc::new(RwLock::new(session.download_url.clone())),
+                    compatibility: config.compatibility.clone(),
                     session,
                 })
             }
@@ -332,13 +1198,61 @@ impl Remote {
     /// in-between the call to `Email/query` and future calls to `Email/changes`. If done in the
     /// opposite order, an `Email` might slip through the cracks.
     pub fn all_email_ids(&mut self) -> Result<(State, HashSet<Id>)> {
+        self.query_email_ids(None)
+    }
+
+    /// Like `all_email_ids`, but only return the IDs of `Email`s received at or after the given
+    /// RFC 3339 `UTCDate`.
+    pub fn email_ids_received_after(&mut self, after: &str) -> Result<(State, HashSet<Id>)> {
+        self.query_email_ids(Some(&json!({ "after": after })))
+    }
+
+    /// Return true if an `Email` with the given `Message-ID` has already been sent or is about to
+    /// be, i.e. if one exists which isn't a draft, or which is a draft with an `EmailSubmission`
+    /// which wasn't canceled, e.g. because its sending is delayed.
+    ///
+    /// This makes it safe to retry sending an email whose submission appeared to fail, e.g. because
+    /// the connection dropped before the server's response arrived. Only emails destroyed once
+    /// sent, because no sent copy is kept, can't be found.
+    pub fn is_sent(&mut self, message_id: &str) -> Result<bool> {
+        let (_, ids) = self.query_email_ids(Some(&json!({
+            "header": ["Message-ID", message_id],
+            "notKeyword": "$draft",
+        })))?;
+        if !ids.is_empty() {
+            return Ok(true);
+        }
+
+        let (_, draft_ids) = self.query_email_ids(Some(&json!({
+            "header": ["Message-ID", message_id],
+            "hasKeyword": "$draft",
+        })))?;
+        if draft_ids.is_empty() {
+            return Ok(false);
+        }
+        let submission_ids = self.query_email_submission_ids(&json!({ "emailIds": draft_ids }))?;
+        let submissions = self.get_email_submissions(&submission_ids.iter().collect::<Vec<_>>())?;
+        Ok(submissions
+            .iter()
+            .any(|submission| submission.undo_status != jmap::UndoStatus::Canceled))
+    }
+
+    /// Return the IDs of all `Email`s with the given `Message-ID`.
+    pub fn email_ids_by_message_id(&mut self, message_id: &str) -> Result<HashSet<Id>> {
+        let (_, ids) = self.query_email_ids(Some(&json!({
+            "header": ["Message-ID", message_id],
+        })))?;
+        Ok(ids)
+    }
+
+    fn query_email_ids(&mut self, filter: Option<&Value>) -> Result<(State, HashSet<Id>)> {
         const GET_METHOD_ID: &str = "0";
         const QUERY_METHOD_ID: &str = "1";
 
-        let account_id = &self.session.primary_accounts.mail;
-        let mut response = self.request(jmap::Request {
-            using: &[jmap::CapabilityKind::Mail],
-            method_calls: &[
+        let account_id = &self.account_id;
+        let mut response = self.request_batched(
+            &[jmap::CapabilityKind::Mail],
+            &[
                 jmap::RequestInvocation {
                     call: jmap::MethodCall::EmailGet {
                         get: jmap::MethodCallGet {
@@ -353,6 +1267,7 @@ impl Remote {
                     call: jmap::MethodCall::EmailQuery {
                         query: jmap::MethodCallQuery {
                             account_id,
+                            filter,
                             position: 0,
                             anchor: None,
                             anchor_offset: 0,
@@ -363,8 +1278,7 @@ impl Remote {
                     id: QUERY_METHOD_ID,
                 },
             ],
-            created_ids: None,
-        })?;
+        )?;
         self.update_session_state(&response.session_state)?;
 
         if response.method_responses.len() != 2 {
@@ -376,10 +1290,23 @@ impl Remote {
 
         let get_response = expect_email_get(GET_METHOD_ID, response.method_responses.remove(0))?;
 
-        // If the server doesn't impose a limit, we're done.
-        let limit = match query_response.limit {
+        let email_ids = self.query_remaining_email_ids(filter, query_response)?;
+        Ok((get_response.state, email_ids))
+    }
+
+    /// Given the first page of results of an `Email/query` with `filter`, return the IDs of all
+    /// `Email`s matching it, querying the remaining pages if the server imposed a limit.
+    fn query_remaining_email_ids(
+        &mut self,
+        filter: Option<&Value>,
+        query_response: jmap::MethodResponseQuery,
+    ) -> Result<HashSet<Id>> {
+        const QUERY_METHOD_ID: &str = "0";
+
+        // If the server doesn't impose a limit, we're done.
+        let limit = match query_response.limit {
             Some(limit) => limit,
-            None => return Ok((get_response.state, query_response.ids.into_iter().collect())),
+            None => return Ok(query_response.ids.into_iter().collect()),
         };
 
         // Nonsense!
@@ -389,24 +1316,36 @@ impl Remote {
 
         // No need to continue processing if we have received fewer than the limit imposed.
         if (query_response.ids.len() as u64) < limit {
-            return Ok((get_response.state, query_response.ids.into_iter().collect()));
+            return Ok(query_response.ids.into_iter().collect());
         }
 
         // If the server imposed a limit on our query, we must continue to make requests until we
-        // have collected all of the IDs.
-        let mut email_ids = query_response.ids;
+        // have collected all of the IDs. Each page is added to the set as it arrives rather than
+        // accumulated separately, since there may be a great many IDs.
+        let mut email_ids: HashSet<Id> = query_response.ids.iter().cloned().collect();
+        let mut page = query_response.ids;
 
         loop {
-            let account_id = &self.session.primary_accounts.mail;
+            // Each page is anchored on the last ID of the page before. Should that email be
+            // destroyed in the meantime, the one before it is used instead, and once none of the
+            // page is left, the query continues from the number of IDs collected so far.
+            let anchor = page.last();
+            let position = if anchor.is_some() {
+                0
+            } else {
+                email_ids.len() as i64
+            };
+            let account_id = &self.account_id;
             let mut response = self.request(jmap::Request {
                 using: &[jmap::CapabilityKind::Mail],
                 method_calls: &[jmap::RequestInvocation {
                     call: jmap::MethodCall::EmailQuery {
                         query: jmap::MethodCallQuery {
                             account_id,
-                            anchor: Some(&email_ids.last().unwrap()),
-                            anchor_offset: 1,
-                            position: 0,
+                            filter,
+                            anchor,
+                            anchor_offset: if anchor.is_some() { 1 } else { 0 },
+                            position,
                             limit: None,
                             calculate_total: false,
                         },
@@ -421,8 +1360,18 @@ impl Remote {
                 return Err(Error::UnexpectedResponse);
             }
 
-            let mut query_response =
-                expect_email_query(QUERY_METHOD_ID, response.method_responses.remove(0))?;
+            let query_response =
+                match expect_email_query(QUERY_METHOD_ID, response.method_responses.remove(0)) {
+                    Ok(query_response) => query_response,
+                    Err(Error::MethodError {
+                        error: jmap::MethodResponseError::AnchorNotFound,
+                    }) if !page.is_empty() => {
+                        debug!("Email/query anchor was destroyed, re-anchoring");
+                        page.pop();
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
 
             // We're done if we don't get any more IDs.
             if query_response.ids.is_empty() {
@@ -430,7 +1379,8 @@ impl Remote {
             }
 
             let len = query_response.ids.len();
-            email_ids.append(&mut query_response.ids);
+            email_ids.extend(query_response.ids.iter().cloned());
+            page = query_response.ids;
 
             let limit = match query_response.limit {
                 Some(limit) => limit,
@@ -448,14 +1398,16 @@ impl Remote {
                 break;
             }
         }
-        Ok((get_response.state, email_ids.into_iter().collect()))
+        Ok(email_ids)
     }
 
     /// Given an `Email/get` state, return the latest `Email/get` state and a list of new/updated
-    /// `Email` IDs and destroyed `Email` IDs.
+    /// `Email` IDs and destroyed `Email` IDs. At most `max_changes` changes are asked for per
+    /// request.
     pub fn changed_email_ids(
         &mut self,
         state: State,
+        max_changes: u64,
     ) -> Result<(State, HashSet<Id>, HashSet<Id>, HashSet<Id>)> {
         const CHANGES_METHOD_ID: &str = "0";
 
@@ -466,7 +1418,7 @@ impl Remote {
         let mut destroyed_ids = HashSet::new();
 
         loop {
-            let account_id = &self.session.primary_accounts.mail;
+            let account_id = &self.account_id;
             let mut response = self.request(jmap::Request {
                 using: &[jmap::CapabilityKind::Mail],
                 method_calls: &[jmap::RequestInvocation {
@@ -474,7 +1426,7 @@ impl Remote {
                         changes: jmap::MethodCallChanges {
                             account_id,
                             since_state: &state,
-                            max_changes: None,
+                            max_changes: Some(max_changes),
                         },
                     },
                     id: CHANGES_METHOD_ID,
@@ -513,53 +1465,118 @@ impl Remote {
         mailboxes: &Mailboxes,
         tags_config: &config::Tags,
     ) -> Result<HashMap<Id, Email>> {
-        const GET_METHOD_ID: &str = "0";
-
-        let chunk_size = self.session.capabilities.core.max_objects_in_get as usize;
-
-        let mut emails: HashMap<Id, Email> = HashMap::new();
-
-        for chunk in &email_ids.into_iter().chunks(chunk_size) {
-            let account_id = &self.session.primary_accounts.mail;
-            let ids = chunk.collect::<Vec<&Id>>();
-            let mut response = self.request(jmap::Request {
-                using: &[jmap::CapabilityKind::Mail],
-                method_calls: &[jmap::RequestInvocation {
-                    call: jmap::MethodCall::EmailGet {
-                        get: jmap::MethodCallGet {
-                            account_id,
-                            ids: Some(&ids),
-                            properties: Some(&["id", "blobId", "keywords", "mailboxIds"]),
-                        },
-                    },
-                    id: GET_METHOD_ID,
-                }],
-                created_ids: None,
-            })?;
-            self.update_session_state(&response.session_state)?;
+        let mut emails = HashMap::new();
+        for chunk in self.get_emails_chunked(email_ids, mailboxes, tags_config) {
+            emails.extend(chunk?);
+        }
+        Ok(emails)
+    }
 
-            if response.method_responses.len() != 1 {
-                return Err(Error::UnexpectedResponse);
+    /// Like `get_emails`, but lazily yields the properties of each chunk of `Email`s as the server
+    /// returns them, so that they can be processed before the rest have been retrieved.
+    pub fn get_emails_chunked<'a, 'b: 'a>(
+        &'a mut self,
+        email_ids: impl Iterator<Item = &'b jmap::Id> + 'a,
+        mailboxes: &'a Mailboxes,
+        tags_config: &'a config::Tags,
+    ) -> impl Iterator<Item = Result<HashMap<Id, Email>>> + 'a {
+        let mut email_ids = email_ids.fuse();
+        iter::from_fn(move || {
+            let ids: Vec<&Id> = email_ids.by_ref().take(self.chunk_sizes.get).collect();
+            if ids.is_empty() {
+                return None;
             }
+            let mut emails = HashMap::new();
+            let result = self.for_each_chunk(&ids, ChunkKind::Get, |remote, chunk| {
+                emails.extend(remote.get_emails_chunk(chunk, mailboxes, tags_config)?);
+                Ok(())
+            });
+            Some(result.map(|()| emails))
+        })
+    }
 
-            let get_response =
-                expect_email_get(GET_METHOD_ID, response.method_responses.remove(0))?;
+    /// Return the `EmailSummary` of each of the given `Email`s, for servers which can't calculate
+    /// changes themselves. Only the properties needed to tell whether an `Email` changed are
+    /// retrieved.
+    pub fn get_email_summaries(&mut self, ids: &HashSet<Id>) -> Result<HashMap<Id, EmailSummary>> {
+        let ids: Vec<&Id> = ids.iter().collect();
+        let mut summaries = HashMap::new();
+        self.for_each_chunk(&ids, ChunkKind::Get, |remote, chunk| {
+            let emails =
+                remote.get_jmap_emails(chunk, &["id", "blobId", "keywords", "mailboxIds"])?;
+            summaries.extend(
+                emails
+                    .iter()
+                    .map(|email| (email.id.clone(), EmailSummary::from_jmap_email(email))),
+            );
+            Ok(())
+        })?;
+        Ok(summaries)
+    }
 
-            for email in get_response.list {
-                emails.insert(
+    fn get_emails_chunk(
+        &mut self,
+        ids: &[&Id],
+        mailboxes: &Mailboxes,
+        tags_config: &config::Tags,
+    ) -> Result<HashMap<Id, Email>> {
+        Ok(self
+            .get_jmap_emails(
+                ids,
+                &[
+                    "id",
+                    "blobId",
+                    "keywords",
+                    "mailboxIds",
+                    "receivedAt",
+                    "messageId",
+                    "size",
+                ],
+            )?
+            .into_iter()
+            .map(|email| {
+                (
                     email.id.clone(),
                     Email::from_jmap_email(email, mailboxes, tags_config),
-                );
-            }
+                )
+            })
+            .collect())
+    }
+
+    /// Return the given properties of the given `Email`s in a single `Email/get` request.
+    fn get_jmap_emails(&mut self, ids: &[&Id], properties: &[&str]) -> Result<Vec<jmap::Email>> {
+        const GET_METHOD_ID: &str = "0";
+
+        let account_id = &self.account_id;
+        let mut response = self.request(jmap::Request {
+            using: &[jmap::CapabilityKind::Mail],
+            method_calls: &[jmap::RequestInvocation {
+                call: jmap::MethodCall::EmailGet {
+                    get: jmap::MethodCallGet {
+                        account_id,
+                        ids: Some(ids),
+                        properties: Some(properties),
+                    },
+                },
+                id: GET_METHOD_ID,
+            }],
+            created_ids: None,
+        })?;
+        self.update_session_state(&response.session_state)?;
+
+        if response.method_responses.len() != 1 {
+            return Err(Error::UnexpectedResponse);
         }
-        Ok(emails)
+
+        let get_response = expect_email_get(GET_METHOD_ID, response.method_responses.remove(0))?;
+        Ok(get_response.list)
     }
 
-    /// Return the `Mailboxes` of the server.
-    pub fn get_mailboxes<'a>(&mut self, tags_config: &config::Tags) -> Result<Mailboxes> {
+    /// Return the raw `jmap::Mailbox` objects of the server, keyed by their IDs.
+    pub fn get_jmap_mailboxes(&mut self) -> Result<HashMap<Id, jmap::Mailbox>> {
         const GET_METHOD_ID: &str = "0";
 
-        let account_id = &self.session.primary_accounts.mail;
+        let account_id = &self.account_id;
         let mut response = self.request(jmap::Request {
             using: &[jmap::CapabilityKind::Mail],
             method_calls: &[jmap::RequestInvocation {
@@ -567,7 +1584,7 @@ impl Remote {
                     get: jmap::MethodCallGet {
                         account_id,
                         ids: None,
-                        properties: Some(&["id", "parentId", "name", "role"]),
+                        properties: Some(MAILBOX_PROPERTIES),
                     },
                 },
                 id: GET_METHOD_ID,
@@ -582,21 +1599,213 @@ impl Remote {
 
         let get_response = expect_mailbox_get(GET_METHOD_ID, response.method_responses.remove(0))?;
 
-        // Reinterpret the mailbox data.
-        let jmap_mailboxes: HashMap<jmap::Id, jmap::Mailbox> = get_response
+        Ok(get_response
             .list
             .into_iter()
             .map(|x| (x.id.clone(), x))
-            .collect();
+            .collect())
+    }
+
+    /// Return the `Mailboxes` of the server.
+    pub fn get_mailboxes(&mut self, config: &Config) -> Result<Mailboxes> {
+        let jmap_mailboxes = self.get_jmap_mailboxes()?;
+        Self::mailboxes_from_jmap(jmap_mailboxes, config)
+    }
+
+    /// Return the `Mailboxes` of the server along with the answer to `query`, asking for both in a
+    /// single request, so that a sync needs one round trip less before it can get to work.
+    ///
+    /// Failing to answer `query`, e.g. because the server can no longer calculate the changes since
+    /// the given state, only fails the inner result.
+    pub fn get_mailboxes_and_email_ids(
+        &mut self,
+        config: &Config,
+        query: EmailIdsQuery,
+    ) -> Result<(Mailboxes, Result<EmailIds>)> {
+        const MAILBOX_GET_METHOD_ID: &str = "0";
+        const CHANGES_METHOD_ID: &str = "1";
+        const GET_METHOD_ID: &str = "1";
+        const QUERY_METHOD_ID: &str = "2";
+
+        let account_id = &self.account_id;
+        let mut method_calls = vec![jmap::RequestInvocation {
+            call: jmap::MethodCall::MailboxGet {
+                get: jmap::MethodCallGet {
+                    account_id,
+                    ids: None,
+                    properties: Some(MAILBOX_PROPERTIES),
+                },
+            },
+            id: MAILBOX_GET_METHOD_ID,
+        }];
+        match query {
+            EmailIdsQuery::Changes(state, max_changes) => {
+                method_calls.push(jmap::RequestInvocation {
+                    call: jmap::MethodCall::EmailChanges {
+                        changes: jmap::MethodCallChanges {
+                            account_id,
+                            since_state: state,
+                            max_changes: Some(max_changes),
+                        },
+                    },
+                    id: CHANGES_METHOD_ID,
+                })
+            }
+            // As in `query_email_ids`, `Email/get` comes before `Email/query`.
+            EmailIdsQuery::Query(filter) => method_calls.extend([
+                jmap::RequestInvocation {
+                    call: jmap::MethodCall::EmailGet {
+                        get: jmap::MethodCallGet {
+                            account_id,
+                            ids: Some(&[]),
+                            properties: Some(&[]),
+                        },
+                    },
+                    id: GET_METHOD_ID,
+                },
+                jmap::RequestInvocation {
+                    call: jmap::MethodCall::EmailQuery {
+                        query: jmap::MethodCallQuery {
+                            account_id,
+                            filter,
+                            position: 0,
+                            anchor: None,
+                            anchor_offset: 0,
+                            limit: None,
+                            calculate_total: false,
+                        },
+                    },
+                    id: QUERY_METHOD_ID,
+                },
+            ]),
+        }
+        let response = self.request_batched(&[jmap::CapabilityKind::Mail], &method_calls)?;
+        let method_call_count = method_calls.len();
+        self.update_session_state(&response.session_state)?;
+
+        if response.method_responses.len() != method_call_count {
+            return Err(Error::UnexpectedResponse);
+        }
+        let mut method_responses = response.method_responses.into_iter();
+
+        let get_response = expect_mailbox_get(
+            MAILBOX_GET_METHOD_ID,
+            method_responses.next().expect("response length checked"),
+        )?;
+        let mailboxes = Self::mailboxes_from_jmap(
+            get_response
+                .list
+                .into_iter()
+                .map(|x| (x.id.clone(), x))
+                .collect(),
+            config,
+        )?;
+
+        let email_ids = match query {
+            EmailIdsQuery::Changes(..) => expect_email_changes(
+                CHANGES_METHOD_ID,
+                method_responses.next().expect("response length checked"),
+            )
+            .map(|changes_response| {
+                let created = changes_response.created.into_iter().collect::<HashSet<_>>();
+                let mut updated = changes_response.updated.into_iter().collect::<HashSet<_>>();
+                updated.retain(|x| !created.contains(x));
+                EmailIds::Changes {
+                    state: changes_response.new_state,
+                    created,
+                    updated,
+                    destroyed: changes_response.destroyed.into_iter().collect(),
+                    has_more_changes: changes_response.has_more_changes,
+                }
+            }),
+            EmailIdsQuery::Query(filter) => {
+                let get_response = method_responses.next().expect("response length checked");
+                let query_response = method_responses.next().expect("response length checked");
+                expect_email_get(GET_METHOD_ID, get_response).and_then(|get_response| {
+                    let query_response = expect_email_query(QUERY_METHOD_ID, query_response)?;
+                    let ids = self.query_remaining_email_ids(filter, query_response)?;
+                    Ok(EmailIds::Query {
+                        state: get_response.state,
+                        ids,
+                    })
+                })
+            }
+        };
+        Ok((mailboxes, email_ids))
+    }
+
+    /// Convert the raw `jmap::Mailbox` objects of the server into `Mailboxes`. A missing archive
+    /// mailbox is left for `create_archive_mailbox` if `auto_create_archive_mailbox` is set.
+    fn mailboxes_from_jmap(
+        mut jmap_mailboxes: HashMap<Id, jmap::Mailbox>,
+        config: &Config,
+    ) -> Result<Mailboxes> {
+        let tags_config = &config.tags;
+
+        // Apply user overrides for mailboxes which the server did not assign the right role to,
+        // e.g. localized mailboxes. An overridden role is taken away from any other mailbox.
+        for (role, name) in [
+            (MailboxRole::Archive, &config.roles.archive),
+            (MailboxRole::Drafts, &config.roles.drafts),
+            (MailboxRole::Flagged, &config.roles.flagged),
+            (MailboxRole::Important, &config.roles.important),
+            (MailboxRole::Inbox, &config.roles.inbox),
+            (MailboxRole::Junk, &config.roles.junk),
+            (MailboxRole::Sent, &config.roles.sent),
+            (MailboxRole::Trash, &config.roles.trash),
+        ] {
+            if let Some(name) = name {
+                let id = find_mailbox_id(&jmap_mailboxes, name, &tags_config.directory_separator)
+                    .context(NoRoleMailboxSnafu {
+                    role: format!("{role:?}").to_lowercase(),
+                    mailbox: name,
+                })?;
+                for mailbox in jmap_mailboxes.values_mut() {
+                    if mailbox.role == Some(role) {
+                        mailbox.role = None;
+                    }
+                }
+                jmap_mailboxes.get_mut(&id).unwrap().role = Some(role);
+            }
+        }
 
         // The archive is special. All email must belong to at least one mailbox, so if an email has
         // no notmuch tags which correspond to other mailboxes, it must be added to the archive.
-        let archive_id = jmap_mailboxes
-            .values()
-            .filter(|x| x.role == Some(MailboxRole::Archive))
-            .map(|x| x.id.clone())
-            .next()
-            .ok_or(Error::NoArchive {})?;
+        let archive_id = match &config.archive_mailbox {
+            Some(archive_mailbox) => find_mailbox_id(
+                &jmap_mailboxes,
+                archive_mailbox,
+                &tags_config.directory_separator,
+            )
+            .map(Some)
+            .context(NoConfiguredArchiveSnafu {
+                mailbox: archive_mailbox,
+            })?,
+            None => match jmap_mailboxes
+                .values()
+                .find(|x| x.role == Some(MailboxRole::Archive))
+            {
+                Some(mailbox) => Some(mailbox.id.clone()),
+                None if config.auto_create_archive_mailbox => None,
+                None => return Err(Error::NoArchive {}),
+            },
+        };
+
+        let configured_sent_id = config
+            .sending
+            .sent_mailbox
+            .as_ref()
+            .map(|sent_mailbox| {
+                find_mailbox_id(
+                    &jmap_mailboxes,
+                    sent_mailbox,
+                    &tags_config.directory_separator,
+                )
+                .context(NoConfiguredSentMailboxSnafu {
+                    mailbox: sent_mailbox,
+                })
+            })
+            .transpose()?;
 
         // Collect the list of available special mailboxes.
         let mut roles: AvailableMailboxRoles = Default::default();
@@ -628,18 +1837,20 @@ impl Remote {
             None => false,
         };
 
-        let lowercase_names: HashMap<&Id, String> = jmap_mailboxes
+        let names: HashMap<&Id, String> = jmap_mailboxes
             .iter()
-            .map(|(id, mailbox)| (id, mailbox.name.to_lowercase()))
+            .map(|(id, mailbox)| (id, mailbox_tag_name(&mailbox.name, tags_config)))
             .collect();
 
-        // Gather the mailbox objects.
-        let mailboxes_by_id: HashMap<Id, Mailbox> = jmap_mailboxes
+        // Gather the mailbox objects, along with their path tags.
+        let mut gathered: Vec<(Id, Mailbox, String)> = jmap_mailboxes
             .values()
             .map(|jmap_mailbox| {
                 if jmap_mailbox.role == Some(MailboxRole::All)
                     || jmap_mailbox.role == Some(MailboxRole::Archive)
+                    || archive_id.as_ref() == Some(&jmap_mailbox.id)
                     || should_ignore_mailbox_role(&jmap_mailbox.role)
+                    || (tags_config.only_subscribed && jmap_mailbox.is_subscribed == Some(false))
                 {
                     return Ok(None);
                 }
@@ -658,7 +1869,7 @@ impl Remote {
                     }
                     maybe_parent_id = &parent.parent_id;
                 }
-                let tag = path_ids
+                let path: Vec<&str> = path_ids
                     .into_iter()
                     .rev()
                     .map(|x| {
@@ -666,8 +1877,8 @@ impl Remote {
                         mailbox
                             .role
                             .map(|x| match x {
-                                MailboxRole::Drafts => Some("draft"),
-                                MailboxRole::Flagged => Some("flagged"),
+                                MailboxRole::Drafts => Some(tags_config.draft.as_str()),
+                                MailboxRole::Flagged => Some(tags_config.flagged.as_str()),
                                 MailboxRole::Important => Some(tags_config.important.as_str()),
                                 MailboxRole::Inbox => Some(tags_config.inbox.as_str()),
                                 MailboxRole::Junk => Some(tags_config.spam.as_str()),
@@ -676,55 +1887,146 @@ impl Remote {
                                 _ => None,
                             })
                             .flatten()
-                            .unwrap_or_else(|| {
-                                if tags_config.lowercase {
-                                    &lowercase_names[&x]
-                                } else {
-                                    &mailbox.name
-                                }
-                            })
+                            .unwrap_or_else(|| &names[&x])
                     })
-                    .join(&tags_config.directory_separator);
-                Ok(Some((
-                    jmap_mailbox.id.clone(),
-                    Mailbox::new(jmap_mailbox.id.clone(), tag),
-                )))
+                    .collect();
+                let path_tag = path.join(&tags_config.directory_separator);
+                let leaf_tag = path[path.len() - 1].to_string();
+                let (tag, alias) = match tags_config.hierarchy {
+                    config::Hierarchy::Path => (path_tag.clone(), None),
+                    config::Hierarchy::Leaf => (leaf_tag, None),
+                    config::Hierarchy::Both => {
+                        let alias = Some(leaf_tag).filter(|x| *x != path_tag);
+                        (path_tag.clone(), alias)
+                    }
+                };
+                let mut mailbox = Mailbox::new(jmap_mailbox.id.clone(), tag);
+                mailbox.alias = alias;
+                if let Some(rights) = &jmap_mailbox.my_rights {
+                    mailbox.may_add_items = rights.may_add_items;
+                    mailbox.may_remove_items = rights.may_remove_items;
+                }
+                Ok(Some((jmap_mailbox.id.clone(), mailbox, path_tag)))
             })
             .collect::<Result<Vec<_>>>()?
             .into_iter()
             .flatten()
-            .filter(|(_, mailbox)| {
-                // Filter out mailboxes with the same tag as automatic tags and warn the user that
-                // they shouldn't do this.
+            .collect();
+
+        // Leaf tags can't tell apart mailboxes with the same name in different places, e.g.
+        // `Lists/a/announce' and `Lists/b/announce', so those are tagged with their paths instead,
+        // and their shared leaf tag is only pulled, as with `hierarchy = "both"'.
+        if tags_config.hierarchy == config::Hierarchy::Leaf {
+            let leaf_counts = gathered
+                .iter()
+                .map(|(_, mailbox, _)| mailbox.tag.clone())
+                .counts();
+            for (_, mailbox, path_tag) in &mut gathered {
+                if leaf_counts[&mailbox.tag] > 1 && mailbox.tag != *path_tag {
+                    debug!(
+                        "Tagging mail in mailbox `{}' with its path, since `{}' is not unique",
+                        path_tag, mailbox.tag
+                    );
+                    mailbox.alias = Some(std::mem::replace(&mut mailbox.tag, path_tag.clone()));
+                }
+            }
+        }
+
+        let mut mailboxes_by_id: HashMap<Id, Mailbox> = gathered
+            .into_iter()
+            .filter_map(|(id, mut mailbox, _)| {
+                // mujmap must not touch automatic tags, so mailboxes with the same tag as one get
+                // a prefixed tag instead, or are ignored if there is no prefix.
                 if local::AUTOMATIC_TAGS.contains(mailbox.tag.as_str()) {
+                    if tags_config.automatic_tag_prefix.is_empty() {
+                        warn!(
+                            concat!(
+                                "The JMAP server contains a mailbox `{}' which has the same name",
+                                " as an automatic tag. This mailbox will be ignored."
+                            ),
+                            mailbox.tag
+                        );
+                        return None;
+                    }
+                    let tag = format!("{}{}", tags_config.automatic_tag_prefix, mailbox.tag);
                     warn!(
                         concat!(
                             "The JMAP server contains a mailbox `{}' which has the same name",
-                            " as an automatic tag. This mailbox will be ignored."
+                            " as an automatic tag. Its mail will be tagged `{}' instead."
                         ),
-                        mailbox.tag
+                        mailbox.tag, tag
                     );
-                    false
-                } else {
-                    true
+                    mailbox.tag = tag;
                 }
+                Some((id, mailbox))
             })
             .collect();
-        let ids_by_tag: HashMap<_, _> = mailboxes_by_id
+        // Mailboxes whose names differ only in case or in replaced text would otherwise silently
+        // share a tag.
+        let mut ids_by_tag: HashMap<String, Id> = HashMap::new();
+        for (id, mailbox) in mailboxes_by_id
             .iter()
-            .map(|(id, mailbox)| (mailbox.tag.clone(), id.clone()))
+            .sorted_by_key(|(id, _)| id.0.as_str())
+        {
+            if let Some(other_id) = ids_by_tag.insert(mailbox.tag.clone(), id.clone()) {
+                let path = |id| mailbox_path(&jmap_mailboxes, id, "/").unwrap_or_default();
+                return Err(Error::MailboxTagCollision {
+                    tag: mailbox.tag.clone(),
+                    first: path(&other_id),
+                    second: path(id),
+                });
+            }
+        }
+
+        // Pushing a leaf tag which is also the tag of another mailbox or of a keyword would add mail
+        // to that mailbox or set that keyword, so such leaf tags are left out. Leaf tags shared by
+        // several mailboxes are fine, since they're never pushed.
+        let keyword_tags = [
+            tags_config.draft.as_str(),
+            &tags_config.flagged,
+            &tags_config.replied,
+            &tags_config.passed,
+            &tags_config.unread,
+            &tags_config.spam,
+            &tags_config.important,
+            &tags_config.phishing,
+        ];
+        for mailbox in mailboxes_by_id.values_mut() {
+            if let Some(alias) = &mailbox.alias {
+                if ids_by_tag.contains_key(alias)
+                    || keyword_tags.contains(&alias.as_str())
+                    || local::AUTOMATIC_TAGS.contains(alias.as_str())
+                {
+                    warn!(
+                        "Not tagging mail in mailbox `{}' with `{}' as well, since that tag is taken",
+                        mailbox.tag, alias
+                    );
+                    mailbox.alias = None;
+                }
+            }
+        }
+        let alias_tags = mailboxes_by_id
+            .values()
+            .filter_map(|mailbox| mailbox.alias.clone())
             .collect();
 
         let ignored_ids = jmap_mailboxes
             .values()
-            .filter(|x| archive_id != x.id && !mailboxes_by_id.contains_key(&x.id))
+            .filter(|x| archive_id.as_ref() != Some(&x.id) && !mailboxes_by_id.contains_key(&x.id))
             .map(|x| x.id.clone())
             .collect();
 
+        let sent_id = configured_sent_id
+            .or_else(|| roles.sent.clone())
+            .or_else(|| archive_id.clone());
+
         Ok(Mailboxes {
             archive_id,
+            unfiled_mail: config.unfiled_mail,
+            sent_id,
             mailboxes_by_id,
             ids_by_tag,
+            alias_tags,
             ignored_ids,
             roles,
         })
@@ -752,7 +2054,11 @@ impl Remote {
             created_ids_by_tag: &'a mut HashMap<String, Id>,
             create_calls: &'a mut Vec<(jmap::Id, jmap::MailboxCreate)>,
         ) -> Id {
-            let (parent_id, name) = match tag.rfind(&tags_config.directory_separator) {
+            // With leaf tags, a tag names a top-level mailbox even if it contains the separator.
+            let separator_index = tag
+                .rfind(&tags_config.directory_separator)
+                .filter(|_| tags_config.hierarchy != config::Hierarchy::Leaf);
+            let (parent_id, name) = match separator_index {
                 Some(index) => {
                     let parent_id = get_or_create_mailbox_id(
                         &tag[..index],
@@ -785,6 +2091,7 @@ impl Remote {
                 jmap::MailboxCreate {
                     parent_id,
                     name: name.to_owned(),
+                    role: None,
                 },
             ));
             created_tags_by_id.push(tag.to_string());
@@ -796,7 +2103,7 @@ impl Remote {
         // requests must be ordered from parent to child. One way to guarantee this in a
         // not-so-clever way is to sort them by the length of the tag.
         let (calls_len, response) = {
-            let account_id = &self.session.primary_accounts.mail;
+            let account_id = &self.account_id;
             for tag in tags.iter().sorted_unstable_by_key(|x| x.len()) {
                 get_or_create_mailbox_id(
                     &tag,
@@ -825,17 +2132,14 @@ impl Remote {
                                 update: None,
                                 destroy: None,
                             },
+                            on_destroy_remove_emails: false,
                         },
                         id: &id.0,
                     }
                 })
                 .collect();
 
-            let response = self.request(jmap::Request {
-                using: &[jmap::CapabilityKind::Mail],
-                method_calls: &method_calls,
-                created_ids: None,
-            })?;
+            let response = self.request_batched(&[jmap::CapabilityKind::Mail], &method_calls)?;
             (method_calls.len(), response)
         };
         self.update_session_state(&response.session_state)?;
@@ -861,11 +2165,141 @@ impl Remote {
         Ok(())
     }
 
+    /// Create a mailbox with the `Archive` role if the server has none, as allowed by
+    /// `auto_create_archive_mailbox`, and record it in `mailboxes`.
+    pub fn create_archive_mailbox(&mut self, mailboxes: &mut Mailboxes) -> Result<()> {
+        if mailboxes.archive_id.is_some() {
+            return Ok(());
+        }
+        warn!("JMAP server has no archive mailbox; creating one");
+        let id = self.create_mailbox(jmap::MailboxCreate {
+            parent_id: None,
+            name: "Archive".to_string(),
+            role: Some(MailboxRole::Archive),
+        })?;
+        mailboxes.sent_id.get_or_insert_with(|| id.clone());
+        mailboxes.archive_id = Some(id);
+        Ok(())
+    }
+
+    /// Create a single mailbox on the server and return its ID.
+    pub fn create_mailbox(&mut self, mailbox_create: jmap::MailboxCreate) -> Result<Id> {
+        const SET_METHOD_ID: &str = "0";
+        lazy_static! {
+            static ref MAILBOX_CLIENT_ID: jmap::Id = jmap::Id("0".into());
+        }
+
+        let account_id = &self.account_id;
+        let mut response = self.request(jmap::Request {
+            using: &[jmap::CapabilityKind::Mail],
+            method_calls: &[jmap::RequestInvocation {
+                call: jmap::MethodCall::MailboxSet {
+                    set: jmap::MethodCallSet {
+                        account_id,
+                        if_in_state: None,
+                        create: Some(HashMap::from([(&*MAILBOX_CLIENT_ID, &mailbox_create)])),
+                        update: None,
+                        destroy: None,
+                    },
+                    on_destroy_remove_emails: false,
+                },
+                id: SET_METHOD_ID,
+            }],
+            created_ids: None,
+        })?;
+        self.update_session_state(&response.session_state)?;
+
+        if response.method_responses.len() != 1 {
+            return Err(Error::UnexpectedResponse);
+        }
+
+        let set_response = expect_mailbox_set(SET_METHOD_ID, response.method_responses.remove(0))?;
+        map_first_method_error_into_result(set_response.not_created)
+            .context(CreateMailboxSnafu {})?;
+        set_response
+            .created
+            .and_then(|mut x| x.remove(&*MAILBOX_CLIENT_ID))
+            .map(|x| x.id)
+            .context(UnexpectedResponseSnafu {})
+    }
+
+    /// Destroy the given mailboxes on the server. Returns the mailboxes which could not be
+    /// destroyed, e.g. because they still have children.
+    pub fn destroy_mailboxes(
+        &mut self,
+        ids: &[&Id],
+        remove_emails: bool,
+    ) -> Result<HashMap<Id, jmap::MethodResponseError>> {
+        const SET_METHOD_ID: &str = "0";
+
+        let account_id = &self.account_id;
+        let mut response = self.request(jmap::Request {
+            using: &[jmap::CapabilityKind::Mail],
+            method_calls: &[jmap::RequestInvocation {
+                call: jmap::MethodCall::MailboxSet {
+                    set: jmap::MethodCallSet {
+                        account_id,
+                        if_in_state: None,
+                        create: None,
+                        update: None,
+                        destroy: Some(ids),
+                    },
+                    on_destroy_remove_emails: remove_emails,
+                },
+                id: SET_METHOD_ID,
+            }],
+            created_ids: None,
+        })?;
+        self.update_session_state(&response.session_state)?;
+
+        if response.method_responses.len() != 1 {
+            return Err(Error::UnexpectedResponse);
+        }
+
+        let set_response = expect_mailbox_set(SET_METHOD_ID, response.method_responses.remove(0))?;
+        Ok(set_response.not_destroyed.unwrap_or_default())
+    }
+
+    /// Apply the given patch to a mailbox on the server.
+    pub fn update_mailbox(&mut self, id: &Id, patch: HashMap<&str, Value>) -> Result<()> {
+        const SET_METHOD_ID: &str = "0";
+
+        let account_id = &self.account_id;
+        let mut response = self.request(jmap::Request {
+            using: &[jmap::CapabilityKind::Mail],
+            method_calls: &[jmap::RequestInvocation {
+                call: jmap::MethodCall::MailboxSet {
+                    set: jmap::MethodCallSet {
+                        account_id,
+                        if_in_state: None,
+                        create: None,
+                        update: Some(HashMap::from([(id, patch)])),
+                        destroy: None,
+                    },
+                    on_destroy_remove_emails: false,
+                },
+                id: SET_METHOD_ID,
+            }],
+            created_ids: None,
+        })?;
+        self.update_session_state(&response.session_state)?;
+
+        if response.method_responses.len() != 1 {
+            return Err(Error::UnexpectedResponse);
+        }
+
+        let set_response = expect_mailbox_set(SET_METHOD_ID, response.method_responses.remove(0))?;
+        map_first_method_error_into_result(set_response.not_updated)
+            .context(UpdateMailboxSnafu {})?;
+
+        Ok(())
+    }
+
     /// Return all `jmap::Identity` objects from the server.
     pub fn get_identities<'a>(&mut self) -> Result<Vec<jmap::Identity>> {
         const GET_METHOD_ID: &str = "0";
 
-        let account_id = &self.session.primary_accounts.mail;
+        let account_id = &self.submission_account_id;
         let mut response = self.request(jmap::Request {
             using: &[jmap::CapabilityKind::Submission],
             method_calls: &[jmap::RequestInvocation {
@@ -873,7 +2307,7 @@ impl Remote {
                     get: jmap::MethodCallGet {
                         account_id,
                         ids: None,
-                        properties: Some(&["id", "email"]),
+                        properties: Some(&["id", "email", "name", "replyTo", "mayDelete"]),
                     },
                 },
                 id: GET_METHOD_ID,
@@ -890,115 +2324,280 @@ impl Remote {
         Ok(get_response.list)
     }
 
-    pub fn read_email_blob(&self, id: &Id) -> Result<impl Read + Send> {
-        let uri = UriTemplate::new(self.session.download_url.as_str())
-            .set("accountId", self.session.primary_accounts.mail.0.as_str())
-            .set("blobId", id.0.as_str())
-            .set("type", "text/plain")
-            .set("name", id.0.as_str())
-            .build();
+    /// Return the quotas of the account, or none if the server doesn't report them.
+    pub fn get_quotas(&mut self) -> Result<Vec<jmap::Quota>> {
+        const GET_METHOD_ID: &str = "0";
+
+        let has_quotas = self
+            .session
+            .accounts
+            .get(&self.account_id)
+            .is_some_and(|account| account.account_capabilities.quota.is_some());
+        if !has_quotas {
+            return Ok(Vec::new());
+        }
+        let account_id = &self.account_id;
+        let mut response = self.request(jmap::Request {
+            using: &[jmap::CapabilityKind::Quota],
+            method_calls: &[jmap::RequestInvocation {
+                call: jmap::MethodCall::QuotaGet {
+                    get: jmap::MethodCallGet {
+                        account_id,
+                        ids: None,
+                        properties: Some(&[
+                            "id",
+                            "resourceType",
+                            "used",
+                            "hardLimit",
+                            "name",
+                            "types",
+                        ]),
+                    },
+                },
+                id: GET_METHOD_ID,
+            }],
+            created_ids: None,
+        })?;
+        self.update_session_state(&response.session_state)?;
 
-        self.http_wrapper.get_reader(uri.as_str())
+        if response.method_responses.len() != 1 {
+            return Err(Error::UnexpectedResponse);
+        }
+
+        let get_response = expect_quota_get(GET_METHOD_ID, response.method_responses.remove(0))?;
+        Ok(get_response.list)
+    }
+
+    /// Return a handle for downloading mail which can be used while this `Remote` is busy.
+    ///
+    /// Blobs larger than `max_email_size` bytes fail to download. If not given, the server's
+    /// `maxSizeUpload` is used, since the server is unlikely to store anything larger.
+    pub fn downloader(&self, max_email_size: Option<u64>) -> Downloader {
+        Downloader {
+            http_wrapper: self.http_wrapper.clone(),
+            download_url: self.download_url.clone(),
+            account_id: self.account_id.clone(),
+            max_size: max_email_size.unwrap_or(self.session.capabilities.core.max_size_upload),
+        }
     }
 
     /// Update all emails on the server with keywords and mailbox IDs corresponding to the local
-    /// notmuch tags.
+    /// notmuch tags, and return how to reverse each change along with the tags which could not be
+    /// pushed.
+    ///
+    /// `progress` is called with how many of the emails which change were updated so far and how
+    /// many there are, first before any are and then as they are.
     pub fn update(
         &mut self,
         local_emails: &HashMap<Id, local::Email>,
         mailboxes: &Mailboxes,
         tags_config: &config::Tags,
-    ) -> Result<()> {
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<Pushed> {
         // Get the latest remote email objects for the set of local emails so that we can determine
         // if we should include any ignored mailboxes in the patch.
         let remote_emails = self.get_emails(local_emails.keys(), mailboxes, tags_config)?;
 
         // Build patches.
+        let mut changes = Vec::new();
+        let mut refused = HashMap::new();
         let updates = local_emails
             .iter()
             .flat_map(|(id, local_email)| {
-                let mut patch = HashMap::new();
-                fn as_value(b: bool) -> Value {
-                    if b {
-                        Value::Bool(true)
-                    } else {
-                        Value::Null
-                    }
-                }
-
                 // The remote email may have been destroyed.
                 let remote_email = match remote_emails.get(id) {
                     Some(x) => x,
                     None => return None,
                 };
 
-                // Keywords.
-                patch.insert(
-                    "keywords/$draft",
-                    as_value(local_email.tags.contains("draft")),
-                );
-                patch.insert(
-                    "keywords/$seen",
-                    as_value(!local_email.tags.contains("unread")),
-                );
-                patch.insert(
-                    "keywords/$flagged",
-                    as_value(local_email.tags.contains("flagged")),
-                );
-                patch.insert(
-                    "keywords/$answered",
-                    as_value(local_email.tags.contains("replied")),
-                );
-                patch.insert(
-                    "keywords/$forwarded",
-                    as_value(local_email.tags.contains("passed")),
-                );
-                if mailboxes.roles.spam.is_none() && !tags_config.spam.is_empty() {
-                    let spam = local_email.tags.contains(&tags_config.spam);
-                    patch.insert("keywords/$junk", as_value(spam));
-                    patch.insert("keywords/$notjunk", as_value(!spam));
-                }
-                if !tags_config.phishing.is_empty() {
-                    patch.insert(
-                        "keywords/$phishing",
-                        as_value(local_email.tags.contains(&tags_config.phishing)),
-                    );
-                }
-                // Set mailboxes.
-                // TODO: eliminate clone here?
-                // Include all ignored mailboxes which the remote email is already included in.
-                let mut new_mailboxes: serde_json::Map<String, Value> = remote_email
-                    .mailbox_ids
-                    .iter()
-                    .filter(|x| mailboxes.ignored_ids.contains(x))
-                    .map(|x| (x.0.clone(), Value::Bool(true)))
+                let patch =
+                    match tags_patch(&local_email.tags, remote_email, mailboxes, tags_config) {
+                        Ok((patch, refused_tags)) => {
+                            if !refused_tags.is_empty() {
+                                refused.insert(
+                                    id.clone(),
+                                    refused_tags.into_iter().map(|x| x.to_owned()).collect(),
+                                );
+                            }
+                            patch
+                        }
+                        Err(e) => return Some(Err(e)),
+                    };
+
+                // The same properties, as they are now, for emails which actually change.
+                let undo_patch: HashMap<String, Value> = patch
+                    .keys()
+                    .map(|&key| {
+                        let value = match key.strip_prefix("keywords/") {
+                            Some(keyword) => as_value(remote_email.keywords.contains(keyword)),
+                            None => Value::Object(
+                                remote_email
+                                    .mailbox_ids
+                                    .iter()
+                                    .map(|x| (x.0.clone(), Value::Bool(true)))
+                                    .collect(),
+                            ),
+                        };
+                        (key.to_owned(), value)
+                    })
                     .collect();
-                // Include all mailboxes which correspond to notmuch tags.
-                new_mailboxes.extend(
-                    mailboxes
-                        .mailboxes_by_id
-                        .values()
-                        .filter(|x| local_email.tags.contains(&x.tag))
-                        .map(|x| (x.id.0.clone(), Value::Bool(true))),
-                );
-                // If no mailboxes were found, assign to Archive.
-                if new_mailboxes.is_empty() {
-                    new_mailboxes.insert(mailboxes.archive_id.0.clone(), Value::Bool(true));
+                if undo_patch
+                    .iter()
+                    .any(|(key, value)| patch.get(key.as_str()) != Some(value))
+                {
+                    changes.push(PushedChange {
+                        id: id.clone(),
+                        message_id: local_email.message_id.clone(),
+                        undo_patch,
+                        old_tags: remote_email
+                            .local_tags(mailboxes)
+                            .into_iter()
+                            .map(|tag| tag.to_owned())
+                            .collect(),
+                    });
                 }
-                patch.insert("mailboxIds", Value::Object(new_mailboxes));
                 Some(Ok((id, patch)))
             })
             .collect::<Result<HashMap<&Id, HashMap<&str, Value>>>>()?;
         debug!("Built patch for remote: {:?}", updates);
 
+        self.set_emails(updates, progress)?;
+        Ok(Pushed { changes, refused })
+    }
+
+    /// Add and remove notmuch tags on the given emails directly on the server, as pushing the same
+    /// change made in notmuch would. Return how many emails were found.
+    pub fn tag(
+        &mut self,
+        ids: &HashSet<Id>,
+        add: &[&str],
+        remove: &[&str],
+        mailboxes: &Mailboxes,
+        tags_config: &config::Tags,
+    ) -> Result<usize> {
+        let remote_emails = self.get_emails(ids.iter(), mailboxes, tags_config)?;
+        let updates = remote_emails
+            .values()
+            .map(|remote_email| -> Result<_> {
+                let mut tags: HashSet<String> = remote_email
+                    .local_tags(mailboxes)
+                    .into_iter()
+                    .filter(|tag| !remove.contains(tag))
+                    .map(|tag| tag.to_owned())
+                    .collect();
+                tags.extend(add.iter().map(|&tag| tag.to_owned()));
+                let (patch, _) = tags_patch(&tags, remote_email, mailboxes, tags_config)?;
+                Ok((&remote_email.id, patch))
+            })
+            .collect::<Result<_>>()?;
+        debug!("Built tag patch for remote: {:?}", updates);
+        self.set_emails(updates, &mut |_, _| {})?;
+        Ok(remote_emails.len())
+    }
+
+    /// Reverse changes previously made by `update`.
+    pub fn undo(&mut self, changes: &[PushedChange]) -> Result<()> {
+        let updates = changes
+            .iter()
+            .map(|change| {
+                let patch = change
+                    .undo_patch
+                    .iter()
+                    .map(|(key, value)| (key.as_str(), value.clone()))
+                    .collect();
+                (&change.id, patch)
+            })
+            .collect();
+        debug!("Built undo patch for remote: {:?}", updates);
+        self.set_emails(updates, &mut |_, _| {})
+    }
+
+    /// Apply patches to emails on the server.
+    ///
+    /// Some servers reject patches to the keywords of some emails as invalid, so those are sent
+    /// again with whole `keywords` objects instead.
+    fn set_emails(
+        &mut self,
+        updates: HashMap<&Id, HashMap<&str, Value>>,
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<()> {
+        let updates: Vec<_> = updates.into_iter().collect();
+        let mut done = 0;
+        progress(done, updates.len());
+        let not_updated = self.set_email_chunks(&updates, &mut |count| {
+            done += count;
+            progress(done, updates.len());
+        })?;
+        let (invalid_patch, not_updated): (HashMap<_, _>, HashMap<_, _>) = not_updated
+            .into_iter()
+            .partition(|(_, e)| matches!(e, jmap::MethodResponseError::InvalidPatch));
+        if !not_updated.is_empty() {
+            return Err(Error::UpdateEmail { not_updated });
+        }
+        if invalid_patch.is_empty() {
+            return Ok(());
+        }
+        debug!(
+            "Server rejected patches for {} emails, replacing their keywords instead",
+            invalid_patch.len()
+        );
+
+        let ids: Vec<&Id> = invalid_patch.keys().collect();
+        let mut current_keywords = HashMap::new();
+        self.for_each_chunk(&ids, ChunkKind::Get, |remote, chunk| {
+            let emails =
+                remote.get_jmap_emails(chunk, &["id", "blobId", "keywords", "mailboxIds"])?;
+            current_keywords.extend(emails.into_iter().map(|email| (email.id, email.keywords)));
+            Ok(())
+        })?;
+        let replacements: Vec<_> = updates
+            .into_iter()
+            .filter(|(id, _)| invalid_patch.contains_key(*id))
+            // The email may have been destroyed since.
+            .filter_map(|(id, patch)| {
+                let mut keywords: serde_json::Map<String, Value> = current_keywords
+                    .remove(id)?
+                    .into_iter()
+                    .filter(|(_, set)| *set)
+                    .map(|(keyword, _)| (keyword, Value::Bool(true)))
+                    .collect();
+                let mut replacement: HashMap<&str, Value> = HashMap::new();
+                for (key, value) in patch {
+                    match key.strip_prefix("keywords/") {
+                        Some(keyword) if value.is_null() => {
+                            keywords.remove(keyword);
+                        }
+                        Some(keyword) => {
+                            keywords.insert(keyword.to_owned(), value);
+                        }
+                        None => {
+                            replacement.insert(key, value);
+                        }
+                    }
+                }
+                replacement.insert("keywords", Value::Object(keywords));
+                Some((id, replacement))
+            })
+            .collect();
+        let not_updated = self.set_email_chunks(&replacements, &mut |_| {})?;
+        ensure!(not_updated.is_empty(), UpdateEmailSnafu { not_updated });
+        Ok(())
+    }
+
+    /// Apply patches to emails on the server, and return the errors for those which weren't
+    /// updated. `progress` is called with the number of patches in each chunk once it's applied.
+    fn set_email_chunks(
+        &mut self,
+        updates: &[(&Id, HashMap<&str, Value>)],
+        progress: &mut dyn FnMut(usize),
+    ) -> Result<HashMap<Id, jmap::MethodResponseError>> {
         // Send it off into cyberspace~
         const SET_METHOD_ID: &str = "0";
 
-        let chunk_size = self.session.capabilities.core.max_objects_in_set as usize;
-
-        for chunk in &updates.into_iter().chunks(chunk_size) {
-            let account_id = &self.session.primary_accounts.mail;
-            let mut response = self.request(jmap::Request {
+        let mut all_not_updated = HashMap::new();
+        self.for_each_chunk(updates, ChunkKind::Set, |remote, chunk| {
+            let account_id = &remote.account_id;
+            let mut response = remote.request(jmap::Request {
                 using: &[jmap::CapabilityKind::Mail],
                 method_calls: &[jmap::RequestInvocation {
                     call: jmap::MethodCall::EmailSet {
@@ -1006,7 +2605,7 @@ impl Remote {
                             account_id,
                             if_in_state: None,
                             create: None,
-                            update: Some(chunk.collect::<HashMap<_, _>>()),
+                            update: Some(chunk.iter().cloned().collect::<HashMap<_, _>>()),
                             destroy: None,
                         },
                     },
@@ -1014,7 +2613,7 @@ impl Remote {
                 }],
                 created_ids: None,
             })?;
-            self.update_session_state(&response.session_state)?;
+            remote.update_session_state(&response.session_state)?;
 
             if response.method_responses.len() != 1 {
                 return Err(Error::UnexpectedResponse);
@@ -1023,23 +2622,35 @@ impl Remote {
             let set_response =
                 expect_email_set(SET_METHOD_ID, response.method_responses.remove(0))?;
 
-            if let Some(not_updated) = set_response.not_updated {
-                return Err(Error::UpdateEmail { not_updated });
-            }
-        }
-
-        Ok(())
+            all_not_updated.extend(set_response.not_updated.unwrap_or_default());
+            progress(chunk.len());
+            Ok(())
+        })?;
+        Ok(all_not_updated)
     }
 
     /// Send an email with the given body.
+    ///
+    /// Unless `release` is `Release::Immediately`, the server holds the email until then, during
+    /// which time it may be canceled with `cancel_pending_submissions`. Emails scheduled for a
+    /// given time stay in the drafts mailbox until `file_released_emails` finds them sent.
+    ///
+    /// Once sent, the email is moved to the sent mailbox if `keep_sent_copy` is true and destroyed
+    /// otherwise, e.g. because the server files a copy of sent mail on its own.
+    ///
+    /// `dsn` is passed on to the server as is, so it must already be supported and valid.
+    #[allow(clippy::too_many_arguments)]
     pub fn send_email(
         &mut self,
         identity_id: jmap::Id,
         mailboxes: &Mailboxes,
         from_address: &str,
         to_addresses: &HashSet<String>,
-        email: &str,
-    ) -> Result<()> {
+        email: &[u8],
+        release: Release,
+        dsn: &Dsn,
+        keep_sent_copy: bool,
+    ) -> Result<SentEmail> {
         const IMPORT_EMAIL_METHOD_ID: &str = "0";
         const SET_EMAIL_SUBMISSION_METHOD_ID: &str = "1";
         lazy_static! {
@@ -1051,35 +2662,58 @@ impl Remote {
 
         let blob_id = self.upload_blob(email)?.blob_id;
 
-        let draft_mailbox_id = mailboxes
-            .roles
-            .draft
-            .as_ref()
-            .unwrap_or(&mailboxes.archive_id);
-        let sent_mailbox_id = mailboxes
-            .roles
-            .sent
-            .as_ref()
-            .unwrap_or(&mailboxes.archive_id);
+        let (draft_mailbox_id, sent_mailbox_id) = mailboxes.draft_and_sent_ids()?;
 
         let draft_mailbox_patch = format!("mailboxIds/{}", draft_mailbox_id.0);
         let sent_mailbox_patch = format!("mailboxIds/{}", sent_mailbox_id.0);
 
-        // TODO: Set $answered and $forwarded properties here?
-        let mut on_success_update_email = HashMap::from([("keywords/$draft", Value::Null)]);
-        if draft_mailbox_id != sent_mailbox_id {
-            on_success_update_email.insert(&draft_mailbox_patch, Value::Null);
-            on_success_update_email.insert(&sent_mailbox_patch, Value::Bool(true));
+        let mut on_success_update_email = HashMap::new();
+        let mut on_success_destroy_email = None;
+        if let Release::At(_) = release {
+            on_success_update_email.insert(
+                const_format::concatcp!("keywords/", SCHEDULED_KEYWORD),
+                Value::Bool(true),
+            );
+        } else if keep_sent_copy {
+            on_success_update_email.insert("keywords/$draft", Value::Null);
+            if draft_mailbox_id != sent_mailbox_id {
+                on_success_update_email.insert(&draft_mailbox_patch, Value::Null);
+                on_success_update_email.insert(&sent_mailbox_patch, Value::Bool(true));
+            }
+        } else {
+            on_success_destroy_email = Some([&*EMAIL_SUBMISSION_CLIENT_ID_REF]);
         }
 
-        let account_id = &self.session.primary_accounts.mail;
+        let account_id = &self.account_id;
         let rcpt_to: Vec<_> = to_addresses
             .iter()
-            .map(|x| jmap::Address { email: x.as_str() })
+            .map(|x| jmap::Address {
+                email: x.as_str(),
+                parameters: dsn
+                    .notify
+                    .as_ref()
+                    .map(|notify| HashMap::from([("NOTIFY", notify.clone())])),
+            })
             .collect();
-        let mut response = self.request(jmap::Request {
-            using: &[jmap::CapabilityKind::Mail, jmap::CapabilityKind::Submission],
-            method_calls: &[
+        // See RFC 4865 for the FUTURERELEASE extension and RFC 3461 for the DSN extension.
+        let mut mail_from_parameters = HashMap::new();
+        match release {
+            Release::Immediately => {}
+            Release::After(delay) => {
+                mail_from_parameters.insert("HOLDFOR", delay.to_string());
+            }
+            Release::At(time) => {
+                mail_from_parameters.insert("HOLDUNTIL", jmap::utc_date(time));
+            }
+        }
+        if let Some(ret) = &dsn.ret {
+            mail_from_parameters.insert("RET", ret.clone());
+        }
+        let mail_from_parameters =
+            (!mail_from_parameters.is_empty()).then_some(mail_from_parameters);
+        let mut response = self.request_batched(
+            &[jmap::CapabilityKind::Mail, jmap::CapabilityKind::Submission],
+            &[
                 jmap::RequestInvocation {
                     call: jmap::MethodCall::EmailImport {
                         account_id,
@@ -1100,7 +2734,7 @@ impl Remote {
                 jmap::RequestInvocation {
                     call: jmap::MethodCall::EmailSubmissionSet {
                         set: jmap::MethodCallSet {
-                            account_id,
+                            account_id: &self.submission_account_id,
                             if_in_state: None,
                             create: Some(HashMap::from([(
                                 &*EMAIL_SUBMISSION_CLIENT_ID,
@@ -1110,6 +2744,7 @@ impl Remote {
                                     envelope: jmap::Envelope {
                                         mail_from: jmap::Address {
                                             email: from_address,
+                                            parameters: mail_from_parameters,
                                         },
                                         rcpt_to: &rcpt_to,
                                     },
@@ -1118,74 +2753,539 @@ impl Remote {
                             update: None,
                             destroy: None,
                         },
-                        on_success_update_email: Some(HashMap::from([(
-                            &*EMAIL_SUBMISSION_CLIENT_ID_REF,
-                            on_success_update_email,
-                        )])),
+                        on_success_update_email: (!on_success_update_email.is_empty()).then(|| {
+                            HashMap::from([(
+                                &*EMAIL_SUBMISSION_CLIENT_ID_REF,
+                                on_success_update_email,
+                            )])
+                        }),
+                        on_success_destroy_email: on_success_destroy_email
+                            .as_ref()
+                            .map(|x| x.as_slice()),
                     },
                     id: SET_EMAIL_SUBMISSION_METHOD_ID,
                 },
             ],
-            created_ids: None,
-        })?;
+        )?;
         self.update_session_state(&response.session_state)?;
 
         // Pop the responses off one at a time so that we process errors in order in case of a
         // partial success.
 
-        // Verify that the email was created and get its ID.
-        if response.method_responses.is_empty() {
+        // Verify that the email was created and get its ID.
+        if response.method_responses.is_empty() {
+            return Err(Error::UnexpectedResponse);
+        }
+        let import_response =
+            expect_email_import(IMPORT_EMAIL_METHOD_ID, response.method_responses.remove(0))?;
+        map_first_method_error_into_result(import_response.not_created)
+            .context(ImportEmailSnafu {})?;
+        let imported_email = import_response
+            .created
+            .and_then(|x| x.into_values().next())
+            .context(UnexpectedResponseSnafu {})?;
+
+        // Verify that the rest of the submission succeeded. If it doesn't, we destroy the draft we
+        // just uploaded.
+        let mut verify_submission = || -> Result<jmap::Id> {
+            if response.method_responses.is_empty() {
+                return Err(Error::UnexpectedResponse);
+            }
+            let set_email_submission_response = expect_email_submission_set(
+                SET_EMAIL_SUBMISSION_METHOD_ID,
+                response.method_responses.remove(0),
+            )?;
+            map_first_method_error_into_result(set_email_submission_response.not_created)
+                .context(CreateEmailSubmissionSnafu {})?;
+            let email_submission_id = set_email_submission_response
+                .created
+                .and_then(|x| x.into_values().map(|object| object.id).next())
+                .context(UnexpectedResponseSnafu {})?;
+
+            if response.method_responses.is_empty() {
+                return Err(Error::UnexpectedResponse);
+            }
+            let set_email_response = expect_email_set(
+                SET_EMAIL_SUBMISSION_METHOD_ID,
+                response.method_responses.remove(0),
+            )?;
+            map_first_method_error_into_result(set_email_response.not_updated)
+                .context(UpdateSubmittedEmailSnafu {})?;
+            map_first_method_error_into_result(set_email_response.not_destroyed)
+                .context(UpdateSubmittedEmailSnafu {})?;
+
+            Ok(email_submission_id)
+        };
+
+        match verify_submission() {
+            Ok(email_submission_id) => Ok(SentEmail {
+                email_id: imported_email.id,
+                blob_id: imported_email.blob_id,
+                thread_id: imported_email.thread_id,
+                email_submission_id,
+            }),
+            Err(e) => {
+                // Delete the email we created and fail as normal.
+                if let Err(e) = self.destroy_email(&imported_email.id) {
+                    warn!("Could not destroy draft: {e}");
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Add a keyword such as `$answered` to every email with one of the given `Message-ID`s.
+    pub fn add_keyword_by_message_id(
+        &mut self,
+        message_ids: &[String],
+        keyword: &str,
+    ) -> Result<()> {
+        const SET_METHOD_ID: &str = "0";
+
+        let mut ids = HashSet::new();
+        for message_id in message_ids {
+            let (_, found_ids) =
+                self.query_email_ids(Some(&json!({ "header": ["Message-ID", message_id] })))?;
+            ids.extend(found_ids);
+        }
+        if ids.is_empty() {
+            return Ok(());
+        }
+        debug!("Adding keyword `{keyword}' to {ids:?}");
+
+        let patch = format!("keywords/{keyword}");
+        let account_id = &self.account_id;
+        let mut response = self.request(jmap::Request {
+            using: &[jmap::CapabilityKind::Mail],
+            method_calls: &[jmap::RequestInvocation {
+                call: jmap::MethodCall::EmailSet {
+                    set: jmap::MethodCallSet {
+                        account_id,
+                        if_in_state: None,
+                        create: None,
+                        update: Some(
+                            ids.iter()
+                                .map(|id| {
+                                    (id, HashMap::from([(patch.as_str(), Value::Bool(true))]))
+                                })
+                                .collect(),
+                        ),
+                        destroy: None,
+                    },
+                },
+                id: SET_METHOD_ID,
+            }],
+            created_ids: None,
+        })?;
+        self.update_session_state(&response.session_state)?;
+
+        if response.method_responses.len() != 1 {
+            return Err(Error::UnexpectedResponse);
+        }
+        let set_response = expect_email_set(SET_METHOD_ID, response.method_responses.remove(0))?;
+        if let Some(not_updated) = set_response.not_updated {
+            return Err(Error::UpdateEmail { not_updated });
+        }
+        Ok(())
+    }
+
+    /// Cancel every `EmailSubmission` which has not been sent yet and move its email back to the
+    /// drafts mailbox. Return the number of submissions canceled.
+    pub fn cancel_pending_submissions(&mut self, mailboxes: &Mailboxes) -> Result<usize> {
+        const QUERY_METHOD_ID: &str = "0";
+        const SET_METHOD_ID: &str = "0";
+
+        let account_id = &self.submission_account_id;
+        let filter = json!({ "undoStatus": "pending" });
+        let mut response = self.request(jmap::Request {
+            using: &[jmap::CapabilityKind::Submission],
+            method_calls: &[jmap::RequestInvocation {
+                call: jmap::MethodCall::EmailSubmissionQuery {
+                    query: jmap::MethodCallQuery {
+                        account_id,
+                        filter: Some(&filter),
+                        position: 0,
+                        anchor: None,
+                        anchor_offset: 0,
+                        limit: None,
+                        calculate_total: false,
+                    },
+                },
+                id: QUERY_METHOD_ID,
+            }],
+            created_ids: None,
+        })?;
+        self.update_session_state(&response.session_state)?;
+
+        if response.method_responses.len() != 1 {
+            return Err(Error::UnexpectedResponse);
+        }
+        let query_response =
+            expect_email_submission_query(QUERY_METHOD_ID, response.method_responses.remove(0))?;
+        if query_response.ids.is_empty() {
+            return Ok(0);
+        }
+
+        let (draft_mailbox_id, sent_mailbox_id) = mailboxes.draft_and_sent_ids()?;
+
+        let draft_mailbox_patch = format!("mailboxIds/{}", draft_mailbox_id.0);
+        let sent_mailbox_patch = format!("mailboxIds/{}", sent_mailbox_id.0);
+
+        // Undo what `send_email` did to the email once it was submitted.
+        let mut on_success_update_email = HashMap::from([
+            ("keywords/$draft", Value::Bool(true)),
+            (
+                const_format::concatcp!("keywords/", SCHEDULED_KEYWORD),
+                Value::Null,
+            ),
+        ]);
+        if draft_mailbox_id != sent_mailbox_id {
+            on_success_update_email.insert(&sent_mailbox_patch, Value::Null);
+            on_success_update_email.insert(&draft_mailbox_patch, Value::Bool(true));
+        }
+
+        let account_id = &self.submission_account_id;
+        let mut response = self.request(jmap::Request {
+            using: &[jmap::CapabilityKind::Mail, jmap::CapabilityKind::Submission],
+            method_calls: &[jmap::RequestInvocation {
+                call: jmap::MethodCall::EmailSubmissionSet {
+                    set: jmap::MethodCallSet {
+                        account_id,
+                        if_in_state: None,
+                        create: None,
+                        update: Some(
+                            query_response
+                                .ids
+                                .iter()
+                                .map(|id| {
+                                    (
+                                        id,
+                                        HashMap::from([(
+                                            "undoStatus",
+                                            Value::String("canceled".into()),
+                                        )]),
+                                    )
+                                })
+                                .collect(),
+                        ),
+                        destroy: None,
+                    },
+                    on_success_update_email: Some(
+                        query_response
+                            .ids
+                            .iter()
+                            .map(|id| (id, on_success_update_email.clone()))
+                            .collect(),
+                    ),
+                    on_success_destroy_email: None,
+                },
+                id: SET_METHOD_ID,
+            }],
+            created_ids: None,
+        })?;
+        self.update_session_state(&response.session_state)?;
+
+        if response.method_responses.is_empty() {
+            return Err(Error::UnexpectedResponse);
+        }
+        let set_email_submission_response =
+            expect_email_submission_set(SET_METHOD_ID, response.method_responses.remove(0))?;
+        map_first_method_error_into_result(set_email_submission_response.not_updated)
+            .context(CancelEmailSubmissionSnafu {})?;
+        let canceled = set_email_submission_response
+            .updated
+            .map_or(0, |updated| updated.len());
+
+        // The implicit `Email/set` call is only made if a submission was canceled.
+        if !response.method_responses.is_empty() {
+            let set_email_response =
+                expect_email_set(SET_METHOD_ID, response.method_responses.remove(0))?;
+            map_first_method_error_into_result(set_email_response.not_updated)
+                .context(UpdateCanceledEmailSnafu {})?;
+        }
+
+        Ok(canceled)
+    }
+
+    /// Destroy the given sent email if the server has filed a sent copy of its own with the same
+    /// `Message-ID`. Return true if it was destroyed.
+    pub fn destroy_duplicate_sent_email(
+        &mut self,
+        email_id: &jmap::Id,
+        message_id: &str,
+    ) -> Result<bool> {
+        let (_, ids) = self.query_email_ids(Some(&json!({
+            "header": ["Message-ID", message_id],
+            "notKeyword": "$draft",
+        })))?;
+        if ids.iter().all(|id| id == email_id) {
+            return Ok(false);
+        }
+        self.destroy_email(email_id)?;
+        Ok(true)
+    }
+
+    /// Return the `EmailSubmission` with the given id, or `None` if the server no longer has it.
+    pub fn get_email_submission(&mut self, id: &jmap::Id) -> Result<Option<jmap::EmailSubmission>> {
+        Ok(self
+            .get_email_submissions(&[id])?
+            .into_iter()
+            .find(|x| &x.id == id))
+    }
+
+    /// Move emails whose scheduled sending has happened from the drafts mailbox to the sent
+    /// mailbox, or destroy them unless `keep_sent_copy` is true, and unmark those whose sending was
+    /// canceled. Return the number of emails which were sent.
+    pub fn file_released_emails(
+        &mut self,
+        mailboxes: &Mailboxes,
+        keep_sent_copy: bool,
+    ) -> Result<usize> {
+        const QUERY_METHOD_ID: &str = "0";
+        const SET_METHOD_ID: &str = "0";
+
+        let (_, email_ids) =
+            self.query_email_ids(Some(&json!({ "hasKeyword": SCHEDULED_KEYWORD })))?;
+        if email_ids.is_empty() {
+            return Ok(0);
+        }
+
+        let account_id = &self.submission_account_id;
+        let filter = json!({ "emailIds": email_ids });
+        let mut response = self.request(jmap::Request {
+            using: &[jmap::CapabilityKind::Submission],
+            method_calls: &[jmap::RequestInvocation {
+                call: jmap::MethodCall::EmailSubmissionQuery {
+                    query: jmap::MethodCallQuery {
+                        account_id,
+                        filter: Some(&filter),
+                        position: 0,
+                        anchor: None,
+                        anchor_offset: 0,
+                        limit: None,
+                        calculate_total: false,
+                    },
+                },
+                id: QUERY_METHOD_ID,
+            }],
+            created_ids: None,
+        })?;
+        self.update_session_state(&response.session_state)?;
+
+        if response.method_responses.len() != 1 {
+            return Err(Error::UnexpectedResponse);
+        }
+        let query_response =
+            expect_email_submission_query(QUERY_METHOD_ID, response.method_responses.remove(0))?;
+        let email_submissions =
+            self.get_email_submissions(&query_response.ids.iter().collect::<Vec<_>>())?;
+
+        // An email is still scheduled as long as any of its submissions is pending. Otherwise, it
+        // was sent unless all of its submissions were canceled. Some servers destroy submissions
+        // once they're sent, so an email with no submissions left is assumed to be sent.
+        let mut statuses: HashMap<&jmap::Id, Vec<jmap::UndoStatus>> =
+            email_ids.iter().map(|id| (id, Vec::new())).collect();
+        for email_submission in &email_submissions {
+            if let Some(statuses) = email_submission
+                .email_id
+                .as_ref()
+                .and_then(|id| statuses.get_mut(id))
+            {
+                statuses.push(email_submission.undo_status);
+            }
+        }
+
+        let (draft_mailbox_id, sent_mailbox_id) = mailboxes.draft_and_sent_ids()?;
+        let draft_mailbox_patch = format!("mailboxIds/{}", draft_mailbox_id.0);
+        let sent_mailbox_patch = format!("mailboxIds/{}", sent_mailbox_id.0);
+        let scheduled_patch = const_format::concatcp!("keywords/", SCHEDULED_KEYWORD);
+
+        let mut sent_patch = HashMap::from([
+            (scheduled_patch, Value::Null),
+            ("keywords/$draft", Value::Null),
+        ]);
+        if draft_mailbox_id != sent_mailbox_id {
+            sent_patch.insert(&draft_mailbox_patch, Value::Null);
+            sent_patch.insert(&sent_mailbox_patch, Value::Bool(true));
+        }
+        let canceled_patch = HashMap::from([(scheduled_patch, Value::Null)]);
+
+        let mut sent = 0;
+        let mut updates: HashMap<&jmap::Id, HashMap<&str, Value>> = HashMap::new();
+        let mut destroys = Vec::new();
+        for (id, statuses) in statuses {
+            if statuses.contains(&jmap::UndoStatus::Pending) {
+                continue;
+            }
+            let is_canceled =
+                !statuses.is_empty() && statuses.iter().all(|x| *x == jmap::UndoStatus::Canceled);
+            if is_canceled {
+                updates.insert(id, canceled_patch.clone());
+            } else {
+                sent += 1;
+                if keep_sent_copy {
+                    updates.insert(id, sent_patch.clone());
+                } else {
+                    destroys.push(id);
+                }
+            }
+        }
+        if updates.is_empty() && destroys.is_empty() {
+            return Ok(0);
+        }
+
+        let account_id = &self.account_id;
+        let mut response = self.request(jmap::Request {
+            using: &[jmap::CapabilityKind::Mail],
+            method_calls: &[jmap::RequestInvocation {
+                call: jmap::MethodCall::EmailSet {
+                    set: jmap::MethodCallSet {
+                        account_id,
+                        if_in_state: None,
+                        create: None,
+                        update: Some(updates),
+                        destroy: Some(&destroys),
+                    },
+                },
+                id: SET_METHOD_ID,
+            }],
+            created_ids: None,
+        })?;
+        self.update_session_state(&response.session_state)?;
+
+        if response.method_responses.len() != 1 {
+            return Err(Error::UnexpectedResponse);
+        }
+        let set_response = expect_email_set(SET_METHOD_ID, response.method_responses.remove(0))?;
+        map_first_method_error_into_result(set_response.not_updated)
+            .context(UpdateSubmittedEmailSnafu {})?;
+        map_first_method_error_into_result(set_response.not_destroyed)
+            .context(DestroyEmailSnafu {})?;
+
+        Ok(sent)
+    }
+
+    /// Return the IDs of all `EmailSubmission`s matching the given filter.
+    fn query_email_submission_ids(&mut self, filter: &Value) -> Result<Vec<jmap::Id>> {
+        const QUERY_METHOD_ID: &str = "0";
+
+        let account_id = &self.submission_account_id;
+        let mut response = self.request(jmap::Request {
+            using: &[jmap::CapabilityKind::Submission],
+            method_calls: &[jmap::RequestInvocation {
+                call: jmap::MethodCall::EmailSubmissionQuery {
+                    query: jmap::MethodCallQuery {
+                        account_id,
+                        filter: Some(filter),
+                        position: 0,
+                        anchor: None,
+                        anchor_offset: 0,
+                        limit: None,
+                        calculate_total: false,
+                    },
+                },
+                id: QUERY_METHOD_ID,
+            }],
+            created_ids: None,
+        })?;
+        self.update_session_state(&response.session_state)?;
+
+        if response.method_responses.len() != 1 {
+            return Err(Error::UnexpectedResponse);
+        }
+        let query_response =
+            expect_email_submission_query(QUERY_METHOD_ID, response.method_responses.remove(0))?;
+        Ok(query_response.ids)
+    }
+
+    fn get_email_submissions(&mut self, ids: &[&jmap::Id]) -> Result<Vec<jmap::EmailSubmission>> {
+        const GET_METHOD_ID: &str = "0";
+
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let account_id = &self.submission_account_id;
+        let mut response = self.request(jmap::Request {
+            using: &[jmap::CapabilityKind::Submission],
+            method_calls: &[jmap::RequestInvocation {
+                call: jmap::MethodCall::EmailSubmissionGet {
+                    get: jmap::MethodCallGet {
+                        account_id,
+                        ids: Some(ids),
+                        properties: Some(&["id", "emailId", "undoStatus", "deliveryStatus"]),
+                    },
+                },
+                id: GET_METHOD_ID,
+            }],
+            created_ids: None,
+        })?;
+        self.update_session_state(&response.session_state)?;
+
+        if response.method_responses.len() != 1 {
+            return Err(Error::UnexpectedResponse);
+        }
+
+        let get_response =
+            expect_email_submission_get(GET_METHOD_ID, response.method_responses.remove(0))?;
+        Ok(get_response.list)
+    }
+
+    /// Import the given message, which must have DOS newlines, into the drafts mailbox as a draft,
+    /// and return the ID of the new `Email`.
+    pub fn import_draft(&mut self, mailboxes: &Mailboxes, email: &[u8]) -> Result<Id> {
+        const IMPORT_METHOD_ID: &str = "0";
+        lazy_static! {
+            static ref EMAIL_CLIENT_ID: jmap::Id = jmap::Id("0".into());
+        }
+
+        let blob_id = self.upload_blob(email)?.blob_id;
+        let (draft_mailbox_id, _) = mailboxes.draft_and_sent_ids()?;
+
+        let account_id = &self.account_id;
+        let mut response = self.request(jmap::Request {
+            using: &[jmap::CapabilityKind::Mail],
+            method_calls: &[jmap::RequestInvocation {
+                call: jmap::MethodCall::EmailImport {
+                    account_id,
+                    emails: HashMap::from([(
+                        &*EMAIL_CLIENT_ID,
+                        jmap::EmailImport {
+                            blob_id,
+                            mailbox_ids: HashMap::from([(draft_mailbox_id, true)]),
+                            keywords: HashMap::from([
+                                (EmailKeyword::Draft, true),
+                                (EmailKeyword::Seen, true),
+                            ]),
+                        },
+                    )]),
+                },
+                id: IMPORT_METHOD_ID,
+            }],
+            created_ids: None,
+        })?;
+        self.update_session_state(&response.session_state)?;
+
+        if response.method_responses.len() != 1 {
             return Err(Error::UnexpectedResponse);
         }
+
         let import_response =
-            expect_email_import(IMPORT_EMAIL_METHOD_ID, response.method_responses.remove(0))?;
+            expect_email_import(IMPORT_METHOD_ID, response.method_responses.remove(0))?;
         map_first_method_error_into_result(import_response.not_created)
             .context(ImportEmailSnafu {})?;
-        let imported_email_id = import_response
+        import_response
             .created
-            .and_then(|x| x.into_iter().map(|(_, object)| object.id).next())
-            .context(UnexpectedResponseSnafu {})?;
-
-        // Verify that the rest of the submission succeeded. If it doesn't, we destroy the draft we
-        // just uploaded.
-        let mut verify_submission = || -> Result<()> {
-            if response.method_responses.is_empty() {
-                return Err(Error::UnexpectedResponse);
-            }
-            let set_email_submission_response = expect_email_submission_set(
-                SET_EMAIL_SUBMISSION_METHOD_ID,
-                response.method_responses.remove(0),
-            )?;
-            map_first_method_error_into_result(set_email_submission_response.not_created)
-                .context(CreateEmailSubmissionSnafu {})?;
-
-            if response.method_responses.is_empty() {
-                return Err(Error::UnexpectedResponse);
-            }
-            let set_email_response = expect_email_set(
-                SET_EMAIL_SUBMISSION_METHOD_ID,
-                response.method_responses.remove(0),
-            )?;
-            map_first_method_error_into_result(set_email_response.not_created)
-                .context(UpdateSubmittedEmailSnafu {})?;
-
-            Ok(())
-        };
-
-        if let Err(e) = verify_submission() {
-            // Delete the email we created and fail as normal.
-            if let Err(e) = self.destroy_email(&imported_email_id) {
-                warn!("Could not destroy draft: {e}");
-            }
-            return Err(e);
-        };
-        Ok(())
+            .and_then(|x| x.into_values().next())
+            .map(|email| email.id)
+            .context(UnexpectedResponseSnafu {})
     }
 
-    fn destroy_email(&mut self, id: &jmap::Id) -> Result<()> {
+    /// Destroy the given email on the server.
+    pub fn destroy_email(&mut self, id: &jmap::Id) -> Result<()> {
         const SET_METHOD_ID: &str = "0";
 
-        let account_id = &self.session.primary_accounts.mail;
+        let account_id = &self.account_id;
         let mut response = self.request(jmap::Request {
             using: &[jmap::CapabilityKind::Mail],
             method_calls: &[jmap::RequestInvocation {
@@ -1215,16 +3315,129 @@ impl Remote {
         Ok(())
     }
 
-    fn upload_blob(&self, body: &str) -> Result<jmap::BlobUploadResponse> {
+    /// Return the IDs of all `Email`s which are in the given mailbox and no other.
+    pub fn email_ids_only_in(&mut self, mailbox_id: &Id) -> Result<HashSet<Id>> {
+        let (_, ids) = self.query_email_ids(Some(&json!({
+            "operator": "AND",
+            "conditions": [
+                { "inMailbox": mailbox_id },
+                { "operator": "NOT", "conditions": [{ "inMailboxOtherThan": [mailbox_id] }] },
+            ],
+        })))?;
+        Ok(ids)
+    }
+
+    /// Destroy the given emails on the server, and return the IDs of those destroyed. Emails which
+    /// are already gone count as destroyed.
+    pub fn destroy_emails(&mut self, ids: &[&Id]) -> Result<Vec<Id>> {
+        const SET_METHOD_ID: &str = "0";
+
+        let mut destroyed = Vec::new();
+        self.for_each_chunk(ids, ChunkKind::Set, |remote, chunk| {
+            let account_id = &remote.account_id;
+            let mut response = remote.request(jmap::Request {
+                using: &[jmap::CapabilityKind::Mail],
+                method_calls: &[jmap::RequestInvocation {
+                    call: jmap::MethodCall::EmailSet {
+                        set: jmap::MethodCallSet {
+                            account_id,
+                            if_in_state: None,
+                            create: None,
+                            update: None,
+                            destroy: Some(chunk),
+                        },
+                    },
+                    id: SET_METHOD_ID,
+                }],
+                created_ids: None,
+            })?;
+            remote.update_session_state(&response.session_state)?;
+
+            if response.method_responses.len() != 1 {
+                return Err(Error::UnexpectedResponse);
+            }
+
+            let set_response =
+                expect_email_set(SET_METHOD_ID, response.method_responses.remove(0))?;
+            let mut not_destroyed = set_response.not_destroyed.unwrap_or_default();
+            not_destroyed.retain(|_, e| !matches!(e, jmap::MethodResponseError::NotFound));
+            for (id, e) in &not_destroyed {
+                debug!("Could not destroy email {id}: {e}");
+            }
+            destroyed.extend(
+                chunk
+                    .iter()
+                    .filter(|id| !not_destroyed.contains_key(id))
+                    .map(|&id| id.clone()),
+            );
+            Ok(())
+        })?;
+        Ok(destroyed)
+    }
+
+    fn upload_blob(&self, body: &[u8]) -> Result<jmap::BlobUploadResponse> {
         let uri = UriTemplate::new(self.session.upload_url.as_str())
-            .set("accountId", self.session.primary_accounts.mail.0.as_str())
+            .set("accountId", self.account_id.0.as_str())
             .build();
 
-        self.http_wrapper.post_string(&uri, body)
+        self.http_wrapper.post_bytes(&uri, body)
     }
 
+    /// Make the given request. If the server no longer accepts our credentials or session, e.g.
+    /// because a password change revoked them, re-authenticate and open the session again, then
+    /// try once more.
     fn request<'a>(&self, request: jmap::Request<'a>) -> Result<jmap::Response> {
-        self.http_wrapper.post_json(&self.session.api_url, request)
+        match self.http_wrapper.post_json(&self.session.api_url, &request) {
+            Err(e) if e.is_session_invalidated() => {
+                warn!("{e}; re-authenticating and opening the session again");
+                let session = self.http_wrapper.reopen_session(&self.session_url)?;
+                // The session state of the response tells `update_session_state` to adopt it.
+                self.http_wrapper.post_json(&session.api_url, &request)
+            }
+            result => result,
+        }
+    }
+
+    /// Make the given method calls in as few requests as the server's `maxCallsInRequest` allows,
+    /// and return all of their responses as one. Creation IDs are carried over from one request to
+    /// the next through `createdIds`, so a call may still refer to a record created by an earlier
+    /// one, e.g. a child mailbox to its parent.
+    fn request_batched(
+        &self,
+        using: &[jmap::CapabilityKind],
+        method_calls: &[jmap::RequestInvocation],
+    ) -> Result<jmap::Response> {
+        let max_calls = (self.session.capabilities.core.max_calls_in_request as usize).max(1);
+        if method_calls.len() <= max_calls {
+            return self.request(jmap::Request {
+                using,
+                method_calls,
+                created_ids: None,
+            });
+        }
+
+        let mut created_ids = HashMap::new();
+        let mut merged = jmap::Response {
+            method_responses: Vec::new(),
+            created_ids: None,
+            session_state: self.session.state.clone(),
+        };
+        for chunk in method_calls.chunks(max_calls) {
+            let response = self.request(jmap::Request {
+                using,
+                method_calls: chunk,
+                created_ids: Some(created_ids),
+            })?;
+            created_ids = response
+                .created_ids
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(creation_id, id)| (creation_id, id.0))
+                .collect();
+            merged.method_responses.extend(response.method_responses);
+            merged.session_state = response.session_state;
+        }
+        Ok(merged)
     }
 
     fn update_session_state(&mut self, session_state: &State) -> Result<()> {
@@ -1234,12 +3447,25 @@ impl Remote {
                 self.session.state,
                 session_state
             );
-            let (_, session) =
+            let (_, mut session) =
                 self.http_wrapper
                     .get_session(&self.session_url)
                     .context(UpdateSessionSnafu {
                         session_url: &self.session_url,
                     })?;
+            override_limits(&mut session, &self.compatibility);
+            // Endpoints may move along with the session, e.g. while the provider migrates the
+            // account. Requests and uploads read them from the session anew each time.
+            if session.api_url != self.session.api_url
+                || session.download_url != self.session.download_url
+                || session.upload_url != self.session.upload_url
+            {
+                debug!(
+                    "Session endpoints changed: API `{}', download `{}', upload `{}'",
+                    session.api_url, session.download_url, session.upload_url
+                );
+            }
+            *self.download_url.write().unwrap() = session.download_url.clone();
             self.session = session;
             trace!("new session state is {}", self.session.state);
         }
@@ -1247,16 +3473,178 @@ impl Remote {
     }
 }
 
+/// Downloads email blobs independently of `Remote`, so that downloads can proceed in parallel
+/// with other requests.
+#[derive(Clone)]
+pub struct Downloader {
+    http_wrapper: HttpWrapper,
+    /// The session's download URL template, which changes along with the session.
+    download_url: Arc<RwLock<String>>,
+    account_id: Id,
+    /// The maximum size in bytes of an `Email` blob.
+    max_size: u64,
+}
+
+impl Downloader {
+    pub fn read_email_blob(&self, id: &Id) -> Result<impl Read + Send> {
+        let download_url = self.download_url.read().unwrap().clone();
+        let uri = UriTemplate::new(download_url.as_str())
+            .set("accountId", self.account_id.0.as_str())
+            .set("blobId", id.0.as_str())
+            .set("type", "text/plain")
+            .set("name", id.0.as_str())
+            .build();
+
+        self.http_wrapper.get_reader(uri.as_str(), self.max_size)
+    }
+}
+
+/// Reader which fails rather than silently truncating its input once more than `max_size` bytes
+/// have been read.
+struct SizeLimitedReader<R> {
+    inner: R,
+    remaining: u64,
+    max_size: u64,
+}
+
+impl<R: Read> Read for SizeLimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Allow reading a single byte past the limit so that we can tell when it's exceeded.
+        let len = usize::try_from(self.remaining.saturating_add(1))
+            .map_or(buf.len(), |len| len.min(buf.len()));
+        let n = self.inner.read(&mut buf[..len])?;
+        self.remaining = self.remaining.checked_sub(n as u64).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Email blob exceeds the maximum of {} bytes; see `max_email_size'",
+                    self.max_size
+                ),
+            )
+        })?;
+        Ok(n)
+    }
+}
+
+/// Return the notmuch tag for a single level of a mailbox path, before any role-specific tags are
+/// considered.
+fn mailbox_tag_name(name: &str, tags_config: &config::Tags) -> String {
+    let mut name: String = name.nfc().collect();
+    for (from, to) in &tags_config.replacements {
+        // Replacing the empty string is rejected by the config.
+        if !from.is_empty() {
+            name = name.replace(from.as_str(), to);
+        }
+    }
+    if tags_config.lowercase {
+        name.to_lowercase()
+    } else {
+        name
+    }
+}
+
+/// Return the full path of the given mailbox, e.g. `Lists/rust`, with each level separated by
+/// `separator`.
+pub fn mailbox_path(
+    jmap_mailboxes: &HashMap<Id, jmap::Mailbox>,
+    id: &Id,
+    separator: &str,
+) -> Option<String> {
+    let mut names = Vec::new();
+    let mut maybe_id = Some(id);
+    while let Some(id) = maybe_id {
+        let mailbox = jmap_mailboxes.get(id)?;
+        // Make sure there isn't a loop.
+        if names.len() > jmap_mailboxes.len() {
+            return None;
+        }
+        names.push(mailbox.name.as_str());
+        maybe_id = mailbox.parent_id.as_ref();
+    }
+    Some(names.into_iter().rev().join(separator))
+}
+
+/// Find a mailbox given either its ID or its full path.
+pub fn find_mailbox_id(
+    jmap_mailboxes: &HashMap<Id, jmap::Mailbox>,
+    mailbox: &str,
+    separator: &str,
+) -> Option<Id> {
+    let id = Id(mailbox.to_string());
+    if jmap_mailboxes.contains_key(&id) {
+        return Some(id);
+    }
+    jmap_mailboxes
+        .keys()
+        .find(|id| mailbox_path(jmap_mailboxes, id, separator).as_deref() == Some(mailbox))
+        .cloned()
+}
+
+/// Keyword given to emails whose sending is scheduled for later, which stay in the drafts mailbox
+/// until the server sends them.
+const SCHEDULED_KEYWORD: &str = "$scheduled";
+
+/// The result of sending an email.
+#[derive(Debug)]
+pub struct SentEmail {
+    /// The ID of the `Email` which was sent.
+    pub email_id: Id,
+    /// The ID of the blob of the raw message.
+    pub blob_id: Id,
+    /// The ID of the `Thread` the `Email` belongs to.
+    pub thread_id: Id,
+    /// The ID of its `EmailSubmission`.
+    pub email_submission_id: Id,
+}
+
+/// When the server should send a submitted email.
+#[derive(Clone, Copy, Debug)]
+pub enum Release {
+    Immediately,
+    /// After the given number of seconds.
+    After(u64),
+    /// At the given time.
+    At(SystemTime),
+}
+
+/// Delivery status notifications to request for a submitted email. See RFC 3461.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Dsn {
+    /// When to notify the sender for each recipient, e.g. `SUCCESS,FAILURE`, or `NEVER`.
+    pub notify: Option<String>,
+    /// How much of the message to return with a notification, either `FULL` or `HDRS`.
+    pub ret: Option<String>,
+}
+
+impl Dsn {
+    /// Return true if any notifications were requested.
+    pub fn is_requested(&self) -> bool {
+        self.notify.is_some() || self.ret.is_some()
+    }
+}
+
 /// Contains processed mailbox data.
 #[derive(Debug)]
 pub struct Mailboxes {
     /// The ID of the archive mailbox. Any mail which does not belong to at least one other mailbox
     /// is instead assigned to this mailbox.
-    pub archive_id: Id,
+    ///
+    /// This is `None` if the server has none yet and `auto_create_archive_mailbox` is set, until
+    /// `Remote::create_archive_mailbox` creates one.
+    pub archive_id: Option<Id>,
+    /// What to do with mail which doesn't belong to any other mailbox.
+    pub unfiled_mail: config::UnfiledMail,
+    /// The ID of the mailbox to file sent mail in. This is the mailbox with the `Sent` role unless
+    /// configured otherwise, or the archive mailbox if there is none.
+    pub sent_id: Option<Id>,
     /// A map of IDs to their corresponding mailboxes.
     pub mailboxes_by_id: HashMap<Id, Mailbox>,
     /// A map of tags to their corresponding mailboxes.
     pub ids_by_tag: HashMap<String, Id>,
+    /// The leaf tags of mailboxes, if `tags.hierarchy` is `"both"`, or of mailboxes whose names are
+    /// not unique if it is `"leaf"`. These are only applied when pulling, and don't correspond to
+    /// any one mailbox.
+    pub alias_tags: HashSet<String>,
     /// A list of IDs of mailboxes to ignore. "Ignore" here means that we will not add or remove
     /// messages from these mailboxes, nor will we assign them to any notmuch tags.
     pub ignored_ids: HashSet<Id>,
@@ -1265,6 +3653,23 @@ pub struct Mailboxes {
     pub roles: AvailableMailboxRoles,
 }
 
+impl Mailboxes {
+    /// Return the IDs of the mailboxes which hold drafts and sent mail, respectively. The archive
+    /// mailbox is used for drafts if the server has no mailbox with that role.
+    fn draft_and_sent_ids(&self) -> Result<(&Id, &Id)> {
+        let draft_id = self.roles.draft.as_ref().or(self.archive_id.as_ref());
+        match (draft_id, &self.sent_id) {
+            (Some(draft_id), Some(sent_id)) => Ok((draft_id, sent_id)),
+            _ => Err(Error::NoArchiveYet {}),
+        }
+    }
+
+    /// Return the ID of the archive mailbox.
+    fn archive_id(&self) -> Result<&Id> {
+        self.archive_id.as_ref().context(NoArchiveYetSnafu {})
+    }
+}
+
 /// Enumerates the special mailboxes that are available for this particular server.
 #[derive(Debug, Default)]
 pub struct AvailableMailboxRoles {
@@ -1276,25 +3681,178 @@ pub struct AvailableMailboxRoles {
     pub sent: Option<Id>,
 }
 
+/// The parts of an `Email` which can change on the server, recorded by syncs with servers which
+/// can't calculate changes themselves, so that the next sync can find the changes by comparing
+/// them instead of treating every `Email` as changed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmailSummary {
+    pub blob_id: Id,
+    /// SHA-256 digest of the `Email`'s keywords and mailboxes.
+    pub digest: String,
+}
+
+impl EmailSummary {
+    fn from_jmap_email(email: &jmap::Email) -> Self {
+        let keywords = email
+            .keywords
+            .iter()
+            .filter(|(_, &set)| set)
+            .map(|(keyword, _)| format!("{:?}", EmailKeyword::from_name(keyword)))
+            .unique()
+            .sorted();
+        let mailbox_ids = email
+            .mailbox_ids
+            .iter()
+            .filter(|(_, &set)| set)
+            .map(|(id, _)| id.0.clone())
+            .sorted();
+        let contents = format!("{}\n{}", keywords.format("\0"), mailbox_ids.format("\0"));
+        Self {
+            blob_id: email.blob_id.clone(),
+            digest: ring::digest::digest(&ring::digest::SHA256, contents.as_bytes())
+                .as_ref()
+                .iter()
+                .map(|x| format!("{x:02x}"))
+                .collect(),
+        }
+    }
+}
+
+/// Number of `Email`s to ask for per `Email/get` request and to change per `Email/set` request.
+///
+/// These start out as the limits advertised by the server, but a proxy in between may reject
+/// requests that large, and a slow server may time out on them, so they adapt to what actually
+/// works. See `Remote::for_each_chunk`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkSizes {
+    pub get: usize,
+    pub set: usize,
+}
+
+impl ChunkSizes {
+    fn new(core: &jmap::CoreCapabilities) -> Self {
+        Self {
+            get: (core.max_objects_in_get as usize).max(1),
+            set: (core.max_objects_in_set as usize).max(1),
+        }
+    }
+}
+
+/// Which of the `ChunkSizes` applies to a request.
+#[derive(Clone, Copy, Debug)]
+enum ChunkKind {
+    Get,
+    Set,
+}
+
+impl ChunkKind {
+    fn size(self, chunk_sizes: &mut ChunkSizes) -> &mut usize {
+        match self {
+            ChunkKind::Get => &mut chunk_sizes.get,
+            ChunkKind::Set => &mut chunk_sizes.set,
+        }
+    }
+}
+
+/// The properties of `Mailbox`es that mujmap asks the server for.
+const MAILBOX_PROPERTIES: &[&str] = &[
+    "id",
+    "parentId",
+    "name",
+    "role",
+    "myRights",
+    "isSubscribed",
+    "totalEmails",
+];
+
+/// The `Email` IDs to ask for alongside the mailboxes in `Remote::get_mailboxes_and_email_ids`.
+#[derive(Debug)]
+pub enum EmailIdsQuery<'a> {
+    /// The changes since the given `Email` state, like `Remote::changed_email_ids`, but only the
+    /// first page of at most the given number of them.
+    Changes(&'a State, u64),
+    /// The IDs of all `Email`s matching the given filter, like `Remote::all_email_ids`.
+    Query(Option<&'a Value>),
+}
+
+/// The answer to an `EmailIdsQuery`.
+#[derive(Debug)]
+pub enum EmailIds {
+    Changes {
+        state: State,
+        created: HashSet<Id>,
+        updated: HashSet<Id>,
+        destroyed: HashSet<Id>,
+        /// Whether there are more changes since `state`, to be asked for with it next.
+        has_more_changes: bool,
+    },
+    Query {
+        state: State,
+        ids: HashSet<Id>,
+    },
+}
+
+/// What `Remote::update` pushed.
+#[derive(Debug)]
+pub struct Pushed {
+    /// How to reverse each change.
+    pub changes: Vec<PushedChange>,
+    /// The tags of each email which could not be pushed because the rights of their mailboxes
+    /// don't allow it.
+    pub refused: HashMap<Id, Vec<String>>,
+}
+
+/// A change to the keywords and mailboxes of a remote Email made by `Remote::update`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PushedChange {
+    pub id: Id,
+    /// The `Message-ID` of the corresponding local message.
+    pub message_id: String,
+    /// A patch which sets the changed properties back to what they were.
+    pub undo_patch: HashMap<String, Value>,
+    /// The notmuch tags corresponding to the email as it was on the server before the change.
+    pub old_tags: Vec<String>,
+}
+
 /// An object which contains only the properties of a remote Email that mujmap cares about.
 #[derive(Debug)]
 pub struct Email {
     pub id: Id,
     pub blob_id: Id,
-    pub keywords: HashSet<jmap::EmailKeyword>,
     pub mailbox_ids: HashSet<Id>,
     pub tags: HashSet<String>,
+    /// The names of the known keywords set on the email, e.g. `$seen`.
+    pub keywords: HashSet<String>,
+    /// The date the email was received, as an RFC 3339 `UTCDate`, which sorts chronologically.
+    pub received_at: Option<String>,
+    /// The `Message-ID` of the email, without angle brackets, if it has one.
+    pub message_id: Option<String>,
+    /// The size of the raw message in octets.
+    pub size: Option<u64>,
 }
 
 #[derive(Debug)]
 pub struct Mailbox {
     pub id: Id,
     pub tag: String,
+    /// The leaf tag which mail in this mailbox is tagged with in addition to `tag`, if
+    /// `tags.hierarchy` is `"both"`, or if it is `"leaf"` and the leaf tag is not unique.
+    pub alias: Option<String>,
+    /// Whether the user may add messages to this mailbox.
+    pub may_add_items: bool,
+    /// Whether the user may remove messages from this mailbox.
+    pub may_remove_items: bool,
 }
 
 impl Mailbox {
     fn new(id: Id, tag: String) -> Self {
-        Mailbox { id, tag }
+        Mailbox {
+            id,
+            tag,
+            alias: None,
+            may_add_items: true,
+            may_remove_items: true,
+        }
     }
 }
 
@@ -1306,9 +3864,10 @@ impl Email {
     ) -> Self {
         let keywords: HashSet<jmap::EmailKeyword> = jmap_email
             .keywords
-            .into_iter()
-            .filter(|(k, v)| *v && *k != jmap::EmailKeyword::Unknown)
-            .map(|(k, _)| k)
+            .iter()
+            .filter(|(_, v)| **v)
+            .map(|(k, _)| EmailKeyword::from_name(k))
+            .filter(|k| *k != jmap::EmailKeyword::Unknown)
             .collect();
         let mailbox_ids = jmap_email
             .mailbox_ids
@@ -1329,22 +3888,22 @@ impl Email {
         let mut tags = HashSet::new();
         for keyword in &keywords {
             if let Some(tag) = match keyword {
-                EmailKeyword::Answered => Some("replied"),
+                EmailKeyword::Answered => Some(tags_config.replied.as_str()),
                 EmailKeyword::Draft => {
                     if mailboxes.roles.draft.is_some() {
                         None
                     } else {
-                        Some("draft")
+                        Some(tags_config.draft.as_str())
                     }
                 }
                 EmailKeyword::Flagged => {
                     if mailboxes.roles.flagged.is_some() {
                         None
                     } else {
-                        Some("flagged")
+                        Some(tags_config.flagged.as_str())
                     }
                 }
-                EmailKeyword::Forwarded => Some("passed"),
+                EmailKeyword::Forwarded => Some(tags_config.passed.as_str()),
                 EmailKeyword::Important => {
                     if mailboxes.roles.important.is_some() {
                         None
@@ -1359,7 +3918,7 @@ impl Email {
             }
         }
         if !keywords.contains(&EmailKeyword::Seen) {
-            tags.insert("unread".to_string());
+            tags.insert(tags_config.unread.clone());
         }
         if mailboxes.roles.spam.is_none()
             && !tags_config.spam.is_empty()
@@ -1372,11 +3931,160 @@ impl Email {
         Self {
             id: jmap_email.id,
             blob_id: jmap_email.blob_id,
-            keywords,
             mailbox_ids,
             tags,
+            keywords: keywords
+                .iter()
+                .flat_map(|keyword| match serde_json::to_value(keyword) {
+                    Ok(Value::String(name)) => Some(name),
+                    _ => None,
+                })
+                .collect(),
+            received_at: jmap_email.received_at,
+            message_id: jmap_email
+                .message_id
+                .and_then(|message_ids| message_ids.into_iter().next()),
+            size: jmap_email.size,
+        }
+    }
+
+    /// Return the tags of the mailboxes this email is in, without their leaf tags.
+    pub fn mailbox_tags<'a>(&'a self, mailboxes: &'a Mailboxes) -> impl Iterator<Item = &'a str> {
+        self.mailbox_ids
+            .iter()
+            .flat_map(|id| mailboxes.mailboxes_by_id.get(id))
+            .map(|mailbox| mailbox.tag.as_str())
+    }
+
+    /// Return the notmuch tags this email should have, including those of its mailboxes.
+    pub fn local_tags<'a>(&'a self, mailboxes: &'a Mailboxes) -> HashSet<&'a str> {
+        self.tags
+            .iter()
+            .map(|tag| tag.as_str())
+            .chain(
+                self.mailbox_ids
+                    .iter()
+                    .flat_map(|id| mailboxes.mailboxes_by_id.get(id))
+                    .flat_map(|mailbox| {
+                        iter::once(mailbox.tag.as_str()).chain(mailbox.alias.as_deref())
+                    }),
+            )
+            .collect()
+    }
+}
+
+/// Build a patch which sets the keywords and mailboxes of `remote_email` to those corresponding to
+/// the notmuch `tags`. Also return the tags of mailboxes which the email can't be added to or
+/// removed from, and so which the patch leaves alone.
+fn tags_patch<'a>(
+    tags: &HashSet<String>,
+    remote_email: &Email,
+    mailboxes: &'a Mailboxes,
+    tags_config: &config::Tags,
+) -> Result<(HashMap<&'static str, Value>, Vec<&'a str>)> {
+    let mut patch = HashMap::new();
+    // Keywords.
+    patch.insert(
+        "keywords/$draft",
+        as_value(tags.contains(&tags_config.draft)),
+    );
+    patch.insert(
+        "keywords/$seen",
+        as_value(!tags.contains(&tags_config.unread)),
+    );
+    patch.insert(
+        "keywords/$flagged",
+        as_value(tags.contains(&tags_config.flagged)),
+    );
+    patch.insert(
+        "keywords/$answered",
+        as_value(tags.contains(&tags_config.replied)),
+    );
+    patch.insert(
+        "keywords/$forwarded",
+        as_value(tags.contains(&tags_config.passed)),
+    );
+    if mailboxes.roles.spam.is_none() && !tags_config.spam.is_empty() {
+        let spam = tags.contains(&tags_config.spam);
+        patch.insert("keywords/$junk", as_value(spam));
+        patch.insert("keywords/$notjunk", as_value(!spam));
+    }
+    if !tags_config.phishing.is_empty() {
+        patch.insert(
+            "keywords/$phishing",
+            as_value(tags.contains(&tags_config.phishing)),
+        );
+    }
+    // Set mailboxes.
+    // TODO: eliminate clone here?
+    // Include all ignored mailboxes which the remote email is already included in.
+    let mut new_mailboxes: serde_json::Map<String, Value> = remote_email
+        .mailbox_ids
+        .iter()
+        .filter(|x| mailboxes.ignored_ids.contains(x))
+        .map(|x| (x.0.clone(), Value::Bool(true)))
+        .collect();
+    // Include all mailboxes which correspond to notmuch tags, as long as we have the
+    // rights to add or remove messages from them.
+    let mut refused = Vec::new();
+    for mailbox in mailboxes.mailboxes_by_id.values() {
+        let in_remote = remote_email.mailbox_ids.contains(&mailbox.id);
+        let in_local = tags.contains(&mailbox.tag);
+        let include = match (in_remote, in_local) {
+            (true, false) if !mailbox.may_remove_items => {
+                warn!(
+                    "Not removing email `{}' from read-only mailbox `{}'",
+                    remote_email.id, mailbox.tag
+                );
+                refused.push(mailbox.tag.as_str());
+                true
+            }
+            (false, true) if !mailbox.may_add_items => {
+                warn!(
+                    "Not adding email `{}' to read-only mailbox `{}'",
+                    remote_email.id, mailbox.tag
+                );
+                refused.push(mailbox.tag.as_str());
+                false
+            }
+            (_, in_local) => in_local,
+        };
+        if include {
+            new_mailboxes.insert(mailbox.id.0.clone(), Value::Bool(true));
+        }
+    }
+    // If no mailboxes were found, assign to Archive, unless configured otherwise.
+    if new_mailboxes.is_empty() {
+        match mailboxes.unfiled_mail {
+            config::UnfiledMail::Keep if !remote_email.mailbox_ids.is_empty() => {
+                new_mailboxes.extend(
+                    remote_email
+                        .mailbox_ids
+                        .iter()
+                        .map(|x| (x.0.clone(), Value::Bool(true))),
+                );
+            }
+            config::UnfiledMail::Error => {
+                return UnfiledEmailSnafu {
+                    id: remote_email.id.clone(),
+                }
+                .fail();
+            }
+            _ => {
+                new_mailboxes.insert(mailboxes.archive_id()?.0.clone(), Value::Bool(true));
+            }
         }
     }
+    patch.insert("mailboxIds", Value::Object(new_mailboxes));
+    Ok((patch, refused))
+}
+
+fn as_value(b: bool) -> Value {
+    if b {
+        Value::Bool(true)
+    } else {
+        Value::Null
+    }
 }
 
 fn expect_email_get(
@@ -1477,6 +4185,34 @@ fn expect_mailbox_set(
     }
 }
 
+fn expect_email_submission_get(
+    id: &str,
+    invocation: jmap::ResponseInvocation,
+) -> Result<jmap::MethodResponseGet<jmap::EmailSubmission>> {
+    if invocation.id != id {
+        return Err(Error::UnexpectedResponse);
+    }
+    match invocation.call {
+        jmap::MethodResponse::EmailSubmissionGet(get) => Ok(get),
+        jmap::MethodResponse::Error(error) => Err(Error::MethodError { error }),
+        _ => Err(Error::UnexpectedResponse),
+    }
+}
+
+fn expect_email_submission_query(
+    id: &str,
+    invocation: jmap::ResponseInvocation,
+) -> Result<jmap::MethodResponseQuery> {
+    if invocation.id != id {
+        return Err(Error::UnexpectedResponse);
+    }
+    match invocation.call {
+        jmap::MethodResponse::EmailSubmissionQuery(query) => Ok(query),
+        jmap::MethodResponse::Error(error) => Err(Error::MethodError { error }),
+        _ => Err(Error::UnexpectedResponse),
+    }
+}
+
 fn expect_email_submission_set(
     id: &str,
     invocation: jmap::ResponseInvocation,
@@ -1505,6 +4241,20 @@ fn expect_identity_get(
     }
 }
 
+fn expect_quota_get(
+    id: &str,
+    invocation: jmap::ResponseInvocation,
+) -> Result<jmap::MethodResponseGet<jmap::Quota>> {
+    if invocation.id != id {
+        return Err(Error::UnexpectedResponse);
+    }
+    match invocation.call {
+        jmap::MethodResponse::QuotaGet(get) => Ok(get),
+        jmap::MethodResponse::Error(error) => Err(Error::MethodError { error }),
+        _ => Err(Error::UnexpectedResponse),
+    }
+}
+
 fn map_first_method_error_into_result(
     errors: Option<HashMap<Id, jmap::MethodResponseError>>,
 ) -> Result<(), jmap::MethodResponseError> {
@@ -1512,3 +4262,48 @@ fn map_first_method_error_into_result(
         .and_then(|map| map.into_iter().next())
         .map_or(Ok(()), |(_, e)| Err(e))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_leaf_names_are_tagged_with_their_paths() {
+        let config: Config = toml::from_str(
+            r#"
+            username = "user"
+            password_command = "true"
+
+            [tags]
+            hierarchy = "leaf"
+            "#,
+        )
+        .unwrap();
+        let jmap_mailboxes: HashMap<Id, jmap::Mailbox> =
+            serde_json::from_value::<Vec<jmap::Mailbox>>(json!([
+                { "id": "archive", "parentId": null, "name": "Archive", "role": "archive" },
+                { "id": "lists", "parentId": null, "name": "Lists", "role": null },
+                { "id": "a", "parentId": "lists", "name": "a", "role": null },
+                { "id": "b", "parentId": "lists", "name": "b", "role": null },
+                { "id": "a-announce", "parentId": "a", "name": "announce", "role": null },
+                { "id": "b-announce", "parentId": "b", "name": "announce", "role": null },
+                { "id": "b-devel", "parentId": "b", "name": "devel", "role": null },
+            ]))
+            .unwrap()
+            .into_iter()
+            .map(|x| (x.id.clone(), x))
+            .collect();
+
+        let mailboxes = Remote::mailboxes_from_jmap(jmap_mailboxes, &config).unwrap();
+
+        let tags = |id: &str| {
+            let mailbox = &mailboxes.mailboxes_by_id[&Id(id.to_owned())];
+            (mailbox.tag.as_str(), mailbox.alias.as_deref())
+        };
+        assert_eq!(tags("a-announce"), ("Lists/a/announce", Some("announce")));
+        assert_eq!(tags("b-announce"), ("Lists/b/announce", Some("announce")));
+        assert_eq!(tags("b-devel"), ("devel", None));
+        assert_eq!(tags("lists"), ("Lists", None));
+        assert!(mailboxes.alias_tags.contains("announce"));
+    }
+}
@@ -1,28 +1,51 @@
 use either::Either;
 use fqdn::FQDN;
+use lazy_static::lazy_static;
 use log::{debug, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 use snafu::prelude::*;
 use std::{
+    borrow::Cow,
     collections::HashSet,
-    io::{Cursor, Read},
+    fs,
+    io::{self, Read},
     iter,
+    path::{Path, PathBuf},
+    process,
     str::FromStr,
-    string::FromUtf8Error,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
-    config::Config,
-    jmap,
-    remote::{self, Remote},
+    aliases::{self, Aliases},
+    config::{Config, SaveToSent},
+    jmap, line_endings,
+    local::{self, Local},
+    lock::{self, Lock},
+    remote::{self, Release, Remote},
 };
 
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display("Could not read mail from stdin: {}", source))]
-    ReadStdin { source: loe::ParseError },
+    ReadStdin { source: io::Error },
 
-    #[snafu(display("Could not read mail from CRLF stdin buffer: {}", source))]
-    ReadCrlfStdin { source: FromUtf8Error },
+    #[snafu(display("Could not read mail from `{}': {}", path.to_string_lossy(), source))]
+    ReadFile { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Could not open local notmuch database: {}", source))]
+    OpenLocal { source: local::Error },
+
+    #[snafu(display("Could not find draft `{}': {}", message_id, source))]
+    GetDraft {
+        message_id: String,
+        source: notmuch::Error,
+    },
+
+    #[snafu(display("No message with ID `{}' exists", message_id))]
+    DraftNotFound { message_id: String },
 
     #[snafu(display("Could not parse mail: {}", source))]
     ParseEmail { source: email_parser::error::Error },
@@ -42,46 +65,251 @@ pub enum Error {
     #[snafu(display("Could not parse JMAP identity domain `{}': {}", domain, source))]
     ParseIdentityDomain { domain: String, source: fqdn::Error },
 
-    #[snafu(display("No JMAP identities match sender `{}'", sender))]
+    #[snafu(display(
+        "No JMAP identities match sender `{}'; see the `identities' configuration option",
+        sender
+    ))]
     NoIdentitiesForSender { sender: String },
 
+    #[snafu(display("No JMAP identity has the ID or address `{}'", identity))]
+    UnknownDefaultIdentity { identity: String },
+
     #[snafu(display("Could not index mailboxes: {}", source))]
     IndexMailboxes { source: remote::Error },
 
+    #[snafu(display("Could not expand aliases: {}", source))]
+    ExpandAliases { source: aliases::Error },
+
     #[snafu(display("No recipients specified. Did you forget to specify `-t'?"))]
     NoRecipients {},
 
     #[snafu(display("Could not send email: {}", source))]
     SendEmail { source: remote::Error },
+
+    #[snafu(display(
+        "Invalid time `{}'; expected e.g. `2024-06-01T09:00Z' or `2024-06-01T09:00:00+02:00'",
+        send_at
+    ))]
+    ParseSendAt { send_at: String },
+
+    #[snafu(display("Time to send at `{}' is in the past", send_at))]
+    SendAtInPast { send_at: String },
+
+    #[snafu(display("JMAP account `{}' may not send mail", account_id))]
+    SubmissionUnsupported { account_id: jmap::Id },
+
+    #[snafu(display(
+        "Invalid delivery status notification events `{}'; expected `never' or any of `success', `failure', and `delay'",
+        notify
+    ))]
+    ParseDsnNotify { notify: String },
+
+    #[snafu(display(
+        "Invalid delivery status notification return `{}'; expected `full' or `hdrs'",
+        ret
+    ))]
+    ParseDsnReturn { ret: String },
+
+    #[snafu(display("Server does not support delivery status notifications"))]
+    DsnUnsupported {},
+
+    #[snafu(display("Server does not support scheduled sending"))]
+    ScheduledSendUnsupported {},
+
+    #[snafu(display(
+        "Server can only schedule sending up to {} seconds in advance",
+        max_delayed_send
+    ))]
+    SendAtTooLate { max_delayed_send: u64 },
+
+    #[snafu(display("Could not cancel sent email: {}", source))]
+    CancelEmail { source: remote::Error },
+
+    #[snafu(display("Could not get delivery status: {}", source))]
+    GetEmailSubmission { source: remote::Error },
+
+    #[snafu(display("Sending was canceled"))]
+    SubmissionCanceled {},
+
+    #[snafu(display("Could not deliver email to: {}", recipients))]
+    DeliveryFailed { recipients: String },
+
+    #[snafu(display("Could not check whether queued email was already sent: {}", source))]
+    CheckSent { source: remote::Error },
+
+    #[snafu(display("Could not read outbox `{}': {}", path.to_string_lossy(), source))]
+    ReadOutbox { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Could not write queued email `{}': {}", path.to_string_lossy(), source))]
+    WriteQueuedEmail { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Could not parse queued email `{}': {}", path.to_string_lossy(), source))]
+    ParseQueuedEmail {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Could not remove queued email `{}': {}", path.to_string_lossy(), source))]
+    RemoveQueuedEmail { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Could not send queued email `{}': {}", path.to_string_lossy(), source))]
+    SendQueuedEmail { path: PathBuf, source: Box<Error> },
+
+    #[snafu(display("Could not lock `{}': {}", path.to_string_lossy(), source))]
+    Lock { path: PathBuf, source: io::Error },
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
-pub fn send(read_recipients: bool, recipients: Vec<String>, config: Config) -> Result<()> {
-    // Read mail from stdin, converting Unix newlines to DOS newlines to coimply with RFC5322.
-    // Truncate the input so we don't infinitely grow a buffer if someone pipes /dev/urandom into
-    // mujmap or something similar by mistake.
-    let mut stdio_crlf = Cursor::new(Vec::new());
-    loe::process(
-        &mut std::io::stdin().take(10_000_000),
-        &mut stdio_crlf,
-        loe::Config::default().transform(loe::TransformMode::Crlf),
-    )
-    .context(ReadStdinSnafu {})?;
-
-    let email_string = String::from_utf8(stdio_crlf.into_inner()).context(ReadCrlfStdinSnafu {})?;
-    let parsed_email =
-        email_parser::email::Email::parse(email_string.as_bytes()).context(ParseEmailSnafu {})?;
+/// Directory in the maildir where mail which could not be sent is queued.
+const OUTBOX_DIR: &str = "mujmap.outbox";
 
-    let mut remote = Remote::open(&config).context(OpenRemoteSnafu {})?;
+/// How often to poll the server for the delivery status of a sent email.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_secs(5);
 
-    let identity_id =
-        get_identity_id_for_sender_address(&parsed_email.sender.address, &mut remote)?;
-    let mailboxes = remote
-        .get_mailboxes(&config.tags)
-        .context(IndexMailboxesSnafu {})?;
+/// How long to wait for a sent email to be delivered before reporting its status anyway.
+const WAIT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// An email waiting in the outbox to be sent.
+#[derive(Serialize, Deserialize)]
+struct QueuedEmail {
+    /// The envelope recipients.
+    recipients: HashSet<String>,
+    /// The message itself, with DOS newlines.
+    #[serde(
+        serialize_with = "serialize_base64",
+        deserialize_with = "deserialize_base64"
+    )]
+    email: Vec<u8>,
+    /// The `Message-ID` of the message, if any, which is used to avoid sending it twice.
+    message_id: Option<String>,
+    /// The time to send the message at, in seconds since the Unix epoch.
+    #[serde(default)]
+    send_at: Option<u64>,
+    /// The envelope sender given with `-f`, if any.
+    #[serde(default)]
+    envelope_from: Option<String>,
+    /// The delivery status notifications to request.
+    #[serde(default)]
+    dsn: remote::Dsn,
+}
+
+/// Where to read the message to send from.
+#[derive(Clone, Debug)]
+pub enum Source {
+    Stdin,
+    File(PathBuf),
+    /// The notmuch message with the given `Message-ID`, optionally prefixed with `id:`.
+    Draft(String),
+}
+
+/// What became of an email given to `send`.
+#[derive(Debug)]
+pub enum Sent {
+    /// The server accepted the email.
+    Submitted {
+        /// The IDs of what the server created.
+        ids: remote::SentEmail,
+        /// The `Message-ID` of the email, if any.
+        message_id: Option<String>,
+        /// When the server sends it; until then, `undo` cancels it.
+        release: Release,
+        /// The delivery status, if it was waited for.
+        delivery: Option<Delivery>,
+    },
+    /// The server could not be reached, so the email was queued in the outbox at the given path.
+    Queued(PathBuf),
+}
+
+/// The delivery status of a sent email.
+#[derive(Debug)]
+pub enum Delivery {
+    /// The server does not report delivery status.
+    Unreported,
+    /// The server no longer has the submission, so its delivery status is unknown.
+    Unknown,
+    /// The status of each recipient, sorted by address, along with the server's SMTP reply.
+    Recipients(Vec<(String, jmap::Delivered, String)>),
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn send(
+    mail_dir: &Path,
+    source: Source,
+    read_recipients: bool,
+    recipients: Vec<String>,
+    expand_aliases: bool,
+    envelope_from: Option<String>,
+    wait: bool,
+    send_at: Option<String>,
+    dsn: remote::Dsn,
+    config: Config,
+) -> Result<Sent> {
+    let send_at = send_at
+        .map(|send_at| {
+            let time = parse_send_at(&send_at).context(ParseSendAtSnafu { send_at: &send_at })?;
+            ensure!(time > SystemTime::now(), SendAtInPastSnafu { send_at });
+            Ok(time)
+        })
+        .transpose()?;
+    let dsn = remote::Dsn {
+        notify: dsn
+            .notify
+            .map(|notify| parse_dsn_notify(&notify).context(ParseDsnNotifySnafu { notify }))
+            .transpose()?,
+        ret: dsn
+            .ret
+            .map(|ret| {
+                let upper = ret.trim().to_ascii_uppercase();
+                ensure!(
+                    upper == "FULL" || upper == "HDRS",
+                    ParseDsnReturnSnafu { ret }
+                );
+                Ok(upper)
+            })
+            .transpose()?,
+    };
+
+    // Read the mail, converting Unix newlines to DOS newlines to comply with RFC5322. The message
+    // is kept as bytes, since mail from older MUAs need not be valid UTF-8. Truncate the input so
+    // we don't infinitely grow a buffer if someone pipes /dev/urandom into mujmap or something
+    // similar by mistake.
+    let mut input = Vec::new();
+    let mut draft_emails = Vec::new();
+    match source {
+        Source::Stdin => {
+            io::stdin()
+                .take(10_000_000)
+                .read_to_end(&mut input)
+                .context(ReadStdinSnafu {})?;
+        }
+        Source::File(path) => {
+            fs::File::open(&path)
+                .and_then(|file| file.take(10_000_000).read_to_end(&mut input))
+                .context(ReadFileSnafu { path })?;
+        }
+        Source::Draft(message_id) => {
+            let message_id = message_id.strip_prefix("id:").unwrap_or(&message_id);
+            let local = Local::open(mail_dir, true, &config).context(OpenLocalSnafu {})?;
+            let (paths, emails) = local
+                .message_files(message_id)
+                .context(GetDraftSnafu { message_id })?;
+            // Any file will do, since they all have the same `Message-ID'.
+            let path = paths
+                .into_iter()
+                .next()
+                .context(DraftNotFoundSnafu { message_id })?;
+            fs::File::open(&path)
+                .and_then(|file| file.take(10_000_000).read_to_end(&mut input))
+                .context(ReadFileSnafu { path })?;
+            draft_emails = emails;
+        }
+    }
+    let email = line_endings::unix_to_dos(&input);
+
+    let header = parseable_header(&email);
+    let parsed_email = email_parser::email::Email::parse(&header).context(ParseEmailSnafu {})?;
 
-    let from_address = address_to_string(&parsed_email.sender.address);
     let addresses_to_iter = |a| {
         // Use `as' here as a workaround for lifetime inference.
         (a as Option<Vec<email_parser::address::Address>>).map_or_else(
@@ -112,47 +340,453 @@ pub fn send(read_recipients: bool, recipients: Vec<String>, config: Config) -> R
             .collect()
     } else {
         // TODO: Locally verify that all recipients are valid email addresses.
-        recipients.into_iter().collect()
+        match &config.sending.aliases_file {
+            Some(path) if expand_aliases => Aliases::from_file(mail_dir.join(path))
+                .and_then(|aliases| aliases.expand(recipients))
+                .context(ExpandAliasesSnafu {})?,
+            _ => recipients.into_iter().collect(),
+        }
     };
 
     ensure!(!to_addresses.is_empty(), NoRecipientsSnafu {});
 
+    let message_id = parsed_email
+        .message_id
+        .as_ref()
+        .map(|(left, right)| format!("{left}@{right}"));
+    // Like sendmail, don't reveal the blind carbon copy recipients to anyone, including in the copy
+    // of the message which is kept in the sent mailbox.
+    let queued_email = QueuedEmail {
+        recipients: to_addresses,
+        email: strip_bcc(&email),
+        message_id,
+        send_at: send_at.map(|time| {
+            time.duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs())
+        }),
+        envelope_from: envelope_from.map(|x| {
+            x.trim()
+                .trim_start_matches('<')
+                .trim_end_matches('>')
+                .to_string()
+        }),
+        dsn,
+    };
+
+    // If the server can't be reached, queue the email to be sent later rather than losing it.
+    let result = Remote::open(&config)
+        .context(OpenRemoteSnafu {})
+        .and_then(|mut remote| {
+            let mailboxes = remote
+                .get_mailboxes(&config)
+                .context(IndexMailboxesSnafu {})?;
+            let (ids, release) = submit(&mut remote, &mailboxes, &queued_email, &config)?;
+            // Destroy the server's copy of the draft which was sent, so that the next sync replaces
+            // it with the sent copy. The local file is left alone if mujmap doesn't own it.
+            for draft_email in &draft_emails {
+                if let Err(e) = remote.destroy_email(&draft_email.id) {
+                    warn!("Could not destroy sent draft: {e}");
+                }
+            }
+            let delivery = if wait {
+                Some(wait_for_delivery(&mut remote, &ids.email_submission_id)?)
+            } else {
+                None
+            };
+            Ok(Sent::Submitted {
+                ids,
+                message_id: queued_email.message_id.clone(),
+                release,
+                delivery,
+            })
+        });
+    match result {
+        Err(Error::OpenRemote { source } | Error::SendEmail { source })
+            if source.is_unreachable() =>
+        {
+            let path = queue(mail_dir, &queued_email)?;
+            warn!(
+                "Could not reach server ({source}); queued email as `{}' to be sent by `mujmap flush' or the next sync",
+                path.to_string_lossy()
+            );
+            Ok(Sent::Queued(path))
+        }
+        result => result,
+    }
+}
+
+/// Cancel all mail whose sending is still being delayed by the server, and return how many were
+/// canceled.
+pub fn undo(config: Config) -> Result<usize> {
+    let mut remote = Remote::open(&config).context(OpenRemoteSnafu {})?;
+    let mailboxes = remote
+        .get_mailboxes(&config)
+        .context(IndexMailboxesSnafu {})?;
+    remote
+        .cancel_pending_submissions(&mailboxes)
+        .context(CancelEmailSnafu {})
+}
+
+/// Send the mail queued in the outbox because the server could not be reached, and return how
+/// many were sent.
+pub fn flush(mail_dir: &Path, config: Config) -> Result<usize> {
+    let mut remote = Remote::open(&config).context(OpenRemoteSnafu {})?;
+    let mailboxes = remote
+        .get_mailboxes(&config)
+        .context(IndexMailboxesSnafu {})?;
+    flush_outbox(mail_dir, &mut remote, &mailboxes, &config)
+}
+
+/// Send the mail queued in the outbox, oldest first, and return how many were sent. Stops at the
+/// first email which fails to send, leaving it and all later emails queued.
+///
+/// Only the outbox is locked meanwhile, so that a long sync doesn't hold up sending.
+pub fn flush_outbox(
+    mail_dir: &Path,
+    remote: &mut Remote,
+    mailboxes: &remote::Mailboxes,
+    config: &Config,
+) -> Result<usize> {
+    let _lock = lock_outbox(mail_dir)?;
+    let outbox = mail_dir.join(OUTBOX_DIR);
+    let entries = match fs::read_dir(&outbox) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e).context(ReadOutboxSnafu { path: outbox }),
+    };
+    let mut paths = entries
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .context(ReadOutboxSnafu { path: &outbox })?;
+    paths.retain(|path| path.extension().is_some_and(|x| x == "json"));
+    paths.sort();
+
+    let mut sent = 0;
+    for path in paths {
+        let contents = fs::read(&path).context(ReadOutboxSnafu { path: &path })?;
+        let queued_email: QueuedEmail =
+            serde_json::from_slice(&contents).context(ParseQueuedEmailSnafu { path: &path })?;
+
+        // If a previous attempt reached the server even though it appeared to fail, the email was
+        // already submitted, so don't send it twice.
+        let already_sent = match &queued_email.message_id {
+            Some(message_id) => remote.is_sent(message_id).context(CheckSentSnafu {})?,
+            None => false,
+        };
+        if already_sent {
+            debug!("Queued email `{}' was already sent", path.to_string_lossy());
+        } else {
+            submit(remote, mailboxes, &queued_email, config)
+                .map_err(Box::new)
+                .context(SendQueuedEmailSnafu { path: &path })?;
+            sent += 1;
+        }
+        fs::remove_file(&path).context(RemoveQueuedEmailSnafu { path: &path })?;
+    }
+    Ok(sent)
+}
+
+/// Write an email to the outbox and return its path.
+fn queue(mail_dir: &Path, queued_email: &QueuedEmail) -> Result<PathBuf> {
+    let _lock = lock_outbox(mail_dir)?;
+    let outbox = mail_dir.join(OUTBOX_DIR);
+    fs::create_dir_all(&outbox).context(WriteQueuedEmailSnafu { path: &outbox })?;
+
+    // Name emails by the time they were queued so that they're sent in order.
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_nanos());
+    let name = format!("{:024}-{}", nanos, process::id());
+    let temporary_path = outbox.join(format!("{name}.tmp"));
+    let path = outbox.join(format!("{name}.json"));
+
+    // Write to a temporary file first so that a partially written email is never sent.
+    let contents = serde_json::to_vec(queued_email).unwrap();
+    fs::write(&temporary_path, contents).context(WriteQueuedEmailSnafu {
+        path: &temporary_path,
+    })?;
+    fs::rename(&temporary_path, &path).context(WriteQueuedEmailSnafu { path: &path })?;
+    Ok(path)
+}
+
+fn lock_outbox(mail_dir: &Path) -> Result<Lock> {
+    let path = mail_dir.join(lock::OUTBOX_LOCK);
+    Lock::acquire(&path).context(LockSnafu { path })
+}
+
+/// Poll the server until the given submission is no longer queued, then return its delivery status
+/// for each recipient. Fails if it could not be delivered to any of them.
+fn wait_for_delivery(remote: &mut Remote, email_submission_id: &jmap::Id) -> Result<Delivery> {
+    let start = Instant::now();
+    let email_submission = loop {
+        let email_submission = match remote
+            .get_email_submission(email_submission_id)
+            .context(GetEmailSubmissionSnafu {})?
+        {
+            Some(x) => x,
+            None => {
+                // Some servers destroy submissions as soon as they're sent.
+                warn!("Server no longer has the submission; delivery status is unknown");
+                return Ok(Delivery::Unknown);
+            }
+        };
+        let is_pending = email_submission.undo_status == jmap::UndoStatus::Pending
+            || email_submission
+                .delivery_status
+                .iter()
+                .flat_map(|x| x.values())
+                .any(|x| x.delivered == jmap::Delivered::Queued);
+        if !is_pending || start.elapsed() >= WAIT_TIMEOUT {
+            break email_submission;
+        }
+        debug!("Email is still queued; checking again in {WAIT_POLL_INTERVAL:?}");
+        thread::sleep(WAIT_POLL_INTERVAL);
+    };
+
+    ensure!(
+        email_submission.undo_status != jmap::UndoStatus::Canceled,
+        SubmissionCanceledSnafu {}
+    );
+
+    let delivery_status = match email_submission.delivery_status {
+        Some(x) => x,
+        None => return Ok(Delivery::Unreported),
+    };
+    let mut recipients: Vec<_> = delivery_status
+        .into_iter()
+        .map(|(recipient, status)| {
+            (
+                recipient,
+                status.delivered,
+                status.smtp_reply.trim().to_string(),
+            )
+        })
+        .collect();
+    recipients.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
+    let failed: Vec<String> = recipients
+        .iter()
+        .filter(|(_, delivered, _)| *delivered == jmap::Delivered::No)
+        .map(|(recipient, _, smtp_reply)| format!("{recipient} ({smtp_reply})"))
+        .collect();
+    ensure!(
+        failed.is_empty(),
+        DeliveryFailedSnafu {
+            recipients: failed.join(", ")
+        }
+    );
+    Ok(Delivery::Recipients(recipients))
+}
+
+/// Send an email through the server and return the IDs of what was created, along with when the
+/// server sends it. Unless the email is scheduled to be sent at a given time, the server is asked
+/// to hold it for `send_delay` seconds first, as far as it supports.
+fn submit(
+    remote: &mut Remote,
+    mailboxes: &remote::Mailboxes,
+    queued_email: &QueuedEmail,
+    config: &Config,
+) -> Result<(remote::SentEmail, Release)> {
+    ensure!(
+        remote.submission_capabilities().is_some(),
+        SubmissionUnsupportedSnafu {
+            account_id: remote.submission_account_id.clone()
+        }
+    );
+
+    ensure!(
+        !queued_email.dsn.is_requested() || remote.submission_extension("DSN").is_some(),
+        DsnUnsupportedSnafu {}
+    );
+
+    let send_delay = config.send_delay;
+    let max_delayed_send = remote.max_delayed_send();
+    let now = SystemTime::now();
+    let release = match queued_email
+        .send_at
+        .map(|x| UNIX_EPOCH + Duration::from_secs(x))
+    {
+        // If the time has passed while the email was queued, send it right away.
+        Some(time) if time > now => {
+            let delay = time.duration_since(now).unwrap_or_default().as_secs();
+            ensure!(max_delayed_send > 0, ScheduledSendUnsupportedSnafu {});
+            ensure!(
+                delay <= max_delayed_send,
+                SendAtTooLateSnafu { max_delayed_send }
+            );
+            Release::At(time)
+        }
+        Some(_) => Release::Immediately,
+        None => {
+            if send_delay > max_delayed_send {
+                if max_delayed_send == 0 {
+                    warn!("Server does not support delayed sending; ignoring `send_delay'");
+                } else {
+                    warn!(
+                        "Server can only delay sending by {max_delayed_send} seconds; ignoring the rest of `send_delay'"
+                    );
+                }
+            }
+            match send_delay.min(max_delayed_send) {
+                0 => Release::Immediately,
+                delay => Release::After(delay),
+            }
+        }
+    };
+
+    let header = parseable_header(&queued_email.email);
+    let parsed_email = email_parser::email::Email::parse(&header).context(ParseEmailSnafu {})?;
+    // The authors in `From' are tried before the `Sender', which is only given when it differs.
+    let sender_addresses: Vec<String> = parsed_email
+        .from
+        .iter()
+        .chain(iter::once(&parsed_email.sender))
+        .map(|mailbox| address_to_string(&mailbox.address))
+        .collect();
+    let (mut from_address, identity_id) = match get_identity_id(
+        &sender_addresses
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>(),
+        config,
+        remote,
+    ) {
+        Ok((address, identity_id)) => (address.to_string(), identity_id),
+        Err(e @ Error::NoIdentitiesForSender { .. }) => {
+            let default_identity = match &config.sending.default_identity {
+                Some(x) => x,
+                None => return Err(e),
+            };
+            let identity = remote
+                .get_identities()
+                .context(GetIdentitiesSnafu {})?
+                .into_iter()
+                .find(|x| x.id.0 == *default_identity || x.email == *default_identity)
+                .context(UnknownDefaultIdentitySnafu {
+                    identity: default_identity,
+                })?;
+            warn!(
+                "{e}; sending as default identity `{} <{}>'",
+                identity.name, identity.email
+            );
+            // A wildcard identity can't be an envelope sender, so fall back to the sender of the
+            // message for those.
+            let address = if identity.email.starts_with("*@") {
+                sender_addresses.first().cloned().unwrap_or_default()
+            } else {
+                identity.email
+            };
+            (address, identity.id)
+        }
+        Err(e) => return Err(e),
+    };
+
+    // Only use the envelope sender given with `-f` if the user could also send mail from it.
+    if let Some(envelope_from) = &queued_email.envelope_from {
+        match get_identity_id(&[envelope_from], config, remote) {
+            Ok(_) => from_address = envelope_from.clone(),
+            Err(e @ Error::NoIdentitiesForSender { .. }) => {
+                warn!("Ignoring envelope sender `{envelope_from}': {e}");
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
     debug!(
         "Envelope sender is `{}', recipients are `{:?}'",
-        from_address, to_addresses
+        from_address, queued_email.recipients
     );
 
     // Create the email!
-    remote
+    let save_to_sent = config.sending.save_to_sent;
+    let sent_email = remote
         .send_email(
             identity_id,
-            &mailboxes,
+            mailboxes,
             &from_address,
-            &to_addresses,
-            &email_string,
+            &queued_email.recipients,
+            &queued_email.email,
+            release,
+            &queued_email.dsn,
+            save_to_sent != SaveToSent::Never,
         )
         .context(SendEmailSnafu {})?;
 
-    Ok(())
+    // Some servers file a copy of sent mail on their own, in which case ours is a duplicate.
+    if save_to_sent == SaveToSent::Auto && !matches!(release, Release::At(_)) {
+        if let Some(message_id) = &queued_email.message_id {
+            match remote.destroy_duplicate_sent_email(&sent_email.email_id, message_id) {
+                Ok(true) => debug!("Server filed its own copy of the sent email; destroyed ours"),
+                Ok(false) => {}
+                Err(e) => warn!("Could not check for a duplicate sent email: {e}"),
+            }
+        }
+    }
+
+    // Mark the message being replied to or forwarded so that every client shows it as such right
+    // away. This isn't worth failing over, since the email has already been sent.
+    let (keyword, message_ids) = referenced_message_ids(&parsed_email);
+    if !message_ids.is_empty() {
+        if let Err(e) = remote.add_keyword_by_message_id(&message_ids, keyword) {
+            warn!("Could not add `{keyword}' to the original message: {e}");
+        }
+    }
+
+    Ok((sent_email, release))
 }
 
-fn get_identity_id_for_sender_address(
-    sender_address: &email_parser::address::EmailAddress,
+/// Return the keyword to add to the messages an email replies to or forwards, along with their
+/// `Message-ID`s.
+///
+/// Replies name the message they reply to in `In-Reply-To`. Forwards, recognized by their subject,
+/// usually name the forwarded message last in `References`, if at all.
+fn referenced_message_ids(email: &email_parser::email::Email) -> (&'static str, Vec<String>) {
+    let to_string = |(left, right): &(Cow<str>, Cow<str>)| format!("{left}@{right}");
+    let is_forward = email.subject.as_ref().is_some_and(|subject| {
+        let subject = subject.trim_start().to_ascii_lowercase();
+        subject.starts_with("fwd:") || subject.starts_with("fw:")
+    });
+    if is_forward {
+        let message_ids = email
+            .references
+            .iter()
+            .flat_map(|references| references.last())
+            .map(to_string)
+            .collect();
+        ("$forwarded", message_ids)
+    } else {
+        let message_ids = email.in_reply_to.iter().flatten().map(to_string).collect();
+        ("$answered", message_ids)
+    }
+}
+
+/// Find the identity to send mail as, trying each of the given addresses in order, and return the
+/// matching address along with the identity's ID.
+///
+/// Addresses matching a pattern in the `identities` configuration option use the configured
+/// identity. Otherwise, an identity with exactly the same address is preferred, followed by one
+/// whose address is the address without its `+` subaddress, followed by a wildcard identity for the
+/// address's domain.
+fn get_identity_id<'a>(
+    addresses: &[&'a str],
+    config: &Config,
     remote: &mut Remote,
-) -> Result<jmap::Id> {
-    let sender_local_part = &sender_address.local_part;
-    let sender_domain = &sender_address.domain;
-    let sender_fqdn = FQDN::from_str(sender_domain.as_ref()).context(ParseSenderDomainSnafu {
-        domain: sender_domain.as_ref(),
-    })?;
-    debug!(
-        "Sender is `{}@{}', fqdn `{}'",
-        sender_local_part, sender_domain, sender_fqdn
-    );
+) -> Result<(&'a str, jmap::Id)> {
+    for &address in addresses {
+        // Prefer exact patterns, then the most specific wildcard pattern.
+        let configured_id = config
+            .identities
+            .iter()
+            .filter(|(pattern, _)| address_matches_pattern(address, pattern))
+            .max_by_key(|(pattern, _)| (!pattern.contains('*'), pattern.len()))
+            .map(|(_, id)| id);
+        if let Some(id) = configured_id {
+            debug!("Configured JMAP identity for `{address}' is `{id}'");
+            return Ok((address, jmap::Id(id.clone())));
+        }
+    }
 
-    // Find the identity which matches the sender of this email.
     let identities = remote.get_identities().context(GetIdentitiesSnafu {})?;
-    let sender_identities: Vec<_> = identities
+    let identities = identities
         .iter()
         .map(|identity| {
             let (local_part, domain) =
@@ -165,37 +799,238 @@ fn get_identity_id_for_sender_address(
             let fqdn = FQDN::from_str(domain).context(ParseIdentityDomainSnafu { domain })?;
             Ok((identity, local_part, fqdn))
         })
-        .collect::<Result<Vec<_>>>()?
-        .into_iter()
-        .filter(|(_, local_part, fqdn)| {
-            *fqdn == sender_fqdn && (*local_part == "*" || *local_part == sender_local_part)
-        })
-        .map(|(identity, local_part, _)| (identity, local_part))
-        .collect();
-    ensure!(
-        !sender_identities.is_empty(),
-        NoIdentitiesForSenderSnafu {
-            sender: address_to_string(&sender_address),
+        .collect::<Result<Vec<_>>>()?;
+
+    for &address in addresses {
+        let (sender_local_part, sender_domain) = match address.split_once('@') {
+            Some(x) => x,
+            None => continue,
+        };
+        let sender_fqdn = FQDN::from_str(sender_domain).context(ParseSenderDomainSnafu {
+            domain: sender_domain,
+        })?;
+        let sender_base_local_part = sender_local_part
+            .split_once('+')
+            .map_or(sender_local_part, |(base, _)| base);
+        debug!("Sender is `{address}', fqdn `{sender_fqdn}'");
+
+        let identity = identities
+            .iter()
+            .filter(|(_, _, fqdn)| *fqdn == sender_fqdn)
+            .filter_map(|(identity, local_part, _)| {
+                let rank = if *local_part == sender_local_part {
+                    3
+                } else if *local_part == sender_base_local_part {
+                    2
+                } else if *local_part == "*" {
+                    1
+                } else {
+                    return None;
+                };
+                Some((identity, rank))
+            })
+            .max_by_key(|(_, rank)| *rank)
+            .map(|(identity, _)| identity);
+        if let Some(identity) = identity {
+            debug!("JMAP identity for sender is `{:?}'", identity);
+            return Ok((address, identity.id.clone()));
         }
-    );
-    // Prefer a concrete identity over a wildcard.
-    let identity = sender_identities
-        .iter()
-        .filter(|(_, local_part)| *local_part != "*")
-        .map(|(identity, _)| identity)
-        .next()
-        .unwrap_or_else(|| {
-            sender_identities
-                .first()
-                .map(|(identity, _)| identity)
-                .unwrap()
-        });
-    debug!("JMAP identity for sender is `{:?}'", identity);
+    }
+
+    NoIdentitiesForSenderSnafu {
+        sender: addresses.join("', `"),
+    }
+    .fail()
+}
 
-    // TODO: avoid clone here?
-    Ok(identity.id.clone())
+/// Return true if the address matches the pattern, in which `*` matches any number of characters.
+/// Both are compared case-insensitively.
+fn address_matches_pattern(address: &str, pattern: &str) -> bool {
+    let regex = pattern
+        .split('*')
+        .map(regex::escape)
+        .collect::<Vec<_>>()
+        .join(".*");
+    Regex::new(&format!("(?i)^{regex}$")).is_ok_and(|regex| regex.is_match(address))
 }
 
 fn address_to_string(address: &email_parser::address::EmailAddress) -> String {
     format!("{}@{}", address.local_part, address.domain)
 }
+
+/// Return the header section of a message with DOS newlines in a form which `email_parser` can
+/// parse.
+///
+/// The body is left out, since it may contain anything. If the header isn't valid UTF-8, e.g.
+/// because it contains raw Latin-1 display names, the offending bytes are replaced with `?`.
+fn parseable_header(email: &[u8]) -> Cow<'_, [u8]> {
+    let header_len = email
+        .windows(4)
+        .position(|x| x == b"\r\n\r\n")
+        .map_or(email.len(), |i| i + 2);
+    let header = &email[..header_len];
+    if std::str::from_utf8(header).is_ok() {
+        Cow::Borrowed(header)
+    } else {
+        Cow::Owned(
+            header
+                .iter()
+                .map(|&x| if x.is_ascii() { x } else { b'?' })
+                .collect(),
+        )
+    }
+}
+
+/// Remove all `Bcc` fields from the header of a message with DOS newlines.
+fn strip_bcc(email: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(email.len());
+    let mut in_bcc = false;
+    let mut lines = email.split_inclusive(|&x| x == b'\n');
+    for line in lines.by_ref() {
+        if line == b"\r\n" {
+            // The header ends at the first empty line.
+            output.extend_from_slice(line);
+            break;
+        }
+        // Continuation lines of a folded field begin with whitespace.
+        if !line.starts_with(b" ") && !line.starts_with(b"\t") {
+            in_bcc = line
+                .splitn(2, |&x| x == b':')
+                .next()
+                .is_some_and(|name| name.trim_ascii().eq_ignore_ascii_case(b"Bcc"));
+        }
+        if !in_bcc {
+            output.extend_from_slice(line);
+        }
+    }
+    output.extend(lines.flatten());
+    output
+}
+
+fn serialize_base64<S: serde::Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&base64::encode(bytes))
+}
+
+fn deserialize_base64<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<u8>, D::Error> {
+    let string = String::deserialize(deserializer)?;
+    base64::decode(string).map_err(serde::de::Error::custom)
+}
+
+/// Parse the events to send delivery status notifications for, e.g. `success,failure`, into the
+/// form of the SMTP `NOTIFY` parameter.
+fn parse_dsn_notify(notify: &str) -> Option<String> {
+    let events: Vec<String> = notify
+        .split(',')
+        .map(|event| event.trim().to_ascii_uppercase())
+        .collect();
+    let valid = if events.iter().any(|event| event == "NEVER") {
+        events.len() == 1
+    } else {
+        events
+            .iter()
+            .all(|event| ["SUCCESS", "FAILURE", "DELAY"].contains(&event.as_str()))
+    };
+    valid.then(|| events.join(","))
+}
+
+/// Parse a time to send mail at, such as `2024-06-01T09:00+02:00`. The time must end with `Z` or a
+/// UTC offset.
+fn parse_send_at(send_at: &str) -> Option<SystemTime> {
+    lazy_static! {
+        static ref SEND_AT_REGEX: Regex = Regex::new(
+            r"^(\d{4})-(\d{2})-(\d{2})[T ](\d{2}):(\d{2})(?::(\d{2}))?(Z|([+-])(\d{2}):(\d{2}))$"
+        )
+        .unwrap();
+    }
+    let captures = SEND_AT_REGEX.captures(send_at.trim())?;
+    let field = |i| {
+        captures
+            .get(i)
+            .map_or(Some(0), |x| x.as_str().parse::<i64>().ok())
+    };
+    let (year, month, day) = (field(1)?, field(2)?, field(3)?);
+    let (hour, minute, second) = (field(4)?, field(5)?, field(6)?);
+    if !(1..=12).contains(&month)
+        || !(1..=days_in_month(year, month)).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return None;
+    }
+
+    // `Z` has no offset fields, which count as zero.
+    let offset = (field(9)? * 3600 + field(10)? * 60)
+        * if captures.get(8).map(|x| x.as_str()) == Some("-") {
+            -1
+        } else {
+            1
+        };
+    let secs =
+        days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset;
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+/// Return the number of days in the given month, which is from 1 to 12.
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Return the number of days since the Unix epoch of a civil date. See
+/// <http://howardhinnant.github.io/date_algorithms.html#days_from_civil>.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(send_at: &str) -> Option<u64> {
+        parse_send_at(send_at).map(|x| x.duration_since(UNIX_EPOCH).unwrap().as_secs())
+    }
+
+    #[test]
+    fn send_at_with_utc_or_offset() {
+        assert_eq!(secs("2024-06-01T09:00Z"), Some(1717232400));
+        assert_eq!(secs("2024-06-01T09:00+02:00"), Some(1717225200));
+        assert_eq!(secs("2024-06-01 09:00:30-05:30"), Some(1717252230));
+    }
+
+    #[test]
+    fn send_at_on_leap_days() {
+        assert_eq!(secs("2024-02-29T00:00Z"), Some(1709164800));
+        assert_eq!(secs("2000-02-29T00:00Z"), Some(951782400));
+        assert_eq!(secs("2023-02-29T00:00Z"), None);
+        assert_eq!(secs("1900-02-29T00:00Z"), None);
+    }
+
+    #[test]
+    fn invalid_send_at_is_rejected() {
+        for send_at in [
+            "2024-02-31T09:00Z",
+            "2024-04-31T09:00Z",
+            "2024-13-01T09:00Z",
+            "2024-06-00T09:00Z",
+            "2024-06-01T24:00Z",
+            "2024-06-01T09:60Z",
+            "2024-06-01T09:00",
+            "1969-12-31T23:59Z",
+            "tomorrow",
+        ] {
+            assert_eq!(secs(send_at), None, "{send_at}");
+        }
+    }
+}
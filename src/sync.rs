@@ -1,33 +1,35 @@
-use crate::args::Args;
 use crate::cache::{self, Cache};
+use crate::config;
+use crate::crypto;
+use crate::lock::{self, Lock};
+use crate::observer::SyncObserver;
 use crate::remote::{self, Remote};
 use crate::{config::Config, local::Local};
-use crate::{jmap, local};
-use atty::Stream;
-use fslock::LockFile;
-use indicatif::ProgressBar;
-use log::{debug, error, warn};
+use crate::{jmap, journal, line_endings, local, send};
+use either::Either;
+use log::{debug, error};
 use rayon::{prelude::*, ThreadPoolBuildError};
 use serde::{Deserialize, Serialize};
 use snafu::prelude::*;
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs::{self, File};
-use std::io::Write;
-use std::io::{self, BufReader, BufWriter};
+use std::io::{self, Read, Write};
+use std::iter;
+use std::panic;
 use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use symlink::symlink_file;
-use termcolor::{ColorSpec, StandardStream, WriteColor};
 
 #[derive(Debug, Snafu)]
 pub enum Error {
-    #[snafu(display("Could not open lock file `{}': {}", path.to_string_lossy(), source))]
-    OpenLockFile { path: PathBuf, source: io::Error },
-
-    #[snafu(display("Could not lock: {}", source))]
-    Lock { source: io::Error },
-
-    #[snafu(display("Could not log string: {}", source))]
-    Log { source: io::Error },
+    #[snafu(display("Could not lock `{}': {}", path.to_string_lossy(), source))]
+    Lock { path: PathBuf, source: io::Error },
 
     #[snafu(display("Could not read mujmap state file `{}': {}", filename.to_string_lossy(), source))]
     ReadStateFile {
@@ -35,6 +37,30 @@ pub enum Error {
         source: io::Error,
     },
 
+    #[snafu(display(
+        "mujmap state file `{}' is encrypted, but `encrypt_cache' is not enabled",
+        filename.to_string_lossy()
+    ))]
+    EncryptedStateFile { filename: PathBuf },
+
+    #[snafu(display("Could not decrypt mujmap state file `{}': {}", filename.to_string_lossy(), source))]
+    DecryptStateFile {
+        filename: PathBuf,
+        source: crypto::Error,
+    },
+
+    #[snafu(display("Could not encrypt mujmap state file `{}': {}", filename.to_string_lossy(), source))]
+    EncryptStateFile {
+        filename: PathBuf,
+        source: crypto::Error,
+    },
+
+    #[snafu(display("Could not get password from config: {}", source))]
+    GetPassword { source: config::Error },
+
+    #[snafu(display("Could not derive encryption key: {}", source))]
+    DeriveKey { source: crypto::Error },
+
     #[snafu(display("Could not parse mujmap state file `{}': {}", filename.to_string_lossy(), source))]
     ParseStateFile {
         filename: PathBuf,
@@ -68,12 +94,18 @@ pub enum Error {
     #[snafu(display("JMAP server is missing mailboxes for these tags: {:?}", tags))]
     MissingMailboxes { tags: Vec<String> },
 
+    #[snafu(display("Could not create archive mailbox: {}", source))]
+    CreateArchiveMailbox { source: remote::Error },
+
     #[snafu(display("Could not create missing mailboxes for tags `{:?}': {}", tags, source))]
     CreateMailboxes {
         tags: Vec<String>,
         source: remote::Error,
     },
 
+    #[snafu(display("Could not rename tags of renamed mailboxes: {}", source))]
+    RenameLocalTags { source: local::Error },
+
     #[snafu(display("Could not index notmuch tags: {}", source))]
     IndexTags { source: notmuch::Error },
 
@@ -95,12 +127,24 @@ pub enum Error {
     #[snafu(display("Could not save email to cache: {}", source))]
     CacheNewEmail { source: cache::Error },
 
+    #[snafu(display("Could not index mail files in maildir: {}", source))]
+    IndexMailFiles { source: local::Error },
+
+    #[snafu(display("Could not recover mail file `{}': {}", path.to_string_lossy(), source))]
+    RecoverMailFile { path: PathBuf, source: cache::Error },
+
+    #[snafu(display("Could not link existing mail file `{}' into cache: {}", path.to_string_lossy(), source))]
+    LinkExistingMessage { path: PathBuf, source: cache::Error },
+
     #[snafu(display("Missing last notmuch database revision"))]
     MissingNotmuchDatabaseRevision {},
 
     #[snafu(display("Could not index local updated emails: {}", source))]
     IndexLocalUpdatedEmails { source: local::Error },
 
+    #[snafu(display("Could not index local drafts: {}", source))]
+    IndexLocalDrafts { source: local::Error },
+
     #[snafu(display("Could not add new local email `{}': {}", filename.to_string_lossy(), source))]
     AddLocalEmail {
         filename: PathBuf,
@@ -113,6 +157,15 @@ pub enum Error {
     #[snafu(display("Could not remove local email: {}", source))]
     RemoveLocalEmail { source: notmuch::Error },
 
+    #[snafu(display("Could not rename local email for its new blob ID: {}", source))]
+    RenameLocalEmail { source: local::Error },
+
+    #[snafu(display(
+        "Could not move local email to the maildir of its mailboxes: {}",
+        source
+    ))]
+    MoveLocalEmail { source: local::Error },
+
     #[snafu(display("Could not get local message from notmuch: {}", source))]
     GetNotmuchMessage { source: notmuch::Error },
 
@@ -145,6 +198,9 @@ pub enum Error {
     #[snafu(display("Could not remove mail file `{}': {}", path.to_string_lossy(), source))]
     RemoveMailFile { path: PathBuf, source: io::Error },
 
+    #[snafu(display("Could not flush maildir `{}' to disk: {}", path.to_string_lossy(), source))]
+    SyncMaildir { path: PathBuf, source: io::Error },
+
     #[snafu(display("Could not begin atomic database operation: {}", source))]
     BeginAtomic { source: notmuch::Error },
 
@@ -154,16 +210,58 @@ pub enum Error {
     #[snafu(display("Could not push changes to JMAP server: {}", source))]
     PushChanges { source: remote::Error },
 
+    #[snafu(display("Could not destroy empty JMAP mailboxes: {}", source))]
+    DestroyMailboxes { source: remote::Error },
+
+    #[snafu(display(
+        "Could not download {} emails, which the next sync tries again: {}",
+        ids.len(),
+        ids.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+    ))]
+    IncompleteDownloads { ids: Vec<jmap::Id> },
+
+    #[snafu(display("Could not run spam training command `{}': {}", command, source))]
+    ExecuteLearnCommand { command: String, source: io::Error },
+
+    #[snafu(display(
+        "Spam training command `{}' exited with `{}': {}",
+        command,
+        status,
+        stderr
+    ))]
+    LearnCommandStatus {
+        command: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+
     #[snafu(display("Programmer error!"))]
     ProgrammerError {},
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// File in the maildir which holds the `LatestState`. It has a lock of its own, so that commands
+/// which only read it needn't take the sync lock.
+pub const STATE_FILE: &str = "mujmap.state.json";
+
+/// The windows of a progressive backfill, given as the maximum age in days of the mail synced in
+/// each. The final window syncs everything.
+const BACKFILL_WINDOWS: [Option<u64>; 4] = [Some(7), Some(30), Some(365), None];
+
+/// The number of new emails to add to the notmuch database at once.
+const INDEX_BATCH_SIZE: usize = 500;
+
+/// How long to wait for more new emails before adding an incomplete batch to the notmuch database.
+const INDEX_BATCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Warning for an account which turns out to be read-only only once changes are pushed to it.
+const READ_ONLY_WARNING: &str = "JMAP account became read-only; local changes will not be pushed";
+
 /// A new email to be eventually added to the maildir.
 #[derive(Debug)]
-pub struct NewEmail<'a> {
-    pub remote_email: &'a remote::Email,
+pub struct NewEmail {
+    pub remote_email: remote::Email,
     pub cache_path: PathBuf,
     pub maildir_path: PathBuf,
 }
@@ -174,310 +272,987 @@ pub struct LatestState {
     pub notmuch_revision: Option<u64>,
     /// Latest JMAP Email state returned by `Email/get`.
     pub jmap_state: Option<jmap::State>,
+    /// The tag of each mailbox as of the last sync, used to detect mailboxes which were renamed.
+    #[serde(default)]
+    pub mailbox_tags: HashMap<jmap::Id, String>,
+    /// Progress of the progressive backfill of the initial sync, if one is underway.
+    #[serde(default)]
+    pub backfill: Option<Backfill>,
+    /// Local drafts which were uploaded to the server, keyed by their `Message-ID`.
+    #[serde(default)]
+    pub pushed_drafts: HashMap<String, PushedDraft>,
+    /// Request chunk sizes learned from the server by the last sync.
+    #[serde(default)]
+    pub chunk_sizes: Option<remote::ChunkSizes>,
+    /// Summary of every `Email` on the server as of `jmap_state`, if the server couldn't calculate
+    /// the changes since the sync before. The next sync finds the changes by comparing against it.
+    #[serde(default)]
+    pub email_summaries: Option<HashMap<jmap::Id, remote::EmailSummary>>,
+    /// When each `Email` only in the trash or spam mailbox was first found there, in seconds since
+    /// the Unix epoch, for `expunge`.
+    #[serde(default)]
+    pub expunge_found_at: HashMap<jmap::Id, u64>,
+}
+
+/// A local draft which was uploaded to the server by `push_drafts`.
+#[derive(Serialize, Deserialize)]
+pub struct PushedDraft {
+    /// The ID of the `Email` created for the draft.
+    pub email_id: jmap::Id,
+    /// SHA-256 digest of the draft's file when it was uploaded, used to detect edits.
+    pub digest: String,
+}
+
+/// Progress of a progressive backfill, which performs the initial sync in windows of
+/// increasingly old mail.
+#[derive(Serialize, Deserialize)]
+pub struct Backfill {
+    /// Index into `BACKFILL_WINDOWS` of the next window to sync.
+    pub window: usize,
+    /// JMAP `Email/get` state from before the backfill started. Once the backfill is complete,
+    /// changes are resolved from this state, so nothing which changed during the backfill is
+    /// missed.
+    pub jmap_state: jmap::State,
+}
+
+/// Summary of a completed sync, printed as a single parsable line for the benefit of scripts.
+pub struct SyncSummary {
+    /// Number of new emails added to the maildir.
+    pub new: usize,
+    /// Number of emails whose remote state was considered.
+    pub changed: usize,
+    /// Number of emails which were destroyed on the server.
+    pub destroyed: usize,
+    /// Number of emails pushed to the server.
+    pub pushed: usize,
+    /// Total wall-clock time of the sync.
+    pub duration: Duration,
+    /// Where the time went, if profiling was asked for.
+    pub profile: Option<Profile>,
+}
+
+/// Time spent in each part of a sync, and the amount of mail downloaded, to help tune
+/// `concurrent_downloads` and the like. Metadata retrieval, downloads, and adding new mail to the
+/// notmuch database happen at the same time, so their times overlap.
+#[derive(Debug, Default)]
+pub struct Profile {
+    /// Resolving the server and opening the JMAP session.
+    pub session: Duration,
+    /// Indexing the mail in the notmuch database.
+    pub local_index: Duration,
+    /// Retrieving the metadata of changed `Email`s.
+    pub metadata: Duration,
+    /// Downloading new mail, summed over all concurrent downloads.
+    pub downloads: Duration,
+    /// Applying changes to the notmuch database.
+    pub merge: Duration,
+    /// Pushing local changes to the server.
+    pub push: Duration,
+    /// Bytes of mail downloaded.
+    pub downloaded_bytes: u64,
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "mujmap: profile session={:.1}s local_index={:.1}s metadata={:.1}s downloads={:.1}s \
+             merge={:.1}s push={:.1}s downloaded_bytes={}",
+            self.session.as_secs_f64(),
+            self.local_index.as_secs_f64(),
+            self.metadata.as_secs_f64(),
+            self.downloads.as_secs_f64(),
+            self.merge.as_secs_f64(),
+            self.push.as_secs_f64(),
+            self.downloaded_bytes
+        )
+    }
+}
+
+impl fmt::Display for SyncSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "mujmap: ok new={} changed={} destroyed={} pushed={} duration={:.1}s",
+            self.new,
+            self.changed,
+            self.destroyed,
+            self.pushed,
+            self.duration.as_secs_f64()
+        )
+    }
 }
 
 impl LatestState {
-    fn open(filename: impl AsRef<Path>) -> Result<Self> {
+    /// Open the state file, decrypting it with `key` if it's encrypted.
+    ///
+    /// The state file has a lock of its own, so that it can be read without waiting for a sync to
+    /// finish.
+    pub fn open(filename: impl AsRef<Path>, key: Option<&crypto::Key>) -> Result<Self> {
         let filename = filename.as_ref();
-        let file = File::open(filename).context(ReadStateFileSnafu { filename })?;
-        let reader = BufReader::new(file);
-        serde_json::from_reader(reader).context(ParseStateFileSnafu { filename })
+        let lock_path = filename.with_extension("lock");
+        let _lock = Lock::acquire(&lock_path).context(LockSnafu { path: &lock_path })?;
+        let mut contents = fs::read(filename).context(ReadStateFileSnafu { filename })?;
+        if crypto::is_encrypted(&contents) {
+            let key = key.context(EncryptedStateFileSnafu { filename })?;
+            contents = key
+                .decrypt(&contents)
+                .context(DecryptStateFileSnafu { filename })?;
+        }
+        serde_json::from_slice(&contents).context(ParseStateFileSnafu { filename })
     }
 
-    fn save(&self, filename: impl AsRef<Path>) -> Result<()> {
+    /// Save the state to the given file. The state is written to a temporary file first and then
+    /// renamed into place, so the file is never left partially written. If `fsync` is true, it's
+    /// also flushed to disk. If `key` is given, the state is encrypted with it.
+    pub fn save(
+        &self,
+        filename: impl AsRef<Path>,
+        fsync: bool,
+        key: Option<&crypto::Key>,
+    ) -> Result<()> {
         let filename = filename.as_ref();
-        let file = File::create(filename).context(CreateStateFileSnafu { filename })?;
-        let writer = BufWriter::new(file);
-        serde_json::to_writer(writer, self).context(WriteStateFileSnafu { filename })
+        let lock_path = filename.with_extension("lock");
+        let _lock = Lock::acquire(&lock_path).context(LockSnafu { path: &lock_path })?;
+        let temporary_filename = filename.with_extension("json.tmp");
+        let mut contents = serde_json::to_vec(self).context(WriteStateFileSnafu { filename })?;
+        if let Some(key) = key {
+            contents = key
+                .encrypt(&contents)
+                .context(EncryptStateFileSnafu { filename })?;
+        }
+        let mut file = File::create(&temporary_filename).context(CreateStateFileSnafu {
+            filename: &temporary_filename,
+        })?;
+        file.write_all(&contents).context(CreateStateFileSnafu {
+            filename: &temporary_filename,
+        })?;
+        if fsync {
+            file.sync_all().context(CreateStateFileSnafu {
+                filename: &temporary_filename,
+            })?;
+        }
+        fs::rename(&temporary_filename, filename).context(CreateStateFileSnafu { filename })?;
+        if fsync {
+            if let Some(dir) = filename.parent() {
+                cache::sync_dir(dir).context(CreateStateFileSnafu { filename })?;
+            }
+        }
+        Ok(())
     }
 
     fn empty() -> Self {
         Self {
             notmuch_revision: None,
             jmap_state: None,
+            mailbox_tags: HashMap::new(),
+            backfill: None,
+            pushed_drafts: HashMap::new(),
+            chunk_sizes: None,
+            email_summaries: None,
+            expunge_found_at: HashMap::new(),
         }
     }
 }
 
+/// Options which control what `sync` does.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options {
+    /// Test a sync without committing any changes.
+    pub dry_run: bool,
+    /// Don't contact the server. Local changes are pushed by the next sync which isn't offline.
+    pub offline: bool,
+    /// Pull changes from the server, rather than only pushing local changes.
+    pub pull: bool,
+    /// Discard the intermediary state and rebuild it from the server, reusing mail files which
+    /// are already in the maildir.
+    pub resync: bool,
+    /// Download at most this many new emails, leaving the rest for the next sync.
+    pub max_messages: Option<usize>,
+    /// Measure the time spent in each part of the sync and report it when finished.
+    pub profile: bool,
+    /// Carry on even if the notmuch database revision of the last sync is missing, in which case
+    /// local changes made since then are not pushed.
+    pub accept_missing_revision: bool,
+}
+
+/// Synchronize the maildir at `mail_dir` with the server.
 pub fn sync(
-    stdout: &mut StandardStream,
-    info_color_spec: ColorSpec,
+    observer: &dyn SyncObserver,
     mail_dir: PathBuf,
-    args: Args,
-    config: Config,
-    pull: bool,
+    options: Options,
+    config: &Config,
 ) -> Result<(), Error> {
-    // Grab lock.
-    let lock_file_path = mail_dir.join("mujmap.lock");
-    let mut lock = LockFile::open(&lock_file_path).context(OpenLockFileSnafu {
-        path: lock_file_path,
-    })?;
-    let is_locked = lock.try_lock().context(LockSnafu {})?;
-    if !is_locked {
-        println!("Lock file owned by another process. Waiting...");
-        lock.lock().context(LockSnafu {})?;
+    let start_time = Instant::now();
+    let key = open_key(&mail_dir, config)?;
+
+    // Working offline only reports on the maildir, so it neither contacts the server nor waits for
+    // a running sync to finish.
+    if options.offline {
+        let latest_state = LatestState::open(mail_dir.join(STATE_FILE), key.as_ref())
+            .unwrap_or_else(|e| {
+                observer.warning(&e.to_string());
+                LatestState::empty()
+            });
+        let local = Local::open(&mail_dir, true, config).context(OpenLocalSnafu {})?;
+        return report_offline(observer, &local, &latest_state, start_time);
     }
 
-    // Load the intermediary state.
-    let latest_state_filename = mail_dir.join("mujmap.state.json");
-    let latest_state = LatestState::open(&latest_state_filename).unwrap_or_else(|e| {
-        warn!("{e}");
+    // A progressive backfill syncs one window at a time, and many changes are synced a batch at a
+    // time, committing after each.
+    while sync_once(observer, &mail_dir, &options, config, key.as_ref())? {}
+    Ok(())
+}
+
+/// Derive the key used to encrypt the cache and state file, if encryption is enabled.
+pub fn open_key(mail_dir: &Path, config: &Config) -> Result<Option<crypto::Key>> {
+    if !config.encrypt_cache {
+        return Ok(None);
+    }
+    let password = config.password().context(GetPasswordSnafu {})?;
+    let key =
+        crypto::Key::derive(&password, &mail_dir.join("mujmap.salt")).context(DeriveKeySnafu {})?;
+    Ok(Some(key))
+}
+
+/// Perform a single sync. Returns true if another sync should be performed, either for the next
+/// window of a progressive backfill or for the next batch of changes on the server.
+fn sync_once(
+    observer: &dyn SyncObserver,
+    mail_dir: &Path,
+    options: &Options,
+    config: &Config,
+    key: Option<&crypto::Key>,
+) -> Result<bool> {
+    let Options {
+        dry_run,
+        pull,
+        resync,
+        max_messages,
+        profile: report_profile,
+        ..
+    } = *options;
+    let start_time = Instant::now();
+    let mut profile = Profile::default();
+
+    let _lock = lock_sync(mail_dir, observer)?;
+
+    // Load the intermediary state. A resync discards it entirely and rebuilds it from scratch.
+    let latest_state_filename = mail_dir.join(STATE_FILE);
+    let latest_state = if resync {
         LatestState::empty()
-    });
+    } else {
+        LatestState::open(&latest_state_filename, key).unwrap_or_else(|e| {
+            observer.warning(&e.to_string());
+            LatestState::empty()
+        })
+    };
 
-    // Open the local notmuch database.
-    let local = Local::open(mail_dir, args.dry_run || !pull).context(OpenLocalSnafu {})?;
+    // Open the local notmuch database. Only a sync which changes the maildir keeps its index up to
+    // date, encrypted like the cache.
+    let mut local = Local::open(mail_dir, dry_run || !pull, &config).context(OpenLocalSnafu {})?;
+    if !dry_run && pull {
+        local.persist_index(key.cloned());
+    }
 
     // Open the local cache.
-    let cache = Cache::open(&local.mail_cur_dir, &config).context(OpenCacheSnafu {})?;
+    let cache =
+        Cache::open(&local.mail_cur_dir, config, key.cloned()).context(OpenCacheSnafu {})?;
+
+    // Open the remote session. If the server can't be reached, e.g. because we're on a plane, there's
+    // nothing more to do. Local changes are tracked by the notmuch revision in the state file, so
+    // they'll be pushed by the next sync which can reach the server.
+    let session_start = Instant::now();
+    let remote = Remote::open(config);
+    profile.session = session_start.elapsed();
+    let mut remote = match remote {
+        Ok(remote) => remote,
+        Err(e) if e.is_unreachable() => {
+            debug!("Could not reach server: {e}");
+            report_offline(observer, &local, &latest_state, start_time)?;
+            return Ok(false);
+        }
+        Err(e) => return Err(e).context(OpenRemoteSnafu {}),
+    };
+
+    if let Some(chunk_sizes) = latest_state.chunk_sizes {
+        remote.learn_chunk_sizes(chunk_sizes);
+    }
 
-    // Open the remote session.
-    let mut remote = Remote::open(&config).context(OpenRemoteSnafu {})?;
+    // Read-only accounts, e.g. shared mailboxes with read-only delegation, can only be pulled from.
+    let mut read_only = remote.is_read_only();
+    if read_only {
+        observer.warning("JMAP account is read-only; local changes will not be pushed");
+    }
 
-    // List all remote mailboxes and convert them to notmuch tags.
-    let mut mailboxes = remote
-        .get_mailboxes(&config.tags)
+    // Continue a progressive backfill if one is underway, or start one if this is the initial sync.
+    // Resyncs always consider everything at once.
+    let backfill = pull
+        && !resync
+        && (latest_state.backfill.is_some()
+            || (config.progressive_backfill && latest_state.notmuch_revision.is_none()));
+
+    // List all remote mailboxes and convert them to notmuch tags. The `Email` IDs to sync are
+    // asked for in the same request, which saves a round trip on slow links. Changes made below,
+    // such as sending queued mail, are therefore pulled by the next sync.
+    let backfill_filter = backfill
+        .then(|| backfill_after(backfill_window(&latest_state)))
+        .flatten()
+        .map(|after| serde_json::json!({ "after": after }));
+    let email_ids_query = match &latest_state.jmap_state {
+        Some(jmap_state) => remote::EmailIdsQuery::Changes(jmap_state, config.max_changes),
+        None => remote::EmailIdsQuery::Query(backfill_filter.as_ref()),
+    };
+    let (mut mailboxes, email_ids) = remote
+        .get_mailboxes_and_email_ids(config, email_ids_query)
         .context(IndexMailboxesSnafu {})?;
     debug!("Got mailboxes: {:?}", mailboxes);
+    if !dry_run && !read_only {
+        remote
+            .create_archive_mailbox(&mut mailboxes)
+            .context(CreateArchiveMailboxSnafu {})?;
+    }
+
+    // Send any mail which was queued while the server was unreachable, and move scheduled mail
+    // which has been sent out of the drafts mailbox. This is never fatal.
+    if !dry_run && !read_only {
+        match send::flush_outbox(mail_dir, &mut remote, &mailboxes, config) {
+            Ok(0) => {}
+            Ok(sent) => observer.phase_started("Sent queued mail...", &format!("{sent} sent")),
+            Err(e) => observer.warning(&format!("Could not send queued mail: {e}")),
+        }
+        match remote.file_released_emails(
+            &mailboxes,
+            config.sending.save_to_sent != config::SaveToSent::Never,
+        ) {
+            Ok(0) => {}
+            Ok(sent) => observer.phase_started("Filing scheduled mail...", &format!("{sent} sent")),
+            Err(e) => observer.warning(&format!("Could not file sent scheduled mail: {e}")),
+        }
+    }
 
-    // Query local database for all email.
-    let local_emails = local.all_emails().context(IndexLocalEmailsSnafu {})?;
+    // Mailboxes which were renamed on the server keep their IDs, so rename their tags locally
+    // instead of treating them as brand new mailboxes.
+    let renamed_tags: Vec<(&str, &str)> = latest_state
+        .mailbox_tags
+        .iter()
+        .flat_map(|(id, old_tag)| {
+            mailboxes
+                .mailboxes_by_id
+                .get(id)
+                .filter(|mailbox| mailbox.tag != *old_tag)
+                .map(|mailbox| (old_tag.as_str(), mailbox.tag.as_str()))
+        })
+        .collect();
+    // Renaming tags bumps the revision of every message in the renamed mailboxes, so the messages
+    // which were really changed locally are noted first, lest all of them are pushed.
+    let mut changed_before_rename: Option<HashSet<String>> = None;
+    if pull && !renamed_tags.is_empty() {
+        observer.phase_started(
+            "Renaming tags of renamed mailboxes...",
+            &format!("{} renamed", renamed_tags.len()),
+        );
+        for (old_tag, new_tag) in &renamed_tags {
+            debug!("Mailbox tag `{old_tag}' renamed to `{new_tag}'");
+        }
+        if !dry_run {
+            changed_before_rename = Some(match latest_state.notmuch_revision {
+                Some(notmuch_revision) => local
+                    .all_emails_since(notmuch_revision)
+                    .context(IndexLocalUpdatedEmailsSnafu {})?
+                    .into_values()
+                    .map(|x| x.message_id)
+                    .collect(),
+                None => HashSet::new(),
+            });
+            local
+                .rename_tags(&renamed_tags)
+                .context(RenameLocalTagsSnafu {})?;
+        }
+    }
+
+    // Query the local database for all email while the server is queried for changes, since both
+    // can take a while.
+    let (local_emails, remote_changes) = thread::scope(|scope| {
+        let remote_changes = scope
+            .spawn(|| remote_changes(&mut remote, &latest_state, backfill, email_ids, observer));
+        let local_index_start = Instant::now();
+        let local_emails = local.all_emails().context(IndexLocalEmailsSnafu {});
+        profile.local_index = local_index_start.elapsed();
+        (
+            local_emails,
+            remote_changes
+                .join()
+                .unwrap_or_else(|e| panic::resume_unwind(e)),
+        )
+    });
+    let local_emails = local_emails?;
+    let (remote_changes, email_summaries) = remote_changes?;
 
     // Function which performs a full sync, i.e. a sync which considers all remote IDs as updated,
     // and determines destroyed IDs by finding the difference of all remote IDs from all local IDs.
-    let full_sync =
-        |remote: &mut Remote| -> Result<(jmap::State, HashSet<jmap::Id>, HashSet<jmap::Id>)> {
-            let (state, updated_ids) = remote.all_email_ids().context(IndexRemoteEmailsSnafu {})?;
-            // TODO can we optimize these two lines?
-            let local_ids: HashSet<jmap::Id> =
-                local_emails.iter().map(|(id, _)| id).cloned().collect();
-            let destroyed_ids = local_ids.difference(&updated_ids).cloned().collect();
-            Ok((state, updated_ids, destroyed_ids))
-        };
+    let full_sync = |state: jmap::State, updated_ids: HashSet<jmap::Id>| {
+        let destroyed_ids = local_emails
+            .keys()
+            .filter(|id| !updated_ids.contains(id))
+            .cloned()
+            .collect();
+        (state, updated_ids, destroyed_ids)
+    };
 
     // Create lists of updated and destroyed `Email` IDs. This is done in one of two ways, depending
     // on if we have a working JMAP `Email` state.
-    let (state, updated_ids, destroyed_ids) = latest_state
-        .jmap_state.clone()
-        .map(|jmap_state| {
-            match remote.changed_email_ids(jmap_state) {
-                Ok((state, created, mut updated, destroyed)) => {
-                    debug!("Remote changes: state={state}, created={created:?}, updated={updated:?}, destroyed={destroyed:?}");
-                    // If we have something in the updated set that isn't in the local database,
-                    // something must have gone wrong somewhere. Do a full sync instead.
-                    if !updated.iter().all(|x| local_emails.contains_key(x)) {
-                        warn!(
-                            "Server sent an update which references an ID we don't know about, doing a full sync instead");
-                        full_sync(&mut remote)
-                    } else {
-                        updated.extend(created);
-                        Ok((state, updated, destroyed))
-                    }
-                },
-                Err(e) => {
-                    // `Email/changes` failed, so fall back to `Email/query`.
-                    warn!(
-                        "Error while attempting to resolve changes, attempting full sync: {e}"
-                    );
-                    full_sync(&mut remote)
-                }
+    let mut next_backfill = None;
+    let mut has_more_changes = false;
+    let (state, mut updated_ids, mut destroyed_ids): (
+        jmap::State,
+        HashSet<jmap::Id>,
+        HashSet<jmap::Id>,
+    ) = match remote_changes {
+        RemoteChanges::Changed {
+            state,
+            created,
+            mut updated,
+            destroyed,
+            more,
+        } => {
+            debug!("Remote changes: state={state}, created={created:?}, updated={updated:?}, destroyed={destroyed:?}");
+            // If we have something in the updated set that isn't in the local database,
+            // something must have gone wrong somewhere. Do a full sync instead.
+            if !updated.iter().all(|x| local_emails.contains_key(x)) {
+                observer.warning(
+                        "Server sent an update which references an ID we don't know about, doing a full sync instead");
+                let (state, ids) = remote.all_email_ids().context(IndexRemoteEmailsSnafu {})?;
+                full_sync(state, ids)
+            } else {
+                has_more_changes = more;
+                updated.extend(created);
+                (state, updated, destroyed)
             }
-        })
-        .unwrap_or_else(|| full_sync(&mut remote))?;
-
-    // Retrieve the updated `Email` objects from the server.
-    stdout.set_color(&info_color_spec).context(LogSnafu {})?;
-    write!(stdout, "Retrieving metadata...").context(LogSnafu {})?;
-    stdout.reset().context(LogSnafu {})?;
-    writeln!(stdout, " ({} possibly changed)", updated_ids.len()).context(LogSnafu {})?;
-    stdout.flush().context(LogSnafu {})?;
-
-    let remote_emails = remote
-        .get_emails(updated_ids.iter(), &mailboxes, &config.tags)
-        .context(GetRemoteEmailsSnafu {})?;
+        }
+        RemoteChanges::All { state, ids } => full_sync(state, ids),
+        RemoteChanges::Backfill { state, ids, window } => {
+            match BACKFILL_WINDOWS[window] {
+                Some(days) => observer.phase_started(
+                    &format!("Backfilling mail received in the last {days} days..."),
+                    "",
+                ),
+                None => observer.phase_started("Backfilling all remaining mail...", ""),
+            }
+            if window + 1 < BACKFILL_WINDOWS.len() {
+                next_backfill = Some(Backfill {
+                    window: window + 1,
+                    jmap_state: state.clone(),
+                });
+                // Older mail simply hasn't been synced yet, so nothing can be destroyed.
+                (state, ids, HashSet::new())
+            } else {
+                full_sync(state, ids)
+            }
+        }
+    };
 
-    // Before merging, download the new files into the cache.
-    let mut new_emails: HashMap<jmap::Id, NewEmail> = remote_emails
-        .values()
-        .filter(|remote_email| match local_emails.get(&remote_email.id) {
-            Some(local_email) => local_email.blob_id != remote_email.blob_id,
-            None => true,
-        })
-        .map(|remote_email| {
-            (
-                remote_email.id.clone(),
-                NewEmail {
-                    remote_email,
-                    cache_path: cache.cache_path(&remote_email.id, &remote_email.blob_id),
-                    maildir_path: local.new_maildir_path(&remote_email.id, &remote_email.blob_id),
-                },
-            )
+    // Determine which local emails were changed since the last sync. These must be computed before
+    // any remote changes are applied to the database.
+    //
+    // A resync has no record of local changes, so the server's tags win.
+    let notmuch_revision = get_notmuch_revision(
+        local_emails.is_empty(),
+        &local,
+        if resync {
+            Some(local.revision())
+        } else {
+            latest_state.notmuch_revision
+        },
+        options.accept_missing_revision,
+    )?;
+    let mut updated_local_emails: HashMap<jmap::Id, local::Email> = local
+        .all_emails_since(notmuch_revision)
+        .context(IndexLocalUpdatedEmailsSnafu {})?
+        .into_iter()
+        // Filter out emails that were destroyed on the server.
+        .filter(|(id, _)| !destroyed_ids.contains(&id))
+        .filter(|(_, local_email)| {
+            changed_before_rename
+                .as_ref()
+                .is_none_or(|changed| changed.contains(&local_email.message_id))
         })
         .collect();
+    if read_only && !updated_local_emails.is_empty() {
+        // Local changes can never be pushed, so let the server's state win instead, fetching these
+        // emails even if they're unchanged on the server so that their local tags are rewritten.
+        observer.warning(&format!(
+            "Discarding local changes to {} emails in read-only account",
+            updated_local_emails.len()
+        ));
+        updated_ids.extend(updated_local_emails.drain().map(|(id, _)| id));
+    }
+    // Destroy old mail in the trash and spam mailboxes, which is then destroyed locally like any
+    // other mail destroyed on the server.
+    let expunge_found_at = if pull && !dry_run && !read_only {
+        let (expunged, expunge_found_at) = expunge(
+            &mut remote,
+            &mailboxes,
+            config,
+            &updated_local_emails,
+            &latest_state.expunge_found_at,
+            observer,
+        );
+        for id in expunged {
+            updated_ids.remove(&id);
+            if local_emails.contains_key(&id) {
+                destroyed_ids.insert(id);
+            }
+        }
+        Some(expunge_found_at)
+    } else {
+        None
+    };
+    // Local changes to excluded messages stay local, but must still not be overwritten by the
+    // server's tags, so they're only left out of the changes pushed.
+    let pushed_local_emails: HashMap<jmap::Id, local::Email> = if config.push_excluded {
+        updated_local_emails.clone()
+    } else {
+        updated_local_emails
+            .iter()
+            .filter(|(_, local_email)| local_email.tags.is_disjoint(&local.exclude_tags))
+            .map(|(id, local_email)| (id.clone(), local_email.clone()))
+            .collect()
+    };
+    if pushed_local_emails.len() < updated_local_emails.len() {
+        debug!(
+            "Not pushing changes to {} emails with excluded tags",
+            updated_local_emails.len() - pushed_local_emails.len()
+        );
+    }
 
-    let new_emails_missing_from_cache: Vec<&NewEmail> = new_emails
+    let mut summary = SyncSummary {
+        new: 0,
+        changed: 0,
+        destroyed: 0,
+        pushed: 0,
+        duration: Duration::ZERO,
+        profile: None,
+    };
+
+    // Retrieve the updated `Email` objects from the server. New mail is downloaded into the cache
+    // as soon as its metadata arrives, and added to the notmuch database in batches as soon as it
+    // has been downloaded, so that it's usable before the whole sync has finished.
+    observer.phase_started(
+        "Retrieving metadata and downloading new mail...",
+        &format!("{} possibly changed", updated_ids.len()),
+    );
+
+    // When resyncing, the maildir may contain files which notmuch has not indexed, e.g. because
+    // the database was rebuilt. Recover them into the cache instead of downloading them again.
+    let mail_files = if resync {
+        local.all_mail_files().context(IndexMailFilesSnafu {})?
+    } else {
+        HashMap::new()
+    };
+
+    // The server may have several emails with the same blob, e.g. a message imported twice. New
+    // ones share storage with a file the maildir already has for the blob.
+    let local_files_by_blob_id: HashMap<&jmap::Id, &Path> = local_emails
         .values()
-        .filter(|x| !x.cache_path.exists() && !local_emails.contains_key(&x.remote_email.id))
+        .map(|x| (&x.blob_id, x.path.as_path()))
+        .collect();
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(concurrent_downloads(&remote, config))
+        .build()
+        .context(CreateDownloadThreadPoolSnafu {})?;
+    let downloader = remote.downloader(config.max_email_size);
+    let serial_downloads = max_requests(&remote, config) == 1;
+    let merge = pull && !dry_run;
+
+    // Collect the local messages which will be destroyed. We will add to this list any messages
+    // with new blob IDs.
+    let mut destroyed_local_emails: Vec<&local::Email> = destroyed_ids
+        .iter()
+        .flat_map(|x| local_emails.get(x))
         .collect();
 
-    if !new_emails_missing_from_cache.is_empty() {
-        stdout.set_color(&info_color_spec).context(LogSnafu {})?;
-        writeln!(stdout, "Downloading new mail...").context(LogSnafu {})?;
-        stdout.reset().context(LogSnafu {})?;
-        stdout.flush().context(LogSnafu {})?;
-
-        let pb = ProgressBar::new(new_emails_missing_from_cache.len() as u64);
-        let pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(config.concurrent_downloads)
-            .build()
-            .context(CreateDownloadThreadPoolSnafu {})?;
-        let result: Result<Vec<_>, Error> = pool.install(|| {
-            new_emails_missing_from_cache
-                .into_par_iter()
-                .map(|new_email| {
+    // Set if there were more new emails than `max_messages`.
+    let capped = AtomicBool::new(false);
+
+    // Emails which could not be downloaded, and are left for the next sync.
+    let mut failed_downloads = Vec::new();
+
+    let mut metadata_time = Duration::ZERO;
+    let download_nanos = AtomicU64::new(0);
+    let downloaded_bytes = AtomicU64::new(0);
+
+    // Emails whose content is unchanged on the server despite a new blob ID, which are renamed
+    // rather than downloaded again.
+    let mut rebased_ids = HashSet::new();
+
+    let remote_emails = thread::scope(|scope| -> Result<HashMap<jmap::Id, remote::Email>> {
+        let (download_sender, download_receiver) = mpsc::channel::<(remote::Email, PathBuf)>();
+        let (new_email_sender, new_email_receiver) = mpsc::channel::<(remote::Email, PathBuf)>();
+
+        let metadata = {
+            let new_email_sender = new_email_sender.clone();
+            let remote = &mut remote;
+            let capped = &capped;
+            let metadata_time = &mut metadata_time;
+            let rebased_ids = &mut rebased_ids;
+            let (updated_ids, mailboxes, config, cache, local_emails, mail_files) = (
+                &updated_ids,
+                &mailboxes,
+                &config,
+                &cache,
+                &local_emails,
+                &mail_files,
+            );
+            let local_files_by_blob_id = &local_files_by_blob_id;
+            scope.spawn(move || -> Result<HashMap<jmap::Id, remote::Email>> {
+                // The database can't be shared between threads, so look up files of new emails
+                // which notmuch already has through a handle of our own.
+                let existing = if config.link_existing_messages {
+                    Some(Local::open(mail_dir, true, config).context(OpenLocalSnafu {})?)
+                } else {
+                    None
+                };
+                let existing_file = |remote_email: &remote::Email| {
+                    let message_id = remote_email.message_id.as_deref()?;
+                    match existing.as_ref()?.foreign_message_file(message_id) {
+                        Ok(path) => path,
+                        Err(e) => {
+                            // It can always be downloaded instead.
+                            debug!("Could not look up existing message `{message_id}': {e}");
+                            None
+                        }
+                    }
+                };
+
+                // Send a new email to be downloaded, unless it's already available.
+                let mut enqueued = 0;
+                let mut enqueue = |remote_email: remote::Email| -> Result<()> {
+                    if max_messages.is_some_and(|max| enqueued >= max) {
+                        capped.store(true, Ordering::Relaxed);
+                        return Ok(());
+                    }
+                    enqueued += 1;
+                    let cache_path = cache.cache_path(&remote_email.id, &remote_email.blob_id);
+                    if local_emails.contains_key(&remote_email.id) || cache_path.exists() {
+                        let _ = new_email_sender.send((remote_email, cache_path));
+                    } else if let Some(path) =
+                        mail_files.get(&(remote_email.id.clone(), remote_email.blob_id.clone()))
+                    {
+                        debug!(
+                            "Moving `{}' into cache `{}'",
+                            path.to_string_lossy(),
+                            cache_path.to_string_lossy()
+                        );
+                        if !dry_run {
+                            cache
+                                .move_into_cache(&cache_path, path)
+                                .context(RecoverMailFileSnafu { path })?;
+                        }
+                        let _ = new_email_sender.send((remote_email, cache_path));
+                    } else if let Some(path) = local_files_by_blob_id
+                        .get(&remote_email.blob_id)
+                        .filter(|path| path.exists())
+                    {
+                        debug!(
+                            "Linking `{}' with the same blob into cache `{}'",
+                            path.to_string_lossy(),
+                            cache_path.to_string_lossy()
+                        );
+                        if !dry_run {
+                            cache
+                                .link_into_cache(&cache_path, path)
+                                .context(LinkExistingMessageSnafu { path: *path })?;
+                        }
+                        let _ = new_email_sender.send((remote_email, cache_path));
+                    } else if let Some(path) = existing_file(&remote_email) {
+                        debug!(
+                            "Linking existing `{}' into cache `{}'",
+                            path.to_string_lossy(),
+                            cache_path.to_string_lossy()
+                        );
+                        if !dry_run {
+                            cache
+                                .link_into_cache(&cache_path, &path)
+                                .context(LinkExistingMessageSnafu { path: &path })?;
+                        }
+                        let _ = new_email_sender.send((remote_email, cache_path));
+                    } else {
+                        observer.items_added(1);
+                        observer.bytes_added(remote_email.size.unwrap_or_default());
+                        let _ = download_sender.send((remote_email, cache_path));
+                    }
+                    Ok(())
+                };
+
+                // Emails which already exist locally and only need their tags updated.
+                let mut remote_emails = HashMap::new();
+                let mut new_emails = Vec::new();
+                let chunks = remote.get_emails_chunked(updated_ids.iter(), mailboxes, &config.tags);
+                for chunk in timed(chunks, metadata_time) {
+                    for (id, remote_email) in chunk.context(GetRemoteEmailsSnafu {})? {
+                        match local_emails.get(&id) {
+                            Some(local_email) if local_email.blob_id == remote_email.blob_id => {
+                                // Only hold on to emails which need to change locally. During a
+                                // full sync, this is usually only a small fraction of them.
+                                if remote_email.local_tags(mailboxes)
+                                    != local_email.tags.iter().map(|tag| tag.as_str()).collect()
+                                {
+                                    remote_emails.insert(id, remote_email);
+                                }
+                            }
+                            Some(local_email)
+                                if same_content(
+                                    local_email,
+                                    &remote_email,
+                                    config.convert_dos_to_unix,
+                                ) =>
+                            {
+                                rebased_ids.insert(id.clone());
+                                remote_emails.insert(id, remote_email);
+                            }
+                            _ => new_emails.push(remote_email),
+                        }
+                    }
+                    if !config.download_newest_first && !config.download_largest_last {
+                        for remote_email in new_emails.drain(..) {
+                            enqueue(remote_email)?;
+                        }
+                    }
+                }
+                new_emails.sort_by(|a, b| b.received_at.cmp(&a.received_at));
+                if config.download_largest_last {
+                    new_emails.sort_by_key(|remote_email| remote_email.size);
+                }
+                for remote_email in new_emails {
+                    enqueue(remote_email)?;
+                }
+                Ok(remote_emails)
+            })
+        };
+
+        // Downloads which fail on every try are put off until all others have finished, then tried
+        // once more. Those which still fail are left for the next sync, rather than holding up all
+        // other new mail.
+        let downloads = {
+            let (downloader, cache, config) = (&downloader, &cache, &config);
+            let (download_nanos, downloaded_bytes) = (&download_nanos, &downloaded_bytes);
+            let pool = &pool;
+            scope.spawn(move || -> Vec<jmap::Id> {
+                let download_with_retries = |remote_email: &remote::Email, cache_path: &Path| {
                     let mut retry_count = 0;
                     loop {
-                        match download(new_email, &remote, &cache, config.convert_dos_to_unix) {
-                            Ok(_) => {
-                                pb.inc(1);
-                                return Ok(());
-                            }
+                        let start = Instant::now();
+                        let result = download(
+                            &remote_email.blob_id,
+                            cache_path,
+                            downloader,
+                            cache,
+                            config.convert_dos_to_unix,
+                            downloaded_bytes,
+                            observer,
+                        );
+                        download_nanos
+                            .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+                        match result {
+                            Ok(_) => return Ok(()),
                             Err(e) => {
                                 // Try again.
                                 retry_count += 1;
                                 if config.retries > 0 && retry_count >= config.retries {
                                     return Err(e);
                                 }
-                                warn!("Download error on try {}, retrying: {}", retry_count, e);
+                                observer.warning(&format!(
+                                    "Download error on try {}, retrying: {}",
+                                    retry_count, e
+                                ));
                             }
                         };
                     }
-                })
-                .collect()
-        });
-        result?;
-        pb.finish_with_message("done");
-    }
+                };
+                // If only one request is allowed at once, nothing is downloaded until all metadata
+                // has been retrieved and the sender is dropped.
+                let queued = if serial_downloads {
+                    Either::Left(
+                        download_receiver
+                            .into_iter()
+                            .collect::<Vec<_>>()
+                            .into_iter(),
+                    )
+                } else {
+                    Either::Right(download_receiver.into_iter())
+                };
+                let deferred: Vec<(remote::Email, PathBuf)> = pool.install(|| {
+                    queued
+                        .par_bridge()
+                        .filter_map(|(remote_email, cache_path)| {
+                            match download_with_retries(&remote_email, &cache_path) {
+                                Ok(()) => {
+                                    observer.item_completed();
+                                    let _ = new_email_sender.send((remote_email, cache_path));
+                                    None
+                                }
+                                Err(e) => {
+                                    observer.warning(&format!(
+                                        "Could not download email `{}', trying again once all \
+                                     others are done: {}",
+                                        remote_email.id, e
+                                    ));
+                                    Some((remote_email, cache_path))
+                                }
+                            }
+                        })
+                        .collect()
+                });
+                let mut failed = Vec::new();
+                for (remote_email, cache_path) in deferred {
+                    match download_with_retries(&remote_email, &cache_path) {
+                        Ok(()) => {
+                            observer.item_completed();
+                            let _ = new_email_sender.send((remote_email, cache_path));
+                        }
+                        Err(e) => {
+                            observer.warning(&format!(
+                                "Skipping email `{}' until the next sync: {}",
+                                remote_email.id, e
+                            ));
+                            failed.push(remote_email.id);
+                        }
+                    }
+                }
+                failed
+            })
+        };
 
-    // Merge locally.
-    //
-    // 1. Symlink the cached messages that were previously downloaded into the maildir. We will
-    // replace these symlinks with the actual files once the atomic sync is complete.
-    //
-    // 2. Add new messages to the database by indexing these symlinks. This is also done for
-    // existing messages which have new blob IDs.
-    //
-    // 3. Update the tags of all local messages *except* the ones which had been modified locally
-    // since mujmap was last run. Neither JMAP nor notmuch support looking at message history, so if
-    // both the local message and the remote message have been flagged as "updated" since the last
-    // sync, we prefer to overwrite remote tags with notmuch's tags.
-    //
-    // 4. Remove messages with destroyed IDs or updated blob IDs.
-    //
-    // 5. Overwrite the symlinks we made earlier with the actual files from the cache.
-    let notmuch_revision = get_notmuch_revision(
-        local_emails.is_empty(),
-        &local,
-        latest_state.notmuch_revision,
-        args.dry_run,
-    )?;
-    let updated_local_emails: HashMap<jmap::Id, local::Email> = local
-        .all_emails_since(notmuch_revision)
-        .context(IndexLocalUpdatedEmailsSnafu {})?
-        .into_iter()
-        // Filter out emails that were destroyed on the server.
-        .filter(|(id, _)| !destroyed_ids.contains(&id))
-        .collect();
+        // Add the new mail to the database in batches as it becomes available. A batch is added
+        // once it's large enough, or once no new mail has arrived for a while.
+        let mut batch = Vec::new();
+        loop {
+            let done = match new_email_receiver.recv_timeout(INDEX_BATCH_TIMEOUT) {
+                Ok((remote_email, cache_path)) => {
+                    batch.push(NewEmail {
+                        maildir_path: local.new_maildir_path(
+                            &remote_email.id,
+                            &remote_email.blob_id,
+                            remote_email.mailbox_tags(&mailboxes),
+                        ),
+                        remote_email,
+                        cache_path,
+                    });
+                    if batch.len() < INDEX_BATCH_SIZE {
+                        continue;
+                    }
+                    false
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if batch.is_empty() {
+                        continue;
+                    }
+                    false
+                }
+                Err(RecvTimeoutError::Disconnected) => true,
+            };
+            if pull {
+                summary.new += batch.len();
+            }
+            if merge && !batch.is_empty() {
+                let merge_start = Instant::now();
+                add_new_emails(
+                    &local,
+                    &cache,
+                    &mut batch,
+                    &local_emails,
+                    &updated_local_emails,
+                    &mailboxes,
+                    &mut destroyed_local_emails,
+                    observer,
+                )?;
+                profile.merge += merge_start.elapsed();
+            }
+            batch.clear();
+            if done {
+                break;
+            }
+        }
+
+        failed_downloads = downloads.join().unwrap_or_else(|e| panic::resume_unwind(e));
+        let remote_emails = metadata
+            .join()
+            .unwrap_or_else(|e| panic::resume_unwind(e))?;
+        Ok(remote_emails)
+    })?;
+
+    profile.metadata = metadata_time;
+    profile.downloads = Duration::from_nanos(download_nanos.into_inner());
+    profile.downloaded_bytes = downloaded_bytes.into_inner();
 
     if pull {
-        stdout.set_color(&info_color_spec).context(LogSnafu {})?;
-        write!(stdout, "Applying changes to notmuch database...").context(LogSnafu {})?;
-        stdout.reset().context(LogSnafu {})?;
-        writeln!(
-            stdout,
-            " ({} new, {} changed, {} destroyed)",
-            new_emails.len(),
-            remote_emails.len(),
-            destroyed_ids.len()
-        )
-        .context(LogSnafu {})?;
-        stdout.flush().context(LogSnafu {})?;
+        let merge_start = Instant::now();
+        summary.changed = remote_emails.len();
+        summary.destroyed = destroyed_ids.len();
+
+        observer.phase_started(
+            "Applying changes to notmuch database...",
+            &format!(
+                "{} new, {} changed, {} destroyed",
+                summary.new, summary.changed, summary.destroyed
+            ),
+        );
+
+        let merged_tags = merge_duplicate_tags(
+            &mut remote,
+            &local_emails,
+            &remote_emails,
+            &mailboxes,
+            &config.tags,
+        )?;
 
         // Update local messages.
-        if !args.dry_run {
-            // Collect the local messages which will be destroyed. We will add to this list any
-            // messages with new blob IDs.
-            let mut destroyed_local_emails: Vec<&local::Email> = destroyed_ids
-                .into_iter()
-                .flat_map(|x| local_emails.get(&x))
-                .collect();
-
-            // Symlink the new mail files into the maildir...
-            for new_email in new_emails.values() {
-                debug!(
-                    "Making symlink from `{}' to `{}'",
-                    &new_email.cache_path.to_string_lossy(),
-                    &new_email.maildir_path.to_string_lossy(),
-                );
-                if new_email.maildir_path.exists() {
-                    warn!(
-                        "File `{}' already existed in maildir but was not indexed. Replacing...",
-                        &new_email.maildir_path.to_string_lossy(),
+        if !dry_run {
+            // Rename files whose blob ID changed but whose content didn't, and move files whose
+            // mailboxes now belong in another maildir.
+            let mut rebased_local_emails = HashMap::new();
+            for remote_email in remote_emails.values() {
+                let id = &remote_email.id;
+                let mut local_email = match local_emails.get(id) {
+                    Some(local_email) => Cow::Borrowed(local_email),
+                    None => continue,
+                };
+                if rebased_ids.contains(id) {
+                    local_email = Cow::Owned(
+                        local
+                            .rename_email_blob(&local_email, &remote_email.blob_id)
+                            .context(RenameLocalEmailSnafu {})?,
                     );
-                    fs::remove_file(&new_email.maildir_path).context(
-                        RemoveUnindexedMailFileSnafu {
-                            path: &new_email.maildir_path,
-                        },
-                    )?;
                 }
-                symlink_file(&new_email.cache_path, &new_email.maildir_path).context(
-                    MakeMaildirSymlinkSnafu {
-                        from: &new_email.cache_path,
-                        to: &new_email.maildir_path,
-                    },
-                )?;
+                let cur_dir = local.cur_dir_for(remote_email.mailbox_tags(&mailboxes));
+                if local_email.path.parent() != Some(cur_dir) {
+                    local_email = Cow::Owned(
+                        local
+                            .move_email(&local_email, cur_dir)
+                            .context(MoveLocalEmailSnafu {})?,
+                    );
+                }
+                if let Cow::Owned(local_email) = local_email {
+                    rebased_local_emails.insert(id, local_email);
+                }
             }
 
-            let mut commit_changes = || -> Result<()> {
-                local.begin_atomic().context(BeginAtomicSnafu {})?;
+            local.begin_atomic().context(BeginAtomicSnafu {})?;
 
-                // ...and add them to the database.
-                let new_local_emails = new_emails
-                    .values()
-                    .map(|new_email| {
-                        let local_email =
-                            local
-                                .add_new_email(&new_email)
-                                .context(AddLocalEmailSnafu {
-                                    filename: &new_email.cache_path,
-                                })?;
-                        if let Some(e) = local_emails.get(&new_email.remote_email.id) {
-                            // Move the old message to the destroyed emails set.
-                            destroyed_local_emails.push(e);
-                        }
-                        Ok((local_email.id.clone(), local_email))
-                    })
-                    .collect::<Result<HashMap<_, _>>>()?;
-
-                // Update local emails with remote tags.
-                //
-                // XXX: If the server contains two or more of a message which notmuch considers a
-                // duplicate, it will be updated *for each duplicate* in a non-deterministic order.
-                // This may cause surprises.
-                for remote_email in remote_emails.values() {
-                    // Skip email which has been updated offline.
-                    if updated_local_emails.contains_key(&remote_email.id) {
-                        continue;
-                    }
+            // Update local emails with remote tags. Messages with several copies on the server are
+            // updated once, with the tags of all copies merged.
+            let mut updated_message_ids = HashSet::new();
+            for remote_email in remote_emails.values() {
+                // Skip email which has been updated offline.
+                if updated_local_emails.contains_key(&remote_email.id) {
+                    continue;
+                }
 
-                    // Do it!
-                    let local_email = [
-                        new_local_emails.get(&remote_email.id),
-                        local_emails.get(&remote_email.id),
-                    ]
-                    .into_iter()
-                    .flatten()
-                    .next()
+                let local_email = rebased_local_emails
+                    .get(&remote_email.id)
+                    .or_else(|| local_emails.get(&remote_email.id))
                     .ok_or_else(|| {
                         error!(
                             "Could not find local email for updated remote ID {}",
@@ -486,112 +1261,65 @@ pub fn sync(
                         Error::ProgrammerError {}
                     })?;
 
-                    // Add mailbox tags
-                    let mut tags: HashSet<&str> =
-                        remote_email.tags.iter().map(|s| s.as_str()).collect();
-                    for id in &remote_email.mailbox_ids {
-                        if let Some(mailbox) = mailboxes.mailboxes_by_id.get(id) {
-                            tags.insert(&mailbox.tag);
+                let tags = match merged_tags.get(local_email.message_id.as_str()) {
+                    Some(tags) => {
+                        if !updated_message_ids.insert(local_email.message_id.as_str()) {
+                            continue;
                         }
+                        tags.iter().map(|tag| tag.as_str()).collect()
                     }
-
-                    local
-                        .update_email_tags(local_email, tags)
-                        .context(UpdateLocalEmailSnafu {})?;
-
-                    // In `update' notmuch may have renamed the file on disk when setting maildir
-                    // flags, so we need to update our idea of the filename to match so that, for
-                    // new messages, we can reliably replace the symlink later.
-                    //
-                    // The `Message' might have multiple paths though (if more than one message has
-                    // the same id) so we have to get all the filenames and then find the one that
-                    // matches ours. Fortunately, our generated name (the raw JMAP mailbox.message
-                    // id) will always be a substring of notmuch's version (same name with flags
-                    // attached), so a starts-with test is enough.
-                    if let Some(mut new_email) = new_emails.get_mut(&remote_email.id) {
-                        if let Some(our_filename) = new_email
-                            .maildir_path
-                            .file_name()
-                            .map(|p| p.to_string_lossy())
-                        {
-                            if let Some(message) = local
-                                .get_message(&local_email.message_id)
-                                .context(GetNotmuchMessageSnafu {})?
-                            {
-                                if let Some(new_maildir_path) = message
-                                    .filenames()
-                                    .into_iter()
-                                    .filter(|f| {
-                                        f.file_name().map_or(false, |p| {
-                                            p.to_string_lossy().starts_with(&*our_filename)
-                                        })
-                                    })
-                                    .next()
-                                {
-                                    new_email.maildir_path = new_maildir_path;
-                                }
-                            }
+                    None => remote_email.local_tags(&mailboxes),
+                };
+
+                // Train local spam filters on messages which were moved into or out of spam on
+                // the server, while the file is still where the index says it is.
+                if !config.tags.spam.is_empty() {
+                    let command = match (
+                        local_email.tags.contains(&config.tags.spam),
+                        tags.contains(config.tags.spam.as_str()),
+                    ) {
+                        (false, true) => config.learn_spam_command.as_deref(),
+                        (true, false) => config.learn_ham_command.as_deref(),
+                        _ => None,
+                    };
+                    if let Some(command) = command {
+                        if let Err(e) = learn(command, &local_email.path) {
+                            observer.warning(&e.to_string());
                         }
                     }
                 }
 
-                // Finally, remove the old messages from the database.
-                for destroyed_local_email in &destroyed_local_emails {
-                    local
-                        .remove_email(*destroyed_local_email)
-                        .context(RemoveLocalEmailSnafu {})?;
-                }
-
-                local.end_atomic().context(EndAtomicSnafu {})?;
-                Ok(())
-            };
-
-            if let Err(e) = commit_changes() {
-                // Remove all the symlinks.
-                for new_email in new_emails.values() {
-                    debug!(
-                        "Removing symlink `{}'",
-                        &new_email.maildir_path.to_string_lossy(),
-                    );
-                    if let Err(e) = fs::remove_file(&new_email.maildir_path) {
-                        warn!(
-                            "Could not remove symlink `{}': {e}",
-                            &new_email.maildir_path.to_string_lossy(),
-                        );
-                    }
-                }
-                // Fail as normal.
-                return Err(e);
+                local
+                    .update_email_tags(local_email, tags)
+                    .context(UpdateLocalEmailSnafu {})?;
             }
 
-            // Now that the atomic database operation has been completed, do the actual file
-            // operations.
-
-            // Replace the symlinks with the real files.
-            for new_email in new_emails.values() {
-                debug!(
-                    "Moving mail from `{}' to `{}'",
-                    &new_email.cache_path.to_string_lossy(),
-                    &new_email.maildir_path.to_string_lossy(),
-                );
-                fs::rename(&new_email.cache_path, &new_email.maildir_path).context(
-                    RenameMailFileSnafu {
-                        from: &new_email.cache_path,
-                        to: &new_email.maildir_path,
-                    },
-                )?;
+            // Finally, remove the old messages from the database.
+            for destroyed_local_email in &destroyed_local_emails {
+                local
+                    .remove_email(*destroyed_local_email)
+                    .context(RemoveLocalEmailSnafu {})?;
             }
 
+            local.end_atomic().context(EndAtomicSnafu {})?;
+
             // Delete the destroyed email files.
+            // Files which were already deleted by hand need no deleting.
             for destroyed_local_email in &destroyed_local_emails {
-                fs::remove_file(&destroyed_local_email.path).context(RemoveMailFileSnafu {
-                    path: &destroyed_local_email.path,
-                })?;
+                match fs::remove_file(&destroyed_local_email.path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                        return Err(e).context(RemoveMailFileSnafu {
+                            path: &destroyed_local_email.path,
+                        });
+                    }
+                    _ => {}
+                }
             }
         }
+        profile.merge += merge_start.elapsed();
     }
 
-    if !args.dry_run {
+    if !dry_run && !read_only {
         // Ensure that for every tag, there exists a corresponding mailbox.
         let tags_with_missing_mailboxes: Vec<String> = local
             .all_tags()
@@ -601,11 +1329,11 @@ pub fn sync(
                 // Any tags which *can* be mapped to a keyword do not require a mailbox.
                 // Additionally, automatic tags are never mapped to mailboxes.
                 if [
-                    "draft",
-                    "flagged",
-                    "passed",
-                    "replied",
-                    "unread",
+                    config.tags.draft.as_str(),
+                    &config.tags.flagged,
+                    &config.tags.passed,
+                    &config.tags.replied,
+                    &config.tags.unread,
                     &config.tags.spam,
                     &config.tags.important,
                     &config.tags.phishing,
@@ -615,7 +1343,7 @@ pub fn sync(
                 {
                     false
                 } else {
-                    !mailboxes.ids_by_tag.contains_key(tag)
+                    !mailboxes.ids_by_tag.contains_key(tag) && !mailboxes.alias_tags.contains(tag)
                 }
             })
             .collect();
@@ -625,134 +1353,1100 @@ pub fn sync(
                     tags: tags_with_missing_mailboxes,
                 });
             }
-            remote
-                .create_mailboxes(&mut mailboxes, &tags_with_missing_mailboxes, &config.tags)
-                .context(CreateMailboxesSnafu {
-                    tags: tags_with_missing_mailboxes,
-                })?;
+            match remote.create_mailboxes(
+                &mut mailboxes,
+                &tags_with_missing_mailboxes,
+                &config.tags,
+            ) {
+                Ok(()) => {}
+                Err(e) if e.is_account_read_only() => {
+                    observer.warning(READ_ONLY_WARNING);
+                    read_only = true;
+                }
+                Err(e) => {
+                    return Err(e).context(CreateMailboxesSnafu {
+                        tags: tags_with_missing_mailboxes,
+                    })
+                }
+            }
         }
     }
 
     // Update remote messages.
-    stdout.set_color(&info_color_spec).context(LogSnafu {})?;
-    write!(stdout, "Applying changes to JMAP server...").context(LogSnafu {})?;
-    stdout.reset().context(LogSnafu {})?;
-    writeln!(stdout, " ({} changed)", updated_local_emails.len()).context(LogSnafu {})?;
-    stdout.flush().context(LogSnafu {})?;
+    observer.phase_started(
+        "Applying changes to JMAP server...",
+        &format!("{} changed", pushed_local_emails.len()),
+    );
+
+    if !dry_run && !read_only {
+        let push_start = Instant::now();
+        let mut completed = 0;
+        let mut progress = |done: usize, total: usize| {
+            if done == 0 {
+                observer.items_added(total as u64);
+            }
+            for _ in completed..done {
+                observer.item_completed();
+            }
+            completed = done;
+        };
+        let changes = match remote.update(
+            &pushed_local_emails,
+            &mailboxes,
+            &config.tags,
+            &mut progress,
+        ) {
+            Ok(remote::Pushed { changes, refused }) => {
+                summary.pushed = pushed_local_emails.len();
+                // Tags which the rights of their mailboxes keep from being pushed are restored, so
+                // that they're only warned about once. The database is only writable when pulling.
+                if pull {
+                    restore_refused_tags(&local, &pushed_local_emails, &refused)?;
+                }
+                changes
+            }
+            Err(e) if e.is_account_read_only() => {
+                observer.warning(READ_ONLY_WARNING);
+                read_only = true;
+                Vec::new()
+            }
+            Err(e) => return Err(e).context(PushChangesSnafu {}),
+        };
+        profile.push = push_start.elapsed();
+        // The push already happened, so failing to record it for `undo` doesn't fail the sync.
+        if !changes.is_empty() {
+            if let Err(e) = journal::record(&mail_dir, &journal::Batch::new(changes)) {
+                observer.warning(&format!("Could not record pushed changes: {e}"));
+            }
+        }
 
-    if !args.dry_run {
-        remote
-            .update(&updated_local_emails, &mailboxes, &config.tags)
-            .context(PushChangesSnafu {})?;
+        if config.auto_destroy_empty_mailboxes && !read_only {
+            destroy_empty_mailboxes(&local, &mut remote, &mut mailboxes, observer)?;
+        }
     }
 
-    if !args.dry_run {
+    let pushed_drafts = if config.push_drafts && !dry_run && !read_only {
+        push_drafts(
+            &local,
+            &mut remote,
+            &mailboxes,
+            latest_state.pushed_drafts,
+            &config.tags.draft,
+            observer,
+        )?
+    } else {
+        latest_state.pushed_drafts
+    };
+
+    // A capped sync keeps the previous JMAP state, so that the next sync considers the emails it
+    // left out again. Those it did add are then merely updated. The same goes for a sync which
+    // failed to download some emails.
+    let capped = capped.into_inner();
+    if capped {
+        observer.warning(&format!(
+            "Stopped after {} new emails; sync again to continue",
+            max_messages.unwrap_or_default()
+        ));
+    }
+    let capped = capped || !failed_downloads.is_empty();
+    let more_backfill = !dry_run && !capped && next_backfill.is_some();
+    let more_changes = !dry_run && !capped && has_more_changes;
+    if !dry_run {
         // Record the final state for the next invocation.
         LatestState {
             notmuch_revision: Some(local.revision() + 1),
-            jmap_state: if pull {
+            jmap_state: if !pull || capped {
+                latest_state.jmap_state
+            } else if next_backfill.is_some() {
+                None
+            } else {
                 Some(state)
+            },
+            mailbox_tags: if pull {
+                mailboxes
+                    .mailboxes_by_id
+                    .values()
+                    .map(|mailbox| (mailbox.id.clone(), mailbox.tag.clone()))
+                    .collect()
             } else {
-                latest_state.jmap_state
+                latest_state.mailbox_tags
+            },
+            backfill: if pull && !capped {
+                next_backfill
+            } else {
+                latest_state.backfill
             },
+            pushed_drafts,
+            chunk_sizes: Some(remote.chunk_sizes()),
+            email_summaries: if !pull || capped {
+                latest_state.email_summaries
+            } else {
+                email_summaries
+            },
+            expunge_found_at: expunge_found_at.unwrap_or(latest_state.expunge_found_at),
         }
-        .save(latest_state_filename)?;
+        .save(latest_state_filename, config.fsync, key)?;
+    }
+
+    // Only once the last batch is done.
+    if config.quota_warning_percent > 0 && !more_backfill && !more_changes {
+        warn_about_quotas(&mut remote, config.quota_warning_percent, observer);
     }
 
+    summary.duration = start_time.elapsed();
+    summary.profile = report_profile.then_some(profile);
+    observer.finished(&summary);
+
+    ensure!(
+        failed_downloads.is_empty(),
+        IncompleteDownloadsSnafu {
+            ids: failed_downloads
+        }
+    );
+    Ok(more_backfill || more_changes)
+}
+
+/// Return the merged tags of each changed message which has more than one copy on the server, e.g.
+/// one in a mailing list's mailbox and another in the inbox, keyed by `Message-ID`.
+///
+/// Tags are merged so that the result doesn't depend on the order of the copies: a message has
+/// the tags of every copy, except that it's only unread, deleted, or spam if every copy is. That
+/// way, a stray copy in the trash doesn't hide the message, and reading any copy reads all of
+/// them. Copies which didn't change since the last sync are retrieved to complete the picture.
+fn merge_duplicate_tags(
+    remote: &mut Remote,
+    local_emails: &HashMap<jmap::Id, local::Email>,
+    remote_emails: &HashMap<jmap::Id, remote::Email>,
+    mailboxes: &remote::Mailboxes,
+    tags_config: &config::Tags,
+) -> Result<HashMap<String, HashSet<String>>> {
+    let mut copies_by_message_id: HashMap<&str, Vec<&jmap::Id>> = HashMap::new();
+    for local_email in local_emails.values() {
+        copies_by_message_id
+            .entry(local_email.message_id.as_str())
+            .or_default()
+            .push(&local_email.id);
+    }
+    copies_by_message_id
+        .retain(|_, ids| ids.len() > 1 && ids.iter().any(|id| remote_emails.contains_key(*id)));
+
+    let unchanged_ids: Vec<&jmap::Id> = copies_by_message_id
+        .values()
+        .flatten()
+        .filter(|id| !remote_emails.contains_key(**id))
+        .copied()
+        .collect();
+    let unchanged_emails = remote
+        .get_emails(unchanged_ids.into_iter(), mailboxes, tags_config)
+        .context(GetRemoteEmailsSnafu {})?;
+
+    Ok(copies_by_message_id
+        .into_iter()
+        .map(|(message_id, ids)| {
+            // Copies destroyed on the server since they were listed have no tags to contribute.
+            let copies: Vec<HashSet<&str>> = ids
+                .into_iter()
+                .flat_map(|id| remote_emails.get(id).or_else(|| unchanged_emails.get(id)))
+                .map(|remote_email| remote_email.local_tags(mailboxes))
+                .collect();
+            let tags = merged_tags(&copies, tags_config)
+                .into_iter()
+                .map(|tag| tag.to_string())
+                .collect();
+            (message_id.to_string(), tags)
+        })
+        .collect())
+}
+
+/// Return the tags of a message with the given copies on the server, each with its own tags. The
+/// message has the tags of all of its copies, but is only unread, deleted, or spam if every copy
+/// is.
+pub fn merged_tags<'a>(
+    copies: &[HashSet<&'a str>],
+    tags_config: &config::Tags,
+) -> HashSet<&'a str> {
+    let exclusive_tags = [
+        tags_config.unread.as_str(),
+        tags_config.deleted.as_str(),
+        tags_config.spam.as_str(),
+    ];
+    copies
+        .iter()
+        .flatten()
+        .filter(|tag| {
+            !exclusive_tags.contains(tag) || copies.iter().all(|copy| copy.contains(*tag))
+        })
+        .copied()
+        .collect()
+}
+
+/// Upload local drafts which are new or were edited since they were last uploaded to the drafts
+/// mailbox, replacing their previous copies, and destroy the copies of drafts which were sent or
+/// deleted locally. Return the drafts which are now on the server.
+///
+/// Failing to push a draft isn't fatal; it's tried again by the next sync.
+fn push_drafts(
+    local: &Local,
+    remote: &mut Remote,
+    mailboxes: &remote::Mailboxes,
+    mut pushed_drafts: HashMap<String, PushedDraft>,
+    draft_tag: &str,
+    observer: &dyn SyncObserver,
+) -> Result<HashMap<String, PushedDraft>> {
+    let local_drafts = local
+        .local_drafts(draft_tag)
+        .context(IndexLocalDraftsSnafu {})?;
+
+    let destroy = |remote: &mut Remote, id: &jmap::Id| match remote.destroy_email(id) {
+        Ok(())
+        | Err(remote::Error::DestroyEmail {
+            source: jmap::MethodResponseError::NotFound,
+        }) => {}
+        Err(e) => observer.warning(&format!("Could not destroy draft `{id}': {e}")),
+    };
+
+    let message_ids: HashSet<&str> = local_drafts
+        .iter()
+        .map(|draft| draft.message_id.as_str())
+        .collect();
+    for (message_id, pushed_draft) in &pushed_drafts {
+        if !message_ids.contains(message_id.as_str()) {
+            debug!("Destroying draft `{message_id}' which is no longer a local draft");
+            destroy(remote, &pushed_draft.email_id);
+        }
+    }
+    pushed_drafts.retain(|message_id, _| message_ids.contains(message_id.as_str()));
+
+    let mut pushed = 0;
+    for draft in local_drafts {
+        let contents = match fs::read(&draft.path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                observer.warning(&format!(
+                    "Could not read draft `{}': {e}",
+                    draft.path.to_string_lossy()
+                ));
+                continue;
+            }
+        };
+        let digest: String = ring::digest::digest(&ring::digest::SHA256, &contents)
+            .as_ref()
+            .iter()
+            .map(|x| format!("{x:02x}"))
+            .collect();
+        let previous = pushed_drafts.get(&draft.message_id);
+        if previous.is_some_and(|x| x.digest == digest) {
+            continue;
+        }
+
+        let email_id = match remote.import_draft(mailboxes, &line_endings::unix_to_dos(&contents)) {
+            Ok(email_id) => email_id,
+            Err(e) => {
+                observer.warning(&format!("Could not push draft `{}': {e}", draft.message_id));
+                continue;
+            }
+        };
+        // Replace the previous version of the draft, including any copies of it that were
+        // downloaded from the server.
+        let old_ids: HashSet<&jmap::Id> = previous
+            .map(|x| &x.email_id)
+            .into_iter()
+            .chain(draft.emails.iter().map(|x| &x.id))
+            .collect();
+        for id in old_ids {
+            destroy(remote, id);
+        }
+        pushed_drafts.insert(draft.message_id, PushedDraft { email_id, digest });
+        pushed += 1;
+    }
+
+    if pushed > 0 {
+        observer.phase_started("Pushing drafts...", &format!("{pushed} pushed"));
+    }
+    Ok(pushed_drafts)
+}
+
+/// Return the IDs of the mailboxes which have no role, no children, contain no messages, and whose
+/// tag no longer exists in notmuch.
+fn empty_mailbox_ids<'a>(
+    mailboxes_by_id: &'a HashMap<jmap::Id, remote::Mailbox>,
+    jmap_mailboxes: &HashMap<jmap::Id, jmap::Mailbox>,
+    local_tags: &HashSet<String>,
+) -> Vec<&'a jmap::Id> {
+    let parent_ids: HashSet<&jmap::Id> = jmap_mailboxes
+        .values()
+        .filter_map(|jmap_mailbox| jmap_mailbox.parent_id.as_ref())
+        .collect();
+    mailboxes_by_id
+        .values()
+        .filter(|mailbox| {
+            !local_tags.contains(&mailbox.tag)
+                // Mailboxes with children are left alone; they will be reconsidered once their
+                // children are destroyed.
+                && !parent_ids.contains(&mailbox.id)
+                && jmap_mailboxes.get(&mailbox.id).is_some_and(|jmap_mailbox| {
+                    jmap_mailbox.role.is_none() && jmap_mailbox.total_emails == Some(0)
+                })
+        })
+        .map(|mailbox| &mailbox.id)
+        .collect()
+}
+
+/// Destroy mailboxes on the server which have no role, contain no messages, and whose tag no
+/// longer exists in notmuch.
+fn destroy_empty_mailboxes(
+    local: &Local,
+    remote: &mut Remote,
+    mailboxes: &mut remote::Mailboxes,
+    observer: &dyn SyncObserver,
+) -> Result<()> {
+    let local_tags: HashSet<String> = local.all_tags().context(IndexTagsSnafu {})?.collect();
+    // Fetch the mailboxes again, since the message counts have likely changed after pushing.
+    let jmap_mailboxes = remote
+        .get_jmap_mailboxes()
+        .context(IndexMailboxesSnafu {})?;
+    let empty_ids = empty_mailbox_ids(&mailboxes.mailboxes_by_id, &jmap_mailboxes, &local_tags);
+    if empty_ids.is_empty() {
+        return Ok(());
+    }
+
+    observer.phase_started(
+        "Destroying empty mailboxes...",
+        &format!("{} empty", empty_ids.len()),
+    );
+
+    let not_destroyed = remote
+        .destroy_mailboxes(&empty_ids, false)
+        .context(DestroyMailboxesSnafu {})?;
+    let destroyed_ids: Vec<jmap::Id> = empty_ids
+        .into_iter()
+        .filter(|id| match not_destroyed.get(id) {
+            Some(e) => {
+                observer.warning(&format!("Could not destroy empty mailbox `{}': {e}", id.0));
+                false
+            }
+            None => true,
+        })
+        .cloned()
+        .collect();
+    for id in destroyed_ids {
+        if let Some(mailbox) = mailboxes.mailboxes_by_id.remove(&id) {
+            mailboxes.ids_by_tag.remove(&mailbox.tag);
+        }
+    }
     Ok(())
 }
 
+/// Add a batch of new emails, which have been downloaded into the cache, to the maildir and the
+/// notmuch database, and tag them. Any local emails which they supersede, i.e. which have the same
+/// ID but a different blob ID, are added to `destroyed_local_emails`.
+///
+/// 1. Symlink the cached messages into the maildir. We will replace these symlinks with the
+///    actual files once the atomic database operation is complete. On filesystems which don't
+///    support symlinks, hard links or copies are made instead; see `link_into_maildir`.
+///
+/// 2. Add the new messages to the database by indexing these symlinks, and update their tags
+///    *except* for the ones which had been modified locally since mujmap was last run. Neither
+///    JMAP nor notmuch support looking at message history, so if both the local message and the
+///    remote message have been flagged as "updated" since the last sync, we prefer to overwrite
+///    remote tags with notmuch's tags.
+///
+/// 3. Overwrite the symlinks we made earlier with the actual files from the cache.
+#[allow(clippy::too_many_arguments)]
+fn add_new_emails<'a>(
+    local: &Local,
+    cache: &Cache,
+    new_emails: &mut [NewEmail],
+    local_emails: &'a HashMap<jmap::Id, local::Email>,
+    updated_local_emails: &HashMap<jmap::Id, local::Email>,
+    mailboxes: &remote::Mailboxes,
+    destroyed_local_emails: &mut Vec<&'a local::Email>,
+    observer: &dyn SyncObserver,
+) -> Result<()> {
+    // Symlink the new mail files into the maildir...
+    for new_email in new_emails.iter() {
+        debug!(
+            "Making symlink from `{}' to `{}'",
+            &new_email.cache_path.to_string_lossy(),
+            &new_email.maildir_path.to_string_lossy(),
+        );
+        if new_email.maildir_path.exists() {
+            observer.warning(&format!(
+                "File `{}' already existed in maildir but was not indexed. Replacing...",
+                &new_email.maildir_path.to_string_lossy(),
+            ));
+            fs::remove_file(&new_email.maildir_path).context(RemoveUnindexedMailFileSnafu {
+                path: &new_email.maildir_path,
+            })?;
+        }
+        if cache.is_encoded() {
+            // notmuch can't index compressed or encrypted files, so decode them into place instead.
+            cache
+                .decode_into(&new_email.cache_path, &new_email.maildir_path)
+                .context(CacheNewEmailSnafu {})?;
+        } else {
+            link_into_maildir(&new_email.cache_path, &new_email.maildir_path).context(
+                MakeMaildirSymlinkSnafu {
+                    from: &new_email.cache_path,
+                    to: &new_email.maildir_path,
+                },
+            )?;
+        }
+    }
+    for cur_dir in local.cur_dirs() {
+        cache
+            .flush_dir(cur_dir)
+            .context(SyncMaildirSnafu { path: cur_dir })?;
+    }
+
+    let mut commit_changes = || -> Result<()> {
+        local.begin_atomic().context(BeginAtomicSnafu {})?;
+
+        for new_email in new_emails.iter_mut() {
+            // ...and add them to the database.
+            let local_email = local.add_new_email(new_email).context(AddLocalEmailSnafu {
+                filename: &new_email.cache_path,
+            })?;
+            if let Some(e) = local_emails.get(&new_email.remote_email.id) {
+                // Move the old message to the destroyed emails set.
+                destroyed_local_emails.push(e);
+            }
+
+            // Skip email which has been updated offline.
+            if updated_local_emails.contains_key(&new_email.remote_email.id) {
+                continue;
+            }
+
+            local
+                .update_email_tags(&local_email, new_email.remote_email.local_tags(mailboxes))
+                .context(UpdateLocalEmailSnafu {})?;
+
+            // In `update' notmuch may have renamed the file on disk when setting maildir flags, so
+            // we need to update our idea of the filename to match so that we can reliably replace
+            // the symlink later.
+            //
+            // The `Message' might have multiple paths though (if more than one message has the
+            // same id) so we have to get all the filenames and then find the one that matches
+            // ours. Fortunately, our generated name (the raw JMAP mailbox.message id) will always
+            // be a substring of notmuch's version (same name with flags attached), so a
+            // starts-with test is enough.
+            if let Some(our_filename) = new_email
+                .maildir_path
+                .file_name()
+                .map(|p| p.to_string_lossy().into_owned())
+            {
+                if let Some(message) = local
+                    .get_message(&local_email.message_id)
+                    .context(GetNotmuchMessageSnafu {})?
+                {
+                    if let Some(new_maildir_path) = message.filenames().into_iter().find(|f| {
+                        f.file_name()
+                            .is_some_and(|p| p.to_string_lossy().starts_with(&our_filename))
+                    }) {
+                        new_email.maildir_path = new_maildir_path;
+                    }
+                }
+            }
+        }
+
+        local.end_atomic().context(EndAtomicSnafu {})?;
+        Ok(())
+    };
+
+    if let Err(e) = commit_changes() {
+        // Remove all the symlinks.
+        for new_email in new_emails.iter() {
+            debug!(
+                "Removing symlink `{}'",
+                &new_email.maildir_path.to_string_lossy(),
+            );
+            if let Err(e) = fs::remove_file(&new_email.maildir_path) {
+                observer.warning(&format!(
+                    "Could not remove symlink `{}': {e}",
+                    &new_email.maildir_path.to_string_lossy(),
+                ));
+            }
+        }
+        // Fail as normal.
+        return Err(e);
+    }
+
+    // Now that the atomic database operation has been completed, do the actual file operations.
+
+    // Replace the symlinks with the real files. Compressed and encrypted files were already decoded
+    // into place, so their cached copies are simply removed.
+    for new_email in new_emails.iter() {
+        if cache.is_encoded() {
+            fs::remove_file(&new_email.cache_path).context(RemoveMailFileSnafu {
+                path: &new_email.cache_path,
+            })?;
+            continue;
+        }
+        debug!(
+            "Moving mail from `{}' to `{}'",
+            &new_email.cache_path.to_string_lossy(),
+            &new_email.maildir_path.to_string_lossy(),
+        );
+        fs::rename(&new_email.cache_path, &new_email.maildir_path).context(
+            RenameMailFileSnafu {
+                from: &new_email.cache_path,
+                to: &new_email.maildir_path,
+            },
+        )?;
+        // Renaming a file onto a hard link of itself does nothing, which leaves the cached name
+        // behind if the maildir file was linked to it.
+        if fs::symlink_metadata(&new_email.cache_path).is_ok() {
+            fs::remove_file(&new_email.cache_path).context(RemoveMailFileSnafu {
+                path: &new_email.cache_path,
+            })?;
+        }
+    }
+    for cur_dir in local.cur_dirs() {
+        cache
+            .flush_dir(cur_dir)
+            .context(SyncMaildirSnafu { path: cur_dir })?;
+    }
+    Ok(())
+}
+
+/// Take the sync lock, waiting for another process to release it if necessary.
+fn lock_sync(mail_dir: &Path, observer: &dyn SyncObserver) -> Result<Lock> {
+    let path = mail_dir.join(lock::SYNC_LOCK);
+    match Lock::try_acquire(&path).context(LockSnafu { path: &path })? {
+        Some(lock) => Ok(lock),
+        None => {
+            observer.phase_started("Lock file owned by another process. Waiting...", "");
+            Lock::acquire(&path).context(LockSnafu { path: &path })
+        }
+    }
+}
+
+/// Restore the given tags of each email, which could not be pushed, to how they are on the server:
+/// those which were added locally are removed, and those which were removed are added back.
+fn restore_refused_tags(
+    local: &Local,
+    local_emails: &HashMap<jmap::Id, local::Email>,
+    refused: &HashMap<jmap::Id, Vec<String>>,
+) -> Result<()> {
+    for (id, refused_tags) in refused {
+        let Some(local_email) = local_emails.get(id) else {
+            continue;
+        };
+        let tags = local_email
+            .tags
+            .iter()
+            .filter(|tag| !refused_tags.contains(tag))
+            .chain(
+                refused_tags
+                    .iter()
+                    .filter(|tag| !local_email.tags.contains(*tag)),
+            )
+            .map(|tag| tag.as_str())
+            .collect();
+        local
+            .update_email_tags(local_email, tags)
+            .context(UpdateLocalEmailSnafu {})?;
+    }
+    Ok(())
+}
+
+/// Report that the server is unreachable along with the number of local changes waiting to be
+/// pushed, then finish with an empty summary.
+fn report_offline(
+    observer: &dyn SyncObserver,
+    local: &Local,
+    latest_state: &LatestState,
+    start_time: Instant,
+) -> Result<()> {
+    let pending = match latest_state.notmuch_revision {
+        Some(notmuch_revision) => local
+            .all_emails_since(notmuch_revision)
+            .context(IndexLocalEmailsSnafu {})?
+            .len(),
+        None => 0,
+    };
+    observer.phase_started(
+        "Offline.",
+        &format!("{pending} changed messages will be pushed by the next sync"),
+    );
+    observer.finished(&SyncSummary {
+        new: 0,
+        changed: 0,
+        destroyed: 0,
+        pushed: 0,
+        duration: start_time.elapsed(),
+        profile: None,
+    });
+    Ok(())
+}
+
+/// Destroy the mail on the server which has been in the trash or spam mailbox for longer than
+/// configured, except mail with local changes, and return the IDs of the destroyed `Email`s. This
+/// is never fatal.
+///
+/// JMAP doesn't tell when mail was moved to a mailbox, so `found_at` records when each sync first
+/// found it there instead. Return the record to keep for the next sync, which only has the mail
+/// still there.
+fn expunge(
+    remote: &mut Remote,
+    mailboxes: &remote::Mailboxes,
+    config: &Config,
+    updated_local_emails: &HashMap<jmap::Id, local::Email>,
+    found_at: &HashMap<jmap::Id, u64>,
+    observer: &dyn SyncObserver,
+) -> (HashSet<jmap::Id>, HashMap<jmap::Id, u64>) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs());
+    let mut expunged = HashSet::new();
+    let mut next_found_at = HashMap::new();
+    for (name, mailbox_id, age) in [
+        (
+            "trash",
+            &mailboxes.roles.deleted,
+            &config.expunge.trash_after,
+        ),
+        ("spam", &mailboxes.roles.spam, &config.expunge.spam_after),
+    ] {
+        let (Some(mailbox_id), Some(age)) =
+            (mailbox_id, age.as_deref().and_then(config::parse_age))
+        else {
+            continue;
+        };
+        let ids = match remote.email_ids_only_in(mailbox_id) {
+            Ok(ids) => ids,
+            Err(e) => {
+                observer.warning(&format!("Could not find old mail in {name}: {e}"));
+                // Don't forget when the mail there was found.
+                next_found_at.extend(found_at.iter().map(|(id, &time)| (id.clone(), time)));
+                continue;
+            }
+        };
+        let mut expired = Vec::new();
+        for id in ids {
+            let time = found_at.get(&id).copied().unwrap_or(now);
+            if now.saturating_sub(time) >= age.as_secs() && !updated_local_emails.contains_key(&id)
+            {
+                expired.push(id.clone());
+            }
+            next_found_at.insert(id, time);
+        }
+        if expired.is_empty() {
+            continue;
+        }
+        match remote.destroy_emails(&expired.iter().collect::<Vec<_>>()) {
+            Ok(destroyed) => {
+                observer.phase_started(
+                    &format!("Expunging old mail in {name}..."),
+                    &format!("{} destroyed", destroyed.len()),
+                );
+                for id in &destroyed {
+                    next_found_at.remove(id);
+                }
+                expunged.extend(destroyed);
+            }
+            Err(e) => observer.warning(&format!("Could not destroy old mail in {name}: {e}")),
+        }
+    }
+    (expunged, next_found_at)
+}
+
+/// Warn about each storage quota of the account which is at least `percent` percent used.
+/// Failing to get the quotas is no reason to fail the sync.
+fn warn_about_quotas(remote: &mut Remote, percent: u8, observer: &dyn SyncObserver) {
+    let quotas = match remote.get_quotas() {
+        Ok(quotas) => quotas,
+        Err(e) => {
+            debug!("Could not get quotas: {e}");
+            return;
+        }
+    };
+    for quota in quotas {
+        if quota.resource_type != "octets" || quota.hard_limit == 0 {
+            continue;
+        }
+        let used_percent = quota.used.saturating_mul(100) / quota.hard_limit;
+        if used_percent >= percent.into() {
+            let name = if quota.name.is_empty() {
+                &quota.id.0
+            } else {
+                &quota.name
+            };
+            observer.warning(&format!(
+                "Quota `{name}' is {used_percent}% used ({} of {} bytes)",
+                quota.used, quota.hard_limit
+            ));
+        }
+    }
+}
+
+/// Return how many requests the server and `max_connections` allow at once.
+fn max_requests(remote: &Remote, config: &Config) -> usize {
+    remote
+        .max_concurrent_requests()
+        .min(config.max_connections.unwrap_or(usize::MAX))
+}
+
+/// Return how many emails to download at once. Metadata is retrieved at the same time, so it's
+/// left one of the requests which are allowed at once. If only one is allowed, downloads wait for
+/// the metadata instead.
+fn concurrent_downloads(remote: &Remote, config: &Config) -> usize {
+    let max = max_requests(remote, config);
+    let concurrent_downloads = config
+        .concurrent_downloads
+        .min(max.saturating_sub(1).max(1));
+    if concurrent_downloads < config.concurrent_downloads {
+        debug!(
+            "Downloading {concurrent_downloads} emails at once rather than {}, since only {max} \
+             requests are allowed at once",
+            config.concurrent_downloads
+        );
+    }
+    concurrent_downloads
+}
+
+/// Run a spam training command with the mail file at `path` as its last argument.
+fn learn(command: &str, path: &Path) -> Result<()> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{command} \"$1\""))
+        .arg("sh")
+        .arg(path)
+        .output()
+        .context(ExecuteLearnCommandSnafu { command })?;
+    ensure!(
+        output.status.success(),
+        LearnCommandStatusSnafu {
+            command,
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        }
+    );
+    Ok(())
+}
+
+/// Make `to` stand in for the cached mail file `from` until the latter is moved into its place.
+///
+/// A symlink is preferred, but some filesystems, e.g. SMB and FAT mounts, don't support them. In
+/// that case, fall back to a hard link, and failing that, a copy. If all of these fail, the error
+/// from making the symlink is returned.
+fn link_into_maildir(from: &Path, to: &Path) -> io::Result<()> {
+    let symlink_error = match symlink_file(from, to) {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+    debug!("Could not make symlink ({symlink_error}); trying a hard link instead");
+    if fs::hard_link(from, to).is_ok() {
+        return Ok(());
+    }
+    debug!("Could not make hard link; copying instead");
+    fs::copy(from, to).map(|_| ()).map_err(|_| symlink_error)
+}
+
+/// Whether `remote_email`, whose blob ID differs from that of `local_email`, still has the same
+/// content. Servers sometimes give unchanged messages new blob IDs, e.g. after a migration.
+fn same_content(
+    local_email: &local::Email,
+    remote_email: &remote::Email,
+    convert_dos_to_unix: bool,
+) -> bool {
+    let Some(size) = remote_email.size else {
+        return false;
+    };
+    if remote_email.message_id.as_deref() != Some(local_email.message_id.as_str()) {
+        return false;
+    }
+    match local_email.size {
+        Some(local_size) => local_size == size,
+        // Converted files no longer have the size of the original.
+        None if !convert_dos_to_unix => {
+            fs::metadata(&local_email.path).is_ok_and(|metadata| metadata.len() == size)
+        }
+        None => false,
+    }
+}
+
 fn download(
-    new_email: &NewEmail,
-    remote: &Remote,
+    blob_id: &jmap::Id,
+    cache_path: &Path,
+    downloader: &remote::Downloader,
     cache: &Cache,
     convert_dos_to_unix: bool,
+    downloaded_bytes: &AtomicU64,
+    observer: &dyn SyncObserver,
 ) -> Result<()> {
-    let remote_email = new_email.remote_email;
-    let reader = remote
-        .read_email_blob(&remote_email.blob_id)
+    let reader = downloader
+        .read_email_blob(blob_id)
         .context(DownloadRemoteEmailSnafu {})?;
-    cache
-        .download_into_cache(&new_email, reader, convert_dos_to_unix)
-        .context(CacheNewEmailSnafu {})?;
-    Ok(())
+    let count = AtomicU64::new(0);
+    let reader = CountingReader {
+        reader,
+        count: &count,
+        observer,
+    };
+    let result = cache
+        .download_into_cache(cache_path, reader, convert_dos_to_unix)
+        .context(CacheNewEmailSnafu {});
+    let count = count.into_inner();
+    downloaded_bytes.fetch_add(count, Ordering::Relaxed);
+    if result.is_err() {
+        // The email is downloaded from the start again, so the bytes so far are transferred twice.
+        observer.bytes_added(count);
+    }
+    result
+}
+
+/// Adds the number of bytes read through it to `count`, and reports them to `observer`.
+struct CountingReader<'a, R> {
+    reader: R,
+    count: &'a AtomicU64,
+    observer: &'a dyn SyncObserver,
+}
+
+impl<R: Read> Read for CountingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        self.observer.bytes_transferred(n as u64);
+        Ok(n)
+    }
+}
+
+/// Add the time spent in each call to `next` of `iter` to `total`.
+fn timed<'a, I: Iterator + 'a>(
+    mut iter: I,
+    total: &'a mut Duration,
+) -> impl Iterator<Item = I::Item> + 'a {
+    iter::from_fn(move || {
+        let start = Instant::now();
+        let item = iter.next();
+        *total += start.elapsed();
+        item
+    })
+}
+
+/// The `Email` IDs which changed on the server since the last sync.
+enum RemoteChanges {
+    /// The changes reported by `Email/changes`.
+    Changed {
+        state: jmap::State,
+        created: HashSet<jmap::Id>,
+        updated: HashSet<jmap::Id>,
+        destroyed: HashSet<jmap::Id>,
+        /// Whether there are more changes since `state`, which are left for another sync.
+        more: bool,
+    },
+    /// Every `Email` ID on the server, for a full sync.
+    All {
+        state: jmap::State,
+        ids: HashSet<jmap::Id>,
+    },
+    /// The `Email` IDs in the given window of a progressive backfill.
+    Backfill {
+        state: jmap::State,
+        ids: HashSet<jmap::Id>,
+        window: usize,
+    },
+}
+
+/// Query the server for changes since the last sync, or for every `Email` ID if that's not
+/// possible. If `backfill` is true, only the `Email` IDs in the next window of the progressive
+/// backfill are queried instead.
+///
+/// `email_ids` is the answer to the query made along with the mailboxes: the changes since the
+/// last sync if its state is known, and the IDs which would otherwise be queried if not. The
+/// server is only queried again if it failed to calculate the changes.
+///
+/// If it did fail, the summary of every `Email` on the server is returned along with the changes,
+/// so that the next sync can find the changes itself by comparing against it.
+fn remote_changes(
+    remote: &mut Remote,
+    latest_state: &LatestState,
+    backfill: bool,
+    email_ids: remote::Result<remote::EmailIds>,
+    observer: &dyn SyncObserver,
+) -> Result<(
+    RemoteChanges,
+    Option<HashMap<jmap::Id, remote::EmailSummary>>,
+)> {
+    let queried = match email_ids {
+        Ok(remote::EmailIds::Changes {
+            state,
+            created,
+            updated,
+            destroyed,
+            has_more_changes,
+        }) => {
+            return Ok((
+                RemoteChanges::Changed {
+                    state,
+                    created,
+                    updated,
+                    destroyed,
+                    more: has_more_changes,
+                },
+                None,
+            ))
+        }
+        Ok(remote::EmailIds::Query { state, ids }) => Some((state, ids)),
+        Err(e) if latest_state.jmap_state.is_some() && !backfill => {
+            // `Email/changes` failed, so fall back to `Email/query`, and find the changes by
+            // comparing every `Email` with its summary from the last sync, if there is one.
+            let (state, ids) = remote.all_email_ids().context(IndexRemoteEmailsSnafu {})?;
+            let summaries = remote
+                .get_email_summaries(&ids)
+                .context(IndexRemoteEmailsSnafu {})?;
+            let changes = match &latest_state.email_summaries {
+                Some(old_summaries) => {
+                    debug!("Error while attempting to resolve changes, comparing summaries: {e}");
+                    let (created, updated, destroyed) =
+                        diff_email_summaries(old_summaries, &summaries);
+                    RemoteChanges::Changed {
+                        state,
+                        created,
+                        updated,
+                        destroyed,
+                        more: false,
+                    }
+                }
+                None => {
+                    observer.warning(&format!(
+                        "Error while attempting to resolve changes, attempting full sync: {e}"
+                    ));
+                    RemoteChanges::All { state, ids }
+                }
+            };
+            return Ok((changes, Some(summaries)));
+        }
+        Err(e) if latest_state.jmap_state.is_some() => {
+            // `Email/changes` failed, so fall back to `Email/query`.
+            observer.warning(&format!(
+                "Error while attempting to resolve changes, attempting full sync: {e}"
+            ));
+            None
+        }
+        Err(e) => return Err(e).context(IndexRemoteEmailsSnafu {}),
+    };
+    if backfill {
+        let window = backfill_window(latest_state);
+        let (state, ids) = match queried {
+            Some(queried) => queried,
+            None => match backfill_after(window) {
+                Some(after) => remote.email_ids_received_after(&after),
+                None => remote.all_email_ids(),
+            }
+            .context(IndexRemoteEmailsSnafu {})?,
+        };
+        let state = match &latest_state.backfill {
+            Some(backfill) => backfill.jmap_state.clone(),
+            None => state,
+        };
+        return Ok((RemoteChanges::Backfill { state, ids, window }, None));
+    }
+    let (state, ids) = match queried {
+        Some(queried) => queried,
+        None => remote.all_email_ids().context(IndexRemoteEmailsSnafu {})?,
+    };
+    Ok((RemoteChanges::All { state, ids }, None))
 }
 
+/// Return the IDs of the `Email`s which were created, updated, and destroyed between two
+/// summaries of every `Email` on the server.
+fn diff_email_summaries(
+    old: &HashMap<jmap::Id, remote::EmailSummary>,
+    new: &HashMap<jmap::Id, remote::EmailSummary>,
+) -> (HashSet<jmap::Id>, HashSet<jmap::Id>, HashSet<jmap::Id>) {
+    let mut created = HashSet::new();
+    let mut updated = HashSet::new();
+    for (id, summary) in new {
+        match old.get(id) {
+            None => created.insert(id.clone()),
+            Some(old_summary) if old_summary != summary => updated.insert(id.clone()),
+            Some(_) => false,
+        };
+    }
+    let destroyed = old
+        .keys()
+        .filter(|id| !new.contains_key(id))
+        .cloned()
+        .collect();
+    (created, updated, destroyed)
+}
+
+/// Return the index into `BACKFILL_WINDOWS` of the window a progressive backfill syncs next.
+fn backfill_window(latest_state: &LatestState) -> usize {
+    latest_state.backfill.as_ref().map_or(0, |backfill| {
+        backfill.window.min(BACKFILL_WINDOWS.len() - 1)
+    })
+}
+
+/// Return the RFC 3339 `UTCDate` from which mail is synced in the given backfill window, or
+/// `None` if it syncs all remaining mail.
+fn backfill_after(window: usize) -> Option<String> {
+    BACKFILL_WINDOWS[window]
+        .map(|days| jmap::utc_date(SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60)))
+}
+
+/// Return the notmuch database revision to look for local changes since. Without the revision of
+/// the last sync, local changes can't be told apart from the rest, so this fails unless there is
+/// no local mail yet or the caller accepted that local changes may be lost.
 fn get_notmuch_revision(
     has_no_local_emails: bool,
     local: &Local,
     notmuch_revision: Option<u64>,
-    dry_run: bool,
+    accept_missing_revision: bool,
 ) -> Result<u64> {
     match notmuch_revision {
         Some(x) => Ok(x),
         None => {
-            if has_no_local_emails {
-                Ok(local.revision())
-            } else {
-                if dry_run {
-                    println!(
-                        "\
-THIS IS A DRY RUN, SO NO CHANGES WILL BE MADE NO MATTER THE CHOICE. HOWEVER,
-HEED THE WARNING FOR THE REAL DEAL.
-"
-                    );
-                }
-                println!(
-                    "\
-mujmap was unable to read the notmuch database revision (stored in
-mujmap.state.json) since the last time it was run. As a result, it cannot
-determine the changes made in the local database since the last time a
-synchronization was performed.
-"
-                );
-                if atty::is(Stream::Stdout) {
-                    println!(
-                        "\
-If you continue, any potential changes made to your database since your last
-sync will not be pushed to the JMAP server, and some may be overwritten by the
-JMAP server's state. Depending on your situation, this has potential to create
-inconsistencies between the state of your database and the state of the server.
-
-If this is the first time you are synchronizing mujmap in a pre-existing
-database, this is not an issue. If the mail files in your database are different
-from your mail on the JMAP server, you can proceed and mujmap will perform the
-initial setup with no issues. This would be the case if you had multiple email
-accounts in the same database, for example.
-
-If this is the first time you are synchronizing, and you are attempting to
-migrate your existing notmuch database tags to mailboxes on a JMAP server,
-DO NOT continue. Instead, follow the relevant instructions in mujmap's README.
-
-If this is the first time you are synchronizing, but you do not care about your
-notmuch tags and would like them to be replaced with the JMAP server's state,
-you may continue.
-
-If this is NOT the first time you are synchronizing, you should quit and force
-a full sync by deleting the `mujmap.state.json' file and invoking mujmap again.
-This will overwrite all of your local JMAP state with the JMAP server's state.
-You will encounter this message again, but at that point you can safely proceed.
-
-Continue? (y/N)
-"
-                    );
-                    let mut response = String::new();
-                    io::stdin().read_line(&mut response).ok();
-                    let trimmed = response.trim();
-                    ensure!(
-                        trimmed == "y" || trimmed == "Y",
-                        MissingNotmuchDatabaseRevisionSnafu {}
-                    );
-                    Ok(local.revision())
-                } else {
-                    println!(
-                        "\
-Please run mujmap again in an interactive terminal to resolve.
-"
-                    );
-                    return Err(Error::MissingNotmuchDatabaseRevision {});
-                }
-            }
+            ensure!(
+                has_no_local_emails || accept_missing_revision,
+                MissingNotmuchDatabaseRevisionSnafu {}
+            );
+            Ok(local.revision())
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mailbox(id: &str, tag: &str) -> (jmap::Id, remote::Mailbox) {
+        let id = jmap::Id(id.to_owned());
+        let mailbox = remote::Mailbox {
+            id: id.clone(),
+            tag: tag.to_owned(),
+            alias: None,
+            may_add_items: true,
+            may_remove_items: true,
+        };
+        (id, mailbox)
+    }
+
+    #[test]
+    fn empty_mailboxes_are_destroyed() {
+        let mailboxes_by_id: HashMap<jmap::Id, remote::Mailbox> = [
+            mailbox("empty", "old"),
+            mailbox("full", "lists"),
+            mailbox("tagged", "work"),
+        ]
+        .into_iter()
+        .collect();
+        let jmap_mailboxes: HashMap<jmap::Id, jmap::Mailbox> = serde_json::from_value::<
+            Vec<jmap::Mailbox>,
+        >(serde_json::json!([
+            { "id": "empty", "parentId": null, "name": "Old", "role": null, "totalEmails": 0 },
+            { "id": "full", "parentId": null, "name": "Lists", "role": null, "totalEmails": 3 },
+            { "id": "tagged", "parentId": null, "name": "Work", "role": null, "totalEmails": 0 },
+        ]))
+        .unwrap()
+        .into_iter()
+        .map(|jmap_mailbox| (jmap_mailbox.id.clone(), jmap_mailbox))
+        .collect();
+        let local_tags: HashSet<String> = ["work".to_owned()].into_iter().collect();
+
+        let empty_ids = empty_mailbox_ids(&mailboxes_by_id, &jmap_mailboxes, &local_tags);
+        assert_eq!(empty_ids, vec![&jmap::Id("empty".to_owned())]);
+    }
+}
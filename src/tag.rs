@@ -0,0 +1,220 @@
+use crate::config::Config;
+use crate::jmap;
+use crate::local::{self, Local};
+use crate::remote::{self, Remote};
+use snafu::prelude::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not open remote session: {}", source))]
+    OpenRemote { source: remote::Error },
+
+    #[snafu(display("Could not index mailboxes: {}", source))]
+    IndexMailboxes { source: remote::Error },
+
+    #[snafu(display("Tag operation `{}' must start with `+' or `-'", operation))]
+    InvalidOperation { operation: String },
+
+    #[snafu(display(
+        "Tag `{}' doesn't correspond to a mailbox or keyword on the server",
+        tag
+    ))]
+    UnknownTag { tag: String },
+
+    #[snafu(display("Search term `{}' is not of the form `id:<message-id>'", query))]
+    UnsupportedQuery { query: String },
+
+    #[snafu(display("Could not find emails with Message-ID `{}': {}", message_id, source))]
+    QueryEmails {
+        message_id: String,
+        source: remote::Error,
+    },
+
+    #[snafu(display("No email on the server has Message-ID `{}'", message_id))]
+    NoSuchMessage { message_id: String },
+
+    #[snafu(display("Could not tag emails on the server: {}", source))]
+    TagEmails { source: remote::Error },
+
+    #[snafu(display("Could not open local database: {}", source))]
+    OpenLocal { source: local::Error },
+
+    #[snafu(display("Could not index local emails: {}", source))]
+    IndexLocalEmails { source: local::Error },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Apply notmuch-style tag operations, e.g. `+archive -inbox`, to the emails with the given
+/// Message-IDs directly on the server, without touching the local maildir or notmuch database.
+///
+/// Every operation and search term is checked before anything is changed. Returns how many emails
+/// were changed.
+pub fn tag(config: Config, operations: &str, queries: &[String]) -> Result<usize> {
+    let mut add = Vec::new();
+    let mut remove = Vec::new();
+    for operation in operations.split_whitespace() {
+        match (operation.strip_prefix('+'), operation.strip_prefix('-')) {
+            (Some(tag), _) if !tag.is_empty() => add.push(tag),
+            (_, Some(tag)) if !tag.is_empty() => remove.push(tag),
+            _ => return InvalidOperationSnafu { operation }.fail(),
+        }
+    }
+    let message_ids = queries
+        .iter()
+        .map(|query| parse_query(query))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut remote = Remote::open(&config).context(OpenRemoteSnafu {})?;
+    let mailboxes = remote
+        .get_mailboxes(&config)
+        .context(IndexMailboxesSnafu {})?;
+
+    let tags = &config.tags;
+    for &tag in add.iter().chain(remove.iter()) {
+        let is_keyword = [
+            tags.draft.as_str(),
+            &tags.flagged,
+            &tags.replied,
+            &tags.passed,
+            &tags.unread,
+            &tags.spam,
+            &tags.phishing,
+        ]
+        .contains(&tag);
+        ensure!(
+            is_keyword || mailboxes.ids_by_tag.contains_key(tag),
+            UnknownTagSnafu { tag }
+        );
+    }
+
+    let mut ids: HashSet<jmap::Id> = HashSet::new();
+    for message_id in message_ids {
+        let found = remote
+            .email_ids_by_message_id(message_id)
+            .context(QueryEmailsSnafu { message_id })?;
+        ensure!(!found.is_empty(), NoSuchMessageSnafu { message_id });
+        ids.extend(found);
+    }
+
+    remote
+        .tag(&ids, &add, &remove, &mailboxes, tags)
+        .context(TagEmailsSnafu {})
+}
+
+/// List every tag mujmap recognizes or finds in the notmuch database, what it maps to on the
+/// server, and how many local messages have it, sorted by tag.
+pub fn list(mail_dir: &Path, config: Config) -> Result<Vec<(String, usize, String)>> {
+    let mut remote = Remote::open(&config).context(OpenRemoteSnafu {})?;
+    let jmap_mailboxes = remote
+        .get_jmap_mailboxes()
+        .context(IndexMailboxesSnafu {})?;
+    let mailboxes = remote
+        .get_mailboxes(&config)
+        .context(IndexMailboxesSnafu {})?;
+    let local = Local::open(mail_dir, true, &config).context(OpenLocalSnafu {})?;
+    let local_emails = local.all_emails().context(IndexLocalEmailsSnafu {})?;
+
+    // What each tag maps to, in the same way as `remote::Email::local_tags`.
+    let mut mappings: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for (id, mailbox) in &mailboxes.mailboxes_by_id {
+        let mapping = mailbox_mapping(&jmap_mailboxes, id, &config);
+        if let Some(alias) = &mailbox.alias {
+            mappings
+                .entry(alias)
+                .or_default()
+                .push(format!("{mapping}, leaf"));
+        }
+        mappings.entry(&mailbox.tag).or_default().push(mapping);
+    }
+    for (tag, mapping) in keyword_mappings(&config, &mailboxes) {
+        mappings.entry(tag).or_default().push(mapping);
+    }
+    for tag in local::AUTOMATIC_TAGS.iter() {
+        mappings
+            .entry(tag)
+            .or_default()
+            .push("automatic, not synced".to_owned());
+    }
+
+    // Messages with several files are counted once.
+    let mut counts: HashMap<&str, HashSet<&str>> = HashMap::new();
+    for email in local_emails.values() {
+        for tag in &email.tags {
+            counts
+                .entry(tag)
+                .or_default()
+                .insert(email.message_id.as_str());
+        }
+    }
+    for tag in counts.keys() {
+        mappings
+            .entry(tag)
+            .or_insert_with(|| vec!["local-only".to_owned()]);
+    }
+
+    Ok(mappings
+        .into_iter()
+        .map(|(tag, mapping)| {
+            let count = counts.get(tag).map_or(0, |x| x.len());
+            (tag.to_owned(), count, mapping.join("; "))
+        })
+        .collect())
+}
+
+/// Describe the mailbox with the given ID by its path and role, e.g. `mailbox INBOX (inbox)`.
+pub fn mailbox_mapping(
+    jmap_mailboxes: &HashMap<jmap::Id, jmap::Mailbox>,
+    id: &jmap::Id,
+    config: &Config,
+) -> String {
+    let mut mapping = format!(
+        "mailbox {}",
+        remote::mailbox_path(jmap_mailboxes, id, &config.tags.directory_separator)
+            .unwrap_or_else(|| id.to_string())
+    );
+    if let Some(role) = jmap_mailboxes.get(id).and_then(|x| x.role) {
+        mapping.push_str(&format!(" ({})", format!("{role:?}").to_lowercase()));
+    }
+    mapping
+}
+
+/// Return each tag which corresponds to a keyword rather than a mailbox, and a description of
+/// that keyword, e.g. `keyword $flagged`.
+pub fn keyword_mappings<'a>(
+    config: &'a Config,
+    mailboxes: &remote::Mailboxes,
+) -> Vec<(&'a str, String)> {
+    let tags = &config.tags;
+    let roles = &mailboxes.roles;
+    let mut mappings: Vec<(&str, String)> = [
+        (&tags.replied, "$answered", false),
+        (&tags.passed, "$forwarded", false),
+        (&tags.draft, "$draft", roles.draft.is_some()),
+        (&tags.flagged, "$flagged", roles.flagged.is_some()),
+        (&tags.important, "$important", roles.important.is_some()),
+        (&tags.phishing, "$phishing", false),
+        (&tags.spam, "$junk", roles.spam.is_some()),
+    ]
+    .into_iter()
+    .filter(|(tag, _, has_role)| !tag.is_empty() && !has_role)
+    .map(|(tag, keyword, _)| (tag.as_str(), format!("keyword {keyword}")))
+    .collect();
+    mappings.push((&tags.unread, "keyword $seen, absent".to_owned()));
+    mappings
+}
+
+/// Return the Message-ID addressed by a notmuch search term like `id:1234@example.com`.
+pub fn parse_query(query: &str) -> Result<&str> {
+    let message_id = query
+        .strip_prefix("id:")
+        .map(|x| x.trim_matches('"'))
+        .filter(|x| !x.is_empty())
+        .context(UnsupportedQuerySnafu { query })?;
+    Ok(message_id
+        .strip_prefix('<')
+        .and_then(|x| x.strip_suffix('>'))
+        .unwrap_or(message_id))
+}
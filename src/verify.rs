@@ -0,0 +1,182 @@
+use crate::config::Config;
+use crate::crypto;
+use crate::jmap;
+use crate::local::{self, Local};
+use crate::lock::{self, Lock};
+use crate::remote::{self, Remote};
+use crate::sync::{self, LatestState};
+use snafu::prelude::*;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not lock `{}': {}", path.to_string_lossy(), source))]
+    Lock { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Could not open local database: {}", source))]
+    OpenLocal { source: local::Error },
+
+    #[snafu(display("Could not index local emails: {}", source))]
+    IndexLocalEmails { source: local::Error },
+
+    #[snafu(display("Could not index mail files in maildir: {}", source))]
+    IndexMailFiles { source: local::Error },
+
+    #[snafu(display("Could not open remote session: {}", source))]
+    OpenRemote { source: remote::Error },
+
+    #[snafu(display("Could not index mailboxes: {}", source))]
+    IndexMailboxes { source: remote::Error },
+
+    #[snafu(display("Could not index all remote email IDs: {}", source))]
+    IndexRemoteEmails { source: remote::Error },
+
+    #[snafu(display("Could not retrieve email properties from remote: {}", source))]
+    GetRemoteEmails { source: remote::Error },
+
+    #[snafu(display("Could not open encryption key: {}", source))]
+    OpenKey { source: sync::Error },
+
+    #[snafu(display("Could not queue repairs: {}", source))]
+    QueueRepairs { source: sync::Error },
+
+    #[snafu(display("Found {} inconsistencies between notmuch and the JMAP server", count))]
+    Inconsistent { count: usize },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Compare the local maildir and notmuch database against the JMAP server without changing
+/// either, and return every inconsistency found, sorted.
+///
+/// If `repair` is true and there are any, the next sync is made to be a full sync, which
+/// reconciles everything except for orphaned files and missing files.
+pub fn verify(
+    mail_dir: PathBuf,
+    config: Config,
+    dry_run: bool,
+    repair: bool,
+) -> Result<Vec<String>> {
+    let local = Local::open(&mail_dir, true, &config).context(OpenLocalSnafu {})?;
+    let local_emails = local.all_emails().context(IndexLocalEmailsSnafu {})?;
+    let mail_files = local.all_mail_files().context(IndexMailFilesSnafu {})?;
+    // Emails changed locally since the last sync have tag changes which are yet to be pushed, so
+    // their tags are expected to differ.
+    let key = sync::open_key(&mail_dir, &config).context(OpenKeySnafu {})?;
+    let pending_ids: HashSet<jmap::Id> =
+        match LatestState::open(mail_dir.join(sync::STATE_FILE), key.as_ref())
+            .ok()
+            .and_then(|latest_state| latest_state.notmuch_revision)
+        {
+            Some(notmuch_revision) => local
+                .all_emails_since(notmuch_revision)
+                .context(IndexLocalEmailsSnafu {})?
+                .into_keys()
+                .collect(),
+            None => HashSet::new(),
+        };
+
+    let mut remote = Remote::open(&config).context(OpenRemoteSnafu {})?;
+    let mailboxes = remote
+        .get_mailboxes(&config)
+        .context(IndexMailboxesSnafu {})?;
+    let (_, remote_ids) = remote.all_email_ids().context(IndexRemoteEmailsSnafu {})?;
+    let remote_emails = remote
+        .get_emails(remote_ids.iter(), &mailboxes, &config.tags)
+        .context(GetRemoteEmailsSnafu {})?;
+
+    let mut report = Vec::new();
+
+    // Files which notmuch has indexed but which no longer exist on disk.
+    for local_email in local_emails.values() {
+        if !local_email.path.exists() {
+            report.push(format!(
+                "missing file: {} `{}'",
+                local_email.id,
+                local_email.path.to_string_lossy()
+            ));
+        }
+    }
+
+    // Files in the maildir which notmuch has not indexed.
+    let indexed_paths: HashSet<&PathBuf> = local_emails.values().map(|x| &x.path).collect();
+    for path in mail_files.values() {
+        if !indexed_paths.contains(path) {
+            report.push(format!("orphaned file: `{}'", path.to_string_lossy()));
+        }
+    }
+
+    // Copies of a message share its tags in notmuch, so the tags of a message are compared against
+    // those of all of its copies on the server, as a sync would merge them.
+    let mut copies_by_message_id: HashMap<&str, Vec<&local::Email>> = HashMap::new();
+    for local_email in local_emails.values() {
+        copies_by_message_id
+            .entry(local_email.message_id.as_str())
+            .or_default()
+            .push(local_email);
+    }
+    for (message_id, copies) in copies_by_message_id {
+        let mut remote_copies = Vec::new();
+        // Messages with a copy whose blob or tags are yet to be synced are expected to differ.
+        let mut skip = false;
+        for local_email in &copies {
+            match remote_emails.get(&local_email.id) {
+                None => report.push(format!("missing on server: {}", local_email.id)),
+                Some(remote_email) if remote_email.blob_id != local_email.blob_id => {
+                    report.push(format!(
+                        "blob mismatch: {} local={} remote={}",
+                        local_email.id, local_email.blob_id, remote_email.blob_id
+                    ));
+                    skip = true;
+                }
+                Some(remote_email) => remote_copies.push(remote_email.local_tags(&mailboxes)),
+            }
+            skip |= pending_ids.contains(&local_email.id);
+        }
+        if skip || remote_copies.is_empty() {
+            continue;
+        }
+        let local_tags: BTreeSet<&str> = copies[0].tags.iter().map(|x| x.as_str()).collect();
+        let remote_tags: BTreeSet<&str> = sync::merged_tags(&remote_copies, &config.tags)
+            .into_iter()
+            .collect();
+        if local_tags != remote_tags {
+            report.push(format!(
+                "tag mismatch: {} local={:?} remote={:?}",
+                message_id, local_tags, remote_tags
+            ));
+        }
+    }
+
+    for remote_email in remote_emails.values() {
+        if !local_emails.contains_key(&remote_email.id) {
+            report.push(format!("missing locally: {}", remote_email.id));
+        }
+    }
+
+    report.sort();
+    if repair && !dry_run && !report.is_empty() {
+        queue_repairs(&mail_dir, config.fsync, key.as_ref())?;
+    }
+    Ok(report)
+}
+
+/// Forget the JMAP state so that the next sync considers every remote email. If there is no state
+/// file, the next sync is already a full sync.
+fn queue_repairs(mail_dir: &Path, fsync: bool, key: Option<&crypto::Key>) -> Result<()> {
+    let lock_file_path = mail_dir.join(lock::SYNC_LOCK);
+    let _lock = Lock::acquire(&lock_file_path).context(LockSnafu {
+        path: &lock_file_path,
+    })?;
+
+    let latest_state_filename = mail_dir.join(sync::STATE_FILE);
+    if let Ok(mut latest_state) = LatestState::open(&latest_state_filename, key) {
+        latest_state.jmap_state = None;
+        latest_state
+            .save(&latest_state_filename, fsync, key)
+            .context(QueueRepairsSnafu {})?;
+    }
+    Ok(())
+}